@@ -0,0 +1,98 @@
+//! Completion of hcloud actions. Assigning an IP only starts an action, which
+//! can still fail afterwards, e.g. while the server is locked by another
+//! action, so a move is only reported done once its action succeeded and is
+//! started again when it conflicted with another action.
+
+use crate::{hcloud_api, Error};
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::action::Status;
+use hcloud::models::Action;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Longest an action may keep running before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How often an action is started before its conflicts are reported.
+const ATTEMPTS: u32 = 5;
+
+fn is_conflict_code(code: &str) -> bool {
+    matches!(code, "locked" | "conflict")
+}
+
+fn is_conflict_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::LOCKED || status == reqwest::StatusCode::CONFLICT
+}
+
+/// Polls `action` until it finished, returning its error when it failed.
+async fn wait(
+    hcloud_conf: &Configuration,
+    mut action: Action,
+) -> Result<Option<hcloud::models::Error>, Error> {
+    let deadline = Instant::now() + TIMEOUT;
+    while action.status == Status::Running {
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "action {} ({}) still running after {:?}",
+                action.id, action.command, TIMEOUT
+            )
+            .into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        action = hcloud_api::api().get_action(hcloud_conf, action.id).await?;
+    }
+    Ok(match action.status {
+        Status::Success => None,
+        _ => Some(action.error.map(|err| *err).unwrap_or_else(|| {
+            hcloud::models::Error::new("unknown".into(), "action failed".into())
+        })),
+    })
+}
+
+/// Starts an action with `start` and waits for it to succeed, starting it
+/// again with a backoff while it fails on a locked resource or another
+/// conflicting action.
+pub async fn confirm<E, F, Fut>(
+    hcloud_conf: &Configuration,
+    what: &str,
+    mut start: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Action, hcloud::apis::Error<E>>>,
+    hcloud::apis::Error<E>: Into<Error>,
+{
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let conflict = match start().await {
+            Ok(action) => match wait(hcloud_conf, action).await? {
+                None => return Ok(()),
+                Some(err) if is_conflict_code(&err.code) => err.message,
+                Some(err) => {
+                    return Err(format!("{} failed: {} ({})", what, err.message, err.code).into())
+                }
+            },
+            Err(hcloud::apis::Error::ResponseError(content))
+                if is_conflict_status(content.status) =>
+            {
+                content.content
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if attempt == ATTEMPTS {
+            return Err(format!(
+                "{} still conflicting after {} attempts: {}",
+                what, ATTEMPTS, conflict
+            )
+            .into());
+        }
+        println!(
+            "{} conflicted with another action, retrying in {:?}: {}",
+            what, delay, conflict
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
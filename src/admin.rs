@@ -0,0 +1,212 @@
+//! Authenticated HTTP API for operators and automation to list where the
+//! floating IPs are and force a manual failover, without editing Kubernetes
+//! objects. It also serves the startup report, the reconcile traces and the
+//! debug state, which name nodes, servers and assignments.
+
+use crate::assign::find_floating_ip;
+use crate::mapping::{claimants, mappings};
+use crate::ownership;
+use crate::projects;
+use crate::throttle::ActionClass;
+use crate::{debug, startup, trace};
+use crate::{evacuation_reason, get_hc_server_id, move_floating_ip};
+use crate::{Context, Error};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use kube::ResourceExt;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    /// Expected as `Authorization: Bearer <token>`.
+    pub token: String,
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec_pretty(body).unwrap()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: impl ToString) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message.to_string() }))
+}
+
+/// Compares in constant time so the token can't be guessed byte by byte.
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let given = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Moves the floating IP `id` to the server of the node `target`, refusing
+/// nodes that would give it up right away.
+/// The percent-decoded value of the `name` query parameter of `req`.
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    form_urlencoded::parse(req.uri().query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+async fn force_move(ctx: &Context, id: &str, target: &str) -> Response<Body> {
+    let node = match ctx
+        .nodes
+        .state()
+        .into_iter()
+        .find(|node| node.name_any() == target)
+    {
+        Some(node) => node,
+        None => return error(StatusCode::NOT_FOUND, format!("unknown node {}", target)),
+    };
+    let server_id = match get_hc_server_id(&node) {
+        Some(server_id) => server_id,
+        None => {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("node {} is not an hcloud server", target),
+            )
+        }
+    };
+    if let Some(reason) = evacuation_reason(&node) {
+        return error(
+            StatusCode::CONFLICT,
+            format!("node {} is {}", target, reason),
+        );
+    }
+    let (project, fip) = match find_floating_ip(&ctx.projects, id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return error(StatusCode::NOT_FOUND, format!("unknown floating ip {}", id)),
+        Err(err) => return error(StatusCode::BAD_GATEWAY, err),
+    };
+    if let Some(reason) = ownership::blocker(&fip) {
+        return error(
+            StatusCode::CONFLICT,
+            format!("{} is not moved, {}", fip.ip, reason),
+        );
+    }
+    match projects::project_server_ids(&ctx.projects, project, &HashSet::from([server_id])).await {
+        Ok(owned) if owned.contains(&server_id) => {}
+        Ok(_) => {
+            return error(
+                StatusCode::CONFLICT,
+                format!(
+                    "node {} is not a server of project {}",
+                    target, project.name
+                ),
+            )
+        }
+        Err(err) => return error(StatusCode::BAD_GATEWAY, err),
+    }
+    if fip.server != Some(server_id) {
+        println!("moving {} to node {} on request", fip.ip, target);
+        match move_floating_ip(&project.conf(), &fip, server_id, ActionClass::Manual).await {
+            Ok(true) => {}
+            // Manual moves are only skipped when the IP moved since it was
+            // looked up.
+            Ok(false) => {
+                return error(
+                    StatusCode::CONFLICT,
+                    format!("{} is not moved, it was moved meanwhile", fip.ip),
+                )
+            }
+            Err(err) => return error(StatusCode::BAD_GATEWAY, err),
+        }
+    }
+    json(
+        StatusCode::OK,
+        &serde_json::json!({ "ip": fip.ip, "server": server_id, "node": target }),
+    )
+}
+
+async fn handle(req: Request<Body>, ctx: Arc<Context>, token: Arc<String>) -> Response<Body> {
+    if !is_authorized(&req, &token) {
+        return error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+    }
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["v1", "assignments"]) => match mappings(
+            &ctx.projects,
+            ctx.nodes.state(),
+            &claimants(ctx.services.state()),
+        )
+        .await
+        {
+            Ok(assignments) => json(StatusCode::OK, &assignments),
+            Err(err) => error(StatusCode::BAD_GATEWAY, err),
+        },
+        (&Method::POST, ["v1", "assignments", id, "move"]) => match query_param(&req, "target") {
+            Some(target) if !target.is_empty() => force_move(&ctx, id, &target).await,
+            _ => error(StatusCode::BAD_REQUEST, "missing target node"),
+        },
+        (&Method::GET, ["v1", "startup-report"]) => match startup::report() {
+            Some(report) => json(StatusCode::OK, report),
+            None => error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "startup reconcile not finished yet",
+            ),
+        },
+        (&Method::GET, ["v1", "traces"]) => {
+            let resource = query_param(&req, "resource");
+            json(StatusCode::OK, &trace::recent(resource.as_deref()))
+        }
+        (&Method::GET, ["v1", "debug", "state"]) => json(StatusCode::OK, &debug::state()),
+        (_, ["v1", "assignments"])
+        | (_, ["v1", "assignments", _, "move"])
+        | (_, ["v1", "startup-report"])
+        | (_, ["v1", "traces"])
+        | (_, ["v1", "debug", "state"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Serves the admin API on `config.addr` until the process exits.
+pub async fn serve(config: AdminConfig, ctx: Arc<Context>) -> Result<(), Error> {
+    let token = Arc::new(config.token);
+    let make_service = make_service_fn(move |_conn| {
+        let ctx = ctx.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let ctx = ctx.clone();
+                let token = token.clone();
+                async move { Ok::<_, Infallible>(handle(req, ctx, token).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&config.addr)?.serve(make_service);
+    println!("serving the admin api on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_are_decoded() {
+        let req = Request::get("/v1/assignments/web/move?force=1&target=worker%2D1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(query_param(&req, "target").as_deref(), Some("worker-1"));
+        assert_eq!(query_param(&req, "resource"), None);
+    }
+}
@@ -0,0 +1,363 @@
+//! Optional validating admission webhook for the annotations the controller
+//! reads, so a typo or a reference to a floating IP that does not exist is
+//! rejected at apply time instead of being ignored with a log line.
+
+use crate::agent::SERVER_ID_ANNOTATION;
+use crate::assign::find_floating_ip;
+use crate::drain::DRAIN_DELAY_ANNOTATION;
+use crate::dual_stack::is_in;
+use crate::egress::EGRESS_IPS_ANNOTATION;
+use crate::load_balancer::{BACKENDS, BACKEND_ANNOTATION, TYPE_ANNOTATION};
+use crate::node_exclude::EXCLUDE_ANNOTATION;
+use crate::pause::PAUSED_ANNOTATION;
+use crate::pin::{self, IP_ANNOTATION};
+use crate::priority::PRIORITY_ANNOTATION;
+use crate::projects::Project;
+use crate::provision::LOCATION_ANNOTATION;
+use crate::rdns::{is_hostname, RDNS_ANNOTATION};
+use crate::{fetch_floating_ips, is_load_balancer, Error};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
+use kube::core::DynamicObject;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+
+const ANNOTATION_PREFIX: &str = "fip.hcloud.barodeur.io/";
+
+type Check = fn(&str) -> Result<(), String>;
+
+/// Annotations read from Services, with the check of their value.
+const SERVICE_ANNOTATIONS: &[(&str, Check)] = &[
+    (DRAIN_DELAY_ANNOTATION, seconds),
+    (LOCATION_ANNOTATION, not_empty),
+    (RDNS_ANNOTATION, hostname),
+    (BACKEND_ANNOTATION, backend),
+    (TYPE_ANNOTATION, not_empty),
+    (IP_ANNOTATION, not_empty),
+    (PAUSED_ANNOTATION, boolean),
+];
+
+/// Annotations read from Nodes, with the check of their value.
+const NODE_ANNOTATIONS: &[(&str, Check)] = &[
+    (PRIORITY_ANNOTATION, integer),
+    (SERVER_ID_ANNOTATION, integer),
+    (EXCLUDE_ANNOTATION, boolean),
+    (EGRESS_IPS_ANNOTATION, ip_list),
+];
+
+#[derive(Debug, Clone)]
+pub struct AdmissionConfig {
+    pub addr: SocketAddr,
+    pub tls_cert: PathBuf,
+    pub tls_key: PathBuf,
+}
+
+fn seconds(value: &str) -> Result<(), String> {
+    value
+        .trim()
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| "expected a number of seconds".into())
+}
+
+fn not_empty(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("expected a value".into());
+    }
+    Ok(())
+}
+
+fn hostname(value: &str) -> Result<(), String> {
+    if !is_hostname(value.trim()) {
+        return Err("expected a hostname".into());
+    }
+    Ok(())
+}
+
+fn backend(value: &str) -> Result<(), String> {
+    if !BACKENDS.contains(&value.trim()) {
+        return Err(format!("expected one of {}", BACKENDS.join(", ")));
+    }
+    Ok(())
+}
+
+fn integer(value: &str) -> Result<(), String> {
+    value
+        .trim()
+        .parse::<i32>()
+        .map(|_| ())
+        .map_err(|_| "expected an integer".into())
+}
+
+fn boolean(value: &str) -> Result<(), String> {
+    value
+        .trim()
+        .to_ascii_lowercase()
+        .parse::<bool>()
+        .map(|_| ())
+        .map_err(|_| "expected true or false".into())
+}
+
+/// Comma separated IPs, IPv6 ones possibly with their prefix length.
+fn ip_list(value: &str) -> Result<(), String> {
+    for ip in value.split(',') {
+        let address = ip.trim().split('/').next().unwrap_or_default();
+        if address.parse::<IpAddr>().is_err() {
+            return Err(format!("expected comma separated IPs, got {:?}", ip));
+        }
+    }
+    Ok(())
+}
+
+fn check_annotations(
+    annotations: Option<&BTreeMap<String, String>>,
+    known: &[(&str, Check)],
+) -> Vec<String> {
+    let mut errors = vec![];
+    for (key, value) in annotations.into_iter().flatten() {
+        if !key.starts_with(ANNOTATION_PREFIX) {
+            continue;
+        }
+        match known.iter().find(|(name, _)| name == key) {
+            Some((_, check)) => {
+                if let Err(err) = check(value) {
+                    errors.push(format!("invalid {} annotation {:?}: {}", key, value, err));
+                }
+            }
+            None => errors.push(format!(
+                "unknown annotation {}, expected one of {}",
+                key,
+                known
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+    errors
+}
+
+async fn check_load_balancer_ip(
+    service: &KubeService,
+    projects: &[Project],
+) -> Result<Option<String>, Error> {
+    let ip = match service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.load_balancer_ip.as_deref())
+    {
+        Some(ip) if is_load_balancer(service) => ip,
+        _ => return Ok(None),
+    };
+    let address: IpAddr = match ip.parse() {
+        Ok(address) => address,
+        Err(_) => return Ok(Some(format!("invalid loadBalancerIP {:?}", ip))),
+    };
+    for project in projects {
+        let fips = fetch_floating_ips(&project.conf()).await?;
+        if fips.iter().any(|fip| is_in(address, &fip.ip)) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(format!(
+        "loadBalancerIP {} is not a floating IP of any project",
+        ip
+    )))
+}
+
+async fn check_pinned_ip(
+    service: &KubeService,
+    projects: &[Project],
+) -> Result<Option<String>, Error> {
+    let id = match pin::pinned(service) {
+        Some(id) => id.trim(),
+        None => return Ok(None),
+    };
+    Ok(find_floating_ip(projects, id).await?.is_none().then(|| {
+        format!(
+            "{} {} is not a floating IP of any project",
+            IP_ANNOTATION, id
+        )
+    }))
+}
+
+fn parse<T: DeserializeOwned>(object: &DynamicObject) -> Result<T, Error> {
+    Ok(serde_json::from_value(serde_json::to_value(object)?)?)
+}
+
+async fn review(request: &AdmissionRequest<DynamicObject>, projects: &[Project]) -> Vec<String> {
+    let object = match &request.object {
+        Some(object) => object,
+        None => return vec![],
+    };
+    let result = match request.kind.kind.as_str() {
+        "Service" => match parse::<KubeService>(object) {
+            Ok(service) => {
+                let mut errors =
+                    check_annotations(service.metadata.annotations.as_ref(), SERVICE_ANNOTATIONS);
+                let checks = [
+                    check_load_balancer_ip(&service, projects).await,
+                    check_pinned_ip(&service, projects).await,
+                ];
+                for check in checks {
+                    match check {
+                        Ok(error) => errors.extend(error),
+                        // Unknown IPs are only rejected when the floating IPs
+                        // could be listed.
+                        Err(err) => println!(
+                            "admission: failed to list floating ips, allowing {}: {}",
+                            request.name, err
+                        ),
+                    }
+                }
+                Ok(errors)
+            }
+            Err(err) => Err(err),
+        },
+        "Node" => parse::<KubeNode>(object)
+            .map(|node| check_annotations(node.metadata.annotations.as_ref(), NODE_ANNOTATIONS)),
+        _ => Ok(vec![]),
+    };
+    result.unwrap_or_else(|err| vec![format!("failed to decode the object: {}", err)])
+}
+
+async fn handle(req: Request<Body>, projects: Arc<Vec<Project>>) -> Response<Body> {
+    if req.method() != Method::POST {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(err) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(err.to_string()))
+                .unwrap()
+        }
+    };
+    let request: Result<AdmissionRequest<DynamicObject>, Error> =
+        serde_json::from_slice::<AdmissionReview<DynamicObject>>(&body)
+            .map_err(Error::from)
+            .and_then(|review| review.try_into().map_err(Error::from));
+    let response = match request {
+        Ok(request) => {
+            let errors = review(&request, &projects).await;
+            let response = AdmissionResponse::from(&request);
+            if errors.is_empty() {
+                response
+            } else {
+                println!(
+                    "admission: rejecting {} {}: {}",
+                    request.kind.kind,
+                    request.name,
+                    errors.join("; ")
+                );
+                response.deny(errors.join("; "))
+            }
+        }
+        Err(err) => AdmissionResponse::invalid(err),
+    };
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(
+            serde_json::to_vec(&response.into_review()).unwrap(),
+        ))
+        .unwrap()
+}
+
+/// The TLS key pair is read for every connection, so a renewed certificate
+/// is served without a restart.
+fn acceptor(config: &AdmissionConfig) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate_chain_file(&config.tls_cert)?;
+    builder.set_private_key_file(&config.tls_key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+/// Serves the webhook over HTTPS on `config.addr`, on every replica.
+pub async fn serve(config: AdmissionConfig, projects: Vec<Project>) -> Result<(), Error> {
+    // Fail at startup on an unusable key pair.
+    acceptor(&config)?;
+    let listener = TcpListener::bind(config.addr).await?;
+    println!("serving admission webhook on {}", config.addr);
+    let projects = Arc::new(projects);
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let config = config.clone();
+        let projects = projects.clone();
+        tokio::spawn(async move {
+            let result: Result<(), Error> = async {
+                let ssl = Ssl::new(acceptor(&config)?.context())?;
+                let mut stream = SslStream::new(ssl, tcp)?;
+                Pin::new(&mut stream).accept().await?;
+                // The API server closes connections without a TLS shutdown,
+                // failing the connection once the reviews are answered.
+                let _ = Http::new()
+                    .serve_connection(
+                        stream,
+                        service_fn(move |req| {
+                            let projects = projects.clone();
+                            async move { Ok::<_, Infallible>(handle(req, projects).await) }
+                        }),
+                    )
+                    .await;
+                Ok(())
+            }
+            .await;
+            if let Err(err) = result {
+                println!("admission connection from {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn service_annotations() {
+        let valid = annotations(&[(PAUSED_ANNOTATION, "true"), (DRAIN_DELAY_ANNOTATION, "30")]);
+        assert!(check_annotations(Some(&valid), SERVICE_ANNOTATIONS).is_empty());
+
+        let invalid = annotations(&[
+            (PAUSED_ANNOTATION, "yes"),
+            ("fip.hcloud.barodeur.io/drain-dealy", "30"),
+        ]);
+        assert_eq!(
+            check_annotations(Some(&invalid), SERVICE_ANNOTATIONS).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn node_annotations() {
+        let valid = annotations(&[(EGRESS_IPS_ANNOTATION, "198.51.100.1,2001:db8::/64")]);
+        assert!(check_annotations(Some(&valid), NODE_ANNOTATIONS).is_empty());
+
+        let invalid = annotations(&[(EGRESS_IPS_ANNOTATION, "198.51.100.1,egress")]);
+        assert_eq!(check_annotations(Some(&invalid), NODE_ANNOTATIONS).len(), 1);
+    }
+}
@@ -0,0 +1,69 @@
+//! Node-local agent, run as a DaemonSet, that reads the ID of the server it
+//! runs on from the hcloud metadata service and annotates its Node with it,
+//! so nodes without an `hcloud://` provider ID are managed without any
+//! configuration.
+
+use crate::clusters;
+use crate::shutdown::Shutdown;
+use crate::Error;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, ResourceExt};
+use std::time::Duration;
+
+/// Server ID of a node, used when its provider ID isn't an hcloud one.
+pub const SERVER_ID_ANNOTATION: &str = "fip.hcloud.barodeur.io/server-id";
+
+const METADATA_URL: &str = "http://169.254.169.254/hetzner/v1/metadata/instance-id";
+
+/// How often the annotation is checked again, in case it was removed.
+const INTERVAL: Duration = Duration::from_secs(300);
+
+/// The ID of the server this runs on.
+pub async fn server_id() -> Result<i32, Error> {
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?
+        .get(METADATA_URL)
+        .send()
+        .await
+        .map_err(|err| format!("failed to reach the hcloud metadata service: {}", err))?
+        .error_for_status()?;
+    let body = response.text().await?;
+    body.trim()
+        .parse()
+        .map_err(|_| format!("invalid server id {:?} from the metadata service", body).into())
+}
+
+async fn annotate(nodes_api: &Api<KubeNode>, node_name: &str, server_id: i32) -> Result<(), Error> {
+    let node = nodes_api.get(node_name).await?;
+    if node.annotations().get(SERVER_ID_ANNOTATION) == Some(&server_id.to_string()) {
+        return Ok(());
+    }
+    println!("annotating node {} with server id {}", node_name, server_id);
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { SERVER_ID_ANNOTATION: server_id.to_string() } }
+    });
+    nodes_api
+        .patch(node_name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    Ok(())
+}
+
+/// Keeps `node_name` annotated with the local server ID until shutdown.
+pub async fn run(node_name: &str) -> Result<(), Error> {
+    let server_id = server_id().await?;
+    println!("running on server {}", server_id);
+    let nodes_api = Api::<KubeNode>::all(clusters::local_client().await?);
+    let mut shutdown = Shutdown::listen();
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return Ok(()),
+        }
+        if let Err(err) = annotate(&nodes_api, node_name, server_id).await {
+            println!("failed to annotate node {}: {}", node_name, err);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{actions, hcloud_api, is_dry_run, throttle, trace, Error};
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::Server;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// An alias IP on an hcloud private network. It is failed over between servers
+/// attached to that network the same way floating IPs are.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AliasIp {
+    pub network: i32,
+    pub ip: String,
+}
+
+impl FromStr for AliasIp {
+    type Err = String;
+
+    /// Parses `<network id>:<ip>`, e.g. `1234:10.0.0.100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, ip) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("invalid alias ip {:?}, expected <network>:<ip>", s))?;
+        let network = network
+            .parse::<i32>()
+            .map_err(|_| format!("invalid network id in alias ip {:?}", s))?;
+        Ok(AliasIp {
+            network,
+            ip: ip.to_string(),
+        })
+    }
+}
+
+fn alias_ips_on(server: &Server, network: i32) -> Option<Vec<String>> {
+    server
+        .private_net
+        .iter()
+        .find(|net| net.network == Some(network))
+        .map(|net| net.alias_ips.clone().unwrap_or_default())
+}
+
+/// Returns the server currently holding the alias IP, if any.
+pub fn find_holder<'a>(servers: &'a [Server], alias: &AliasIp) -> Option<&'a Server> {
+    servers.iter().find(|server| {
+        alias_ips_on(server, alias.network)
+            .map(|ips| ips.contains(&alias.ip))
+            .unwrap_or(false)
+    })
+}
+
+/// Returns the subset of `server_ids` attached to the alias IP's network.
+pub fn attached_server_ids(
+    servers: &[Server],
+    alias: &AliasIp,
+    server_ids: &HashSet<i32>,
+) -> Vec<i32> {
+    servers
+        .iter()
+        .filter(|server| server_ids.contains(&server.id))
+        .filter(|server| alias_ips_on(server, alias.network).is_some())
+        .map(|server| server.id)
+        .collect()
+}
+
+async fn change_alias_ips(
+    hcloud_conf: &Configuration,
+    server_id: i32,
+    network: i32,
+    alias_ips: Vec<String>,
+) -> Result<(), Error> {
+    let what = format!("changing the alias ips of {}", server_id);
+    actions::confirm(hcloud_conf, &what, || {
+        let alias_ips = alias_ips.clone();
+        async move {
+            let result = hcloud_api::api()
+                .change_alias_ips(hcloud_conf, server_id, network, alias_ips)
+                .await;
+            if let Err(hcloud::apis::Error::ResponseError(content)) = &result {
+                if content.status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    throttle::throttled();
+                }
+            }
+            result
+        }
+    })
+    .await
+}
+
+/// Moves the alias IP from its current holder (if any) to `server_id`.
+///
+/// The IP has to be released by the previous holder first, hcloud refuses to
+/// have the same alias IP on two servers of a network. It is given back to
+/// the previous holder when the target can't take it.
+pub async fn move_alias_ip(
+    hcloud_conf: &Configuration,
+    servers: &[Server],
+    alias: &AliasIp,
+    server_id: i32,
+) -> Result<(), Error> {
+    let holder = find_holder(servers, alias);
+    if holder.map(|holder| holder.id) == Some(server_id) {
+        return Ok(());
+    }
+    let target = servers
+        .iter()
+        .find(|server| server.id == server_id)
+        .ok_or_else(|| format!("server {} not found", server_id))?;
+    let mut alias_ips = alias_ips_on(target, alias.network).ok_or_else(|| {
+        format!(
+            "server {} is not attached to network {}",
+            server_id, alias.network
+        )
+    })?;
+    alias_ips.push(alias.ip.clone());
+
+    if is_dry_run() {
+        println!(
+            "dry run: would assign alias ip {} to {}",
+            alias.ip, server_id
+        );
+        trace::record(format!(
+            "dry run: assign alias ip {} to {}",
+            alias.ip, server_id
+        ));
+        return Ok(());
+    }
+    println!("assigning alias ip {} to {}", alias.ip, server_id);
+    trace::record(format!("assign alias ip {} to {}", alias.ip, server_id));
+
+    let held = holder.map(|holder| {
+        (
+            holder.id,
+            alias_ips_on(holder, alias.network).unwrap_or_default(),
+        )
+    });
+    if let Some((holder_id, held_ips)) = &held {
+        let remaining = held_ips
+            .iter()
+            .filter(|ip| *ip != &alias.ip)
+            .cloned()
+            .collect();
+        change_alias_ips(hcloud_conf, *holder_id, alias.network, remaining).await?;
+    }
+    let result = change_alias_ips(hcloud_conf, server_id, alias.network, alias_ips).await;
+    if let (Err(err), Some((holder_id, held_ips))) = (&result, held) {
+        println!(
+            "assigning alias ip {} to {} failed, giving it back to {}: {}",
+            alias.ip, server_id, holder_id, err
+        );
+        if let Err(err) = change_alias_ips(hcloud_conf, holder_id, alias.network, held_ips).await {
+            println!(
+                "giving alias ip {} back to {} failed: {}",
+                alias.ip, holder_id, err
+            );
+        }
+    }
+    result
+}
@@ -0,0 +1,114 @@
+//! One-off manual assignment of a floating IP to a node or server, validated
+//! like the controller would place it, for drills and emergency moves.
+
+use crate::clusters;
+use crate::ownership;
+use crate::projects::{self, Project};
+use crate::throttle::ActionClass;
+use crate::{
+    evacuation_reason, fetch_floating_ips, get_hc_server_id, list_all, move_floating_ip, Error,
+};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::ListParams;
+use kube::{Api, ResourceExt};
+use std::collections::HashSet;
+
+/// Whether `id` names `fip` by its IP, its IPv6 network without the prefix
+/// length, or its name.
+pub(crate) fn identifies(fip: &FloatingIp, id: &str) -> bool {
+    fip.ip == id || fip.ip.split('/').next() == Some(id) || fip.name == id
+}
+
+/// The floating IP named by `id` in `projects`, with its project.
+pub async fn find_floating_ip<'a>(
+    projects: &'a [Project],
+    id: &str,
+) -> Result<Option<(&'a Project, FloatingIp)>, Error> {
+    for project in projects {
+        let fips = fetch_floating_ips(&project.conf()).await?;
+        if let Some(fip) = fips.into_iter().find(|fip| identifies(fip, id)) {
+            return Ok(Some((project, fip)));
+        }
+    }
+    Ok(None)
+}
+
+/// The server of `target`, a node name or server ID, refusing nodes the
+/// controller would move the IP off again unless `force` is set.
+async fn target_server(target: &str, force: bool) -> Result<i32, Error> {
+    let nodes = list_all(
+        &Api::<KubeNode>::all(clusters::local_client().await?),
+        &ListParams::default(),
+    )
+    .await?;
+    let node = match target.parse::<i32>() {
+        Ok(server_id) => nodes
+            .into_iter()
+            .find(|node| get_hc_server_id(node) == Some(server_id)),
+        Err(_) => Some(
+            nodes
+                .into_iter()
+                .find(|node| node.name_any() == target)
+                .ok_or_else(|| format!("unknown node {}", target))?,
+        ),
+    };
+    let node = match node {
+        Some(node) => node,
+        None if force => return Ok(target.parse().unwrap()),
+        None => {
+            return Err(format!(
+                "server {} is not a node of the cluster, pass --force to assign it anyway",
+                target
+            )
+            .into())
+        }
+    };
+    let server_id = get_hc_server_id(&node)
+        .ok_or_else(|| format!("node {} is not an hcloud server", node.name_any()))?;
+    match evacuation_reason(&node) {
+        Some(reason) if !force => Err(format!(
+            "node {} is {}, pass --force to assign it anyway",
+            node.name_any(),
+            reason
+        )
+        .into()),
+        _ => Ok(server_id),
+    }
+}
+
+/// Assigns the floating IP `fip` to `target`.
+pub async fn run(projects: &[Project], fip: &str, target: &str, force: bool) -> Result<(), Error> {
+    let (project, fip) = find_floating_ip(projects, fip)
+        .await?
+        .ok_or_else(|| format!("unknown floating ip {}", fip))?;
+    match ownership::blocker(&fip) {
+        Some(reason) if !force => {
+            return Err(format!(
+                "{} is not moved, {}, pass --force to move it anyway",
+                fip.ip, reason
+            )
+            .into())
+        }
+        _ => {}
+    }
+    let server_id = target_server(target, force).await?;
+    let owned =
+        projects::project_server_ids(projects, project, &HashSet::from([server_id])).await?;
+    if !owned.contains(&server_id) {
+        return Err(format!(
+            "server {} is not in project {} of {}",
+            server_id, project.name, fip.ip
+        )
+        .into());
+    }
+    if fip.server == Some(server_id) {
+        eprintln!("{} is already assigned to {}", fip.ip, server_id);
+        return Ok(());
+    }
+    eprintln!("{}: {:?} -> {}", fip.ip, fip.server, server_id);
+    if !move_floating_ip(&project.conf(), &fip, server_id, ActionClass::Manual).await? {
+        return Err(format!("{} is not moved, it was moved meanwhile", fip.ip).into());
+    }
+    Ok(())
+}
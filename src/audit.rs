@@ -0,0 +1,133 @@
+//! Append-only audit log of the assignment decisions, one JSON document per
+//! line, kept apart from the regular logs for post-incident analysis.
+
+use crate::{is_dry_run, trace, Error};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+static LOG: OnceCell<Mutex<Box<dyn Write + Send>>> = OnceCell::new();
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry<'a> {
+    pub timestamp: String,
+    /// Key of the reconciled object that led to the decision, e.g.
+    /// `node/worker-1`, none for rotation, canary and verification.
+    pub trigger: Option<String>,
+    pub ip: &'a str,
+    /// hcloud server ID, or server IP for Robot failover IPs.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Why the IP was moved: `failover`, `reassign`, `rebalance` or
+    /// `release`.
+    pub strategy: &'a str,
+    /// `moved`, `dry-run`, `unassigned`, `deleted`, `failed: <error>` or
+    /// `skipped: <reason>`.
+    pub outcome: String,
+}
+
+/// Appends the audit log to `path`, or writes it to standard output for `-`.
+pub fn open(path: &Path) -> Result<(), Error> {
+    let log: Box<dyn Write + Send> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| format!("failed to open {}: {}", path.display(), err))?,
+        )
+    };
+    let _ = LOG.set(Mutex::new(log));
+    Ok(())
+}
+
+/// Records a decision about `ip`, ignored without an audit log.
+fn record(
+    ip: &str,
+    from: Option<impl ToString>,
+    to: Option<impl ToString>,
+    strategy: &str,
+    outcome: String,
+) {
+    let log = match LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+    let entry = Entry {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        trigger: trace::current_resource(),
+        ip,
+        from: from.map(|from| from.to_string()),
+        to: to.map(|to| to.to_string()),
+        strategy,
+        outcome,
+    };
+    let mut log = log.lock().unwrap();
+    let result =
+        writeln!(log, "{}", serde_json::to_string(&entry).unwrap()).and_then(|_| log.flush());
+    if let Err(err) = result {
+        println!("failed to write the audit log: {}", err);
+    }
+}
+
+/// Records the move of `ip` to `to` and how it went.
+pub fn moved(
+    ip: &str,
+    from: Option<impl ToString>,
+    to: impl ToString,
+    strategy: &str,
+    result: &Result<(), Error>,
+) {
+    let outcome = match result {
+        Ok(()) if is_dry_run() => "dry-run".into(),
+        Ok(()) => "moved".into(),
+        Err(err) => format!("failed: {}", err),
+    };
+    record(ip, from, Some(to), strategy, outcome);
+}
+
+/// Records that `ip` was unassigned from `from`.
+pub fn unassigned(
+    ip: &str,
+    from: Option<impl ToString>,
+    strategy: &str,
+    result: &Result<(), Error>,
+) {
+    let outcome = match result {
+        Ok(()) if is_dry_run() => "dry-run".into(),
+        Ok(()) => "unassigned".into(),
+        Err(err) => format!("failed: {}", err),
+    };
+    record(ip, from, None::<String>, strategy, outcome);
+}
+
+/// Records that `ip` was unassigned from `from`, or deleted, for a deleted
+/// Service.
+pub fn released(ip: &str, from: Option<impl ToString>, deleted: bool, result: &Result<(), Error>) {
+    if !deleted {
+        return unassigned(ip, from, "release", result);
+    }
+    let outcome = match result {
+        Ok(()) => "deleted".into(),
+        Err(err) => format!("failed: {}", err),
+    };
+    record(ip, from, None::<String>, "release", outcome);
+}
+
+/// Records that `ip` was deliberately left on `from`.
+pub fn skipped(ip: &str, from: Option<impl ToString>, strategy: &str, reason: &str) {
+    record(
+        ip,
+        from,
+        None::<String>,
+        strategy,
+        format!("skipped: {}", reason),
+    );
+}
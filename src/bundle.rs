@@ -0,0 +1,387 @@
+//! The failover policy as a single YAML bundle: the settings, the floating IPs
+//! pinned to Services, the node and floating IP pools and the Services holding
+//! their IPs in place, to replicate a failover setup to another cluster or
+//! keep it in Git.
+
+use crate::assign::find_floating_ip;
+use crate::config::Cli;
+use crate::pause::{self, PAUSED_ANNOTATION};
+use crate::pin::{self, IP_ANNOTATION};
+use crate::projects::Project;
+use crate::Error;
+use crate::{clusters, fetch_floating_ips, fip_cache, hcloud_api, is_dry_run, list_all, pools};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::{Api, Client as KubeClient, ResourceExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const API_VERSION: &str = "fip.hcloud.barodeur.io/v1";
+const KIND: &str = "PolicyBundle";
+
+/// The settings making up the failover policy, by argument ID with their
+/// environment variable and value delimiter: every setting except the ones
+/// whose values are hidden, i.e. credentials, and the path of the
+/// configuration file.
+fn policy_vars() -> Vec<(String, String, char)> {
+    Cli::command()
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_env_values_set() && arg.get_id() != "config")
+        .filter_map(|arg| {
+            let env = arg.get_env()?.to_str()?.to_string();
+            let delimiter = arg.get_value_delimiter().unwrap_or(',');
+            Some((arg.get_id().to_string(), env, delimiter))
+        })
+        .collect()
+}
+
+/// Quotes `value` for the `.env` format, so spaces, quotes and `$` survive.
+fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\\' | '"' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Node and floating IP pools, by node name and floating IP name, as set with
+/// `--node-pool-label` and `--fip-pool-label`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pools {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub nodes: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub floating_ips: BTreeMap<String, String>,
+}
+
+impl Pools {
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.floating_ips.is_empty()
+    }
+}
+
+/// The controller policy as a single YAML document.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyBundle {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+    /// The floating IP, by address or name, pinned to each Service by
+    /// `namespace/name`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pins: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Pools::is_empty")]
+    pub pools: Pools,
+    /// The paused Services by `namespace/name`, whose IPs are held where
+    /// they are.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub holds: Vec<String>,
+}
+
+fn service_name(service: &KubeService) -> String {
+    format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    )
+}
+
+impl PolicyBundle {
+    /// Collects the settings explicitly given on the command line or in the
+    /// environment, defaults are left out.
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let settings = policy_vars()
+            .into_iter()
+            .filter(|(id, _, _)| {
+                matches!(
+                    matches.value_source(id),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+            .filter_map(|(id, env, delimiter)| {
+                let values: Vec<String> = matches
+                    .get_raw(&id)?
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect();
+                Some((env, values.join(&delimiter.to_string())))
+            })
+            .collect();
+        PolicyBundle {
+            api_version: API_VERSION.into(),
+            kind: KIND.into(),
+            settings,
+            pins: BTreeMap::new(),
+            pools: Pools::default(),
+            holds: vec![],
+        }
+    }
+
+    /// Adds the pins and holds of `services`, and the pools of `nodes` and
+    /// `floating_ips` when pools are enabled.
+    fn collect(
+        &mut self,
+        services: &[KubeService],
+        nodes: &[KubeNode],
+        floating_ips: &[hcloud::models::FloatingIp],
+    ) {
+        for service in services {
+            if let Some(id) = pin::pinned(service) {
+                self.pins.insert(service_name(service), id.clone());
+            }
+            if pause::is_paused(service) {
+                self.holds.push(service_name(service));
+            }
+        }
+        if let Some((fip_label, node_label)) = pools::labels() {
+            for node in nodes {
+                if let Some(pool) = node.labels().get(node_label) {
+                    self.pools.nodes.insert(node.name_any(), pool.clone());
+                }
+            }
+            for fip in floating_ips {
+                if let Some(pool) = fip.labels.get(fip_label) {
+                    self.pools
+                        .floating_ips
+                        .insert(fip.name.clone(), pool.clone());
+                }
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.api_version != API_VERSION || self.kind != KIND {
+            return Err(format!(
+                "unsupported bundle {}/{}, expected {}/{}",
+                self.api_version, self.kind, API_VERSION, KIND
+            )
+            .into());
+        }
+        let vars = policy_vars();
+        if let Some(key) = self
+            .settings
+            .keys()
+            .find(|key| !vars.iter().any(|(_, env, _)| env == *key))
+        {
+            return Err(format!("unknown setting {} in bundle", key).into());
+        }
+        if let Some(name) = self
+            .pins
+            .keys()
+            .chain(&self.holds)
+            .find(|name| !name.contains('/'))
+        {
+            return Err(format!("service {} in bundle is not namespace/name", name).into());
+        }
+        if !self.pools.is_empty() && pools::labels().is_none() {
+            return Err("the bundle has pools, --fip-pool-label must be set to import them".into());
+        }
+        Ok(())
+    }
+
+    /// Renders the settings in the `.env` format read at startup.
+    pub fn to_dotenv(&self) -> String {
+        self.settings
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, quote(value)))
+            .collect()
+    }
+}
+
+/// Prints the policy of the current configuration and cluster as a YAML
+/// bundle.
+pub async fn export_config(matches: &ArgMatches, projects: &[Project]) -> Result<(), Error> {
+    let client = clusters::local_client().await?;
+    let services = list_all(
+        &Api::<KubeService>::all(client.clone()),
+        &ListParams::default(),
+    )
+    .await?;
+    let nodes = list_all(&Api::<KubeNode>::all(client), &ListParams::default()).await?;
+    let mut floating_ips = vec![];
+    for project in projects {
+        floating_ips.extend(fetch_floating_ips(&project.conf()).await?);
+    }
+    let mut bundle = PolicyBundle::from_matches(matches);
+    bundle.collect(&services, &nodes, &floating_ips);
+    print!("{}", serde_yaml::to_string(&bundle)?);
+    Ok(())
+}
+
+/// Sets the annotation `key` of the Service `name`, given as
+/// `namespace/name`.
+async fn annotate_service(
+    client: &KubeClient,
+    name: &str,
+    key: &str,
+    value: &str,
+) -> Result<(), Error> {
+    let (namespace, name) = clusters::namespaced_name(name);
+    if is_dry_run() {
+        eprintln!(
+            "dry run: would annotate service {}/{} {}={}",
+            namespace, name, key, value
+        );
+        return Ok(());
+    }
+    eprintln!(
+        "annotating service {}/{} {}={}",
+        namespace, name, key, value
+    );
+    let patch = serde_json::json!({ "metadata": { "annotations": { key: value } } });
+    Api::<KubeService>::namespaced(client.clone(), &namespace)
+        .patch(&name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    Ok(())
+}
+
+async fn label_node(client: &KubeClient, name: &str, key: &str, value: &str) -> Result<(), Error> {
+    if is_dry_run() {
+        eprintln!("dry run: would label node {} {}={}", name, key, value);
+        return Ok(());
+    }
+    eprintln!("labeling node {} {}={}", name, key, value);
+    let patch = serde_json::json!({ "metadata": { "labels": { key: value } } });
+    Api::<KubeNode>::all(client.clone())
+        .patch(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    Ok(())
+}
+
+async fn label_floating_ip(
+    projects: &[Project],
+    id: &str,
+    key: &str,
+    value: &str,
+) -> Result<(), Error> {
+    let (project, fip) = find_floating_ip(projects, id)
+        .await?
+        .ok_or_else(|| format!("unknown floating ip {}", id))?;
+    if fip.labels.get(key).map(String::as_str) == Some(value) {
+        return Ok(());
+    }
+    if is_dry_run() {
+        eprintln!("dry run: would label floating ip {} {}={}", id, key, value);
+        return Ok(());
+    }
+    eprintln!("labeling floating ip {} {}={}", id, key, value);
+    let mut labels = fip.labels;
+    labels.insert(key.into(), value.into());
+    let hcloud_conf = &project.conf();
+    hcloud_api::api()
+        .set_floating_ip_labels(hcloud_conf, fip.id, labels)
+        .await?;
+    fip_cache::invalidate(hcloud_conf);
+    Ok(())
+}
+
+/// Validates the bundle at `path`, applies its pins, pools and holds to the
+/// cluster and hcloud, and prints its settings as `.env` settings. Objects
+/// of the bundle that can't be applied are reported after the others are.
+pub async fn import_config(path: &Path, projects: &[Project]) -> Result<(), Error> {
+    let bundle: PolicyBundle = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+    bundle.validate()?;
+
+    let client = clusters::local_client().await?;
+    let mut failures = vec![];
+    for (service, id) in &bundle.pins {
+        if let Err(err) = annotate_service(&client, service, IP_ANNOTATION, id).await {
+            failures.push(format!("pin of service {}: {}", service, err));
+        }
+    }
+    for service in &bundle.holds {
+        if let Err(err) = annotate_service(&client, service, PAUSED_ANNOTATION, "true").await {
+            failures.push(format!("hold of service {}: {}", service, err));
+        }
+    }
+    if let Some((fip_label, node_label)) = pools::labels() {
+        for (node, pool) in &bundle.pools.nodes {
+            if let Err(err) = label_node(&client, node, node_label, pool).await {
+                failures.push(format!("pool of node {}: {}", node, err));
+            }
+        }
+        for (fip, pool) in &bundle.pools.floating_ips {
+            if let Err(err) = label_floating_ip(projects, fip, fip_label, pool).await {
+                failures.push(format!("pool of floating ip {}: {}", fip, err));
+            }
+        }
+    }
+
+    print!("{}", bundle.to_dotenv());
+    if !failures.is_empty() {
+        return Err(format!("failed to import {}", failures.join(", ")).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip_through_dotenv() {
+        let windows = "mon-fri 02:00-04:00;sat 01:00-03:00";
+        let matches = Cli::command().get_matches_from([
+            "hcloud-fip-controller",
+            "--maintenance-windows",
+            windows,
+            "--egress-ips=egress-1,egress-2",
+        ]);
+        let bundle = PolicyBundle::from_matches(&matches);
+        assert_eq!(bundle.settings["MAINTENANCE_WINDOWS"], windows);
+        assert_eq!(bundle.settings["EGRESS_IPS"], "egress-1,egress-2");
+
+        let path = std::env::temp_dir().join(format!("bundle-{}.env", std::process::id()));
+        fs::write(&path, bundle.to_dotenv()).unwrap();
+        // Unlike `from_path`, the iterator doesn't set the variables.
+        #[allow(deprecated)]
+        let parsed: BTreeMap<String, String> = dotenv::from_path_iter(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(parsed, bundle.settings);
+
+        // Read back as the environment would be, every value parses again.
+        let args = std::iter::once("hcloud-fip-controller".to_string()).chain(
+            policy_vars()
+                .into_iter()
+                .filter_map(|(id, env, _)| Some((id, parsed.get(&env)?.clone())))
+                .map(|(id, value)| {
+                    let arg = Cli::command()
+                        .get_arguments()
+                        .find(|arg| arg.get_id() == id.as_str())
+                        .unwrap()
+                        .get_long()
+                        .unwrap()
+                        .to_string();
+                    format!("--{}={}", arg, value)
+                }),
+        );
+        let reparsed = Cli::command().try_get_matches_from(args).unwrap();
+        assert_eq!(
+            PolicyBundle::from_matches(&reparsed).settings,
+            bundle.settings
+        );
+    }
+
+    #[test]
+    fn quotes_dotenv_values() {
+        assert_eq!(quote("a b"), r#""a b""#);
+        assert_eq!(quote(r#"say "$HOME\""#), r#""say \"\$HOME\\\"""#);
+    }
+}
@@ -0,0 +1,171 @@
+//! Self-test with a dedicated canary floating IP, periodically failed over
+//! between two nodes so broken tokens, permissions or routing show up before
+//! a real incident does.
+
+use crate::hcloud_api;
+use crate::health::HealthCheck;
+use crate::projects::{self, Project};
+use crate::shutdown::{self, Shutdown};
+use crate::throttle::ActionClass;
+use crate::{available_nodes, get_hc_server_id, metrics, move_floating_ip, Error};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use once_cell::sync::OnceCell;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+static CANARY_IP: OnceCell<String> = OnceCell::new();
+
+/// How long the canary IP gets to answer on its new node.
+const DATA_PATH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    pub ip: String,
+    pub interval: Duration,
+    /// Probed on the canary IP after each failover, the data path is not
+    /// checked without it.
+    pub health_check: Option<HealthCheck>,
+}
+
+/// Keeps the canary IP away from the regular reconciles.
+pub fn set_ip(ip: String) {
+    let _ = CANARY_IP.set(ip);
+}
+
+pub fn is_canary(fip: &FloatingIp) -> bool {
+    CANARY_IP.get() == Some(&fip.ip)
+}
+
+enum Outcome {
+    Moved,
+    Skipped(&'static str),
+}
+
+async fn find(projects: &[Project], ip: &str) -> Result<Option<(Project, FloatingIp)>, Error> {
+    for project in projects {
+        let fips = hcloud_api::api()
+            .list_floating_ips(&project.conf(), None)
+            .await?;
+        if let Some(fip) = fips.into_iter().find(|fip| fip.ip == ip) {
+            return Ok(Some((project.clone(), fip)));
+        }
+    }
+    Ok(None)
+}
+
+/// Waits for the canary IP to answer the health check.
+async fn probe_data_path(health_check: &HealthCheck, ip: IpAddr) -> Result<(), Error> {
+    let started = Instant::now();
+    while started.elapsed() < DATA_PATH_TIMEOUT {
+        if health_check.probe(ip).await {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Err(format!(
+        "canary ip {} did not answer within {:?}",
+        ip, DATA_PATH_TIMEOUT
+    )
+    .into())
+}
+
+/// Moves the canary IP to the other of the two lowest server IDs among the
+/// available nodes and measures how long it takes.
+async fn fail_over(
+    projects: &[Project],
+    nodes: &Store<KubeNode>,
+    config: &CanaryConfig,
+) -> Result<Outcome, Error> {
+    let (project, fip) = match find(projects, &config.ip).await? {
+        Some(found) => found,
+        None => return Err(format!("canary ip {} not found in any project", config.ip).into()),
+    };
+    let available: HashSet<i32> = available_nodes(nodes)
+        .iter()
+        .filter_map(get_hc_server_id)
+        .collect();
+    let mut server_ids: Vec<i32> = projects::project_server_ids(projects, &project, &available)
+        .await?
+        .into_iter()
+        .collect();
+    server_ids.sort();
+    if server_ids.len() < 2 {
+        return Ok(Outcome::Skipped("less than two eligible nodes"));
+    }
+    let target = if fip.server == Some(server_ids[0]) {
+        server_ids[1]
+    } else {
+        server_ids[0]
+    };
+
+    let started = Instant::now();
+    // A move held back proves nothing, the probe fails.
+    if !move_floating_ip(&project.conf(), &fip, target, ActionClass::Rebalance).await? {
+        return Err(format!("canary ip {} was not moved", fip.ip).into());
+    }
+    metrics::CANARY_SECONDS
+        .with_label_values(&["assign"])
+        .observe(started.elapsed().as_secs_f64());
+    if let Some(health_check) = &config.health_check {
+        probe_data_path(health_check, config.ip.parse()?).await?;
+        metrics::CANARY_SECONDS
+            .with_label_values(&["data_path"])
+            .observe(started.elapsed().as_secs_f64());
+    }
+    println!(
+        "canary ip {} failed over from {:?} to {} in {:?}",
+        fip.ip,
+        fip.server,
+        target,
+        started.elapsed()
+    );
+    Ok(Outcome::Moved)
+}
+
+/// Fails the canary IP over every `config.interval`, starting one interval
+/// after startup, until shutdown is requested.
+pub async fn run(
+    projects: Vec<Project>,
+    nodes: Store<KubeNode>,
+    config: CanaryConfig,
+    mut shutdown: Shutdown,
+    shutdown_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        let result = shutdown::run_graceful(
+            &mut shutdown,
+            shutdown_timeout,
+            "canary",
+            fail_over(&projects, &nodes, &config),
+        )
+        .await;
+        match result {
+            Some(Ok(Outcome::Moved)) => {
+                metrics::CANARY_RUNS.with_label_values(&["success"]).inc();
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                metrics::LAST_CANARY_SUCCESS.set(now.as_secs() as i64);
+            }
+            Some(Ok(Outcome::Skipped(reason))) => {
+                metrics::CANARY_RUNS.with_label_values(&["skipped"]).inc();
+                println!("canary skipped, {}", reason);
+            }
+            Some(Err(err)) => {
+                metrics::CANARY_RUNS.with_label_values(&["error"]).inc();
+                println!("canary failed: {}", err);
+            }
+            None => metrics::CANARY_RUNS.with_label_values(&["aborted"]).inc(),
+        }
+        if shutdown.is_requested() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Preflight checks of `hcloud-fip-controller check`, run before deploying
+//! the controller to a new cluster or from CI: every hcloud project and the
+//! cluster are reached the way the controller would, and each problem found
+//! is reported instead of stopping at the first one.
+
+use crate::clusters;
+use crate::config::{Config, Mode};
+use crate::projects::Project;
+use crate::{
+    fetch_floating_ips, fetch_servers, get_hc_server_id, get_robot_server_number, list_all, Error,
+};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::ListParams;
+use kube::{Api, ResourceExt};
+use std::collections::HashSet;
+use std::fmt::Display;
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&self, message: impl Display) {
+        println!("ok    {}", message);
+    }
+
+    fn fail(&mut self, message: impl Display) {
+        self.failures += 1;
+        println!("FAIL  {}", message);
+    }
+}
+
+/// The hcloud server IDs of every project, failing the projects that can't
+/// be listed.
+async fn check_projects(report: &mut Report, projects: &[Project]) -> HashSet<i32> {
+    let mut server_ids = HashSet::new();
+    for project in projects {
+        let hcloud_conf = &project.conf();
+        match fetch_servers(hcloud_conf).await {
+            Ok(servers) => {
+                report.ok(format!(
+                    "project {}: token valid, {} servers",
+                    project.name,
+                    servers.len()
+                ));
+                server_ids.extend(servers.iter().map(|server| server.id));
+            }
+            Err(err) => {
+                report.fail(format!(
+                    "project {}: listing servers: {}",
+                    project.name, err
+                ));
+                continue;
+            }
+        }
+        match fetch_floating_ips(hcloud_conf).await {
+            Ok(fips) if fips.is_empty() => report.fail(format!(
+                "project {}: no floating ip would be managed",
+                project.name
+            )),
+            Ok(fips) => {
+                let ips: Vec<_> = fips.iter().map(|fip| fip.ip.as_str()).collect();
+                report.ok(format!(
+                    "project {}: managing {} floating ips: {}",
+                    project.name,
+                    fips.len(),
+                    ips.join(", ")
+                ));
+            }
+            Err(err) => report.fail(format!(
+                "project {}: listing floating ips: {}",
+                project.name, err
+            )),
+        }
+    }
+    server_ids
+}
+
+/// Lists one `K` to check that the controller may list them.
+async fn check_access<K>(report: &mut Report, api: Api<K>, kind: &str)
+where
+    K: Clone + serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    match api.list(&ListParams::default().limit(1)).await {
+        Ok(_) => report.ok(format!("may list {}", kind)),
+        Err(err) => report.fail(format!("listing {}: {}", kind, err)),
+    }
+}
+
+fn check_nodes(report: &mut Report, nodes: &[KubeNode], server_ids: &HashSet<i32>, robot: bool) {
+    for node in nodes {
+        let name = node.name_any();
+        if let Some(server_id) = get_hc_server_id(node) {
+            if !server_ids.contains(&server_id) {
+                report.fail(format!(
+                    "node {}: server {} is in no project",
+                    name, server_id
+                ));
+            }
+            continue;
+        }
+        match get_robot_server_number(node) {
+            Some(_) if robot => {}
+            Some(number) => report.fail(format!(
+                "node {}: Robot server {} but no Robot credentials are set",
+                name, number
+            )),
+            None => report.fail(format!(
+                "node {}: no hcloud server id in provider id {:?}",
+                name,
+                node.spec
+                    .as_ref()
+                    .and_then(|spec| spec.provider_id.as_ref())
+            )),
+        }
+    }
+    report.ok(format!("checked the server ids of {} nodes", nodes.len()));
+}
+
+/// Runs every check, fails when any did. The cluster isn't checked in
+/// standalone mode.
+pub async fn run(projects: &[Project], config: &Config) -> Result<(), Error> {
+    let mut report = Report::default();
+    let server_ids = check_projects(&mut report, projects).await;
+    let robot = config.robot().is_some();
+    if config.mode == Mode::Standalone {
+        return finish(report);
+    }
+    match clusters::local_client().await {
+        Ok(client) => {
+            check_access(
+                &mut report,
+                Api::<KubeService>::all(client.clone()),
+                "services",
+            )
+            .await;
+            check_access(
+                &mut report,
+                Api::<EndpointSlice>::all(client.clone()),
+                "endpointslices",
+            )
+            .await;
+            match list_all(&Api::<KubeNode>::all(client), &ListParams::default()).await {
+                Ok(nodes) => {
+                    report.ok("may list nodes");
+                    check_nodes(&mut report, &nodes, &server_ids, robot);
+                }
+                Err(err) => report.fail(format!("listing nodes: {}", err)),
+            }
+        }
+        Err(err) => report.fail(format!("connecting to the cluster: {}", err)),
+    }
+    finish(report)
+}
+
+fn finish(report: Report) -> Result<(), Error> {
+    if report.failures > 0 {
+        return Err(format!("{} checks failed", report.failures).into());
+    }
+    println!("all checks passed");
+    Ok(())
+}
@@ -0,0 +1,508 @@
+//! Circuit breaker around the hcloud API. After `--hcloud-circuit-threshold`
+//! consecutive 5xx answers or timeouts the circuit opens: calls fail at once
+//! instead of piling up on an API that is down, the floating IP and server
+//! lists are served as they were last seen, and the controller reports itself
+//! degraded. Every `--hcloud-circuit-open` seconds one call is let through to
+//! probe the API, and its success closes the circuit.
+
+use crate::hcloud_api::{self, AliasIpsError, AssignError, HcloudApi, UnassignError};
+use crate::metrics;
+use crate::projects::Project;
+use crate::Error;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hcloud::apis::actions_api::GetActionError;
+use hcloud::apis::configuration::Configuration;
+use hcloud::apis::floating_ips_api::{
+    ChangeReverseDnsEntryForFloatingIpError, CreateFloatingIpError, DeleteFloatingIpError,
+    GetFloatingIpError, ListFloatingIpsError, ReplaceFloatingIpError,
+};
+use hcloud::apis::load_balancers_api::{
+    AddServiceError, AddTargetError, CreateLoadBalancerError, DeleteLoadBalancerError,
+    DeleteServiceError, ListLoadBalancersError, RemoveTargetError, UpdateServiceError,
+};
+use hcloud::apis::servers_api::ListServersError;
+use hcloud::models::{
+    Action, CreateFloatingIpRequest, CreateLoadBalancerRequest, FloatingIp, LoadBalancer,
+    LoadBalancerService, Server,
+};
+use k8s_openapi::chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const OPEN_MESSAGE: &str = "hcloud api circuit open";
+
+/// Consecutive outage answers opening the circuit, zero disables it.
+static THRESHOLD: AtomicU32 = AtomicU32::new(0);
+static OPEN_SECS: AtomicU64 = AtomicU64::new(30);
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    opened: Option<(Instant, DateTime<Utc>)>,
+    /// When the call probing an open circuit started.
+    probing: Option<Instant>,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(Default::default);
+
+/// API token, and label selector for floating IPs.
+type ListKey = (String, Option<String>);
+
+/// Last lists seen by project.
+static FLOATING_IPS: Lazy<Mutex<HashMap<ListKey, Vec<FloatingIp>>>> = Lazy::new(Default::default);
+static SERVERS: Lazy<Mutex<HashMap<String, Vec<Server>>>> = Lazy::new(Default::default);
+
+pub fn set_circuit(threshold: u32, open: Duration) {
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+    OPEN_SECS.store(open.as_secs(), Ordering::Relaxed);
+}
+
+fn open_for() -> Duration {
+    Duration::from_secs(OPEN_SECS.load(Ordering::Relaxed))
+}
+
+/// Since when the circuit is open, if it is.
+pub fn open_since() -> Option<DateTime<Utc>> {
+    STATE.lock().unwrap().opened.map(|(_, since)| since)
+}
+
+/// Whether a call may go to hcloud: always with a closed circuit, and as a
+/// probe once the circuit has been open long enough. A probe that never
+/// finished is replaced after the same time.
+fn admit() -> bool {
+    let mut state = STATE.lock().unwrap();
+    let opened_at = match state.opened {
+        Some((opened_at, _)) => opened_at,
+        None => return true,
+    };
+    let due = |since: Instant| since.elapsed() >= open_for();
+    if due(opened_at) && state.probing.map(due).unwrap_or(true) {
+        state.probing = Some(Instant::now());
+        return true;
+    }
+    false
+}
+
+fn settle(outage: bool) {
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return;
+    }
+    let mut state = STATE.lock().unwrap();
+    if !outage {
+        if state.opened.is_some() {
+            println!("hcloud api answered again, closing the circuit");
+            metrics::HCLOUD_CIRCUIT_OPEN.set(0);
+        }
+        *state = State::default();
+        return;
+    }
+    state.failures += 1;
+    if state.probing.take().is_some() {
+        if let Some(opened) = &mut state.opened {
+            println!("hcloud api probe failed, keeping the circuit open");
+            opened.0 = Instant::now();
+        }
+    } else if state.opened.is_none() && state.failures >= threshold {
+        println!(
+            "hcloud api failed {} times in a row, opening the circuit for {:?}",
+            state.failures,
+            open_for()
+        );
+        state.opened = Some((Instant::now(), Utc::now()));
+        metrics::HCLOUD_CIRCUIT_OPEN.set(1);
+    }
+}
+
+/// Whether `err` means hcloud is down or unreachable rather than refusing
+/// the call.
+fn is_outage_of<T>(err: &hcloud::apis::Error<T>) -> bool {
+    match err {
+        hcloud::apis::Error::Reqwest(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err
+                    .status()
+                    .map(|status| status.is_server_error())
+                    .unwrap_or(false)
+        }
+        hcloud::apis::Error::ResponseError(content) => content.status.is_server_error(),
+        _ => false,
+    }
+}
+
+fn is_outage(err: &Error) -> bool {
+    macro_rules! outage_of {
+        ($($error:ty),* $(,)?) => {
+            $(
+                if let Some(err) = err.downcast_ref::<hcloud::apis::Error<$error>>() {
+                    return is_outage_of(err);
+                }
+            )*
+        };
+    }
+    outage_of!(
+        ListFloatingIpsError,
+        GetFloatingIpError,
+        ReplaceFloatingIpError,
+        CreateFloatingIpError,
+        DeleteFloatingIpError,
+        ChangeReverseDnsEntryForFloatingIpError,
+        ListServersError,
+        ListLoadBalancersError,
+        CreateLoadBalancerError,
+        DeleteLoadBalancerError,
+        AddServiceError,
+        UpdateServiceError,
+        DeleteServiceError,
+        AddTargetError,
+        RemoveTargetError,
+        GetActionError,
+    );
+    false
+}
+
+/// Runs `call` unless the circuit is open, `None` when it is.
+async fn guarded<T, E>(
+    call: impl Future<Output = Result<T, E>>,
+    outage: impl Fn(&E) -> bool,
+) -> Option<Result<T, E>> {
+    if !admit() {
+        return None;
+    }
+    let result = call.await;
+    settle(matches!(&result, Err(err) if outage(err)));
+    Some(result)
+}
+
+fn token(conf: &Configuration) -> String {
+    conf.bearer_access_token.clone().unwrap_or_default()
+}
+
+/// Wraps an implementation with the circuit breaker.
+pub struct Breaker(pub Box<dyn HcloudApi>);
+
+impl HcloudApi for Breaker {
+    fn list_floating_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<FloatingIp>, Error>> {
+        async move {
+            let key = (token(conf), label_selector.clone());
+            match guarded(self.0.list_floating_ips(conf, label_selector), is_outage).await {
+                Some(Ok(fips)) => {
+                    FLOATING_IPS.lock().unwrap().insert(key, fips.clone());
+                    Ok(fips)
+                }
+                Some(Err(err)) => Err(err),
+                None => FLOATING_IPS
+                    .lock()
+                    .unwrap()
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| OPEN_MESSAGE.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            guarded(self.0.get_floating_ip(conf, id), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn assign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, AssignError>> {
+        async move {
+            guarded(self.0.assign_floating_ip(conf, id, server_id), is_outage_of)
+                .await
+                .unwrap_or_else(|| Err(io::Error::other(OPEN_MESSAGE).into()))
+        }
+        .boxed()
+    }
+
+    fn unassign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, UnassignError>> {
+        async move {
+            guarded(self.0.unassign_floating_ip(conf, id), is_outage_of)
+                .await
+                .unwrap_or_else(|| Err(io::Error::other(OPEN_MESSAGE).into()))
+        }
+        .boxed()
+    }
+
+    fn set_floating_ip_labels<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        labels: HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            guarded(self.0.set_floating_ip_labels(conf, id, labels), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn create_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateFloatingIpRequest,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            guarded(self.0.create_floating_ip(conf, request), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn delete_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            guarded(self.0.delete_floating_ip(conf, id), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn set_floating_ip_rdns<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        ip: String,
+        dns_ptr: Option<String>,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.set_floating_ip_rdns(conf, id, ip, dns_ptr),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn list_servers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+    ) -> BoxFuture<'a, Result<Vec<Server>, Error>> {
+        async move {
+            let key = token(conf);
+            match guarded(self.0.list_servers(conf), is_outage).await {
+                Some(Ok(servers)) => {
+                    SERVERS.lock().unwrap().insert(key, servers.clone());
+                    Ok(servers)
+                }
+                Some(Err(err)) => Err(err),
+                None => SERVERS
+                    .lock()
+                    .unwrap()
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| OPEN_MESSAGE.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn change_alias_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> BoxFuture<'a, Result<Action, AliasIpsError>> {
+        async move {
+            guarded(
+                self.0.change_alias_ips(conf, server_id, network, alias_ips),
+                is_outage_of,
+            )
+            .await
+            .unwrap_or_else(|| Err(io::Error::other(OPEN_MESSAGE).into()))
+        }
+        .boxed()
+    }
+
+    fn list_load_balancers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<LoadBalancer>, Error>> {
+        async move {
+            guarded(self.0.list_load_balancers(conf, label_selector), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn create_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateLoadBalancerRequest,
+    ) -> BoxFuture<'a, Result<LoadBalancer, Error>> {
+        async move {
+            guarded(self.0.create_load_balancer(conf, request), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn delete_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            guarded(self.0.delete_load_balancer(conf, id), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn add_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.add_load_balancer_service(conf, id, service),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn update_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.update_load_balancer_service(conf, id, service),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn delete_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        listen_port: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.delete_load_balancer_service(conf, id, listen_port),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn add_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.add_load_balancer_target(conf, id, server_id),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn remove_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(
+                self.0.remove_load_balancer_target(conf, id, server_id),
+                is_outage,
+            )
+            .await
+            .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+
+    fn get_action<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            guarded(self.0.get_action(conf, id), is_outage)
+                .await
+                .unwrap_or_else(|| Err(OPEN_MESSAGE.into()))
+        }
+        .boxed()
+    }
+}
+
+/// Probes the API with the first project while the circuit is open, so it
+/// closes even when no reconcile calls hcloud.
+pub async fn run(projects: Vec<Project>) {
+    let project = match projects.first() {
+        Some(project) => project,
+        None => return,
+    };
+    loop {
+        tokio::time::sleep(open_for()).await;
+        if open_since().is_some() {
+            let _ = hcloud_api::api()
+                .list_floating_ips(&project.conf(), None)
+                .await;
+        }
+    }
+}
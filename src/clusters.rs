@@ -0,0 +1,169 @@
+//! The clusters the controller talks to. This one is reached with the
+//! in-cluster config unless `--kubeconfig` or `--context` is set, e.g. to
+//! run the controller from a laptop or a management VM.
+//!
+//! Peer clusters share the hcloud projects, e.g. the other half of a
+//! blue/green pair. Their nodes and Services are watched with their own
+//! kubeconfig, and when no node of this cluster can take a floating IP it
+//! fails over to the nodes of the first peer, in the order given, that is
+//! available and claims the IP too. IPs move back once a node of this
+//! cluster is available again.
+
+use crate::conflicts::claimed_ips;
+use crate::{available_nodes, claims_ips, Error};
+use backoff::ExponentialBackoff;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::api::ListParams;
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::runtime::reflector::{self, Store};
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::{Api, Client as KubeClient};
+use once_cell::sync::OnceCell;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// `--kubeconfig` and `--context`.
+static LOCAL: OnceCell<(Option<PathBuf>, Option<String>)> = OnceCell::new();
+
+pub fn set_local(kubeconfig: Option<PathBuf>, context: Option<String>) {
+    let _ = LOCAL.set((kubeconfig, context));
+}
+
+/// Namespace and name of a `[NAMESPACE/]NAME` object of this cluster, in
+/// POD_NAMESPACE or default unless given.
+pub fn namespaced_name(object: &str) -> (String, String) {
+    match object.split_once('/') {
+        Some((namespace, name)) => (namespace.into(), name.into()),
+        None => (
+            env::var("POD_NAMESPACE").unwrap_or_else(|_| "default".into()),
+            object.into(),
+        ),
+    }
+}
+
+/// A peer cluster as `NAME=KUBECONFIG[:CONTEXT]`.
+#[derive(Debug, Clone)]
+pub struct PeerSpec {
+    pub name: String,
+    pub kubeconfig: PathBuf,
+    /// The current context of the kubeconfig when not set.
+    pub context: Option<String>,
+}
+
+impl FromStr for PeerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=KUBECONFIG[:CONTEXT], got {:?}", s))?;
+        let (kubeconfig, context) = match rest.split_once(':') {
+            Some((kubeconfig, context)) => (kubeconfig, Some(context.to_string())),
+            None => (rest, None),
+        };
+        if name.is_empty() || kubeconfig.is_empty() {
+            return Err(format!("expected NAME=KUBECONFIG[:CONTEXT], got {:?}", s));
+        }
+        Ok(PeerSpec {
+            name: name.into(),
+            kubeconfig: kubeconfig.into(),
+            context,
+        })
+    }
+}
+
+pub struct Peer {
+    pub name: String,
+    pub nodes: Store<KubeNode>,
+    pub services: Store<KubeService>,
+}
+
+/// A client for `context` of the kubeconfig at `path`, its current one when
+/// not set.
+pub async fn client(path: &Path, context: Option<&str>) -> Result<KubeClient, Error> {
+    let kubeconfig = Kubeconfig::read_from(path)?;
+    let options = KubeConfigOptions {
+        context: context.map(String::from),
+        ..Default::default()
+    };
+    let config = kube::Config::from_custom_kubeconfig(kubeconfig, &options).await?;
+    Ok(KubeClient::try_from(config)?)
+}
+
+/// A client for this cluster, from `--kubeconfig` and `--context` when set,
+/// inferred from the environment, the in-cluster config included, otherwise.
+pub async fn local_client() -> Result<KubeClient, Error> {
+    match LOCAL.get() {
+        Some((Some(path), context)) => client(path, context.as_deref()).await,
+        Some((None, Some(context))) => {
+            let options = KubeConfigOptions {
+                context: Some(context.clone()),
+                ..Default::default()
+            };
+            let config = kube::Config::from_kubeconfig(&options).await?;
+            Ok(KubeClient::try_from(config)?)
+        }
+        _ => Ok(KubeClient::try_default().await?),
+    }
+}
+
+/// Starts watching the nodes and Services of the peer cluster.
+pub async fn connect(spec: &PeerSpec, backoff: ExponentialBackoff) -> Result<Peer, Error> {
+    let client = client(&spec.kubeconfig, spec.context.as_deref()).await?;
+    let (nodes, nodes_writer) = reflector::store();
+    let (services, services_writer) = reflector::store();
+    let name = spec.name.clone();
+    let nodes_stream = reflector::reflector(
+        nodes_writer,
+        watcher(Api::<KubeNode>::all(client.clone()), ListParams::default())
+            .backoff(backoff.clone()),
+    )
+    .applied_objects()
+    .map(|result| result.map(|_| ()));
+    let services_stream = reflector::reflector(
+        services_writer,
+        watcher(Api::<KubeService>::all(client), ListParams::default()).backoff(backoff),
+    )
+    .applied_objects()
+    .map(|result| result.map(|_| ()));
+    tokio::spawn(
+        futures::stream::select(nodes_stream, services_stream).for_each(move |result| {
+            if let Err(err) = result {
+                println!("watch of cluster {} failed: {}", name, err);
+            }
+            futures::future::ready(())
+        }),
+    );
+    println!("watching peer cluster {}", spec.name);
+    Ok(Peer {
+        name: spec.name.clone(),
+        nodes,
+        services,
+    })
+}
+
+/// The available nodes of the first peer having any, among those with a
+/// Service claiming one of the IPs of `service` when given.
+pub fn fallback_nodes(peers: &[Peer], service: Option<&KubeService>) -> Vec<KubeNode> {
+    let claimed = service.map(claimed_ips).unwrap_or_default();
+    for peer in peers {
+        let claims = service.is_none()
+            || peer.services.state().iter().any(|other| {
+                claims_ips(other) && claimed_ips(other).iter().any(|ip| claimed.contains(ip))
+            });
+        if !claims {
+            continue;
+        }
+        let nodes = available_nodes(&peer.nodes);
+        if !nodes.is_empty() {
+            println!(
+                "no available node in this cluster, failing over to cluster {}",
+                peer.name
+            );
+            return nodes;
+        }
+    }
+    vec![]
+}
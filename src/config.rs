@@ -0,0 +1,1185 @@
+use crate::admin::AdminConfig;
+use crate::admission::AdmissionConfig;
+use crate::alias_ips::AliasIp;
+use crate::canary::CanaryConfig;
+use crate::clusters::PeerSpec;
+use crate::drift::DriftPolicy;
+use crate::egress::EgressConfig;
+use crate::failback::FailbackConfig;
+use crate::fip_filter::{FipFilter, FipMatcher};
+use crate::gateway::{GatewayConfig, GatewayPolicy};
+use crate::health::{HealthCheck, TargetProbe};
+use crate::load_balancer::LoadBalancerConfig;
+use crate::maintenance::Window;
+use crate::mapping::Output;
+use crate::notify::Notifier;
+use crate::otlp::OtlpConfig;
+use crate::placement::{FailureDomain, LocationPolicy, NoTargetPolicy};
+use crate::projects::ClientConfig;
+use crate::provision::ProvisionConfig;
+use crate::release::ReleaseConfig;
+use crate::robot::RobotClient;
+use crate::rotation::{RotationConfig, RotationPolicy};
+use crate::secrets::{
+    SecretBackend, SecretBackendKind, SopsFile, Vault, VaultAuth, VaultAuthMethod,
+};
+use crate::standalone::StandaloneConfig;
+use crate::triggers::{self, Trigger, Triggers};
+use crate::verify::VerifyConfig;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Fails Hetzner Cloud floating IPs over between Kubernetes nodes"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub config: Config,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the failover policy, with the pins, pools and holds, as a YAML bundle
+    ExportConfig,
+    /// Apply the pins, pools and holds of a YAML policy bundle and print its settings as .env settings
+    ImportConfig {
+        /// Path of the bundle
+        path: PathBuf,
+    },
+    /// Print the CustomResourceDefinition of the FipControllerStatus resource
+    Crd,
+    /// List the floating IPs with their Service, server, node and its health
+    Status {
+        #[arg(long, short, value_enum, default_value_t = Output::Table)]
+        output: Output,
+    },
+    /// Assign a floating IP, by address or name, to a node or server ID
+    Assign {
+        fip: String,
+        /// Node name or hcloud server ID
+        target: String,
+        /// Assign to nodes the controller would move the IP off again
+        #[arg(long)]
+        force: bool,
+    },
+    /// Annotate the Node this runs on with its server ID from the hcloud metadata service
+    Agent {
+        /// Name of the Node, usually set from the downward API
+        #[arg(long, env = "NODE_NAME")]
+        node_name: String,
+    },
+    /// Save the floating IP to server assignments of every project
+    Snapshot {
+        /// File to write the snapshot to, standard output by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// ConfigMap to save the snapshot to instead
+        #[arg(long, conflicts_with = "output")]
+        configmap: Option<String>,
+        /// Namespace of the ConfigMap, POD_NAMESPACE or default
+        #[arg(long, requires = "configmap")]
+        namespace: Option<String>,
+    },
+    /// Check the hcloud tokens, the managed floating IPs, the cluster access and the server ID of each node
+    Check,
+    /// Move the floating IPs back to the servers recorded in a snapshot
+    Restore {
+        /// Snapshot file, standard input by default
+        path: Option<PathBuf>,
+        /// ConfigMap to read the snapshot from instead
+        #[arg(long, conflicts_with = "path")]
+        configmap: Option<String>,
+        /// Namespace of the ConfigMap, POD_NAMESPACE or default
+        #[arg(long, requires = "configmap")]
+        namespace: Option<String>,
+        /// Don't ask for confirmation before moving the floating IPs
+        #[arg(long, short)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Follow the ingress IPs of LoadBalancer Services
+    Service,
+    /// Keep every floating IP on a single gateway node
+    Gateway,
+    /// Fail over between --standalone-servers by health check, without Kubernetes
+    Standalone,
+}
+
+/// Every setting of the controller, each flag can also be set through the
+/// environment variable shown in `--help`.
+#[derive(Debug, Clone, Args)]
+pub struct Config {
+    /// YAML or TOML configuration file, flags and environment variables take precedence
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Make every decision but only log the moves instead of performing them
+    #[arg(long, env = "DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Kubeconfig to reach the cluster with instead of the in-cluster config, e.g. to run outside of it
+    #[arg(long, env = "KUBECONFIG", value_name = "PATH")]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// Context of the kubeconfig to use instead of its current one
+    #[arg(long, env = "KUBE_CONTEXT")]
+    pub context: Option<String>,
+
+    /// Simulate hcloud in memory, from floating IPs, servers and Load Balancers recorded from its API
+    #[arg(long, env = "FAKE_HCLOUD", value_name = "PATH")]
+    pub fake_hcloud: Option<PathBuf>,
+
+    /// hcloud API token of the default project
+    #[arg(long, env = "HCLOUD_TOKEN", hide_env_values = true)]
+    pub hcloud_token: Option<String>,
+
+    /// Read the token of the default project from this file, re-read on change
+    #[arg(long, env = "HCLOUD_TOKEN_FILE", conflicts_with = "hcloud_token")]
+    pub hcloud_token_file: Option<PathBuf>,
+
+    /// Read the token of the default project from an external secret backend
+    #[arg(
+        long,
+        env = "SECRET_BACKEND",
+        value_enum,
+        conflicts_with_all = ["hcloud_token", "hcloud_token_file"]
+    )]
+    pub secret_backend: Option<SecretBackendKind>,
+
+    /// Address of the Vault server, e.g. https://vault:8200
+    #[arg(long, env = "VAULT_ADDR")]
+    pub vault_addr: Option<String>,
+
+    /// How to log in to Vault
+    #[arg(long, env = "VAULT_AUTH", value_enum, default_value_t = VaultAuthMethod::Kubernetes)]
+    pub vault_auth: VaultAuthMethod,
+
+    /// Mount path of the Vault auth method, defaults to the method name
+    #[arg(long, env = "VAULT_AUTH_MOUNT")]
+    pub vault_auth_mount: Option<String>,
+
+    /// Vault role to log in as with the Kubernetes auth method
+    #[arg(long, env = "VAULT_ROLE")]
+    pub vault_role: Option<String>,
+
+    /// AppRole role ID
+    #[arg(long, env = "VAULT_ROLE_ID")]
+    pub vault_role_id: Option<String>,
+
+    /// AppRole secret ID
+    #[arg(long, env = "VAULT_SECRET_ID", hide_env_values = true)]
+    pub vault_secret_id: Option<String>,
+
+    /// API path of the Vault secret holding the token, e.g. secret/data/hcloud-fip-controller
+    #[arg(long, env = "VAULT_SECRET_PATH")]
+    pub vault_secret_path: Option<String>,
+
+    /// Key of the token in the Vault secret
+    #[arg(long, env = "VAULT_SECRET_KEY", default_value = "token")]
+    pub vault_secret_key: String,
+
+    /// SOPS encrypted file holding the token
+    #[arg(long, env = "SOPS_FILE")]
+    pub sops_file: Option<PathBuf>,
+
+    /// Key of the token in the SOPS file
+    #[arg(long, env = "SOPS_KEY", default_value = "hcloud_token")]
+    pub sops_key: String,
+
+    /// Token of an additional project as <NAME>=<TOKEN>, also read from HCLOUD_TOKEN_<NAME>
+    #[arg(long = "project-token", value_name = "NAME=TOKEN", value_parser = parse_key_value)]
+    pub project_tokens: Vec<(String, String)>,
+
+    /// Token file of an additional project as <NAME>=<PATH>, also read from HCLOUD_TOKEN_<NAME>_FILE
+    #[arg(long = "project-token-file", value_name = "NAME=PATH", value_parser = parse_key_value)]
+    pub project_token_files: Vec<(String, String)>,
+
+    /// Regex extracting the hcloud server ID from nonstandard node provider IDs, from the group named `id` or the first one
+    #[arg(long, env = "PROVIDER_ID_PATTERN", value_parser = parse_provider_id_pattern)]
+    pub provider_id_pattern: Option<Regex>,
+
+    /// Whether floating IPs fail over to servers outside their home location
+    #[arg(long, env = "LOCATION_POLICY", value_enum, default_value_t = LocationPolicy::Prefer)]
+    pub location_policy: LocationPolicy,
+
+    /// hcloud label binding a floating IP to the nodes whose --node-pool-label has the same value, e.g. pool
+    #[arg(long, env = "FIP_POOL_LABEL")]
+    pub fip_pool_label: Option<String>,
+
+    /// Node label naming the pool of a node for --fip-pool-label, the same key unless set, e.g. fip-pool
+    #[arg(long, env = "NODE_POOL_LABEL", requires = "fip_pool_label")]
+    pub node_pool_label: Option<String>,
+
+    /// Spread reassigned IPs across the hcloud datacenters or locations of the available servers
+    #[arg(long, env = "SPREAD_FAILURE_DOMAIN", value_enum)]
+    pub spread_failure_domain: Option<FailureDomain>,
+
+    /// What happens to floating IPs no available server can take
+    #[arg(long, env = "NO_TARGET_POLICY", value_enum, default_value_t = NoTargetPolicy::Keep)]
+    pub no_target_policy: NoTargetPolicy,
+
+    /// What happens to floating IPs moved between eligible servers outside of the controller
+    #[arg(long, env = "DRIFT_POLICY", value_enum, default_value_t = DriftPolicy::Correct)]
+    pub drift_policy: DriftPolicy,
+
+    /// Seconds a floating IP stays where it was moved to unless its server is down, 0 disables the cooldown
+    #[arg(
+        long,
+        env = "MOVE_COOLDOWN",
+        value_name = "SECONDS",
+        default_value_t = 0
+    )]
+    pub move_cooldown: u64,
+
+    /// Move evacuated floating IPs back to their server once its node is available again
+    #[arg(long, env = "FAILBACK")]
+    pub failback: bool,
+
+    /// Seconds the node of a server must stay available before its evacuated floating IPs move back
+    #[arg(
+        long,
+        env = "FAILBACK_DELAY",
+        value_name = "SECONDS",
+        default_value_t = 300
+    )]
+    pub failback_delay: u64,
+
+    /// UTC windows, as [DAYS ]HH:MM-HH:MM separated by ';', during which floating IPs only fail over off servers that are down, e.g. "mon-fri 02:00-04:00"
+    #[arg(long, env = "MAINTENANCE_WINDOWS", value_delimiter = ';')]
+    pub maintenance_windows: Vec<Window>,
+
+    /// UTC windows, as [DAYS ]HH:MM-HH:MM separated by ';', outside of which rotation and failback don't move floating IPs
+    #[arg(long, env = "REBALANCE_WINDOWS", value_delimiter = ';')]
+    pub rebalance_windows: Vec<Window>,
+
+    /// Floating IPs kept attached to an available node whatever the Services, as SNAT egress addresses, by ID, IP or name
+    #[arg(long, env = "EGRESS_IPS", value_delimiter = ',')]
+    pub egress_ips: Vec<FipMatcher>,
+
+    /// hcloud label key marking the egress floating IPs, in addition to --egress-ips
+    #[arg(long, env = "EGRESS_FIP_LABEL")]
+    pub egress_fip_label: Option<String>,
+
+    /// Label set to true on the nodes holding egress IPs, which are listed in their fip.hcloud.barodeur.io/egress-ips annotation
+    #[arg(long, env = "EGRESS_NODE_LABEL")]
+    pub egress_node_label: Option<String>,
+
+    /// hcloud server ID floating IPs go to with --no-target-policy fallback, e.g. a maintenance page server
+    #[arg(long, env = "FALLBACK_SERVER")]
+    pub fallback_server: Option<i32>,
+
+    /// Placement mode
+    #[arg(long, env = "FIP_MODE", value_enum, default_value_t = Mode::Service)]
+    pub mode: Mode,
+
+    /// Clusters sharing the hcloud projects floating IPs fail over to, in order, when no node of this one is available, as NAME=KUBECONFIG[:CONTEXT]
+    #[arg(long, env = "PEER_CLUSTERS", value_delimiter = ',')]
+    pub peer_clusters: Vec<PeerSpec>,
+
+    /// How a new gateway is elected when the current one fails
+    #[arg(long, env = "GATEWAY_POLICY", value_enum, default_value_t = GatewayPolicy::Oldest)]
+    pub gateway_policy: GatewayPolicy,
+
+    /// Only nodes carrying this label can become the gateway
+    #[arg(long, env = "GATEWAY_NODE_LABEL")]
+    pub gateway_node_label: Option<String>,
+
+    /// Rotate floating IPs across nodes every given number of seconds
+    #[arg(long, env = "ROTATION_INTERVAL", value_name = "SECONDS")]
+    pub rotation_interval: Option<u64>,
+
+    /// How floating IPs are moved on each rotation
+    #[arg(long, env = "ROTATION_POLICY", value_enum, default_value_t = RotationPolicy::Shift)]
+    pub rotation_policy: RotationPolicy,
+
+    /// hcloud label selector of the floating IPs taking part in the rotation
+    #[arg(long, env = "ROTATION_FIP_SELECTOR")]
+    pub rotation_fip_selector: Option<String>,
+
+    /// Only nodes carrying this label receive rotated floating IPs
+    #[arg(long, env = "ROTATION_NODE_LABEL")]
+    pub rotation_node_label: Option<String>,
+
+    /// Dedicated floating IP failed over between two nodes as a self-test, never used for Services
+    #[arg(long, env = "CANARY_IP")]
+    pub canary_ip: Option<String>,
+
+    /// Seconds between two canary failovers
+    #[arg(
+        long,
+        env = "CANARY_INTERVAL",
+        value_name = "SECONDS",
+        default_value_t = 900
+    )]
+    pub canary_interval: u64,
+
+    /// Create a floating IP for LoadBalancer Services without one and publish it in their status
+    #[arg(long, env = "PROVISION_IPS")]
+    pub provision_ips: bool,
+
+    /// Home location of the created floating IPs, e.g. fsn1, overridden by the fip.hcloud.barodeur.io/location Service annotation
+    #[arg(long, env = "PROVISION_LOCATION")]
+    pub provision_location: Option<String>,
+
+    /// Name of the created floating IPs, {namespace} and {name} are those of the Service
+    #[arg(
+        long,
+        env = "PROVISION_NAME_TEMPLATE",
+        default_value = "{namespace}-{name}"
+    )]
+    pub provision_name_template: String,
+
+    /// Labels of the created floating IPs as <KEY>=<VALUE>, templated like the name
+    #[arg(
+        long,
+        env = "PROVISION_LABELS",
+        value_name = "KEY=VALUE",
+        value_delimiter = ',',
+        value_parser = parse_key_value
+    )]
+    pub provision_labels: Vec<(String, String)>,
+
+    /// Project the floating IPs are created in, the first one by default
+    #[arg(long, env = "PROVISION_PROJECT")]
+    pub provision_project: Option<String>,
+
+    /// Keep LoadBalancer Services with a finalizer until their floating IPs are unassigned once deleted
+    #[arg(long, env = "RELEASE_IPS")]
+    pub release_ips: bool,
+
+    /// Delete the floating IPs created by --provision-ips for deleted Services instead of only unassigning them
+    #[arg(long, env = "DELETE_PROVISIONED_IPS", requires = "release_ips")]
+    pub delete_provisioned_ips: bool,
+
+    /// Location of the Load Balancers created for Services annotated with fip.hcloud.barodeur.io/backend: load-balancer
+    #[arg(long, env = "LOAD_BALANCER_LOCATION")]
+    pub load_balancer_location: Option<String>,
+
+    /// Type of the created Load Balancers, overridden by the fip.hcloud.barodeur.io/load-balancer-type annotation
+    #[arg(long, env = "LOAD_BALANCER_TYPE", default_value = "lb11")]
+    pub load_balancer_type: String,
+
+    /// Only manage these floating IPs, by ID, address or name with * wildcards
+    #[arg(long, env = "FIP_INCLUDE", value_delimiter = ',')]
+    pub fip_include: Vec<FipMatcher>,
+
+    /// Never touch these floating IPs, by ID, address or name with * wildcards
+    #[arg(long, env = "FIP_EXCLUDE", value_delimiter = ',')]
+    pub fip_exclude: Vec<FipMatcher>,
+
+    /// Name of this controller, floating IPs labelled fip.hcloud.barodeur.io/owned-by with another name are left alone
+    #[arg(long, env = "OWNER", default_value = "hcloud-fip-controller")]
+    pub owner: String,
+
+    /// Leave the floating IPs with hcloud delete protection enabled where they are
+    #[arg(long, env = "RESPECT_PROTECTION")]
+    pub respect_protection: bool,
+
+    /// Private network alias IPs to manage, as <NETWORK ID>:<IP>
+    #[arg(long, env = "HCLOUD_ALIAS_IPS", value_delimiter = ',')]
+    pub alias_ips: Vec<AliasIp>,
+
+    /// Hetzner Robot webservice user, enables failover IP routing between dedicated servers
+    #[arg(long, env = "ROBOT_USER", requires = "robot_password")]
+    pub robot_user: Option<String>,
+
+    /// Hetzner Robot webservice password
+    #[arg(
+        long,
+        env = "ROBOT_PASSWORD",
+        hide_env_values = true,
+        requires = "robot_user"
+    )]
+    pub robot_password: Option<String>,
+
+    /// Webhook URLs receiving a JSON payload whenever an IP moves, fails to move or has no eligible server
+    #[arg(
+        long,
+        env = "NOTIFY_WEBHOOK_URLS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    pub notify_webhook_urls: Vec<String>,
+
+    /// Slack incoming webhook URLs receiving the same notifications as messages
+    #[arg(
+        long,
+        env = "NOTIFY_SLACK_URLS",
+        hide_env_values = true,
+        value_delimiter = ','
+    )]
+    pub notify_slack_urls: Vec<String>,
+
+    /// hcloud server IDs to fail over between in standalone mode, in order of preference
+    #[arg(long, env = "STANDALONE_SERVERS", value_delimiter = ',')]
+    pub standalone_servers: Vec<i32>,
+
+    /// hcloud label selector of the floating IPs managed in standalone mode
+    #[arg(long, env = "STANDALONE_FIP_SELECTOR")]
+    pub standalone_fip_selector: Option<String>,
+
+    /// Port of the health check
+    #[arg(long, env = "HEALTH_CHECK_PORT")]
+    pub health_check_port: Option<u16>,
+
+    /// HTTP path of the health check, a TCP connect is used when unset
+    #[arg(long, env = "HEALTH_CHECK_PATH")]
+    pub health_check_path: Option<String>,
+
+    /// Timeout of a single health check
+    #[arg(
+        long,
+        env = "HEALTH_CHECK_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 2
+    )]
+    pub health_check_timeout: u64,
+
+    /// Health check candidate servers before moving IPs to them and skip those failing it
+    #[arg(long, env = "PROBE_TARGETS")]
+    pub probe_targets: bool,
+
+    /// Probe moved floating IPs themselves and move them again when they don't answer within this many seconds
+    #[arg(long, env = "VERIFY_WINDOW", value_name = "SECONDS")]
+    pub verify_window: Option<u64>,
+
+    /// Seconds between two health check rounds in standalone mode
+    #[arg(
+        long,
+        env = "HEALTH_CHECK_INTERVAL",
+        value_name = "SECONDS",
+        default_value_t = 10
+    )]
+    pub health_check_interval: u64,
+
+    /// Probe servers on their IP in this private network instead of their public IPv4
+    #[arg(long, env = "HEALTH_CHECK_NETWORK")]
+    pub health_check_network: Option<i32>,
+
+    /// Reconcile traces kept in memory and served on /v1/traces by the admin API, 0 disables them
+    #[arg(long, env = "TRACE_BUFFER", default_value_t = 200)]
+    pub trace_buffer: usize,
+
+    /// OTLP/HTTP collector to export the reconciles to as traces, e.g. http://tempo:4318
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name of the exported traces
+    #[arg(
+        long,
+        env = "OTEL_SERVICE_NAME",
+        default_value = "hcloud-fip-controller"
+    )]
+    pub otlp_service_name: String,
+
+    /// Append every assignment decision as a JSON line to this file, `-` for standard output
+    #[arg(long, env = "AUDIT_LOG", value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Publish the controller status to the cluster-scoped FipControllerStatus of this name
+    #[arg(long, env = "STATUS_RESOURCE")]
+    pub status_resource: Option<String>,
+
+    /// Keep a cluster-scoped FloatingIPStatus per managed floating IP
+    #[arg(long, env = "FLOATING_IP_STATUSES")]
+    pub floating_ip_statuses: bool,
+
+    /// Quiet period in milliseconds after which the pending watch events are reconciled, coalesced per object
+    #[arg(
+        long,
+        env = "DEBOUNCE_MS",
+        value_name = "MILLISECONDS",
+        default_value_t = 500
+    )]
+    pub debounce_ms: u64,
+
+    /// Reconcile every node and Service again every given number of seconds, 0 disables it
+    #[arg(
+        long,
+        env = "RESYNC_INTERVAL",
+        value_name = "SECONDS",
+        default_value_t = 300
+    )]
+    pub resync_interval: u64,
+
+    /// Longest wait in seconds between restarts of a failed watch
+    #[arg(
+        long,
+        env = "WATCH_BACKOFF_MAX",
+        value_name = "SECONDS",
+        default_value_t = 60
+    )]
+    pub watch_backoff_max: u64,
+
+    /// Random spread of the resync interval and the watch backoff, in percent either way
+    #[arg(
+        long,
+        env = "JITTER_PERCENT",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u64).range(0..=100)
+    )]
+    pub jitter_percent: u64,
+
+    /// Node reconciles run at once, kept apart from Service reconciles so failovers are never queued behind them
+    #[arg(long, env = "NODE_CONCURRENCY", default_value_t = 4)]
+    pub node_concurrency: usize,
+
+    /// Keep the IPs of every LoadBalancer Service on nodes running one of its ready pods, not only with externalTrafficPolicy Local
+    #[arg(long, env = "FOLLOW_ENDPOINTS")]
+    pub follow_endpoints: bool,
+
+    /// Publish the spec.loadBalancerIP of LoadBalancer Services in their status when it is a floating IP, for external-dns
+    #[arg(long, env = "PUBLISH_LOAD_BALANCER_IP")]
+    pub publish_load_balancer_ip: bool,
+
+    /// spec.loadBalancerClass of the LoadBalancer Services to manage, e.g. hcloud-fip, only Services without one are managed when not set
+    #[arg(long, env = "LOAD_BALANCER_CLASS")]
+    pub load_balancer_class: Option<String>,
+
+    /// [NAMESPACE/]NAME of a ConfigMap pausing the controller while its paused key is "true", in POD_NAMESPACE unless given
+    #[arg(long, env = "PAUSE_CONFIGMAP")]
+    pub pause_configmap: Option<String>,
+
+    /// [NAMESPACE/]NAME of a ConfigMap whose config.yaml key holds runtime settings applied live, in POD_NAMESPACE unless given
+    #[arg(long, env = "SETTINGS_CONFIGMAP")]
+    pub settings_configmap: Option<String>,
+
+    /// Node taint keys that evacuate the node like a cordon does, whatever their effect
+    #[arg(
+        long,
+        env = "EVACUATE_TAINTS",
+        value_delimiter = ',',
+        default_value = "node.kubernetes.io/unreachable,node.kubernetes.io/not-ready,fip.hcloud.barodeur.io/evacuate"
+    )]
+    pub evacuate_taints: Vec<String>,
+
+    /// Conditions that evacuate a node, combined with && and ||: cordoned, tainted, not-ready, deleting, annotation:<key>[=<value>]
+    #[arg(
+        long,
+        env = "EVACUATE_WHEN",
+        value_name = "EXPR",
+        default_value = triggers::DEFAULT,
+        value_parser = Trigger::parse
+    )]
+    pub evacuate_when: Trigger,
+
+    /// Node label naming the pool of a node, for --evacuate-when-pool
+    #[arg(long, env = "EVACUATE_POOL_LABEL")]
+    pub evacuate_pool_label: Option<String>,
+
+    /// Conditions replacing --evacuate-when for the nodes of a pool
+    #[arg(
+        long,
+        env = "EVACUATE_WHEN_POOL",
+        value_name = "POOL=EXPR",
+        value_delimiter = ',',
+        value_parser = parse_pool_trigger,
+        requires = "evacuate_pool_label"
+    )]
+    pub evacuate_when_pool: Vec<(String, Trigger)>,
+
+    /// Check the hcloud servers of the nodes every given number of seconds, evacuating the ones off, rebuilding or deleted
+    #[arg(long, env = "SERVER_CHECK_INTERVAL", value_name = "SECONDS")]
+    pub server_check_interval: Option<u64>,
+
+    /// Service reconciles run at once
+    #[arg(long, env = "SERVICE_CONCURRENCY", default_value_t = 2)]
+    pub service_concurrency: usize,
+
+    /// Seconds the floating IP list of a project is cached for, 0 disables the cache
+    #[arg(
+        long,
+        env = "FIP_CACHE_TTL",
+        value_name = "SECONDS",
+        default_value_t = 5
+    )]
+    pub fip_cache_ttl: u64,
+
+    /// hcloud IP moves run at once, waiting moves are started failovers first and rebalances last
+    #[arg(long, env = "HCLOUD_MAX_INFLIGHT", default_value_t = 4)]
+    pub hcloud_max_inflight: usize,
+
+    /// Items per page of the hcloud list calls, every page is fetched
+    #[arg(
+        long,
+        env = "HCLOUD_PER_PAGE",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(i32).range(1..=50)
+    )]
+    pub hcloud_per_page: i32,
+
+    /// Consecutive hcloud 5xx answers or timeouts opening the circuit breaker, 0 disables it
+    #[arg(long, env = "HCLOUD_CIRCUIT_THRESHOLD", default_value_t = 5)]
+    pub hcloud_circuit_threshold: u32,
+
+    /// Seconds the hcloud circuit breaker stays open before probing the API
+    #[arg(
+        long,
+        env = "HCLOUD_CIRCUIT_OPEN",
+        value_name = "SECONDS",
+        default_value_t = 30
+    )]
+    pub hcloud_circuit_open: u64,
+
+    /// Seconds to wait for a connection to the hcloud API
+    #[arg(
+        long,
+        env = "HCLOUD_CONNECT_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 10
+    )]
+    pub hcloud_connect_timeout: u64,
+
+    /// Seconds an hcloud API call may take in total, so a hanging endpoint can't stall failovers
+    #[arg(
+        long,
+        env = "HCLOUD_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 30
+    )]
+    pub hcloud_timeout: u64,
+
+    /// Proxy URL of the hcloud API calls, e.g. http://proxy.corp:3128, HTTPS_PROXY is used when not set
+    #[arg(long, env = "HCLOUD_PROXY")]
+    pub hcloud_proxy: Option<String>,
+
+    /// PEM file of CA certificates trusted for the hcloud API in addition to the system ones, e.g. of a TLS intercepting proxy
+    #[arg(long, env = "HCLOUD_CA_CERT")]
+    pub hcloud_ca_cert: Option<PathBuf>,
+
+    /// Namespace of the Leases taken per floating IP before moving it, so other replicas or scripts taking them never move it meanwhile
+    #[arg(long, env = "FIP_LEASE_NAMESPACE")]
+    pub fip_lease_namespace: Option<String>,
+
+    /// Seconds a floating IP Lease is held without being renewed
+    #[arg(
+        long,
+        env = "FIP_LEASE_DURATION",
+        value_name = "SECONDS",
+        default_value_t = 30
+    )]
+    pub fip_lease_duration: u64,
+
+    /// Seconds to let an in-flight reconcile finish after SIGTERM or SIGINT
+    #[arg(
+        long,
+        env = "SHUTDOWN_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 20
+    )]
+    pub shutdown_timeout: u64,
+
+    /// Address to serve Prometheus metrics on
+    #[arg(long, env = "METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Address to serve the validating admission webhook on, over HTTPS
+    #[arg(
+        long,
+        env = "ADMISSION_ADDR",
+        requires = "admission_tls_cert",
+        requires = "admission_tls_key"
+    )]
+    pub admission_addr: Option<SocketAddr>,
+
+    /// PEM certificate chain of the admission webhook
+    #[arg(long, env = "ADMISSION_TLS_CERT", value_name = "PATH")]
+    pub admission_tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the admission webhook
+    #[arg(long, env = "ADMISSION_TLS_KEY", value_name = "PATH")]
+    pub admission_tls_key: Option<PathBuf>,
+
+    /// Address to serve the admin API on, to list the assignments and force moves
+    #[arg(long, env = "ADMIN_ADDR", requires = "admin_token")]
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Bearer token of the admin API
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected <NAME>=<VALUE>, got {:?}", s))
+}
+
+fn parse_pool_trigger(s: &str) -> Result<(String, Trigger), String> {
+    let (pool, expr) = parse_key_value(s)?;
+    Ok((pool, Trigger::parse(&expr)?))
+}
+
+fn parse_provider_id_pattern(s: &str) -> Result<Regex, String> {
+    let pattern = Regex::new(s).map_err(|err| err.to_string())?;
+    if pattern.captures_len() < 2 {
+        return Err("the pattern needs a capture group for the server id".into());
+    }
+    Ok(pattern)
+}
+
+impl Config {
+    /// Checks the settings that depend on each other, exiting with a usage
+    /// error when they don't fit.
+    pub fn validate(&self) {
+        if self.server_check_interval == Some(0) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    "--server-check-interval must be at least 1 second",
+                )
+                .exit();
+        }
+        if self.rotation_interval.is_some() && self.mode == Mode::Gateway {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--rotation-interval is not supported in gateway mode",
+                )
+                .exit();
+        }
+        if self.mode == Mode::Standalone {
+            if self.standalone_servers.is_empty() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "--standalone-servers is required in standalone mode",
+                    )
+                    .exit();
+            }
+            if self.health_check_port.is_none() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "--health-check-port is required in standalone mode",
+                    )
+                    .exit();
+            }
+            if self.rotation_interval.is_some() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--rotation-interval is not supported in standalone mode",
+                    )
+                    .exit();
+            }
+        }
+        let missing = match self.secret_backend {
+            Some(SecretBackendKind::Vault) => {
+                let mut missing = vec![];
+                if self.vault_addr.is_none() {
+                    missing.push("--vault-addr");
+                }
+                if self.vault_secret_path.is_none() {
+                    missing.push("--vault-secret-path");
+                }
+                match self.vault_auth {
+                    VaultAuthMethod::Kubernetes if self.vault_role.is_none() => {
+                        missing.push("--vault-role")
+                    }
+                    VaultAuthMethod::Approle => {
+                        if self.vault_role_id.is_none() {
+                            missing.push("--vault-role-id");
+                        }
+                        if self.vault_secret_id.is_none() {
+                            missing.push("--vault-secret-id");
+                        }
+                    }
+                    _ => {}
+                }
+                missing
+            }
+            Some(SecretBackendKind::Sops) if self.sops_file.is_none() => vec!["--sops-file"],
+            _ => vec![],
+        };
+        if !missing.is_empty() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    format!("{} required by --secret-backend", missing.join(", ")),
+                )
+                .exit();
+        }
+        if self.node_concurrency == 0 || self.service_concurrency == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--node-concurrency and --service-concurrency must be greater than zero",
+                )
+                .exit();
+        }
+        if self.probe_targets && self.health_check_port.is_none() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--health-check-port is required by --probe-targets",
+                )
+                .exit();
+        }
+        if self.verify_window.is_some() && self.health_check_port.is_none() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--health-check-port is required by --verify-window",
+                )
+                .exit();
+        }
+        if self.verify_window == Some(0) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--verify-window must be greater than zero",
+                )
+                .exit();
+        }
+        if self.hcloud_max_inflight == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--hcloud-max-inflight must be greater than zero",
+                )
+                .exit();
+        }
+        if self.hcloud_connect_timeout == 0 || self.hcloud_timeout == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--hcloud-connect-timeout and --hcloud-timeout must be greater than zero",
+                )
+                .exit();
+        }
+        if self.hcloud_circuit_open == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--hcloud-circuit-open must be greater than zero",
+                )
+                .exit();
+        }
+        if self.fip_lease_duration < 3 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--fip-lease-duration must be at least 3 seconds",
+                )
+                .exit();
+        }
+        if self.health_check_interval == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--health-check-interval must be greater than zero",
+                )
+                .exit();
+        }
+        if let Some(ip) = &self.canary_ip {
+            if self.mode != Mode::Service {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--canary-ip is only supported in service mode",
+                    )
+                    .exit();
+            }
+            if ip.parse::<std::net::IpAddr>().is_err() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        format!("--canary-ip {} is not an ip address", ip),
+                    )
+                    .exit();
+            }
+            if self.canary_interval == 0 {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        "--canary-interval must be greater than zero",
+                    )
+                    .exit();
+            }
+        }
+        if self.provision_ips {
+            if self.mode != Mode::Service {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--provision-ips is only supported in service mode",
+                    )
+                    .exit();
+            }
+            if self.provision_location.is_none() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "--provision-location is required with --provision-ips",
+                    )
+                    .exit();
+            }
+        }
+        if self.no_target_policy == NoTargetPolicy::Fallback && self.fallback_server.is_none() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "--fallback-server is required with --no-target-policy fallback",
+                )
+                .exit();
+        }
+        if !self.peer_clusters.is_empty() && self.mode != Mode::Service {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--peer-clusters is only supported in service mode",
+                )
+                .exit();
+        }
+        if self.failback && self.mode != Mode::Service {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--failback is only supported in service mode",
+                )
+                .exit();
+        }
+        if self.egress_config().is_some() && self.mode != Mode::Service {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--egress-ips and --egress-fip-label are only supported in service mode",
+                )
+                .exit();
+        }
+        if self.release_ips && self.mode != Mode::Service {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--release-ips is only supported in service mode",
+                )
+                .exit();
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ValueValidation,
+                        "--otlp-endpoint must be an http:// or https:// URL",
+                    )
+                    .exit();
+            }
+        }
+        if self.watch_backoff_max == 0 {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--watch-backoff-max must be greater than zero",
+                )
+                .exit();
+        }
+        if self.rotation_interval == Some(0) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    "--rotation-interval must be greater than zero",
+                )
+                .exit();
+        }
+    }
+
+    pub fn gateway_config(&self) -> Option<GatewayConfig> {
+        (self.mode == Mode::Gateway).then(|| GatewayConfig {
+            policy: self.gateway_policy,
+            node_label: self.gateway_node_label.clone(),
+        })
+    }
+
+    pub fn health_check(&self) -> Option<HealthCheck> {
+        self.health_check_port.map(|port| HealthCheck {
+            port,
+            path: self.health_check_path.clone(),
+            timeout: Duration::from_secs(self.health_check_timeout),
+        })
+    }
+
+    pub fn canary_config(&self) -> Option<CanaryConfig> {
+        self.canary_ip.clone().map(|ip| CanaryConfig {
+            ip,
+            interval: Duration::from_secs(self.canary_interval),
+            health_check: self.health_check(),
+        })
+    }
+
+    pub fn triggers(&self) -> Triggers {
+        Triggers {
+            default: self.evacuate_when.clone(),
+            pool_label: self.evacuate_pool_label.clone(),
+            pools: self.evacuate_when_pool.clone(),
+        }
+    }
+
+    pub fn provision_config(&self) -> Option<ProvisionConfig> {
+        self.provision_ips.then(|| ProvisionConfig {
+            location: self.provision_location.clone().unwrap(),
+            name_template: self.provision_name_template.clone(),
+            labels: self.provision_labels.clone(),
+            project: self.provision_project.clone(),
+        })
+    }
+
+    pub fn hcloud_client_config(&self) -> ClientConfig {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(self.hcloud_connect_timeout),
+            timeout: Duration::from_secs(self.hcloud_timeout),
+            proxy: self.hcloud_proxy.clone(),
+            ca_cert: self.hcloud_ca_cert.clone(),
+        }
+    }
+
+    pub fn egress_config(&self) -> Option<EgressConfig> {
+        (!self.egress_ips.is_empty() || self.egress_fip_label.is_some()).then(|| EgressConfig {
+            ips: self.egress_ips.clone(),
+            fip_label: self.egress_fip_label.clone(),
+            node_label: self.egress_node_label.clone(),
+        })
+    }
+
+    pub fn failback_config(&self) -> Option<FailbackConfig> {
+        self.failback.then(|| FailbackConfig {
+            delay: Duration::from_secs(self.failback_delay),
+        })
+    }
+
+    pub fn release_config(&self) -> Option<ReleaseConfig> {
+        self.release_ips.then_some(ReleaseConfig {
+            delete_provisioned: self.delete_provisioned_ips,
+        })
+    }
+
+    pub fn load_balancer_config(&self) -> LoadBalancerConfig {
+        LoadBalancerConfig {
+            location: self.load_balancer_location.clone(),
+            load_balancer_type: self.load_balancer_type.clone(),
+        }
+    }
+
+    pub fn target_probe(&self) -> Option<TargetProbe> {
+        self.probe_targets.then(|| TargetProbe {
+            health_check: self.health_check().unwrap(),
+            network: self.health_check_network,
+        })
+    }
+
+    pub fn verify_config(&self) -> Option<VerifyConfig> {
+        self.verify_window.map(|window| VerifyConfig {
+            health_check: self.health_check().unwrap(),
+            window: Duration::from_secs(window),
+        })
+    }
+
+    pub fn standalone_config(&self) -> Option<StandaloneConfig> {
+        (self.mode == Mode::Standalone).then(|| StandaloneConfig {
+            server_ids: self.standalone_servers.clone(),
+            fip_selector: self.standalone_fip_selector.clone(),
+            health_check: self.health_check().unwrap(),
+            network: self.health_check_network,
+            interval: Duration::from_secs(self.health_check_interval),
+        })
+    }
+
+    pub fn rotation_config(&self) -> Option<RotationConfig> {
+        self.rotation_interval.map(|interval| RotationConfig {
+            interval: Duration::from_secs(interval),
+            policy: self.rotation_policy,
+            fip_selector: self.rotation_fip_selector.clone(),
+            node_label: self.rotation_node_label.clone(),
+        })
+    }
+
+    pub fn secret_backend(&self) -> Option<SecretBackend> {
+        match self.secret_backend? {
+            SecretBackendKind::Vault => {
+                let auth = match self.vault_auth {
+                    VaultAuthMethod::Kubernetes => VaultAuth::Kubernetes {
+                        role: self.vault_role.clone()?,
+                    },
+                    VaultAuthMethod::Approle => VaultAuth::AppRole {
+                        role_id: self.vault_role_id.clone()?,
+                        secret_id: self.vault_secret_id.clone()?,
+                    },
+                };
+                let auth_mount = self.vault_auth_mount.clone().unwrap_or_else(|| {
+                    self.vault_auth
+                        .to_possible_value()
+                        .unwrap()
+                        .get_name()
+                        .to_string()
+                });
+                Some(SecretBackend::Vault(Vault {
+                    client: reqwest::Client::new(),
+                    addr: self.vault_addr.clone()?.trim_end_matches('/').to_string(),
+                    auth,
+                    auth_mount,
+                    path: self.vault_secret_path.clone()?,
+                    key: self.vault_secret_key.clone(),
+                }))
+            }
+            SecretBackendKind::Sops => Some(SecretBackend::Sops(SopsFile {
+                path: self.sops_file.clone()?,
+                key: self.sops_key.clone(),
+            })),
+        }
+    }
+
+    pub fn admission_config(&self) -> Option<AdmissionConfig> {
+        Some(AdmissionConfig {
+            addr: self.admission_addr?,
+            tls_cert: self.admission_tls_cert.clone()?,
+            tls_key: self.admission_tls_key.clone()?,
+        })
+    }
+
+    pub fn fip_filter(&self) -> FipFilter {
+        FipFilter {
+            include: self.fip_include.clone(),
+            exclude: self.fip_exclude.clone(),
+        }
+    }
+
+    pub fn otlp_config(&self) -> Option<OtlpConfig> {
+        Some(OtlpConfig {
+            endpoint: self.otlp_endpoint.clone()?,
+            service_name: self.otlp_service_name.clone(),
+        })
+    }
+
+    pub fn admin_config(&self) -> Option<AdminConfig> {
+        Some(AdminConfig {
+            addr: self.admin_addr?,
+            token: self.admin_token.clone()?,
+        })
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            client: reqwest::Client::new(),
+            webhook_urls: self.notify_webhook_urls.clone(),
+            slack_urls: self.notify_slack_urls.clone(),
+        }
+    }
+
+    pub fn robot(&self) -> Option<RobotClient> {
+        match (&self.robot_user, &self.robot_password) {
+            (Some(user), Some(password)) => Some(RobotClient::new(user.clone(), password.clone())),
+            _ => None,
+        }
+    }
+}
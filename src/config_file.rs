@@ -0,0 +1,581 @@
+//! Typed configuration file, as an alternative to a dozen environment
+//! variables. Values are applied as the flag matching their environment
+//! variable unless that flag or variable is already set, so the precedence is
+//! flags, then environment, then file, then defaults.
+
+use crate::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConfigFile {
+    pub mode: Option<String>,
+    pub dry_run: Option<bool>,
+    pub kubeconfig: Option<PathBuf>,
+    pub context: Option<String>,
+    pub fake_hcloud: Option<PathBuf>,
+    /// Seconds.
+    pub shutdown_timeout: Option<u64>,
+    /// Milliseconds.
+    pub debounce_ms: Option<u64>,
+    /// Seconds.
+    pub resync_interval: Option<u64>,
+    /// Seconds.
+    pub watch_backoff_max: Option<u64>,
+    pub jitter_percent: Option<u64>,
+    pub node_concurrency: Option<u64>,
+    pub service_concurrency: Option<u64>,
+    pub follow_endpoints: Option<bool>,
+    pub publish_load_balancer_ip: Option<bool>,
+    pub load_balancer_class: Option<String>,
+    /// `[NAMESPACE/]NAME`.
+    pub pause_config_map: Option<String>,
+    /// `[NAMESPACE/]NAME`.
+    pub settings_config_map: Option<String>,
+    /// `-` for standard output.
+    pub audit_log: Option<PathBuf>,
+    /// Node taint keys evacuated like a cordon.
+    #[serde(default)]
+    pub evacuate_taints: Vec<String>,
+    /// Expression of the conditions evacuating a node.
+    pub evacuate_when: Option<String>,
+    pub evacuate_pool_label: Option<String>,
+    /// Expressions replacing `evacuateWhen` by node pool.
+    #[serde(default)]
+    pub evacuate_when_pools: BTreeMap<String, String>,
+    /// Seconds.
+    pub server_check_interval: Option<u64>,
+    /// `NAME=KUBECONFIG[:CONTEXT]` of each peer cluster, in order.
+    #[serde(default)]
+    pub peer_clusters: Vec<String>,
+    #[serde(default)]
+    pub hcloud: HcloudSection,
+    #[serde(default)]
+    pub secrets: SecretsSection,
+    #[serde(default)]
+    pub robot: RobotSection,
+    #[serde(default)]
+    pub gateway: GatewaySection,
+    #[serde(default)]
+    pub rotation: RotationSection,
+    #[serde(default)]
+    pub canary: CanarySection,
+    #[serde(default)]
+    pub provision: ProvisionSection,
+    #[serde(default)]
+    pub release: ReleaseSection,
+    #[serde(default)]
+    pub failback: FailbackSection,
+    #[serde(default)]
+    pub maintenance: MaintenanceSection,
+    #[serde(default)]
+    pub egress: EgressSection,
+    #[serde(default)]
+    pub load_balancer: LoadBalancerSection,
+    #[serde(default)]
+    pub standalone: StandaloneSection,
+    #[serde(default)]
+    pub health_check: HealthCheckSection,
+    #[serde(default)]
+    pub metrics: MetricsSection,
+    #[serde(default)]
+    pub status: StatusSection,
+    #[serde(default)]
+    pub admission: AdmissionSection,
+    #[serde(default)]
+    pub admin: AdminSection,
+    #[serde(default)]
+    pub otlp: OtlpSection,
+    #[serde(default)]
+    pub leases: LeasesSection,
+    #[serde(default)]
+    pub notify: NotifySection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HcloudSection {
+    pub token_file: Option<PathBuf>,
+    pub provider_id_pattern: Option<String>,
+    pub fip_pool_label: Option<String>,
+    pub node_pool_label: Option<String>,
+    pub location_policy: Option<String>,
+    pub spread_failure_domain: Option<String>,
+    pub no_target_policy: Option<String>,
+    pub drift_policy: Option<String>,
+    /// Seconds.
+    pub move_cooldown: Option<u64>,
+    pub fallback_server: Option<i32>,
+    #[serde(default)]
+    pub alias_ips: Vec<String>,
+    /// Floating IP IDs, addresses or name patterns.
+    #[serde(default)]
+    pub fip_include: Vec<String>,
+    #[serde(default)]
+    pub fip_exclude: Vec<String>,
+    pub owner: Option<String>,
+    pub respect_protection: Option<bool>,
+    /// Seconds.
+    pub fip_cache_ttl: Option<u64>,
+    pub max_inflight: Option<u64>,
+    pub per_page: Option<u64>,
+    pub circuit_threshold: Option<u64>,
+    /// Seconds.
+    pub circuit_open: Option<u64>,
+    /// Seconds.
+    pub connect_timeout: Option<u64>,
+    /// Seconds.
+    pub timeout: Option<u64>,
+    pub proxy: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SecretsSection {
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub vault: VaultSection,
+    #[serde(default)]
+    pub sops: SopsSection,
+}
+
+/// The AppRole secret ID is left to the environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct VaultSection {
+    pub addr: Option<String>,
+    pub auth: Option<String>,
+    pub auth_mount: Option<String>,
+    pub role: Option<String>,
+    pub role_id: Option<String>,
+    pub secret_path: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SopsSection {
+    pub file: Option<PathBuf>,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RobotSection {
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct GatewaySection {
+    pub policy: Option<String>,
+    pub node_label: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RotationSection {
+    /// Seconds between two rotations.
+    pub interval: Option<u64>,
+    pub policy: Option<String>,
+    pub fip_selector: Option<String>,
+    pub node_label: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CanarySection {
+    pub ip: Option<String>,
+    /// Seconds between two canary failovers.
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProvisionSection {
+    pub enabled: Option<bool>,
+    pub location: Option<String>,
+    pub name_template: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub project: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ReleaseSection {
+    pub enabled: Option<bool>,
+    pub delete_provisioned: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FailbackSection {
+    pub enabled: Option<bool>,
+    /// Seconds.
+    pub delay: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EgressSection {
+    /// IDs, IPs or names.
+    #[serde(default)]
+    pub ips: Vec<String>,
+    pub fip_label: Option<String>,
+    pub node_label: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MaintenanceSection {
+    #[serde(default)]
+    pub windows: Vec<String>,
+    #[serde(default)]
+    pub rebalance_windows: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LoadBalancerSection {
+    pub location: Option<String>,
+    #[serde(rename = "type")]
+    pub load_balancer_type: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StandaloneSection {
+    #[serde(default)]
+    pub servers: Vec<i32>,
+    pub fip_selector: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HealthCheckSection {
+    pub port: Option<u16>,
+    pub path: Option<String>,
+    /// Seconds.
+    pub timeout: Option<u64>,
+    /// Seconds.
+    pub interval: Option<u64>,
+    pub network: Option<i32>,
+    pub probe_targets: Option<bool>,
+    /// Seconds.
+    pub verify_window: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MetricsSection {
+    pub addr: Option<SocketAddr>,
+    pub trace_buffer: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StatusSection {
+    pub resource: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AdmissionSection {
+    pub addr: Option<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+/// The token is only read from the flag or the environment, so it stays out
+/// of the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AdminSection {
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LeasesSection {
+    pub namespace: Option<String>,
+    /// Seconds.
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OtlpSection {
+    pub endpoint: Option<String>,
+    pub service_name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NotifySection {
+    #[serde(default)]
+    pub webhook_urls: Vec<String>,
+    #[serde(default)]
+    pub slack_urls: Vec<String>,
+}
+
+fn join<T: ToString>(values: &[T]) -> Option<String> {
+    (!values.is_empty()).then(|| {
+        values
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    })
+}
+
+impl ConfigFile {
+    /// Reads a YAML file, or a TOML file when the extension is `.toml`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let toml = path.extension().and_then(|ext| ext.to_str()) == Some("toml");
+        Self::parse(&content, toml, &path.display().to_string())
+    }
+
+    /// Parses YAML `content`, or TOML, read from `origin`.
+    pub fn parse(content: &str, toml: bool, origin: &str) -> Result<Self, Error> {
+        let config = if toml {
+            toml::from_str(content)
+                .map_err(|err| format!("invalid config file {}: {}", origin, err))?
+        } else {
+            serde_yaml::from_str(content)
+                .map_err(|err| format!("invalid config file {}: {}", origin, err))?
+        };
+        Ok(config)
+    }
+
+    /// The file values keyed by the environment variable of their flag.
+    pub fn to_env(&self) -> Vec<(&'static str, String)> {
+        let string = |value: &Option<String>| value.clone();
+        let number = |value: Option<u64>| value.map(|value| value.to_string());
+        // Day lists use commas.
+        let windows = |values: &Vec<String>| (!values.is_empty()).then(|| values.join(";"));
+        let path = |value: &Option<PathBuf>| value.as_ref().map(|path| path.display().to_string());
+        let vars = [
+            ("FIP_MODE", string(&self.mode)),
+            ("DRY_RUN", self.dry_run.map(|dry_run| dry_run.to_string())),
+            ("KUBECONFIG", path(&self.kubeconfig)),
+            ("KUBE_CONTEXT", string(&self.context)),
+            ("FAKE_HCLOUD", path(&self.fake_hcloud)),
+            ("SHUTDOWN_TIMEOUT", number(self.shutdown_timeout)),
+            ("DEBOUNCE_MS", number(self.debounce_ms)),
+            ("RESYNC_INTERVAL", number(self.resync_interval)),
+            ("WATCH_BACKOFF_MAX", number(self.watch_backoff_max)),
+            ("JITTER_PERCENT", number(self.jitter_percent)),
+            ("NODE_CONCURRENCY", number(self.node_concurrency)),
+            (
+                "FOLLOW_ENDPOINTS",
+                self.follow_endpoints.map(|follow| follow.to_string()),
+            ),
+            (
+                "PUBLISH_LOAD_BALANCER_IP",
+                self.publish_load_balancer_ip
+                    .map(|publish| publish.to_string()),
+            ),
+            ("LOAD_BALANCER_CLASS", string(&self.load_balancer_class)),
+            ("PAUSE_CONFIGMAP", string(&self.pause_config_map)),
+            ("SETTINGS_CONFIGMAP", string(&self.settings_config_map)),
+            ("EVACUATE_TAINTS", join(&self.evacuate_taints)),
+            ("PEER_CLUSTERS", join(&self.peer_clusters)),
+            ("EVACUATE_WHEN", string(&self.evacuate_when)),
+            ("EVACUATE_POOL_LABEL", string(&self.evacuate_pool_label)),
+            ("SERVER_CHECK_INTERVAL", number(self.server_check_interval)),
+            (
+                "EVACUATE_WHEN_POOL",
+                join(
+                    &self
+                        .evacuate_when_pools
+                        .iter()
+                        .map(|(pool, expr)| format!("{}={}", pool, expr))
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            ("AUDIT_LOG", path(&self.audit_log)),
+            ("SERVICE_CONCURRENCY", number(self.service_concurrency)),
+            (
+                "HCLOUD_TOKEN_FILE",
+                self.hcloud
+                    .token_file
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+            ),
+            ("HCLOUD_ALIAS_IPS", join(&self.hcloud.alias_ips)),
+            ("FIP_INCLUDE", join(&self.hcloud.fip_include)),
+            ("FIP_EXCLUDE", join(&self.hcloud.fip_exclude)),
+            ("OWNER", string(&self.hcloud.owner)),
+            (
+                "RESPECT_PROTECTION",
+                self.hcloud
+                    .respect_protection
+                    .map(|respect| respect.to_string()),
+            ),
+            ("LOCATION_POLICY", string(&self.hcloud.location_policy)),
+            (
+                "SPREAD_FAILURE_DOMAIN",
+                string(&self.hcloud.spread_failure_domain),
+            ),
+            ("NO_TARGET_POLICY", string(&self.hcloud.no_target_policy)),
+            ("DRIFT_POLICY", string(&self.hcloud.drift_policy)),
+            ("MOVE_COOLDOWN", number(self.hcloud.move_cooldown)),
+            (
+                "FALLBACK_SERVER",
+                self.hcloud.fallback_server.map(|id| id.to_string()),
+            ),
+            (
+                "PROVIDER_ID_PATTERN",
+                string(&self.hcloud.provider_id_pattern),
+            ),
+            ("FIP_POOL_LABEL", string(&self.hcloud.fip_pool_label)),
+            ("NODE_POOL_LABEL", string(&self.hcloud.node_pool_label)),
+            ("FIP_CACHE_TTL", number(self.hcloud.fip_cache_ttl)),
+            ("HCLOUD_MAX_INFLIGHT", number(self.hcloud.max_inflight)),
+            ("HCLOUD_PER_PAGE", number(self.hcloud.per_page)),
+            (
+                "HCLOUD_CIRCUIT_THRESHOLD",
+                number(self.hcloud.circuit_threshold),
+            ),
+            ("HCLOUD_CIRCUIT_OPEN", number(self.hcloud.circuit_open)),
+            (
+                "HCLOUD_CONNECT_TIMEOUT",
+                number(self.hcloud.connect_timeout),
+            ),
+            ("HCLOUD_TIMEOUT", number(self.hcloud.timeout)),
+            ("HCLOUD_PROXY", string(&self.hcloud.proxy)),
+            ("HCLOUD_CA_CERT", path(&self.hcloud.ca_cert)),
+            ("SECRET_BACKEND", string(&self.secrets.backend)),
+            ("VAULT_ADDR", string(&self.secrets.vault.addr)),
+            ("VAULT_AUTH", string(&self.secrets.vault.auth)),
+            ("VAULT_AUTH_MOUNT", string(&self.secrets.vault.auth_mount)),
+            ("VAULT_ROLE", string(&self.secrets.vault.role)),
+            ("VAULT_ROLE_ID", string(&self.secrets.vault.role_id)),
+            ("VAULT_SECRET_PATH", string(&self.secrets.vault.secret_path)),
+            ("VAULT_SECRET_KEY", string(&self.secrets.vault.secret_key)),
+            (
+                "SOPS_FILE",
+                self.secrets
+                    .sops
+                    .file
+                    .as_ref()
+                    .map(|path| path.display().to_string()),
+            ),
+            ("SOPS_KEY", string(&self.secrets.sops.key)),
+            ("ROBOT_USER", string(&self.robot.user)),
+            ("GATEWAY_POLICY", string(&self.gateway.policy)),
+            ("GATEWAY_NODE_LABEL", string(&self.gateway.node_label)),
+            ("ROTATION_INTERVAL", number(self.rotation.interval)),
+            ("ROTATION_POLICY", string(&self.rotation.policy)),
+            ("ROTATION_FIP_SELECTOR", string(&self.rotation.fip_selector)),
+            ("ROTATION_NODE_LABEL", string(&self.rotation.node_label)),
+            ("CANARY_IP", string(&self.canary.ip)),
+            ("CANARY_INTERVAL", number(self.canary.interval)),
+            (
+                "PROVISION_IPS",
+                self.provision.enabled.map(|enabled| enabled.to_string()),
+            ),
+            ("PROVISION_LOCATION", string(&self.provision.location)),
+            (
+                "PROVISION_NAME_TEMPLATE",
+                string(&self.provision.name_template),
+            ),
+            (
+                "PROVISION_LABELS",
+                join(
+                    &self
+                        .provision
+                        .labels
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            ("PROVISION_PROJECT", string(&self.provision.project)),
+            (
+                "RELEASE_IPS",
+                self.release.enabled.map(|enabled| enabled.to_string()),
+            ),
+            (
+                "LOAD_BALANCER_LOCATION",
+                string(&self.load_balancer.location),
+            ),
+            (
+                "LOAD_BALANCER_TYPE",
+                string(&self.load_balancer.load_balancer_type),
+            ),
+            (
+                "DELETE_PROVISIONED_IPS",
+                self.release
+                    .delete_provisioned
+                    .map(|delete| delete.to_string()),
+            ),
+            (
+                "FAILBACK",
+                self.failback.enabled.map(|enabled| enabled.to_string()),
+            ),
+            ("FAILBACK_DELAY", number(self.failback.delay)),
+            ("EGRESS_IPS", join(&self.egress.ips)),
+            ("EGRESS_FIP_LABEL", string(&self.egress.fip_label)),
+            ("EGRESS_NODE_LABEL", string(&self.egress.node_label)),
+            ("MAINTENANCE_WINDOWS", windows(&self.maintenance.windows)),
+            (
+                "REBALANCE_WINDOWS",
+                windows(&self.maintenance.rebalance_windows),
+            ),
+            ("STANDALONE_SERVERS", join(&self.standalone.servers)),
+            (
+                "STANDALONE_FIP_SELECTOR",
+                string(&self.standalone.fip_selector),
+            ),
+            (
+                "HEALTH_CHECK_PORT",
+                self.health_check.port.map(|port| port.to_string()),
+            ),
+            ("HEALTH_CHECK_PATH", string(&self.health_check.path)),
+            ("HEALTH_CHECK_TIMEOUT", number(self.health_check.timeout)),
+            ("HEALTH_CHECK_INTERVAL", number(self.health_check.interval)),
+            (
+                "PROBE_TARGETS",
+                self.health_check
+                    .probe_targets
+                    .map(|probe| probe.to_string()),
+            ),
+            ("VERIFY_WINDOW", number(self.health_check.verify_window)),
+            (
+                "HEALTH_CHECK_NETWORK",
+                self.health_check.network.map(|network| network.to_string()),
+            ),
+            (
+                "METRICS_ADDR",
+                self.metrics.addr.map(|addr| addr.to_string()),
+            ),
+            ("TRACE_BUFFER", number(self.metrics.trace_buffer)),
+            ("STATUS_RESOURCE", string(&self.status.resource)),
+            (
+                "ADMISSION_ADDR",
+                self.admission.addr.map(|addr| addr.to_string()),
+            ),
+            ("ADMISSION_TLS_CERT", path(&self.admission.tls_cert)),
+            ("ADMISSION_TLS_KEY", path(&self.admission.tls_key)),
+            ("ADMIN_ADDR", self.admin.addr.map(|addr| addr.to_string())),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", string(&self.otlp.endpoint)),
+            ("OTEL_SERVICE_NAME", string(&self.otlp.service_name)),
+            ("FIP_LEASE_NAMESPACE", string(&self.leases.namespace)),
+            ("FIP_LEASE_DURATION", number(self.leases.duration)),
+            ("NOTIFY_WEBHOOK_URLS", join(&self.notify.webhook_urls)),
+            ("NOTIFY_SLACK_URLS", join(&self.notify.slack_urls)),
+        ];
+        vars.into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
@@ -0,0 +1,122 @@
+//! Detection of floating IPs claimed by more than one Service. Neither Service
+//! of a conflicting pair gets the IP managed, otherwise each of their
+//! reconciles would undo the other's assignment.
+
+use crate::events::EventPublisher;
+use crate::{claims_ips, metrics, pin};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::runtime::reflector::Store;
+use kube::{Resource, ResourceExt};
+use std::collections::HashMap;
+
+/// The IPs a Service claims: its pinned floating IP, or else the IPs of its
+/// load balancer ingress status and its `spec.externalIPs`.
+pub fn claimed_ips(service: &KubeService) -> Vec<&String> {
+    if let Some(id) = pin::pinned(service) {
+        return vec![id];
+    }
+    let ingress = service
+        .status
+        .as_ref()
+        .and_then(|s| s.load_balancer.as_ref())
+        .and_then(|lb| lb.ingress.as_ref())
+        .into_iter()
+        .flatten()
+        .flat_map(|i| i.ip.as_ref());
+    let external = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.external_ips.as_ref())
+        .into_iter()
+        .flatten();
+    let mut ips = vec![];
+    for ip in ingress.chain(external) {
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips
+}
+
+fn full_name(service: &KubeService) -> String {
+    format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    )
+}
+
+/// Exports how many IPs are claimed by more than one LoadBalancer Service.
+pub fn update_metric(services: &Store<KubeService>) {
+    let mut claims: HashMap<String, usize> = HashMap::new();
+    for service in services.state() {
+        if !claims_ips(&service) {
+            continue;
+        }
+        for ip in claimed_ips(&service) {
+            *claims.entry(ip.clone()).or_default() += 1;
+        }
+    }
+    let conflicting = claims.values().filter(|count| **count > 1).count();
+    metrics::CONFLICTING_IPS.set(conflicting as i64);
+}
+
+/// Returns the IPs of `service` also claimed by another LoadBalancer Service,
+/// with the name of that Service.
+pub fn find_conflicts(
+    service: &KubeService,
+    services: &Store<KubeService>,
+) -> Vec<(String, String)> {
+    let ips = claimed_ips(service);
+    let name = full_name(service);
+    let mut conflicts = vec![];
+    for other in services.state() {
+        if !claims_ips(&other) || full_name(&other) == name {
+            continue;
+        }
+        for ip in claimed_ips(&other) {
+            if ips.contains(&ip) {
+                conflicts.push((ip.clone(), full_name(&other)));
+            }
+        }
+    }
+    conflicts
+}
+
+/// Publishes a `FloatingIPConflict` warning on both Services of every conflict.
+pub async fn report(
+    events: &EventPublisher,
+    service: &KubeService,
+    services: &Store<KubeService>,
+    conflicts: &[(String, String)],
+) {
+    let name = full_name(service);
+    for (ip, other_name) in conflicts {
+        println!(
+            "{} is claimed by both {} and {}, leaving it unmanaged",
+            ip, name, other_name
+        );
+        let note = format!(
+            "{} is claimed by both {} and {}, it is not managed until one releases it",
+            ip, name, other_name
+        );
+        events
+            .warning(
+                service.object_ref(&()),
+                "FloatingIPConflict",
+                "Reconcile",
+                note.clone(),
+            )
+            .await;
+        if let Some(other) = services.find(|other| full_name(other) == *other_name) {
+            events
+                .warning(
+                    other.object_ref(&()),
+                    "FloatingIPConflict",
+                    "Reconcile",
+                    note,
+                )
+                .await;
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Cooldown after a floating IP moved, so a node condition flapping back and
+//! forth doesn't bounce the IP between servers every few seconds, breaking
+//! long-lived connections each time. A move held back is retried by a later
+//! reconcile, at the latest the periodic resync. IPs held by a server that is
+//! down for good are moved anyway.
+
+use crate::get_hc_server_id;
+use crate::server_failures;
+use crate::triggers::Condition;
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static COOLDOWN_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// When each floating IP last moved, by ID.
+static LAST_MOVED: Lazy<Mutex<HashMap<i32, Instant>>> = Lazy::new(Default::default);
+
+pub fn set_cooldown(cooldown: Duration) {
+    COOLDOWN_SECS.store(cooldown.as_secs(), Ordering::Relaxed);
+}
+
+/// Notes that `fip_id` was just moved.
+pub fn moved(fip_id: i32) {
+    LAST_MOVED.lock().unwrap().insert(fip_id, Instant::now());
+}
+
+/// How long `fip_id` has to stay where it is, if it is cooling down.
+fn remaining(fip_id: i32) -> Option<Duration> {
+    let cooldown = Duration::from_secs(COOLDOWN_SECS.load(Ordering::Relaxed));
+    let last = *LAST_MOVED.lock().unwrap().get(&fip_id)?;
+    left(cooldown, last.elapsed())
+}
+
+/// What is left of `cooldown` after `elapsed`, if anything.
+fn left(cooldown: Duration, elapsed: Duration) -> Option<Duration> {
+    cooldown.checked_sub(elapsed).filter(|left| !left.is_zero())
+}
+
+/// The floating IPs cooling down by ID, with how long they have left.
+pub fn pending() -> Vec<(i32, Duration)> {
+    let ids: Vec<i32> = LAST_MOVED.lock().unwrap().keys().copied().collect();
+    let mut pending: Vec<_> = ids
+        .into_iter()
+        .filter_map(|fip_id| remaining(fip_id).map(|left| (fip_id, left)))
+        .collect();
+    pending.sort();
+    pending
+}
+
+/// Whether the server holding an IP can't serve it at all: its node is not
+/// ready, being deleted or gone, or the server failed in hcloud. Cordoned or
+/// tainted nodes still serve.
+pub fn is_hard_down(nodes: &Store<KubeNode>, server_id: i32) -> bool {
+    let node = nodes
+        .state()
+        .into_iter()
+        .find(|node| get_hc_server_id(node) == Some(server_id));
+    match node {
+        Some(node) => {
+            Condition::NotReady.reason(&node).is_some()
+                || Condition::Deleting.reason(&node).is_some()
+                || server_failures::reason(&node).is_some()
+        }
+        None => true,
+    }
+}
+
+/// Why `fip` must not move yet, if it must not.
+pub fn blocker(nodes: &Store<KubeNode>, fip: &FloatingIp) -> Option<String> {
+    let left = remaining(fip.id)?;
+    if fip.server.map(|id| is_hard_down(nodes, id)).unwrap_or(true) {
+        return None;
+    }
+    Some(format!(
+        "it is cooling down for another {}s",
+        left.as_secs() + 1
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::runtime::{reflector, watcher};
+
+    fn nodes(nodes: serde_json::Value) -> Store<KubeNode> {
+        let (store, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Restarted(
+            serde_json::from_value(nodes).unwrap(),
+        ));
+        store
+    }
+
+    #[test]
+    fn cools_down_for_the_configured_time() {
+        let cooldown = Duration::from_secs(60);
+        assert_eq!(
+            left(cooldown, Duration::from_secs(15)),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(left(cooldown, cooldown), None);
+        assert_eq!(left(cooldown, Duration::from_secs(61)), None);
+        assert_eq!(left(Duration::ZERO, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn only_unready_deleted_or_missing_nodes_are_hard_down() {
+        let nodes = nodes(serde_json::json!([
+            {
+                "metadata": {"name": "cordoned"},
+                "spec": {"providerID": "hcloud://901", "unschedulable": true},
+                "status": {"conditions": [{"type": "Ready", "status": "True"}]},
+            },
+            {
+                "metadata": {"name": "unready"},
+                "spec": {"providerID": "hcloud://902"},
+                "status": {"conditions": [{"type": "Ready", "status": "False"}]},
+            },
+            {
+                "metadata": {"name": "deleting", "deletionTimestamp": "2023-01-01T00:00:00Z"},
+                "spec": {"providerID": "hcloud://903"},
+                "status": {"conditions": [{"type": "Ready", "status": "True"}]},
+            },
+        ]));
+        assert!(!is_hard_down(&nodes, 901));
+        assert!(is_hard_down(&nodes, 902));
+        assert!(is_hard_down(&nodes, 903));
+        assert!(is_hard_down(&nodes, 904));
+    }
+}
@@ -0,0 +1,175 @@
+//! Dump of the internal view of the controller, served on `/v1/debug/state`
+//! by the admin API: what it knows of the nodes, which servers may take
+//! IPs, the cached floating IPs, the moves waiting on a timer and the last
+//! errors. Meant to answer why an expected failover didn't happen without
+//! restarting with debug logging.
+
+use crate::trace::{self, Trace};
+use crate::{
+    available_hc_server_ids, circuit, cooldown, evacuation_reason, failback, fip_cache,
+    get_hc_server_id, pause, status, Context,
+};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::ResourceExt;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// How many failed reconciles are dumped.
+const FAILED_RECONCILES: usize = 10;
+
+static CONTEXT: OnceCell<Arc<Context>> = OnceCell::new();
+
+/// Makes the state of `ctx` available, the dump is empty before.
+pub fn set_context(ctx: Arc<Context>) {
+    let _ = CONTEXT.set(ctx);
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub generated_at: String,
+    pub nodes: Vec<NodeState>,
+    /// Servers of the available nodes, the failover targets before probes.
+    pub candidate_server_ids: Vec<i32>,
+    pub floating_ips: Vec<CachedFloatingIps>,
+    pub cooldowns: Vec<Cooldown>,
+    pub failbacks: Vec<PendingFailback>,
+    pub paused: bool,
+    pub hcloud_circuit_open_since: Option<String>,
+    pub reconcile_errors: i64,
+    pub last_error: Option<LastError>,
+    /// The last failed reconciles, newest first.
+    pub failed_reconciles: Vec<Trace>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeState {
+    pub name: String,
+    pub server_id: Option<i32>,
+    /// Why the node can't hold IPs, if it can't.
+    pub evacuation_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedFloatingIp {
+    pub id: i32,
+    pub ip: String,
+    pub name: String,
+    pub server_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedFloatingIps {
+    pub project: String,
+    pub age_secs: u64,
+    pub floating_ips: Vec<CachedFloatingIp>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cooldown {
+    pub fip_id: i32,
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingFailback {
+    pub fip_id: i32,
+    pub home_server_id: i32,
+    /// Unset while the node of the home server isn't available.
+    pub remaining_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastError {
+    pub at: String,
+    pub message: String,
+}
+
+fn dump_nodes(ctx: &Context) -> Vec<NodeState> {
+    let mut nodes: Vec<NodeState> = ctx
+        .nodes
+        .state()
+        .iter()
+        .map(|node| NodeState {
+            name: node.name_any(),
+            server_id: get_hc_server_id(node),
+            evacuation_reason: evacuation_reason(node),
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    nodes
+}
+
+fn dump_floating_ips(ctx: &Context) -> Vec<CachedFloatingIps> {
+    ctx.projects
+        .iter()
+        .filter_map(|project| {
+            let (age, fips) = fip_cache::cached(&project.conf())?;
+            Some(CachedFloatingIps {
+                project: project.name.clone(),
+                age_secs: age.as_secs(),
+                floating_ips: fips
+                    .into_iter()
+                    .map(|fip| CachedFloatingIp {
+                        id: fip.id,
+                        ip: fip.ip,
+                        name: fip.name,
+                        server_id: fip.server,
+                    })
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// The current state, mostly empty until the controller has started.
+pub fn state() -> State {
+    let (nodes, candidate_server_ids, floating_ips) = match CONTEXT.get() {
+        Some(ctx) => {
+            let mut candidates: Vec<i32> =
+                available_hc_server_ids(&ctx.nodes).into_iter().collect();
+            candidates.sort();
+            (dump_nodes(ctx), candidates, dump_floating_ips(ctx))
+        }
+        None => Default::default(),
+    };
+    let (reconcile_errors, last_error) = status::errors();
+    State {
+        generated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        nodes,
+        candidate_server_ids,
+        floating_ips,
+        cooldowns: cooldown::pending()
+            .into_iter()
+            .map(|(fip_id, left)| Cooldown {
+                fip_id,
+                remaining_secs: left.as_secs() + 1,
+            })
+            .collect(),
+        failbacks: failback::pending()
+            .into_iter()
+            .map(|(fip_id, home_server_id, left)| PendingFailback {
+                fip_id,
+                home_server_id,
+                remaining_secs: left.map(|left| left.as_secs()),
+            })
+            .collect(),
+        paused: pause::is_global(),
+        hcloud_circuit_open_since: circuit::open_since()
+            .map(|since| since.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        reconcile_errors,
+        last_error: last_error.map(|(at, message)| LastError { at, message }),
+        failed_reconciles: trace::recent(None)
+            .into_iter()
+            .filter(|trace| trace.outcome != "ok")
+            .take(FAILED_RECONCILES)
+            .collect(),
+    }
+}
@@ -0,0 +1,172 @@
+//! Connection draining before floating IPs are moved off a cordoned node.
+//!
+//! A LoadBalancer Service can ask for a drain delay with the
+//! `fip.hcloud.barodeur.io/drain-delay` annotation, in seconds. When a node
+//! holding one of its IPs is cordoned, a `FloatingIPDraining` event is
+//! published on the node and the IPs only move once the delay has passed,
+//! giving external load balancers or hooks the time to stop sending traffic.
+//! The node's reconcile is queued again for the end of the drain rather than
+//! waiting for it, and IPs held by a server that is down for good move at
+//! once.
+
+use crate::assign::identifies;
+use crate::conflicts::claimed_ips;
+use crate::{pin, provision};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::runtime::reflector::Store;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const DRAIN_DELAY_ANNOTATION: &str = "fip.hcloud.barodeur.io/drain-delay";
+
+fn drain_delay(service: &KubeService) -> Option<Duration> {
+    let value = service
+        .metadata
+        .annotations
+        .as_ref()?
+        .get(DRAIN_DELAY_ANNOTATION)?;
+    match value.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            println!(
+                "ignoring invalid {} annotation {:?} of service {}",
+                DRAIN_DELAY_ANNOTATION,
+                value,
+                service.metadata.name.as_deref().unwrap_or_default()
+            );
+            None
+        }
+    }
+}
+
+/// When the drain of each node ends, by node name.
+static DEADLINES: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Default::default);
+
+/// The IPs the reconcile of `service` moves: those it claims, its pinned
+/// floating IP resolved by address or name, the floating IP provisioned for
+/// it and its `spec.loadBalancerIP` when that is a floating IP.
+fn service_ips(service: &KubeService, floating_ips: &[FloatingIp]) -> Vec<String> {
+    let mut ips: Vec<String> = match pin::pinned(service) {
+        Some(id) => floating_ips
+            .iter()
+            .filter(|fip| identifies(fip, id.trim()))
+            .map(|fip| fip.ip.clone())
+            .collect(),
+        None => claimed_ips(service).into_iter().cloned().collect(),
+    };
+    let load_balancer_ip = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.load_balancer_ip.as_ref());
+    ips.extend(
+        floating_ips
+            .iter()
+            .filter(|fip| provision::is_owned_by(fip, service) || Some(&fip.ip) == load_balancer_ip)
+            .map(|fip| fip.ip.clone()),
+    );
+    ips
+}
+
+/// Returns the drain delay of every IP of the annotated services, floating
+/// IPs being looked up among `floating_ips`.
+pub fn drain_delays(
+    services: &Store<KubeService>,
+    floating_ips: &[FloatingIp],
+) -> HashMap<String, Duration> {
+    let mut delays: HashMap<String, Duration> = HashMap::new();
+    for service in services.state() {
+        let delay = match drain_delay(&service) {
+            Some(delay) => delay,
+            None => continue,
+        };
+        for ip in service_ips(&service, floating_ips) {
+            let entry = delays.entry(ip).or_default();
+            *entry = (*entry).max(delay);
+        }
+    }
+    delays
+}
+
+/// Starts draining `node` for `delay`, returning when the drain ends.
+pub fn start(node: &str, delay: Duration) -> Instant {
+    let deadline = Instant::now() + delay;
+    DEADLINES.lock().unwrap().insert(node.to_string(), deadline);
+    deadline
+}
+
+/// When the drain of `node` ends or ended, if it was started.
+pub fn deadline(node: &str) -> Option<Instant> {
+    DEADLINES.lock().unwrap().get(node).copied()
+}
+
+/// When the reconcile of `node` has to run again, if it is still draining.
+pub fn pending(node: &str) -> Option<Instant> {
+    deadline(node).filter(|deadline| *deadline > Instant::now())
+}
+
+/// Forgets the drain of `node` once it is available again, returning whether
+/// it was still draining.
+pub fn cancel(node: &str) -> bool {
+    DEADLINES
+        .lock()
+        .unwrap()
+        .remove(node)
+        .map(|deadline| deadline > Instant::now())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fip(id: i32, ip: &str, name: &str, labels: &[(&str, &str)]) -> FloatingIp {
+        FloatingIp {
+            id,
+            ip: ip.into(),
+            name: name.into(),
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn service(value: serde_json::Value) -> KubeService {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn service_ips_cover_pins_external_and_provisioned_ips() {
+        let fips = vec![
+            fip(1, "198.51.100.1", "web", &[]),
+            fip(
+                2,
+                "198.51.100.2",
+                "provisioned",
+                &[
+                    (provision::NAMESPACE_LABEL, "default"),
+                    (provision::NAME_LABEL, "api"),
+                ],
+            ),
+        ];
+
+        let pinned = service(serde_json::json!({
+            "metadata": {"name": "web", "annotations": {pin::IP_ANNOTATION: "web"}},
+        }));
+        assert_eq!(service_ips(&pinned, &fips), vec!["198.51.100.1"]);
+
+        let provisioned = service(serde_json::json!({
+            "metadata": {"name": "api", "namespace": "default"},
+            "spec": {"externalIPs": ["203.0.113.1"]},
+        }));
+        assert_eq!(
+            service_ips(&provisioned, &fips),
+            vec!["203.0.113.1", "198.51.100.2"]
+        );
+    }
+}
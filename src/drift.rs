@@ -0,0 +1,76 @@
+//! Floating IPs moved behind the controller's back, e.g. from the console or
+//! by other tooling. The server each IP was last moved to or first found on
+//! is remembered, and an IP found elsewhere on a later reconcile has drifted:
+//! it is reported, and moved back with `--drift-policy correct`.
+
+use crate::metrics;
+use clap::ValueEnum;
+use hcloud::models::FloatingIp;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DriftPolicy {
+    /// Move drifted IPs back to their server while it is eligible
+    Correct,
+    /// Only report drifted IPs
+    Report,
+}
+
+static POLICY: Lazy<RwLock<DriftPolicy>> = Lazy::new(|| RwLock::new(DriftPolicy::Correct));
+
+/// Server of each floating IP by ID, as the controller left it.
+static DESIRED: Lazy<Mutex<HashMap<i32, i32>>> = Lazy::new(Default::default);
+
+pub fn set_policy(policy: DriftPolicy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+pub fn policy() -> DriftPolicy {
+    *POLICY.read().unwrap()
+}
+
+/// Notes that the controller moved `fip_id` to `server_id`.
+pub fn assigned(fip_id: i32, server_id: i32) {
+    DESIRED.lock().unwrap().insert(fip_id, server_id);
+}
+
+/// Notes that the controller unassigned `fip_id`.
+pub fn unassigned(fip_id: i32) {
+    DESIRED.lock().unwrap().remove(&fip_id);
+}
+
+/// The server `fip` should be on when it drifted off it. IPs seen for the
+/// first time are remembered where they are.
+pub fn check(fip: &FloatingIp) -> Option<i32> {
+    let mut desired = DESIRED.lock().unwrap();
+    match (fip.server, desired.get(&fip.id).copied()) {
+        (Some(server), Some(want)) if server != want => Some(want),
+        (None, Some(want)) => Some(want),
+        (Some(server), None) => {
+            desired.insert(fip.id, server);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Counts a drift of `fip` off `desired`, `corrected` when it is moved back.
+/// Drifts left alone are reported once, the IP is then expected where it is.
+pub fn record(fip: &FloatingIp, desired: i32, corrected: bool) {
+    let ip = &fip.ip;
+    let actual = fip.server;
+    if let (false, Some(actual)) = (corrected, actual) {
+        assigned(fip.id, actual);
+    }
+    println!(
+        "{} drifted from server {} to {:?}{}",
+        ip,
+        desired,
+        actual,
+        if corrected { ", moving it back" } else { "" }
+    );
+    let action = if corrected { "corrected" } else { "reported" };
+    metrics::DRIFTS.with_label_values(&[action]).inc();
+}
@@ -0,0 +1,140 @@
+//! Dual-stack Services. A Service claiming an address in an IPv6 floating IP,
+//! whose network is all hcloud knows of, claims that floating IP. When it
+//! claims an IPv4 and an IPv6 floating IP both are kept on the same server:
+//! the IPv6 one follows wherever the IPv4 one goes, and they fail over
+//! together in the same reconcile.
+
+use crate::conflicts::claimed_ips;
+use crate::{claims_ips, Context};
+use hcloud::models::{FloatingIp, IpType};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Whether `ip` is the floating IP `fip`, or in it for IPv6 networks.
+pub fn is_in(ip: IpAddr, fip: &str) -> bool {
+    let (address, prefix) = match fip.split_once('/') {
+        Some((address, prefix)) => (address, prefix.parse::<u32>().unwrap_or(128)),
+        None => (fip, 128),
+    };
+    match (ip, address.parse::<IpAddr>()) {
+        (IpAddr::V4(ip), Ok(IpAddr::V4(address))) => ip == address,
+        (IpAddr::V6(ip), Ok(IpAddr::V6(address))) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Whether one of `ips` is `fip` or in it.
+pub fn claims<S: AsRef<str>>(ips: impl IntoIterator<Item = S>, fip: &FloatingIp) -> bool {
+    ips.into_iter().any(|ip| {
+        let ip = ip.as_ref();
+        ip == fip.ip
+            || ip
+                .parse()
+                .map(|address| is_in(address, &fip.ip))
+                .unwrap_or(false)
+    })
+}
+
+/// The IPv4 and IPv6 floating IP IDs claimed together by a Service, among
+/// `fips`.
+pub fn pairs(ctx: &Context, fips: &[FloatingIp]) -> Vec<(i32, i32)> {
+    let mut pairs = vec![];
+    for service in ctx.services.state() {
+        if !claims_ips(&service) {
+            continue;
+        }
+        let ips = claimed_ips(&service);
+        let family = |type_: IpType| {
+            fips.iter()
+                .find(|fip| fip.r#type == type_ && claims(&ips, fip))
+                .map(|fip| fip.id)
+        };
+        if let (Some(v4), Some(v6)) = (family(IpType::Ipv4), family(IpType::Ipv6)) {
+            if !pairs.contains(&(v4, v6)) {
+                pairs.push((v4, v6));
+            }
+        }
+    }
+    pairs
+}
+
+/// Points the moves of paired floating IPs at the same server: the IPv6 one
+/// goes where the IPv4 one goes, and an IP moved alone joins its partner
+/// when that one is held by one of `candidates`.
+pub fn align(
+    moves: &mut [(FloatingIp, Option<i32>)],
+    pairs: &[(i32, i32)],
+    fips: &[FloatingIp],
+    candidates: &HashSet<i32>,
+) {
+    let target = |moves: &[(FloatingIp, Option<i32>)], id: i32| {
+        moves
+            .iter()
+            .find(|(fip, _)| fip.id == id)
+            .map(|(_, target)| *target)
+    };
+    let holder = |id: i32| {
+        fips.iter()
+            .find(|fip| fip.id == id)
+            .and_then(|fip| fip.server)
+            .filter(|server| candidates.contains(server))
+    };
+    for &(v4, v6) in pairs {
+        let (follower, target) = match (target(moves, v4), target(moves, v6)) {
+            (Some(v4_target), Some(_)) => (v6, v4_target),
+            (Some(_), None) => match holder(v6) {
+                Some(server) => (v4, Some(server)),
+                None => continue,
+            },
+            (None, Some(_)) => match holder(v4) {
+                Some(server) => (v6, Some(server)),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+        for (fip, old) in moves.iter_mut().filter(|(fip, _)| fip.id == follower) {
+            if *old != target {
+                println!(
+                    "keeping {} with its dual-stack pair on {:?}",
+                    fip.ip, target
+                );
+                *old = target;
+            }
+        }
+    }
+}
+
+/// Moves bringing the IPv6 floating IP of each pair back next to its IPv4
+/// one, when both are held by one of `candidates` but different servers.
+pub fn diverged(
+    pairs: &[(i32, i32)],
+    fips: &[FloatingIp],
+    candidates: &HashSet<i32>,
+) -> Vec<(FloatingIp, Option<i32>)> {
+    let find = |id: i32| fips.iter().find(|fip| fip.id == id);
+    let mut moves = vec![];
+    for &(v4, v6) in pairs {
+        let (v4, v6) = match (find(v4), find(v6)) {
+            (Some(v4), Some(v6)) => (v4, v6),
+            _ => continue,
+        };
+        match (v4.server, v6.server) {
+            (Some(v4_server), Some(v6_server))
+                if v4_server != v6_server
+                    && candidates.contains(&v4_server)
+                    && candidates.contains(&v6_server) =>
+            {
+                println!(
+                    "{} diverged from its dual-stack pair {}, moving it to {}",
+                    v6.ip, v4.ip, v4_server
+                );
+                moves.push((v6.clone(), Some(v4_server)));
+            }
+            _ => {}
+        }
+    }
+    moves
+}
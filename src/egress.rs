@@ -0,0 +1,195 @@
+//! Egress IPs, floating IPs used as stable SNAT source addresses rather than
+//! claimed by a Service. Each one is kept attached to an available node at
+//! all times: unassigned ones or ones held by a server that can't take them
+//! are moved to the least loaded candidate. With `--egress-node-label` the
+//! nodes holding egress IPs carry that label, and the IPs they hold in the
+//! `fip.hcloud.barodeur.io/egress-ips` annotation, so an egress gateway such
+//! as an SNAT rule manager can react.
+
+use crate::fip_filter::FipMatcher;
+use crate::shutdown::{self, Shutdown};
+use crate::throttle::ActionClass;
+use crate::{
+    available_hc_server_ids, fetch_floating_ips, get_hc_server_id, is_dry_run, move_floating_ips,
+    placement, pools, priority, probed_candidates, projects, Context, Error,
+};
+use hcloud::models::FloatingIp;
+use kube::api::{Patch, PatchParams};
+use kube::ResourceExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const EGRESS_IPS_ANNOTATION: &str = "fip.hcloud.barodeur.io/egress-ips";
+
+/// How often the egress IPs are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct EgressConfig {
+    /// Egress floating IPs by ID, address or name.
+    pub ips: Vec<FipMatcher>,
+    /// hcloud label key marking egress floating IPs.
+    pub fip_label: Option<String>,
+    /// Label set to `true` on the nodes holding egress IPs.
+    pub node_label: Option<String>,
+}
+
+impl EgressConfig {
+    fn is_egress(&self, fip: &FloatingIp) -> bool {
+        self.ips.iter().any(|matcher| matcher.matches(fip))
+            || self
+                .fip_label
+                .as_ref()
+                .map(|label| fip.labels.contains_key(label))
+                .unwrap_or(false)
+    }
+}
+
+/// Attaches the egress IPs of every project.
+async fn attach(ctx: &Context, config: &EgressConfig) -> Result<(), Error> {
+    let available = available_hc_server_ids(&ctx.nodes);
+    let priorities = priority::hc_priorities(&ctx.nodes);
+    let nodes = pools::known_nodes(ctx);
+    for project in &ctx.projects {
+        let hcloud_conf = &project.conf();
+        let fips = fetch_floating_ips(hcloud_conf).await?;
+        let project_available =
+            projects::project_server_ids(&ctx.projects, project, &available).await?;
+        let stray: Vec<_> = fips
+            .iter()
+            .filter(|fip| config.is_egress(fip))
+            .filter(|fip| match fip.server {
+                Some(server) => {
+                    !project_available.contains(&server) || !pools::allows(&nodes, fip, server)
+                }
+                None => true,
+            })
+            .cloned()
+            .collect();
+        if stray.is_empty() {
+            continue;
+        }
+        let candidates = probed_candidates(ctx, hcloud_conf, &project_available).await?;
+        let locations = placement::server_locations(hcloud_conf, ctx.location_policy).await?;
+        let domains = placement::server_domains(hcloud_conf, ctx.failure_domain).await?;
+        let mut load = placement::load(&fips);
+        let moves = stray
+            .into_iter()
+            .map(|fip| {
+                let pooled = pools::restrict(&nodes, &fip, &candidates);
+                let home =
+                    placement::in_home_location(ctx.location_policy, &fip, &pooled, &locations);
+                let ids = priority::preferred(home, &priorities);
+                let target_id = placement::least_loaded(&ids, &mut load, &domains);
+                if let Some(target_id) = target_id {
+                    println!("attaching egress ip {} to {}", fip.ip, target_id);
+                }
+                (fip, target_id)
+            })
+            .collect();
+        move_floating_ips(ctx, project, moves, ActionClass::Reassign).await?;
+    }
+    Ok(())
+}
+
+/// The egress IPs held by each server as hcloud has them, i.e. after the
+/// moves that went through, not the ones held back or failed.
+async fn held(ctx: &Context, config: &EgressConfig) -> Result<HashMap<i32, Vec<String>>, Error> {
+    let mut held: HashMap<i32, Vec<String>> = HashMap::new();
+    for project in &ctx.projects {
+        for fip in fetch_floating_ips(&project.conf()).await? {
+            if let Some(server) = fip.server.filter(|_| config.is_egress(&fip)) {
+                held.entry(server).or_default().push(fip.ip);
+            }
+        }
+    }
+    Ok(held)
+}
+
+/// Labels the nodes holding egress IPs and unlabels the others.
+async fn label_nodes(
+    ctx: &Context,
+    node_label: &str,
+    held: &HashMap<i32, Vec<String>>,
+) -> Result<(), Error> {
+    for node in ctx.nodes.state() {
+        let mut ips = get_hc_server_id(&node)
+            .and_then(|server| held.get(&server))
+            .cloned()
+            .unwrap_or_default();
+        ips.sort();
+        let labeled = node.labels().get(node_label).map(String::as_str) == Some("true");
+        let annotated = node.annotations().get(EGRESS_IPS_ANNOTATION);
+        let patch = if ips.is_empty() && (labeled || annotated.is_some()) {
+            serde_json::json!({
+                "metadata": {
+                    "labels": { node_label: null },
+                    "annotations": { EGRESS_IPS_ANNOTATION: null },
+                }
+            })
+        } else if !ips.is_empty() && (!labeled || annotated != Some(&ips.join(","))) {
+            serde_json::json!({
+                "metadata": {
+                    "labels": { node_label: "true" },
+                    "annotations": { EGRESS_IPS_ANNOTATION: ips.join(",") },
+                }
+            })
+        } else {
+            continue;
+        };
+        if is_dry_run() {
+            println!(
+                "dry run: would update the egress label of node {}",
+                node.name_any()
+            );
+            continue;
+        }
+        ctx.nodes_api
+            .patch(
+                &node.name_any(),
+                &PatchParams::default(),
+                &Patch::Merge(&patch),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn check(ctx: &Context, config: &EgressConfig) -> Result<(), Error> {
+    // The nodes are labeled even when a move failed, after the others.
+    let attached = attach(ctx, config).await;
+    if let Some(node_label) = &config.node_label {
+        label_nodes(ctx, node_label, &held(ctx, config).await?).await?;
+    }
+    attached
+}
+
+/// Keeps the egress IPs attached until shutdown.
+pub async fn run(
+    ctx: Arc<Context>,
+    config: EgressConfig,
+    mut shutdown: Shutdown,
+    shutdown_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        let result = shutdown::run_graceful(
+            &mut shutdown,
+            shutdown_timeout,
+            "egress",
+            check(&ctx, &config),
+        )
+        .await;
+        if let Some(Err(err)) = result {
+            println!("egress check failed: {}", err);
+        }
+        if shutdown.is_requested() {
+            return;
+        }
+    }
+}
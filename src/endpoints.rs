@@ -0,0 +1,69 @@
+//! Ready endpoints of Services, whose IPs are kept on nodes running one of
+//! their pods: always with `externalTrafficPolicy: Local`, whose traffic is
+//! only answered by such nodes, and for every Service with
+//! `--follow-endpoints`.
+
+use k8s_openapi::api::core::v1::Service as KubeService;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::runtime::reflector::{ObjectRef, Store};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+static FOLLOW_ALL: AtomicBool = AtomicBool::new(false);
+
+pub fn set_follow_all(follow_all: bool) {
+    FOLLOW_ALL.store(follow_all, Ordering::Relaxed);
+}
+
+fn is_local(service: &KubeService) -> bool {
+    service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.external_traffic_policy.as_deref())
+        == Some("Local")
+}
+
+/// Whether the IPs of `service` follow its ready endpoints.
+pub fn follows(service: &KubeService) -> bool {
+    FOLLOW_ALL.load(Ordering::Relaxed) || is_local(service)
+}
+
+/// The Service `slice` belongs to, if it is in the cache.
+pub fn owner(slice: &EndpointSlice, services: &Store<KubeService>) -> Option<KubeService> {
+    let name = slice.metadata.labels.as_ref()?.get(SERVICE_NAME_LABEL)?;
+    let namespace = slice.metadata.namespace.as_ref()?;
+    services
+        .get(&ObjectRef::new(name).within(namespace))
+        .map(|service| (*service).clone())
+}
+
+/// Names of the nodes running a ready endpoint of `service`. Endpoints
+/// without a ready condition count as ready, as in kube-proxy.
+pub fn ready_nodes(service: &KubeService, slices: &Store<EndpointSlice>) -> HashSet<String> {
+    let name = service.metadata.name.as_ref();
+    let namespace = service.metadata.namespace.as_ref();
+    slices
+        .state()
+        .iter()
+        .filter(|slice| {
+            slice.metadata.namespace.as_ref() == namespace
+                && slice
+                    .metadata
+                    .labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(SERVICE_NAME_LABEL))
+                    == name
+        })
+        .flat_map(|slice| slice.endpoints.iter())
+        .filter(|endpoint| {
+            endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                .unwrap_or(true)
+        })
+        .filter_map(|endpoint| endpoint.node_name.clone())
+        .collect()
+}
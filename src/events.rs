@@ -0,0 +1,69 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::Client as KubeClient;
+use std::env;
+
+/// Publishes Kubernetes events on the objects the controller acts upon.
+///
+/// Failing to publish an event is logged and otherwise ignored, it must never
+/// get in the way of a failover.
+#[derive(Clone)]
+pub struct EventPublisher {
+    client: KubeClient,
+    reporter: Reporter,
+}
+
+impl EventPublisher {
+    pub fn new(client: KubeClient) -> Self {
+        EventPublisher {
+            client,
+            reporter: Reporter {
+                controller: "hcloud-fip-controller".into(),
+                instance: env::var("POD_NAME").ok(),
+            },
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        reference: ObjectReference,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) {
+        let recorder = Recorder::new(self.client.clone(), self.reporter.clone(), reference);
+        let event = Event {
+            type_,
+            reason: reason.into(),
+            note: Some(note),
+            action: action.into(),
+            secondary: None,
+        };
+        if let Err(err) = recorder.publish(event).await {
+            println!("failed to publish {} event: {}", reason, err);
+        }
+    }
+
+    pub async fn normal(
+        &self,
+        reference: ObjectReference,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) {
+        self.publish(reference, EventType::Normal, reason, action, note)
+            .await
+    }
+
+    pub async fn warning(
+        &self,
+        reference: ObjectReference,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) {
+        self.publish(reference, EventType::Warning, reason, action, note)
+            .await
+    }
+}
@@ -0,0 +1,198 @@
+//! Failback of evacuated floating IPs. Without it an IP stays on the server
+//! it failed over to forever, so after a rolling maintenance every IP ends up
+//! on the last nodes drained. With `--failback` each evacuated IP remembers
+//! the server it was moved off, and once the node of that server has been
+//! available for `--failback-delay` the IP is moved back, unless its Service
+//! can't be served from there, the IP is cooling down, or it is outside of
+//! the `--rebalance-windows`.
+
+use crate::conflicts::claimed_ips;
+use crate::cooldown;
+use crate::maintenance;
+use crate::pools;
+use crate::shutdown::{self, Shutdown};
+use crate::throttle::ActionClass;
+use crate::{
+    claims_ips, eligible_nodes, evacuation_reason, fetch_floating_ips, get_hc_server_id,
+    move_floating_ips, Context, Error,
+};
+use hcloud::models::FloatingIp;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the home servers are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct FailbackConfig {
+    /// How long the node of a home server must be available before its IPs
+    /// move back.
+    pub delay: Duration,
+}
+
+/// The `--failback-delay`, set when failback is enabled.
+static DELAY: OnceCell<Duration> = OnceCell::new();
+
+/// Server each evacuated floating IP was moved off, by ID.
+static HOMES: Lazy<Mutex<HashMap<i32, i32>>> = Lazy::new(Default::default);
+
+/// Since when the node of each home server is available, by server ID.
+static AVAILABLE_SINCE: Lazy<Mutex<HashMap<i32, Instant>>> = Lazy::new(Default::default);
+
+pub fn enable(delay: Duration) {
+    let _ = DELAY.set(delay);
+}
+
+/// Notes that `fip` was just moved to `server_id` as `class`: an evacuated
+/// IP remembers the server it was moved off, and forgets it once moved back.
+pub fn moved(fip: &FloatingIp, server_id: i32, class: ActionClass) {
+    if DELAY.get().is_none() {
+        return;
+    }
+    let mut homes = HOMES.lock().unwrap();
+    match fip.server {
+        Some(from) if class == ActionClass::Failover => {
+            homes.entry(fip.id).or_insert(from);
+        }
+        _ if homes.get(&fip.id) == Some(&server_id) => {
+            homes.remove(&fip.id);
+        }
+        _ => {}
+    }
+}
+
+/// The evacuated floating IPs by ID, with their home server and how long
+/// until they may fail back once its node stays available, `None` while it
+/// isn't.
+pub fn pending() -> Vec<(i32, i32, Option<Duration>)> {
+    let delay = DELAY.get().copied().unwrap_or_default();
+    let homes = HOMES.lock().unwrap();
+    let available_since = AVAILABLE_SINCE.lock().unwrap();
+    let mut pending: Vec<_> = homes
+        .iter()
+        .map(|(fip_id, home)| {
+            let left = available_since
+                .get(home)
+                .map(|since| delay.saturating_sub(since.elapsed()));
+            (*fip_id, *home, left)
+        })
+        .collect();
+    pending.sort();
+    pending
+}
+
+/// The home servers whose node has been available for `delay`. Homes whose
+/// node is gone are forgotten.
+fn stable_homes(ctx: &Context, delay: Duration) -> Vec<i32> {
+    let nodes = ctx.nodes.state();
+    let mut homes = HOMES.lock().unwrap();
+    let mut available_since = AVAILABLE_SINCE.lock().unwrap();
+    let mut stable = vec![];
+    let servers: Vec<i32> = homes.values().copied().collect();
+    for server_id in servers {
+        let node = nodes
+            .iter()
+            .find(|node| get_hc_server_id(node) == Some(server_id));
+        match node {
+            None => {
+                homes.retain(|_, home| *home != server_id);
+                available_since.remove(&server_id);
+            }
+            Some(node) if evacuation_reason(node).is_some() => {
+                available_since.remove(&server_id);
+            }
+            Some(_) => {
+                let since = *available_since
+                    .entry(server_id)
+                    .or_insert_with(Instant::now);
+                if since.elapsed() >= delay && !stable.contains(&server_id) {
+                    stable.push(server_id);
+                }
+            }
+        }
+    }
+    stable
+}
+
+/// Whether the Services claiming `ip` may be served from `server_id`.
+fn may_serve(ctx: &Context, ip: &String, server_id: i32) -> bool {
+    ctx.services
+        .state()
+        .iter()
+        .filter(|service| claims_ips(service) && claimed_ips(service).contains(&ip))
+        .all(|service| {
+            eligible_nodes(ctx, service)
+                .iter()
+                .any(|node| get_hc_server_id(node) == Some(server_id))
+        })
+}
+
+async fn fail_back(ctx: &Context, delay: Duration) -> Result<(), Error> {
+    let stable = stable_homes(ctx, delay);
+    // Evacuated IPs stay where they are until a rebalance window opens.
+    if !maintenance::may_rebalance() {
+        return Ok(());
+    }
+    if stable.is_empty() {
+        return Ok(());
+    }
+    let homes = HOMES.lock().unwrap().clone();
+    for project in &ctx.projects {
+        let mut moves = vec![];
+        for fip in fetch_floating_ips(&project.conf()).await? {
+            let home = match homes.get(&fip.id) {
+                Some(home) if stable.contains(home) => *home,
+                _ => continue,
+            };
+            // Moved back by hand meanwhile.
+            if fip.server == Some(home) {
+                HOMES.lock().unwrap().remove(&fip.id);
+                continue;
+            }
+            // Kept, and retried later, until the cooldown is over or the IP
+            // may be served from its home again. The home is forgotten once
+            // it was moved back.
+            if cooldown::blocker(&ctx.nodes, &fip).is_some()
+                || !may_serve(ctx, &fip.ip, home)
+                || !pools::allows(&pools::known_nodes(ctx), &fip, home)
+            {
+                continue;
+            }
+            println!("{} failing back to server {}", fip.ip, home);
+            moves.push((fip, Some(home)));
+        }
+        move_floating_ips(ctx, project, moves, ActionClass::Rebalance).await?;
+    }
+    Ok(())
+}
+
+/// Moves the evacuated IPs back to their home server until shutdown.
+pub async fn run(
+    ctx: Arc<Context>,
+    config: FailbackConfig,
+    mut shutdown: Shutdown,
+    shutdown_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        let result = shutdown::run_graceful(
+            &mut shutdown,
+            shutdown_timeout,
+            "failback",
+            fail_back(&ctx, config.delay),
+        )
+        .await;
+        if let Some(Err(err)) = result {
+            println!("failback failed: {}", err);
+        }
+        if shutdown.is_requested() {
+            return;
+        }
+    }
+}
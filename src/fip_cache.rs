@@ -0,0 +1,67 @@
+//! Short-lived cache of the floating IP list of each project, so a burst of
+//! watch events doesn't turn into a burst of hcloud API calls. Entries are
+//! dropped as soon as a floating IP of the project is assigned.
+
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::FloatingIp;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Zero disables the cache.
+static TTL_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    fetched_at: Instant,
+    floating_ips: Vec<FloatingIp>,
+}
+
+/// Keyed by the API token, which identifies the project.
+static CACHE: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(Default::default);
+
+pub fn set_ttl(ttl: Duration) {
+    TTL_MILLIS.store(ttl.as_millis() as u64, Ordering::Relaxed);
+}
+
+fn ttl() -> Duration {
+    Duration::from_millis(TTL_MILLIS.load(Ordering::Relaxed))
+}
+
+pub fn get(hcloud_conf: &Configuration) -> Option<Vec<FloatingIp>> {
+    let token = hcloud_conf.bearer_access_token.as_ref()?;
+    let cache = CACHE.lock().unwrap();
+    let entry = cache.get(token)?;
+    (entry.fetched_at.elapsed() < ttl()).then(|| entry.floating_ips.clone())
+}
+
+/// The cached list of the project of `hcloud_conf` with its age, even when
+/// expired.
+pub fn cached(hcloud_conf: &Configuration) -> Option<(Duration, Vec<FloatingIp>)> {
+    let token = hcloud_conf.bearer_access_token.as_ref()?;
+    let cache = CACHE.lock().unwrap();
+    let entry = cache.get(token)?;
+    Some((entry.fetched_at.elapsed(), entry.floating_ips.clone()))
+}
+
+pub fn insert(hcloud_conf: &Configuration, fips: &[FloatingIp]) {
+    if ttl().is_zero() {
+        return;
+    }
+    if let Some(token) = &hcloud_conf.bearer_access_token {
+        CACHE.lock().unwrap().insert(
+            token.clone(),
+            Entry {
+                fetched_at: Instant::now(),
+                floating_ips: fips.to_vec(),
+            },
+        );
+    }
+}
+
+pub fn invalidate(hcloud_conf: &Configuration) {
+    if let Some(token) = &hcloud_conf.bearer_access_token {
+        CACHE.lock().unwrap().remove(token);
+    }
+}
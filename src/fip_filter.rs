@@ -0,0 +1,76 @@
+//! Floating IPs explicitly included in or excluded from management, e.g. a
+//! legacy IP of the project that must never be touched. Left out IPs are
+//! invisible to the controller, as if they were in another project.
+
+use hcloud::models::FloatingIp;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Names floating IPs by ID, address or name.
+#[derive(Debug, Clone)]
+pub enum FipMatcher {
+    Id(i32),
+    /// An IPv4 address or IPv6 network, with or without its prefix length.
+    Ip(String),
+    /// A name, where `*` matches any characters.
+    Name(Regex),
+}
+
+impl FromStr for FipMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty floating ip matcher".into());
+        }
+        if let Ok(id) = s.parse() {
+            return Ok(FipMatcher::Id(id));
+        }
+        let address = s.split('/').next().unwrap_or(s);
+        if address.parse::<IpAddr>().is_ok() {
+            return Ok(FipMatcher::Ip(address.to_string()));
+        }
+        let pattern = s
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(".*");
+        Regex::new(&format!("^{}$", pattern))
+            .map(FipMatcher::Name)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl FipMatcher {
+    pub fn matches(&self, fip: &FloatingIp) -> bool {
+        match self {
+            FipMatcher::Id(id) => fip.id == *id,
+            FipMatcher::Ip(ip) => fip.ip.split('/').next() == Some(ip),
+            FipMatcher::Name(name) => name.is_match(&fip.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FipFilter {
+    /// Only these are managed when not empty.
+    pub include: Vec<FipMatcher>,
+    /// Never managed, even when included.
+    pub exclude: Vec<FipMatcher>,
+}
+
+static FILTER: Lazy<RwLock<FipFilter>> = Lazy::new(Default::default);
+
+pub fn set_filter(filter: FipFilter) {
+    *FILTER.write().unwrap() = filter;
+}
+
+/// Whether the controller may see and move `fip`.
+pub fn is_managed(fip: &FloatingIp) -> bool {
+    let filter = FILTER.read().unwrap();
+    (filter.include.is_empty() || filter.include.iter().any(|matcher| matcher.matches(fip)))
+        && !filter.exclude.iter().any(|matcher| matcher.matches(fip))
+}
@@ -0,0 +1,234 @@
+//! Per floating and alias IP locks shared by every reconcile task, so two
+//! tasks never move the same IP at once.
+//!
+//! With `--fip-lease-namespace` each lock also takes a Kubernetes Lease named
+//! after the IP, so neither other replicas of the controller nor a
+//! migration script taking the same Leases assign it meanwhile. The Lease is
+//! renewed while the IP is being moved and released afterwards, and the move
+//! is cancelled when it can't be renewed; one held by a holder that stopped
+//! renewing it is taken over once it expires. `hold` keeps a Lease across
+//! calls instead, such as the one of the replica publishing the status.
+
+use crate::alias_ips::AliasIp;
+use crate::{clusters, Error};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+use kube::api::PostParams;
+use kube::Api;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// The locks by the name of their Lease.
+static LOCKS: Lazy<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>> = Lazy::new(Default::default);
+
+/// Namespace and duration of the Leases.
+static LEASES: OnceCell<(String, Duration)> = OnceCell::new();
+static LEASES_API: tokio::sync::OnceCell<Api<Lease>> = tokio::sync::OnceCell::const_new();
+
+/// Holder of the Leases taken by this process, the pod or host name with the
+/// process ID, so a script run in the controller pod doesn't pass for it.
+static IDENTITY: Lazy<String> = Lazy::new(|| {
+    let name = env::var("POD_NAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".into());
+    format!("{}-{}", name, process::id())
+});
+
+pub fn set_leases(namespace: String, duration: Duration) {
+    let _ = LEASES.set((namespace, duration));
+}
+
+pub fn leases_enabled() -> bool {
+    LEASES.get().is_some()
+}
+
+/// Holder identity of the Leases taken by this process.
+pub fn identity() -> &'static str {
+    &IDENTITY
+}
+
+/// Takes or renews the Lease `name` for `duration` without releasing it, so
+/// it stays with this process as long as it keeps calling this. Whether this
+/// process holds it, false while someone else does.
+pub async fn hold(name: &str, duration: Duration) -> Result<bool, Error> {
+    let held_by_other = api()
+        .await?
+        .get_opt(name)
+        .await?
+        .and_then(|lease| lease.spec)
+        .map(|spec| other_holder(&spec).is_some())
+        .unwrap_or(false);
+    if held_by_other {
+        return Ok(false);
+    }
+    acquire(name, duration).await?;
+    Ok(true)
+}
+
+/// Runs `action` under the lock of `fip_id`: waits until no other task holds
+/// it, then takes its Lease when enabled and releases it once `action` is
+/// done. Fails when someone else holds the Lease, and cancels `action` when
+/// the Lease can't be renewed before it expires, as another holder may take
+/// it then.
+pub async fn locked<T>(
+    fip_id: i32,
+    action: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    locked_as(format!("hcloud-fip-{}", fip_id), action).await
+}
+
+/// Runs `action` under the lock of the alias IP `alias`, like `locked`.
+pub async fn alias_locked<T>(
+    alias: &AliasIp,
+    action: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    // Lease names can't hold the colons of IPv6 addresses.
+    let ip = alias.ip.to_ascii_lowercase().replace([':', '/'], "-");
+    locked_as(format!("hcloud-alias-{}-{}", alias.network, ip), action).await
+}
+
+async fn locked_as<T>(
+    name: String,
+    action: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let lock = LOCKS
+        .lock()
+        .unwrap()
+        .entry(name.clone())
+        .or_default()
+        .clone();
+    let _local = lock.lock_owned().await;
+    let duration = match LEASES.get() {
+        Some((_, duration)) => *duration,
+        None => return action.await,
+    };
+    acquire(&name, duration).await?;
+    let result = tokio::select! {
+        result = action => result,
+        err = renew(&name, duration) => Err(err),
+    };
+    // Released before the next task waiting on the lock takes the Lease.
+    if let Err(err) = release(&name).await {
+        println!("releasing lease {} failed: {}", name, err);
+    }
+    result
+}
+
+async fn api() -> Result<&'static Api<Lease>, Error> {
+    LEASES_API
+        .get_or_try_init(|| async {
+            let (namespace, _) = LEASES.get().ok_or("floating ip leases are disabled")?;
+            let client = clusters::local_client().await?;
+            Ok::<_, Error>(Api::namespaced(client, namespace))
+        })
+        .await
+}
+
+fn now() -> MicroTime {
+    MicroTime(Utc::now())
+}
+
+/// The holder of `spec` unless it is this process or let it expire.
+fn other_holder(spec: &LeaseSpec) -> Option<&String> {
+    let holder = spec.holder_identity.as_ref()?;
+    if *holder == *IDENTITY {
+        return None;
+    }
+    let renewed = spec.renew_time.as_ref().or(spec.acquire_time.as_ref())?;
+    let duration = ChronoDuration::seconds(spec.lease_duration_seconds.unwrap_or(0).into());
+    (renewed.0 + duration > Utc::now()).then_some(holder)
+}
+
+/// Maps a conflict, another holder taking the Lease first, to a readable
+/// error.
+fn taken(err: kube::Error, name: &str) -> Error {
+    match err {
+        kube::Error::Api(response) if response.code == 409 => {
+            format!("lease {} was taken meanwhile", name).into()
+        }
+        err => err.into(),
+    }
+}
+
+async fn acquire(name: &str, duration: Duration) -> Result<(), Error> {
+    let api = api().await?;
+    let seconds = duration.as_secs() as i32;
+    let mut lease = match api.get_opt(name).await? {
+        Some(lease) => lease,
+        None => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(name.into()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(IDENTITY.clone()),
+                    lease_duration_seconds: Some(seconds),
+                    acquire_time: Some(now()),
+                    renew_time: Some(now()),
+                    lease_transitions: Some(0),
+                }),
+            };
+            api.create(&PostParams::default(), &lease)
+                .await
+                .map_err(|err| taken(err, name))?;
+            return Ok(());
+        }
+    };
+    let spec = lease.spec.get_or_insert_with(Default::default);
+    if let Some(holder) = other_holder(spec) {
+        return Err(format!("lease {} is held by {}", name, holder).into());
+    }
+    if spec.holder_identity.as_ref() != Some(&*IDENTITY) {
+        spec.holder_identity = Some(IDENTITY.clone());
+        spec.acquire_time = Some(now());
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+    }
+    spec.lease_duration_seconds = Some(seconds);
+    spec.renew_time = Some(now());
+    // Carries the resource version, so a concurrent taker conflicts.
+    api.replace(name, &PostParams::default(), &lease)
+        .await
+        .map_err(|err| taken(err, name))?;
+    Ok(())
+}
+
+/// Renews the Lease three times per duration, returns why once it failed to
+/// for so long that it expires before the next attempt.
+async fn renew(name: &str, duration: Duration) -> Error {
+    let mut renewed = Instant::now();
+    loop {
+        tokio::time::sleep(duration / 3).await;
+        match acquire(name, duration).await {
+            Ok(()) => renewed = Instant::now(),
+            Err(err) if renewed.elapsed() + duration / 3 >= duration => {
+                println!("lost lease {}, cancelling the move: {}", name, err);
+                return format!("lost lease {}: {}", name, err).into();
+            }
+            Err(err) => println!("renewing lease {} failed: {}", name, err),
+        }
+    }
+}
+
+/// Lets go of the Lease unless someone else took it over.
+async fn release(name: &str) -> Result<(), Error> {
+    let api = api().await?;
+    let mut lease = match api.get_opt(name).await? {
+        Some(lease) => lease,
+        None => return Ok(()),
+    };
+    let spec = lease.spec.get_or_insert_with(Default::default);
+    if spec.holder_identity.as_ref() != Some(&*IDENTITY) {
+        return Ok(());
+    }
+    spec.holder_identity = None;
+    api.replace(name, &PostParams::default(), &lease).await?;
+    Ok(())
+}
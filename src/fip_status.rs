@@ -0,0 +1,158 @@
+//! Cluster-scoped `FloatingIPStatus` resources, one per managed floating IP,
+//! reflecting where the controller sees it, so `kubectl get fip` answers
+//! "where is my IP right now?".
+
+use crate::mapping::claimants;
+use crate::projects::Project;
+use crate::{fetch_floating_ips, get_hc_server_id, list_all, pause, Error};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams};
+use kube::runtime::reflector::Store;
+use kube::{Api, Client as KubeClient, CustomResource, Resource, ResourceExt};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const FIELD_MANAGER: &str = "hcloud-fip-controller";
+const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+
+static CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[kube(
+    group = "fip.hcloud.barodeur.io",
+    version = "v1",
+    kind = "FloatingIPStatus",
+    struct = "FloatingIpStatus",
+    status = "Assignment",
+    shortname = "fip",
+    printcolumn = r#"{"name":"IP", "type":"string", "jsonPath":".status.ip"}"#,
+    printcolumn = r#"{"name":"Service", "type":"string", "jsonPath":".status.service"}"#,
+    printcolumn = r#"{"name":"Node", "type":"string", "jsonPath":".status.node"}"#,
+    printcolumn = r#"{"name":"Server", "type":"integer", "jsonPath":".status.server"}"#,
+    printcolumn = r#"{"name":"Since", "type":"date", "jsonPath":".status.lastTransitionTime"}"#
+)]
+pub struct FloatingIpStatusSpec {}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Assignment {
+    pub ip: String,
+    pub project: String,
+    /// `namespace/name` of the Service claiming the IP.
+    pub service: Option<String>,
+    /// hcloud server ID the IP is assigned to.
+    pub server: Option<i32>,
+    pub node: Option<String>,
+    /// Set while the controller or the claiming Service is paused, the IP
+    /// isn't moved then.
+    #[serde(default)]
+    pub paused: bool,
+    /// Last time the IP moved to another server, as seen by the controller.
+    pub last_transition_time: String,
+}
+
+/// Publishes the statuses without waiting for the next interval, after an
+/// IP moved.
+pub fn changed() {
+    CHANGED.notify_one();
+}
+
+/// Object name of `ip`, IPv6 networks are not valid names as is.
+fn object_name(ip: &str) -> String {
+    ip.replace([':', '/'], "-")
+}
+
+async fn publish(
+    api: &Api<FloatingIpStatus>,
+    projects: &[Project],
+    nodes: &Store<KubeNode>,
+    services: &Store<KubeService>,
+) -> Result<(), Error> {
+    let selector = format!("{}={}", MANAGED_BY_LABEL, FIELD_MANAGER);
+    let mut existing: HashMap<String, Assignment> =
+        list_all(api, &ListParams::default().labels(&selector))
+            .await?
+            .into_iter()
+            .map(|object| (object.name_any(), object.status.unwrap_or_default()))
+            .collect();
+    let node_names: HashMap<i32, String> = nodes
+        .state()
+        .iter()
+        .filter_map(|node| Some((get_hc_server_id(node)?, node.name_any())))
+        .collect();
+    let claimants = claimants(services.state());
+    let params = PatchParams::apply(FIELD_MANAGER).force();
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    for project in projects {
+        for fip in fetch_floating_ips(&project.conf()).await? {
+            let name = object_name(&fip.ip);
+            let previous = existing.remove(&name);
+            let last_transition_time = match previous {
+                Some(ref previous) if previous.server == fip.server => {
+                    previous.last_transition_time.clone()
+                }
+                _ => now.clone(),
+            };
+            let assignment = Assignment {
+                ip: fip.ip.clone(),
+                project: project.name.clone(),
+                service: claimants.get(&fip.ip).cloned(),
+                server: fip.server,
+                node: fip.server.and_then(|id| node_names.get(&id).cloned()),
+                paused: pause::blocker(&fip).is_some(),
+                last_transition_time,
+            };
+            if previous.as_ref() == Some(&assignment) {
+                continue;
+            }
+
+            let mut object = FloatingIpStatus::new(&name, FloatingIpStatusSpec {});
+            object.metadata.labels = Some(BTreeMap::from([(
+                MANAGED_BY_LABEL.to_string(),
+                FIELD_MANAGER.to_string(),
+            )]));
+            api.patch(&name, &params, &Patch::Apply(&object)).await?;
+            let status = serde_json::json!({
+                "apiVersion": FloatingIpStatus::api_version(&()),
+                "kind": FloatingIpStatus::kind(&()),
+                "status": assignment,
+            });
+            api.patch_status(&name, &params, &Patch::Apply(&status))
+                .await?;
+        }
+    }
+
+    // Left over from floating IPs deleted or no longer managed.
+    for name in existing.keys() {
+        api.delete(name, &DeleteParams::default()).await?;
+    }
+    Ok(())
+}
+
+/// Keeps a `FloatingIPStatus` per floating IP of `projects` up to date, every
+/// 30 seconds and whenever an IP moved.
+pub async fn run(
+    client: KubeClient,
+    projects: Vec<Project>,
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+) {
+    let api = Api::<FloatingIpStatus>::all(client);
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = CHANGED.notified() => {}
+        }
+        if let Err(err) = publish(&api, &projects, &nodes, &services).await {
+            println!("failed to publish floating ip statuses: {}", err);
+        }
+    }
+}
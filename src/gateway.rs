@@ -0,0 +1,96 @@
+use crate::throttle::ActionClass;
+use crate::{fetch_floating_ips, get_hc_server_id, is_hcloud_node, move_floating_ip, Error};
+use clap::ValueEnum;
+use hcloud::apis::configuration::Configuration;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use std::collections::HashMap;
+
+/// How the gateway node is elected among the eligible nodes when the current
+/// gateway is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GatewayPolicy {
+    /// The node with the oldest creation timestamp.
+    Oldest,
+    /// The node whose name sorts first.
+    Name,
+}
+
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    pub policy: GatewayPolicy,
+    /// Only nodes carrying this label are gateway candidates.
+    pub node_label: Option<String>,
+}
+
+fn is_candidate(config: &GatewayConfig, node: &KubeNode) -> bool {
+    match &config.node_label {
+        Some(label) => node
+            .metadata
+            .labels
+            .as_ref()
+            .map(|labels| labels.contains_key(label))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Picks the gateway server among `nodes`.
+///
+/// The server currently holding the most floating IPs keeps the role as long
+/// as it is still a candidate, so the gateway only moves on failure.
+pub fn elect_gateway(
+    config: &GatewayConfig,
+    nodes: &[KubeNode],
+    holders: &HashMap<i32, usize>,
+) -> Option<i32> {
+    let mut candidates: Vec<&KubeNode> = nodes
+        .iter()
+        .filter(|node| is_hcloud_node(node) && is_candidate(config, node))
+        .collect();
+
+    let current = candidates
+        .iter()
+        .filter_map(|node| get_hc_server_id(node))
+        .filter(|id| holders.contains_key(id))
+        .max_by_key(|id| holders[id]);
+    if current.is_some() {
+        return current;
+    }
+
+    match config.policy {
+        GatewayPolicy::Oldest => {
+            candidates.sort_by_key(|node| node.metadata.creation_timestamp.clone())
+        }
+        GatewayPolicy::Name => candidates.sort_by_key(|node| node.metadata.name.clone()),
+    }
+    candidates.first().and_then(|node| get_hc_server_id(node))
+}
+
+/// Keeps every floating IP on the elected gateway node.
+pub async fn reconcile(
+    hcloud_conf: &Configuration,
+    config: &GatewayConfig,
+    available_nodes: &[KubeNode],
+) -> Result<(), Error> {
+    let floating_ips = fetch_floating_ips(hcloud_conf).await?;
+
+    let mut holders = HashMap::new();
+    for server_id in floating_ips.iter().flat_map(|fip| fip.server) {
+        *holders.entry(server_id).or_insert(0) += 1;
+    }
+
+    let gateway_id = match elect_gateway(config, available_nodes, &holders) {
+        Some(id) => id,
+        None => {
+            println!("no gateway candidate available, leaving floating ips in place");
+            return Ok(());
+        }
+    };
+
+    for fip in floating_ips {
+        if fip.server != Some(gateway_id) {
+            move_floating_ip(hcloud_conf, &fip, gateway_id, ActionClass::Failover).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1557 @@
+//! The hcloud calls the reconcile logic makes, behind a trait implemented by
+//! the real client and by an in-memory fake, so failover decisions can be
+//! exercised without a real hcloud project. The fake is loaded from floating
+//! IPs, servers and Load Balancers recorded from the API and replays them
+//! with `--fake-hcloud`.
+
+use crate::otlp::{self, SpanKind};
+use crate::Error;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hcloud::apis::configuration::Configuration;
+use hcloud::apis::floating_ips_api::{
+    AssignFloatingIpToServerError, AssignFloatingIpToServerParams,
+    ChangeReverseDnsEntryForFloatingIpParams, CreateFloatingIpParams, DeleteFloatingIpParams,
+    GetFloatingIpParams, ListFloatingIpsParams, ReplaceFloatingIpParams, UnassignFloatingIpError,
+    UnassignFloatingIpParams,
+};
+use hcloud::apis::load_balancers_api::{
+    AddServiceParams, AddTargetParams, CreateLoadBalancerParams, DeleteLoadBalancerParams,
+    DeleteServiceParams, ListLoadBalancersParams, RemoveTargetParams, UpdateServiceParams,
+};
+use hcloud::apis::servers_api::{
+    ChangeAliasIpsOfNetworkError, ChangeAliasIpsOfNetworkParams, ListServersParams,
+};
+use hcloud::apis::{actions_api, floating_ips_api, load_balancers_api, servers_api};
+use hcloud::models::action::Status;
+use hcloud::models::{
+    add_target_request, remove_target_request, target, Action, AddTargetRequest,
+    AddTargetRequestServer, AssignFloatingIpToServerRequest, ChangeAliasIpsOfNetworkRequest,
+    ChangeReverseDnsEntryForFloatingIpRequest, CreateFloatingIpRequest, CreateLoadBalancerRequest,
+    DeleteServiceRequest, DnsPtr, FloatingIp, IpType, LoadBalancer, LoadBalancerService, Location,
+    Meta, RemoveTargetRequest, ReplaceFloatingIpRequest, ResourceId, Server, Target,
+};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+pub type AssignError = hcloud::apis::Error<AssignFloatingIpToServerError>;
+pub type UnassignError = hcloud::apis::Error<UnassignFloatingIpError>;
+pub type AliasIpsError = hcloud::apis::Error<ChangeAliasIpsOfNetworkError>;
+
+/// Every method takes the configuration of the project it is made for.
+pub trait HcloudApi: Send + Sync {
+    /// The floating IPs matching the hcloud `label_selector`, all without one.
+    fn list_floating_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<FloatingIp>, Error>>;
+
+    fn get_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>>;
+
+    fn assign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, AssignError>>;
+
+    fn unassign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, UnassignError>>;
+
+    /// Replaces the labels of the floating IP `id`.
+    fn set_floating_ip_labels<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        labels: HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>>;
+
+    fn create_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateFloatingIpRequest,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>>;
+
+    fn delete_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Sets the PTR record of `ip` of the floating IP `id`, the default one
+    /// without `dns_ptr`.
+    fn set_floating_ip_rdns<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        ip: String,
+        dns_ptr: Option<String>,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    fn list_servers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+    ) -> BoxFuture<'a, Result<Vec<Server>, Error>>;
+
+    /// Replaces the alias IPs of `server_id` on `network`.
+    fn change_alias_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> BoxFuture<'a, Result<Action, AliasIpsError>>;
+
+    /// The Load Balancers matching the hcloud `label_selector`, all without
+    /// one.
+    fn list_load_balancers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<LoadBalancer>, Error>>;
+
+    fn create_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateLoadBalancerRequest,
+    ) -> BoxFuture<'a, Result<LoadBalancer, Error>>;
+
+    fn delete_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn add_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    /// Replaces the service of the Load Balancer `id` listening on the same
+    /// port.
+    fn update_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    fn delete_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        listen_port: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    fn add_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    fn remove_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+
+    fn get_action<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>>;
+}
+
+/// Items asked for per page of the list calls, hcloud allows up to 50.
+static PER_PAGE: AtomicI32 = AtomicI32::new(50);
+
+pub fn set_per_page(per_page: i32) {
+    PER_PAGE.store(per_page, Ordering::Relaxed);
+}
+
+fn next_page(meta: Option<Box<Meta>>) -> Option<i32> {
+    meta?.pagination.next_page
+}
+
+/// The hcloud API itself. List calls fetch every page.
+pub struct Client;
+
+impl HcloudApi for Client {
+    fn list_floating_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<FloatingIp>, Error>> {
+        async move {
+            let mut fips = vec![];
+            let mut page = Some(1);
+            while let Some(current) = page {
+                let params = ListFloatingIpsParams {
+                    label_selector: label_selector.clone(),
+                    page: Some(current),
+                    per_page: Some(PER_PAGE.load(Ordering::Relaxed)),
+                    ..Default::default()
+                };
+                let response = floating_ips_api::list_floating_ips(conf, params).await?;
+                fips.extend(response.floating_ips);
+                page = next_page(response.meta);
+            }
+            Ok(fips)
+        }
+        .boxed()
+    }
+
+    fn get_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            Ok(
+                *floating_ips_api::get_floating_ip(conf, GetFloatingIpParams { id })
+                    .await?
+                    .floating_ip,
+            )
+        }
+        .boxed()
+    }
+
+    fn assign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, AssignError>> {
+        async move {
+            let params = AssignFloatingIpToServerParams {
+                id,
+                assign_floating_ip_to_server_request: Some(AssignFloatingIpToServerRequest {
+                    server: server_id,
+                }),
+            };
+            Ok(
+                *floating_ips_api::assign_floating_ip_to_server(conf, params)
+                    .await?
+                    .action,
+            )
+        }
+        .boxed()
+    }
+
+    fn unassign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, UnassignError>> {
+        async move {
+            Ok(
+                *floating_ips_api::unassign_floating_ip(conf, UnassignFloatingIpParams { id })
+                    .await?
+                    .action,
+            )
+        }
+        .boxed()
+    }
+
+    fn set_floating_ip_labels<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        labels: HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            let params = ReplaceFloatingIpParams {
+                id,
+                replace_floating_ip_request: Some(ReplaceFloatingIpRequest {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+            };
+            Ok(*floating_ips_api::replace_floating_ip(conf, params)
+                .await?
+                .floating_ip)
+        }
+        .boxed()
+    }
+
+    fn create_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateFloatingIpRequest,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        async move {
+            let params = CreateFloatingIpParams {
+                create_floating_ip_request: Some(request),
+            };
+            Ok(*floating_ips_api::create_floating_ip(conf, params)
+                .await?
+                .floating_ip)
+        }
+        .boxed()
+    }
+
+    fn delete_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            floating_ips_api::delete_floating_ip(conf, DeleteFloatingIpParams { id }).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_floating_ip_rdns<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        ip: String,
+        dns_ptr: Option<String>,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = ChangeReverseDnsEntryForFloatingIpParams {
+                id,
+                change_reverse_dns_entry_for_floating_ip_request: Some(
+                    ChangeReverseDnsEntryForFloatingIpRequest::new(dns_ptr, ip),
+                ),
+            };
+            Ok(
+                *floating_ips_api::change_reverse_dns_entry_for_floating_ip(conf, params)
+                    .await?
+                    .action,
+            )
+        }
+        .boxed()
+    }
+
+    fn list_servers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+    ) -> BoxFuture<'a, Result<Vec<Server>, Error>> {
+        async move {
+            let mut servers = vec![];
+            let mut page = Some(1);
+            while let Some(current) = page {
+                let params = ListServersParams {
+                    page: Some(current),
+                    per_page: Some(PER_PAGE.load(Ordering::Relaxed)),
+                    ..Default::default()
+                };
+                let response = servers_api::list_servers(conf, params).await?;
+                servers.extend(response.servers);
+                page = next_page(response.meta);
+            }
+            Ok(servers)
+        }
+        .boxed()
+    }
+
+    fn change_alias_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> BoxFuture<'a, Result<Action, AliasIpsError>> {
+        async move {
+            let params = ChangeAliasIpsOfNetworkParams {
+                id: server_id,
+                change_alias_ips_of_network_request: Some(ChangeAliasIpsOfNetworkRequest {
+                    alias_ips,
+                    network,
+                }),
+            };
+            Ok(*servers_api::change_alias_ips_of_network(conf, params)
+                .await?
+                .action)
+        }
+        .boxed()
+    }
+
+    fn list_load_balancers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<LoadBalancer>, Error>> {
+        async move {
+            let mut load_balancers = vec![];
+            let mut page = Some(1);
+            while let Some(current) = page {
+                let params = ListLoadBalancersParams {
+                    label_selector: label_selector.clone(),
+                    page: Some(current),
+                    per_page: Some(PER_PAGE.load(Ordering::Relaxed)),
+                    ..Default::default()
+                };
+                let response = load_balancers_api::list_load_balancers(conf, params).await?;
+                load_balancers.extend(response.load_balancers);
+                page = next_page(response.meta);
+            }
+            Ok(load_balancers)
+        }
+        .boxed()
+    }
+
+    fn create_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateLoadBalancerRequest,
+    ) -> BoxFuture<'a, Result<LoadBalancer, Error>> {
+        async move {
+            let params = CreateLoadBalancerParams {
+                create_load_balancer_request: Some(request),
+            };
+            Ok(*load_balancers_api::create_load_balancer(conf, params)
+                .await?
+                .load_balancer)
+        }
+        .boxed()
+    }
+
+    fn delete_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            load_balancers_api::delete_load_balancer(conf, DeleteLoadBalancerParams { id }).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn add_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = AddServiceParams {
+                id,
+                body: Some(service),
+            };
+            Ok(*load_balancers_api::add_service(conf, params).await?.action)
+        }
+        .boxed()
+    }
+
+    fn update_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = UpdateServiceParams {
+                id,
+                body: Some(service),
+            };
+            Ok(*load_balancers_api::update_service(conf, params)
+                .await?
+                .action)
+        }
+        .boxed()
+    }
+
+    fn delete_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        listen_port: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = DeleteServiceParams {
+                id,
+                delete_service_request: Some(DeleteServiceRequest::new(listen_port)),
+            };
+            Ok(*load_balancers_api::delete_service(conf, params)
+                .await?
+                .action)
+        }
+        .boxed()
+    }
+
+    fn add_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = AddTargetParams {
+                id,
+                add_target_request: Some(AddTargetRequest {
+                    server: Some(Box::new(AddTargetRequestServer::new(server_id))),
+                    ..AddTargetRequest::new(add_target_request::Type::Server)
+                }),
+            };
+            Ok(*load_balancers_api::add_target(conf, params).await?.action)
+        }
+        .boxed()
+    }
+
+    fn remove_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            let params = RemoveTargetParams {
+                id,
+                remove_target_request: Some(RemoveTargetRequest {
+                    server: Some(Box::new(AddTargetRequestServer::new(server_id))),
+                    ..RemoveTargetRequest::new(remove_target_request::Type::Server)
+                }),
+            };
+            Ok(*load_balancers_api::remove_target(conf, params)
+                .await?
+                .action)
+        }
+        .boxed()
+    }
+
+    fn get_action<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move {
+            Ok(
+                *actions_api::get_action(conf, actions_api::GetActionParams { id })
+                    .await?
+                    .action,
+            )
+        }
+        .boxed()
+    }
+}
+
+/// Floating IPs, servers and Load Balancers as the hcloud API returns them,
+/// e.g. the responses of `GET /v1/floating_ips` and `GET /v1/servers` merged.
+#[derive(Debug, Default, Deserialize)]
+struct State {
+    #[serde(default)]
+    floating_ips: Vec<FloatingIp>,
+    #[serde(default)]
+    servers: Vec<Server>,
+    #[serde(default)]
+    load_balancers: Vec<LoadBalancer>,
+}
+
+/// In-memory hcloud, shared by every project, where actions complete at once.
+pub struct Fake {
+    state: Mutex<State>,
+}
+
+/// Whether `labels` match an hcloud label selector of `key=value`,
+/// `key==value`, `key!=value`, `key` and `!key` terms.
+fn matches_selector(labels: &HashMap<String, String>, selector: &str) -> bool {
+    selector.split(',').map(str::trim).all(|term| {
+        if let Some((key, value)) = term.split_once("!=") {
+            labels.get(key).map(String::as_str) != Some(value)
+        } else if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+            labels.get(key).map(String::as_str) == Some(value)
+        } else if let Some(key) = term.strip_prefix('!') {
+            !labels.contains_key(key)
+        } else {
+            labels.contains_key(term)
+        }
+    })
+}
+
+fn completed(command: &str, id: i32) -> Action {
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    Action::new(
+        command.into(),
+        None,
+        Some(now.clone()),
+        id,
+        100.0,
+        vec![],
+        now,
+        Status::Success,
+    )
+}
+
+fn not_found(id: i32) -> Error {
+    format!("floating ip {} not found", id).into()
+}
+
+fn load_balancer_not_found(id: i32) -> Error {
+    format!("load balancer {} not found", id).into()
+}
+
+impl Fake {
+    /// Loads the state from a JSON or YAML file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        Self::parse(&content, &path.display().to_string())
+    }
+
+    /// Parses the JSON or YAML state read from `origin`.
+    pub fn parse(content: &str, origin: &str) -> Result<Self, Error> {
+        let state = serde_yaml::from_str(content)
+            .map_err(|err| format!("invalid hcloud state {}: {}", origin, err))?;
+        Ok(Fake {
+            state: Mutex::new(state),
+        })
+    }
+
+    fn set_server(&self, id: i32, server_id: Option<i32>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(server_id) = server_id {
+            if !state.servers.iter().any(|server| server.id == server_id) {
+                return Err(format!("server {} not found", server_id).into());
+            }
+        }
+        let fip = state
+            .floating_ips
+            .iter_mut()
+            .find(|fip| fip.id == id)
+            .ok_or_else(|| not_found(id))?;
+        fip.server = server_id;
+        Ok(())
+    }
+
+    fn set_alias_ips(
+        &self,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let taken = state
+            .servers
+            .iter()
+            .filter(|server| server.id != server_id)
+            .flat_map(|server| &server.private_net)
+            .filter(|net| net.network == Some(network))
+            .flat_map(|net| net.alias_ips.iter().flatten())
+            .find(|ip| alias_ips.contains(ip));
+        if let Some(ip) = taken {
+            return Err(format!("alias ip {} is already in use", ip).into());
+        }
+        let server = state
+            .servers
+            .iter_mut()
+            .find(|server| server.id == server_id)
+            .ok_or_else(|| format!("server {} not found", server_id))?;
+        let net = server
+            .private_net
+            .iter_mut()
+            .find(|net| net.network == Some(network))
+            .ok_or_else(|| {
+                format!(
+                    "server {} is not attached to network {}",
+                    server_id, network
+                )
+            })?;
+        net.alias_ips = Some(alias_ips);
+        Ok(())
+    }
+
+    fn change_floating_ip<T>(
+        &self,
+        id: i32,
+        change: impl FnOnce(&mut FloatingIp) -> T,
+    ) -> Result<T, Error> {
+        let mut state = self.state.lock().unwrap();
+        let fip = state
+            .floating_ips
+            .iter_mut()
+            .find(|fip| fip.id == id)
+            .ok_or_else(|| not_found(id))?;
+        Ok(change(fip))
+    }
+
+    /// Changes the Load Balancer `id`, `change` failing with a message.
+    fn change_load_balancer(
+        &self,
+        id: i32,
+        command: &str,
+        change: impl FnOnce(&mut LoadBalancer) -> Result<(), String>,
+    ) -> Result<Action, Error> {
+        let mut state = self.state.lock().unwrap();
+        let load_balancer = state
+            .load_balancers
+            .iter_mut()
+            .find(|load_balancer| load_balancer.id == id)
+            .ok_or_else(|| load_balancer_not_found(id))?;
+        change(load_balancer)?;
+        Ok(completed(command, id))
+    }
+}
+
+impl HcloudApi for Fake {
+    fn list_floating_ips<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<FloatingIp>, Error>> {
+        let fips = self
+            .state
+            .lock()
+            .unwrap()
+            .floating_ips
+            .iter()
+            .filter(|fip| match &label_selector {
+                Some(selector) => matches_selector(&fip.labels, selector),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        async move { Ok(fips) }.boxed()
+    }
+
+    fn get_floating_ip<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        let fip = self
+            .state
+            .lock()
+            .unwrap()
+            .floating_ips
+            .iter()
+            .find(|fip| fip.id == id)
+            .cloned()
+            .ok_or_else(|| not_found(id));
+        async move { fip }.boxed()
+    }
+
+    fn assign_floating_ip<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, AssignError>> {
+        let action = self
+            .set_server(id, Some(server_id))
+            .map(|()| completed("assign_floating_ip", id))
+            .map_err(|err| hcloud::apis::Error::Io(std::io::Error::other(err)));
+        async move { action }.boxed()
+    }
+
+    fn unassign_floating_ip<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, UnassignError>> {
+        let action = self
+            .set_server(id, None)
+            .map(|()| completed("unassign_floating_ip", id))
+            .map_err(|err| hcloud::apis::Error::Io(std::io::Error::other(err)));
+        async move { action }.boxed()
+    }
+
+    fn set_floating_ip_labels<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        labels: HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        let fip = self
+            .state
+            .lock()
+            .unwrap()
+            .floating_ips
+            .iter_mut()
+            .find(|fip| fip.id == id)
+            .map(|fip| {
+                fip.labels = labels;
+                fip.clone()
+            })
+            .ok_or_else(|| not_found(id));
+        async move { fip }.boxed()
+    }
+
+    fn create_floating_ip<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        request: CreateFloatingIpRequest,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        let mut state = self.state.lock().unwrap();
+        let id = state
+            .floating_ips
+            .iter()
+            .map(|fip| fip.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let ip = match request.r#type {
+            IpType::Ipv4 => format!("203.0.113.{}", id % 256),
+            IpType::Ipv6 => format!("2001:db8:{:x}::/64", id),
+        };
+        let fip = FloatingIp {
+            id,
+            ip,
+            r#type: request.r#type,
+            name: request.name.unwrap_or_else(|| id.to_string()),
+            description: request.description,
+            labels: request.labels.unwrap_or_default(),
+            home_location: Box::new(Location {
+                name: request.home_location.unwrap_or_default(),
+                ..Default::default()
+            }),
+            server: request.server,
+            ..Default::default()
+        };
+        state.floating_ips.push(fip.clone());
+        async move { Ok(fip) }.boxed()
+    }
+
+    fn delete_floating_ip<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.floating_ips.len();
+        state.floating_ips.retain(|fip| fip.id != id);
+        let result = match state.floating_ips.len() < count {
+            true => Ok(()),
+            false => Err(not_found(id)),
+        };
+        async move { result }.boxed()
+    }
+
+    fn set_floating_ip_rdns<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        ip: String,
+        dns_ptr: Option<String>,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self
+            .change_floating_ip(id, |fip| {
+                fip.dns_ptr.retain(|ptr| ptr.ip != ip);
+                if let Some(dns_ptr) = dns_ptr {
+                    fip.dns_ptr.push(DnsPtr { dns_ptr, ip });
+                }
+            })
+            .map(|()| completed("change_dns_ptr", id));
+        async move { action }.boxed()
+    }
+
+    fn list_servers<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+    ) -> BoxFuture<'a, Result<Vec<Server>, Error>> {
+        let servers = self.state.lock().unwrap().servers.clone();
+        async move { Ok(servers) }.boxed()
+    }
+
+    fn change_alias_ips<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> BoxFuture<'a, Result<Action, AliasIpsError>> {
+        let action = self
+            .set_alias_ips(server_id, network, alias_ips)
+            .map(|()| completed("change_alias_ips", server_id))
+            .map_err(|err| hcloud::apis::Error::Io(std::io::Error::other(err)));
+        async move { action }.boxed()
+    }
+
+    fn list_load_balancers<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<LoadBalancer>, Error>> {
+        let load_balancers = self
+            .state
+            .lock()
+            .unwrap()
+            .load_balancers
+            .iter()
+            .filter(|load_balancer| match &label_selector {
+                Some(selector) => matches_selector(&load_balancer.labels, selector),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        async move { Ok(load_balancers) }.boxed()
+    }
+
+    fn create_load_balancer<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        request: CreateLoadBalancerRequest,
+    ) -> BoxFuture<'a, Result<LoadBalancer, Error>> {
+        let mut state = self.state.lock().unwrap();
+        let id = state
+            .load_balancers
+            .iter()
+            .map(|load_balancer| load_balancer.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut load_balancer = LoadBalancer {
+            id,
+            name: request.name,
+            algorithm: request.algorithm,
+            labels: request.labels.unwrap_or_default(),
+            location: Box::new(Location {
+                name: request.location.unwrap_or_default(),
+                ..Default::default()
+            }),
+            services: request.services.unwrap_or_default(),
+            targets: request.targets.unwrap_or_default(),
+            ..Default::default()
+        };
+        load_balancer.load_balancer_type.name = request.load_balancer_type;
+        load_balancer.public_net.enabled = true;
+        load_balancer.public_net.ipv4.ip = Some(format!("198.51.100.{}", id % 256));
+        state.load_balancers.push(load_balancer.clone());
+        async move { Ok(load_balancer) }.boxed()
+    }
+
+    fn delete_load_balancer<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.load_balancers.len();
+        state
+            .load_balancers
+            .retain(|load_balancer| load_balancer.id != id);
+        let result = match state.load_balancers.len() < count {
+            true => Ok(()),
+            false => Err(load_balancer_not_found(id)),
+        };
+        async move { result }.boxed()
+    }
+
+    fn add_load_balancer_service<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self.change_load_balancer(id, "add_service", |load_balancer| {
+            let port = service.listen_port;
+            if load_balancer
+                .services
+                .iter()
+                .any(|current| current.listen_port == port)
+            {
+                return Err(format!("port {} is already in use", port));
+            }
+            load_balancer.services.push(service);
+            Ok(())
+        });
+        async move { action }.boxed()
+    }
+
+    fn update_load_balancer_service<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self.change_load_balancer(id, "update_service", |load_balancer| {
+            let port = service.listen_port;
+            let current = load_balancer
+                .services
+                .iter_mut()
+                .find(|current| current.listen_port == port)
+                .ok_or_else(|| format!("no service listens on port {}", port))?;
+            *current = service;
+            Ok(())
+        });
+        async move { action }.boxed()
+    }
+
+    fn delete_load_balancer_service<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        listen_port: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self.change_load_balancer(id, "delete_service", |load_balancer| {
+            let count = load_balancer.services.len();
+            load_balancer
+                .services
+                .retain(|current| current.listen_port != listen_port);
+            match load_balancer.services.len() < count {
+                true => Ok(()),
+                false => Err(format!("no service listens on port {}", listen_port)),
+            }
+        });
+        async move { action }.boxed()
+    }
+
+    fn add_load_balancer_target<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self.change_load_balancer(id, "add_target", |load_balancer| {
+            load_balancer.targets.push(Target {
+                server: Some(Box::new(ResourceId::new(server_id))),
+                ..Target::new(target::Type::Server)
+            });
+            Ok(())
+        });
+        async move { action }.boxed()
+    }
+
+    fn remove_load_balancer_target<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        let action = self.change_load_balancer(id, "remove_target", |load_balancer| {
+            load_balancer
+                .targets
+                .retain(|target| target.server.as_ref().map(|server| server.id) != Some(server_id));
+            Ok(())
+        });
+        async move { action }.boxed()
+    }
+
+    fn get_action<'a>(
+        &'a self,
+        _conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        async move { Ok(completed("unknown", id)) }.boxed()
+    }
+}
+
+/// Wraps an implementation to export every call as a client span of the
+/// reconcile making it.
+pub struct Traced(pub Box<dyn HcloudApi>);
+
+fn failure<T, E: Display>(result: &Result<T, E>) -> Option<String> {
+    result.as_ref().err().map(ToString::to_string)
+}
+
+impl HcloudApi for Traced {
+    fn list_floating_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<FloatingIp>, Error>> {
+        let attributes = label_selector
+            .iter()
+            .map(|selector| ("hcloud.label_selector", selector.clone()))
+            .collect();
+        otlp::span(
+            "hcloud list_floating_ips",
+            SpanKind::Client,
+            attributes,
+            failure,
+            self.0.list_floating_ips(conf, label_selector),
+        )
+        .boxed()
+    }
+
+    fn get_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        otlp::span(
+            "hcloud get_floating_ip",
+            SpanKind::Client,
+            vec![("hcloud.floating_ip", id.to_string())],
+            failure,
+            self.0.get_floating_ip(conf, id),
+        )
+        .boxed()
+    }
+
+    fn assign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, AssignError>> {
+        otlp::span(
+            "hcloud assign_floating_ip",
+            SpanKind::Client,
+            vec![
+                ("hcloud.floating_ip", id.to_string()),
+                ("hcloud.server", server_id.to_string()),
+            ],
+            failure,
+            self.0.assign_floating_ip(conf, id, server_id),
+        )
+        .boxed()
+    }
+
+    fn unassign_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, UnassignError>> {
+        otlp::span(
+            "hcloud unassign_floating_ip",
+            SpanKind::Client,
+            vec![("hcloud.floating_ip", id.to_string())],
+            failure,
+            self.0.unassign_floating_ip(conf, id),
+        )
+        .boxed()
+    }
+
+    fn set_floating_ip_labels<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        labels: HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        otlp::span(
+            "hcloud set_floating_ip_labels",
+            SpanKind::Client,
+            vec![("hcloud.floating_ip", id.to_string())],
+            failure,
+            self.0.set_floating_ip_labels(conf, id, labels),
+        )
+        .boxed()
+    }
+
+    fn create_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateFloatingIpRequest,
+    ) -> BoxFuture<'a, Result<FloatingIp, Error>> {
+        let attributes = request
+            .name
+            .iter()
+            .map(|name| ("hcloud.name", name.clone()))
+            .collect();
+        otlp::span(
+            "hcloud create_floating_ip",
+            SpanKind::Client,
+            attributes,
+            failure,
+            self.0.create_floating_ip(conf, request),
+        )
+        .boxed()
+    }
+
+    fn delete_floating_ip<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        otlp::span(
+            "hcloud delete_floating_ip",
+            SpanKind::Client,
+            vec![("hcloud.floating_ip", id.to_string())],
+            failure,
+            self.0.delete_floating_ip(conf, id),
+        )
+        .boxed()
+    }
+
+    fn set_floating_ip_rdns<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        ip: String,
+        dns_ptr: Option<String>,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud set_floating_ip_rdns",
+            SpanKind::Client,
+            vec![("hcloud.floating_ip", id.to_string())],
+            failure,
+            self.0.set_floating_ip_rdns(conf, id, ip, dns_ptr),
+        )
+        .boxed()
+    }
+
+    fn list_servers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+    ) -> BoxFuture<'a, Result<Vec<Server>, Error>> {
+        otlp::span(
+            "hcloud list_servers",
+            SpanKind::Client,
+            vec![],
+            failure,
+            self.0.list_servers(conf),
+        )
+        .boxed()
+    }
+
+    fn change_alias_ips<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        server_id: i32,
+        network: i32,
+        alias_ips: Vec<String>,
+    ) -> BoxFuture<'a, Result<Action, AliasIpsError>> {
+        otlp::span(
+            "hcloud change_alias_ips",
+            SpanKind::Client,
+            vec![
+                ("hcloud.server", server_id.to_string()),
+                ("hcloud.network", network.to_string()),
+            ],
+            failure,
+            self.0.change_alias_ips(conf, server_id, network, alias_ips),
+        )
+        .boxed()
+    }
+
+    fn list_load_balancers<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        label_selector: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<LoadBalancer>, Error>> {
+        let attributes = label_selector
+            .iter()
+            .map(|selector| ("hcloud.label_selector", selector.clone()))
+            .collect();
+        otlp::span(
+            "hcloud list_load_balancers",
+            SpanKind::Client,
+            attributes,
+            failure,
+            self.0.list_load_balancers(conf, label_selector),
+        )
+        .boxed()
+    }
+
+    fn create_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        request: CreateLoadBalancerRequest,
+    ) -> BoxFuture<'a, Result<LoadBalancer, Error>> {
+        otlp::span(
+            "hcloud create_load_balancer",
+            SpanKind::Client,
+            vec![("hcloud.name", request.name.clone())],
+            failure,
+            self.0.create_load_balancer(conf, request),
+        )
+        .boxed()
+    }
+
+    fn delete_load_balancer<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        otlp::span(
+            "hcloud delete_load_balancer",
+            SpanKind::Client,
+            vec![("hcloud.load_balancer", id.to_string())],
+            failure,
+            self.0.delete_load_balancer(conf, id),
+        )
+        .boxed()
+    }
+
+    fn add_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud add_load_balancer_service",
+            SpanKind::Client,
+            vec![
+                ("hcloud.load_balancer", id.to_string()),
+                ("hcloud.listen_port", service.listen_port.to_string()),
+            ],
+            failure,
+            self.0.add_load_balancer_service(conf, id, service),
+        )
+        .boxed()
+    }
+
+    fn update_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        service: LoadBalancerService,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud update_load_balancer_service",
+            SpanKind::Client,
+            vec![
+                ("hcloud.load_balancer", id.to_string()),
+                ("hcloud.listen_port", service.listen_port.to_string()),
+            ],
+            failure,
+            self.0.update_load_balancer_service(conf, id, service),
+        )
+        .boxed()
+    }
+
+    fn delete_load_balancer_service<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        listen_port: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud delete_load_balancer_service",
+            SpanKind::Client,
+            vec![
+                ("hcloud.load_balancer", id.to_string()),
+                ("hcloud.listen_port", listen_port.to_string()),
+            ],
+            failure,
+            self.0.delete_load_balancer_service(conf, id, listen_port),
+        )
+        .boxed()
+    }
+
+    fn add_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud add_load_balancer_target",
+            SpanKind::Client,
+            vec![
+                ("hcloud.load_balancer", id.to_string()),
+                ("hcloud.server", server_id.to_string()),
+            ],
+            failure,
+            self.0.add_load_balancer_target(conf, id, server_id),
+        )
+        .boxed()
+    }
+
+    fn remove_load_balancer_target<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+        server_id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud remove_load_balancer_target",
+            SpanKind::Client,
+            vec![
+                ("hcloud.load_balancer", id.to_string()),
+                ("hcloud.server", server_id.to_string()),
+            ],
+            failure,
+            self.0.remove_load_balancer_target(conf, id, server_id),
+        )
+        .boxed()
+    }
+
+    fn get_action<'a>(
+        &'a self,
+        conf: &'a Configuration,
+        id: i32,
+    ) -> BoxFuture<'a, Result<Action, Error>> {
+        otlp::span(
+            "hcloud get_action",
+            SpanKind::Client,
+            vec![("hcloud.action", id.to_string())],
+            failure,
+            self.0.get_action(conf, id),
+        )
+        .boxed()
+    }
+}
+
+static API: OnceCell<Box<dyn HcloudApi>> = OnceCell::new();
+
+/// Replaces the hcloud API, before any call is made.
+pub fn set(api: Box<dyn HcloudApi>) {
+    let _ = API.set(api);
+}
+
+pub fn api() -> &'static dyn HcloudApi {
+    API.get_or_init(|| Box::new(Client)).as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn selector_terms() {
+        let labels = labels(&[("role", "ingress"), ("env", "prod")]);
+        assert!(matches_selector(&labels, "role=ingress"));
+        assert!(matches_selector(&labels, "role==ingress"));
+        assert!(matches_selector(&labels, "role!=egress"));
+        assert!(matches_selector(&labels, "env"));
+        assert!(matches_selector(&labels, "!legacy"));
+        assert!(matches_selector(&labels, "role==ingress, env=prod"));
+        assert!(!matches_selector(&labels, "role==egress"));
+        assert!(!matches_selector(&labels, "role!=ingress"));
+        assert!(!matches_selector(&labels, "!env"));
+        assert!(!matches_selector(&labels, "role=ingress,legacy"));
+    }
+
+    #[tokio::test]
+    async fn fake_assigns_known_servers_only() {
+        let fake = Fake::parse(
+            r#"{"floating_ips": [{"id": 1, "ip": "198.51.100.1", "server": 10}]}"#,
+            "test",
+        );
+        // Invalid without the required fields of hcloud.
+        assert!(fake.is_err());
+
+        let fip = FloatingIp {
+            id: 1,
+            server: Some(10),
+            ..Default::default()
+        };
+        let servers = [10, 11].map(|id| Server {
+            id,
+            ..Default::default()
+        });
+        let state = serde_json::json!({ "floating_ips": [fip], "servers": servers });
+        let fake = Fake::parse(&state.to_string(), "test").unwrap();
+        let conf = Configuration::default();
+        fake.assign_floating_ip(&conf, 1, 11).await.unwrap();
+        assert_eq!(
+            fake.get_floating_ip(&conf, 1).await.unwrap().server,
+            Some(11)
+        );
+        assert!(fake.assign_floating_ip(&conf, 1, 12).await.is_err());
+        fake.unassign_floating_ip(&conf, 1).await.unwrap();
+        assert_eq!(fake.get_floating_ip(&conf, 1).await.unwrap().server, None);
+    }
+
+    #[tokio::test]
+    async fn fake_provisions_floating_ips() {
+        let fake = Fake::parse("{}", "test").unwrap();
+        let conf = Configuration::default();
+        let request = CreateFloatingIpRequest {
+            name: Some("web".into()),
+            home_location: Some("fsn1".into()),
+            labels: Some(labels(&[("role", "ingress")])),
+            ..CreateFloatingIpRequest::new(IpType::Ipv4)
+        };
+        let fip = fake.create_floating_ip(&conf, request).await.unwrap();
+        assert_eq!(fip.home_location.name, "fsn1");
+        let listed = fake
+            .list_floating_ips(&conf, Some("role=ingress".into()))
+            .await
+            .unwrap();
+        assert_eq!(listed, vec![fip.clone()]);
+
+        fake.set_floating_ip_rdns(
+            &conf,
+            fip.id,
+            fip.ip.clone(),
+            Some("web.example.com".into()),
+        )
+        .await
+        .unwrap();
+        let ptrs = fake.get_floating_ip(&conf, fip.id).await.unwrap().dns_ptr;
+        assert_eq!(ptrs.len(), 1);
+        assert_eq!(ptrs[0].dns_ptr, "web.example.com");
+
+        fake.delete_floating_ip(&conf, fip.id).await.unwrap();
+        assert!(fake.get_floating_ip(&conf, fip.id).await.is_err());
+        assert!(fake.delete_floating_ip(&conf, fip.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_syncs_load_balancers() {
+        let fake = Fake::parse("{}", "test").unwrap();
+        let conf = Configuration::default();
+        let service = |listen_port, destination_port| LoadBalancerService {
+            listen_port,
+            destination_port,
+            ..Default::default()
+        };
+        let request = CreateLoadBalancerRequest {
+            labels: Some(labels(&[("name", "web")])),
+            services: Some(vec![service(80, 30080)]),
+            ..Default::default()
+        };
+        let id = fake.create_load_balancer(&conf, request).await.unwrap().id;
+        let find = || async {
+            fake.list_load_balancers(&conf, Some("name=web".into()))
+                .await
+                .unwrap()
+                .remove(0)
+        };
+        assert!(find().await.public_net.ipv4.ip.is_some());
+
+        assert!(fake
+            .add_load_balancer_service(&conf, id, service(80, 30081))
+            .await
+            .is_err());
+        fake.update_load_balancer_service(&conf, id, service(80, 30081))
+            .await
+            .unwrap();
+        fake.add_load_balancer_service(&conf, id, service(443, 30443))
+            .await
+            .unwrap();
+        fake.delete_load_balancer_service(&conf, id, 80)
+            .await
+            .unwrap();
+        let ports: Vec<_> = find()
+            .await
+            .services
+            .iter()
+            .map(|service| (service.listen_port, service.destination_port))
+            .collect();
+        assert_eq!(ports, vec![(443, 30443)]);
+
+        fake.add_load_balancer_target(&conf, id, 10).await.unwrap();
+        fake.add_load_balancer_target(&conf, id, 11).await.unwrap();
+        fake.remove_load_balancer_target(&conf, id, 10)
+            .await
+            .unwrap();
+        let targets: Vec<_> = find()
+            .await
+            .targets
+            .iter()
+            .filter_map(|target| target.server.as_ref().map(|server| server.id))
+            .collect();
+        assert_eq!(targets, vec![11]);
+
+        fake.delete_load_balancer(&conf, id).await.unwrap();
+        assert!(fake
+            .list_load_balancers(&conf, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
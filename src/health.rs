@@ -0,0 +1,94 @@
+use hcloud::models::Server;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// A TCP or HTTP health check against a server address.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub port: u16,
+    /// When set, an HTTP GET of this path must succeed, otherwise a TCP
+    /// connect is enough.
+    pub path: Option<String>,
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    pub async fn probe(&self, ip: IpAddr) -> bool {
+        match &self.path {
+            Some(path) => self.probe_http(ip, path).await,
+            None => self.probe_tcp(ip).await,
+        }
+    }
+
+    async fn probe_tcp(&self, ip: IpAddr) -> bool {
+        matches!(
+            tokio::time::timeout(self.timeout, TcpStream::connect((ip, self.port))).await,
+            Ok(Ok(_))
+        )
+    }
+
+    async fn probe_http(&self, ip: IpAddr, path: &str) -> bool {
+        let host = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        let url = format!("http://{}:{}{}", host, self.port, path);
+        let response = reqwest::Client::new()
+            .get(url)
+            .timeout(self.timeout)
+            .send()
+            .await;
+        matches!(response, Ok(response) if response.status().is_success())
+    }
+}
+
+/// The address `server` is probed on: its IP in `network` when set, its
+/// public IPv4 otherwise.
+pub fn probe_address(server: &Server, network: Option<i32>) -> Option<IpAddr> {
+    let ip = match network {
+        Some(network) => server
+            .private_net
+            .iter()
+            .find(|net| net.network == Some(network))?
+            .ip
+            .clone()?,
+        None => server.public_net.ipv4.as_ref()?.ip.clone(),
+    };
+    ip.parse().ok()
+}
+
+/// Health check of the candidate servers before IPs are moved to them, as
+/// schedulable doesn't mean able to serve traffic.
+#[derive(Debug, Clone)]
+pub struct TargetProbe {
+    pub health_check: HealthCheck,
+    pub network: Option<i32>,
+}
+
+impl TargetProbe {
+    /// The servers among `candidates` passing the health check, probed at
+    /// once.
+    pub async fn healthy(&self, servers: &[Server], candidates: &HashSet<i32>) -> HashSet<i32> {
+        let probes = servers
+            .iter()
+            .filter(|server| candidates.contains(&server.id))
+            .map(|server| async move {
+                let healthy = match probe_address(server, self.network) {
+                    Some(ip) => self.health_check.probe(ip).await,
+                    None => false,
+                };
+                if !healthy {
+                    println!("server {} failed its health check, skipping it", server.id);
+                }
+                (server.id, healthy)
+            });
+        futures::future::join_all(probes)
+            .await
+            .into_iter()
+            .filter(|(_, healthy)| *healthy)
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
@@ -0,0 +1,80 @@
+//! Floating IPs published in the load balancer ingress of their Service
+//! status, which external-dns and other consumers read to follow the IP,
+//! without a cloud controller writing it.
+
+use crate::conflicts::claimed_ips;
+use crate::{fetch_floating_ips, is_dry_run, is_load_balancer, trace, Context, Error};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, ResourceExt};
+
+/// Sets the load balancer ingress of `service` to `ip`.
+pub async fn publish(
+    services_api: &Api<KubeService>,
+    service: &KubeService,
+    ip: &str,
+) -> Result<(), Error> {
+    let api = Api::<KubeService>::namespaced(
+        services_api.clone().into_client(),
+        &service.namespace().unwrap_or_default(),
+    );
+    let status = serde_json::json!({
+        "status": { "loadBalancer": { "ingress": [{ "ip": ip }] } }
+    });
+    api.patch_status(
+        &service.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(&status),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Publishes the `spec.loadBalancerIP` of `service` when it is a floating IP
+/// and the Service has no ingress yet, returning it.
+pub async fn bind_load_balancer_ip(
+    ctx: &Context,
+    service: &KubeService,
+) -> Result<Option<String>, Error> {
+    if !is_load_balancer(service) || !claimed_ips(service).is_empty() {
+        return Ok(None);
+    }
+    let ip = match service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.load_balancer_ip.as_ref())
+    {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+    let mut is_floating_ip = false;
+    for project in &ctx.projects {
+        if fetch_floating_ips(&project.conf())
+            .await?
+            .iter()
+            .any(|fip| &fip.ip == ip)
+        {
+            is_floating_ip = true;
+            break;
+        }
+    }
+    if !is_floating_ip {
+        return Ok(None);
+    }
+    if is_dry_run() {
+        println!(
+            "dry run: would publish {} in the status of service {}",
+            ip,
+            service.name_any()
+        );
+        return Ok(None);
+    }
+    println!(
+        "publishing {} in the status of service {}",
+        ip,
+        service.name_any()
+    );
+    trace::record(format!("publish {} in the service status", ip));
+    publish(&ctx.services_api, service, ip).await?;
+    Ok(Some(ip.clone()))
+}
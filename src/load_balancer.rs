@@ -0,0 +1,339 @@
+//! hcloud Load Balancers for the LoadBalancer Services annotated with
+//! `fip.hcloud.barodeur.io/backend: load-balancer`, instead of a floating IP.
+//! The controller creates the Load Balancer, forwards the Service ports to
+//! their node ports on the eligible nodes and publishes its IP, so a couple
+//! of Load Balancers do not need the cloud controller manager.
+
+use crate::conflicts::claimed_ips;
+use crate::provision::{LOCATION_ANNOTATION, NAMESPACE_LABEL, NAME_LABEL};
+use crate::{eligible_nodes, get_hc_server_id, ingress, is_dry_run, is_load_balancer, projects};
+use crate::{hcloud_api, trace, Context, Error};
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::{
+    load_balancer_algorithm, load_balancer_service, load_balancer_service_health_check,
+    CreateLoadBalancerRequest, LoadBalancer, LoadBalancerAlgorithm, LoadBalancerService,
+    LoadBalancerServiceHealthCheck,
+};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::ResourceExt;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::Mutex as AsyncMutex;
+
+pub const BACKEND_ANNOTATION: &str = "fip.hcloud.barodeur.io/backend";
+pub const TYPE_ANNOTATION: &str = "fip.hcloud.barodeur.io/load-balancer-type";
+
+/// Values of the backend annotation, floating IPs being the default.
+pub const BACKENDS: &[&str] = &["floating-ip", "load-balancer"];
+
+/// Load Balancers are synced one at a time, so a Service and a node
+/// reconcile never both create the Load Balancer of a new Service.
+static SYNC: Lazy<AsyncMutex<()>> = Lazy::new(Default::default);
+
+/// Targets last synced by Service, node reconciles skip the Services whose
+/// targets did not change.
+static SYNCED_TARGETS: Lazy<Mutex<HashMap<String, BTreeSet<i32>>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone)]
+pub struct LoadBalancerConfig {
+    /// Location of the created Load Balancers, overridden by the
+    /// `fip.hcloud.barodeur.io/location` annotation.
+    pub location: Option<String>,
+    /// Type of the created Load Balancers, e.g. `lb11`.
+    pub load_balancer_type: String,
+}
+
+/// Whether `service` is backed by an hcloud Load Balancer.
+pub fn is_backed(service: &KubeService) -> bool {
+    service
+        .annotations()
+        .get(BACKEND_ANNOTATION)
+        .map(String::as_str)
+        == Some("load-balancer")
+}
+
+fn full_name(service: &KubeService) -> String {
+    format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    )
+}
+
+/// Forwards every TCP port of `service` to its node port, health checked on
+/// the node port.
+fn desired_services(service: &KubeService) -> Vec<LoadBalancerService> {
+    service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == "TCP")
+        .filter_map(|port| {
+            let node_port = port.node_port?;
+            let health_check = LoadBalancerServiceHealthCheck::new(
+                15,
+                node_port,
+                load_balancer_service_health_check::Protocol::Tcp,
+                3,
+                10,
+            );
+            Some(LoadBalancerService::new(
+                node_port,
+                health_check,
+                port.port,
+                load_balancer_service::Protocol::Tcp,
+                false,
+            ))
+        })
+        .collect()
+}
+
+async fn desired_targets(ctx: &Context, service: &KubeService) -> Result<BTreeSet<i32>, Error> {
+    let eligible: HashSet<i32> = eligible_nodes(ctx, service)
+        .iter()
+        .filter_map(get_hc_server_id)
+        .collect();
+    let project = ctx.projects.first().ok_or("no project")?;
+    let targets = projects::project_server_ids(&ctx.projects, project, &eligible).await?;
+    Ok(targets.into_iter().collect())
+}
+
+async fn find(
+    hcloud_conf: &Configuration,
+    service: &KubeService,
+) -> Result<Option<LoadBalancer>, Error> {
+    let selector = format!(
+        "{}={},{}={}",
+        NAMESPACE_LABEL,
+        service.namespace().unwrap_or_default(),
+        NAME_LABEL,
+        service.name_any()
+    );
+    Ok(hcloud_api::api()
+        .list_load_balancers(hcloud_conf, Some(selector))
+        .await?
+        .into_iter()
+        .next())
+}
+
+async fn create(
+    hcloud_conf: &Configuration,
+    config: &LoadBalancerConfig,
+    service: &KubeService,
+    targets: &BTreeSet<i32>,
+) -> Result<LoadBalancer, Error> {
+    let location = service
+        .annotations()
+        .get(LOCATION_ANNOTATION)
+        .or(config.location.as_ref())
+        .ok_or_else(|| {
+            format!(
+                "no location for the load balancer of {}, set --load-balancer-location or {}",
+                full_name(service),
+                LOCATION_ANNOTATION
+            )
+        })?;
+    let load_balancer_type = service
+        .annotations()
+        .get(TYPE_ANNOTATION)
+        .unwrap_or(&config.load_balancer_type);
+    let labels = HashMap::from([
+        (
+            NAMESPACE_LABEL.to_string(),
+            service.namespace().unwrap_or_default(),
+        ),
+        (NAME_LABEL.to_string(), service.name_any()),
+    ]);
+    let targets = targets
+        .iter()
+        .map(|id| hcloud::models::Target {
+            server: Some(Box::new(hcloud::models::ResourceId::new(*id))),
+            ..hcloud::models::Target::new(hcloud::models::target::Type::Server)
+        })
+        .collect();
+    let request = CreateLoadBalancerRequest {
+        labels: Some(labels),
+        location: Some(location.clone()),
+        services: Some(desired_services(service)),
+        targets: Some(targets),
+        ..CreateLoadBalancerRequest::new(
+            LoadBalancerAlgorithm::new(load_balancer_algorithm::Type::RoundRobin),
+            load_balancer_type.clone(),
+            format!(
+                "{}-{}",
+                service.namespace().unwrap_or_default(),
+                service.name_any()
+            ),
+        )
+    };
+    hcloud_api::api()
+        .create_load_balancer(hcloud_conf, request)
+        .await
+}
+
+async fn sync_services(
+    hcloud_conf: &Configuration,
+    load_balancer: &LoadBalancer,
+    service: &KubeService,
+) -> Result<(), Error> {
+    let desired = desired_services(service);
+    for wanted in &desired {
+        match load_balancer
+            .services
+            .iter()
+            .find(|current| current.listen_port == wanted.listen_port)
+        {
+            Some(current) if current.destination_port == wanted.destination_port => {}
+            Some(_) => {
+                hcloud_api::api()
+                    .update_load_balancer_service(hcloud_conf, load_balancer.id, wanted.clone())
+                    .await?;
+            }
+            None => {
+                hcloud_api::api()
+                    .add_load_balancer_service(hcloud_conf, load_balancer.id, wanted.clone())
+                    .await?;
+            }
+        }
+    }
+    for current in &load_balancer.services {
+        if !desired
+            .iter()
+            .any(|wanted| wanted.listen_port == current.listen_port)
+        {
+            hcloud_api::api()
+                .delete_load_balancer_service(hcloud_conf, load_balancer.id, current.listen_port)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn sync_targets(
+    hcloud_conf: &Configuration,
+    load_balancer: &LoadBalancer,
+    targets: &BTreeSet<i32>,
+) -> Result<(), Error> {
+    let current: BTreeSet<i32> = load_balancer
+        .targets
+        .iter()
+        .filter_map(|target| target.server.as_ref().map(|server| server.id))
+        .collect();
+    for id in targets.difference(&current) {
+        println!(
+            "adding server {} to load balancer {}",
+            id, load_balancer.name
+        );
+        hcloud_api::api()
+            .add_load_balancer_target(hcloud_conf, load_balancer.id, *id)
+            .await?;
+    }
+    for id in current.difference(targets) {
+        println!(
+            "removing server {} from load balancer {}",
+            id, load_balancer.name
+        );
+        hcloud_api::api()
+            .remove_load_balancer_target(hcloud_conf, load_balancer.id, *id)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Creates or updates the Load Balancer of `service` and publishes its IP.
+pub async fn reconcile(
+    ctx: &Context,
+    config: &LoadBalancerConfig,
+    service: &KubeService,
+) -> Result<(), Error> {
+    let _sync = SYNC.lock().await;
+    let project = ctx.projects.first().ok_or("no project")?;
+    let hcloud_conf = &project.conf();
+    let targets = desired_targets(ctx, service).await?;
+    let name = full_name(service);
+    if is_dry_run() {
+        println!(
+            "dry run: would sync the load balancer of {} to servers {:?}",
+            name, targets
+        );
+        return Ok(());
+    }
+    let load_balancer = match find(hcloud_conf, service).await? {
+        Some(load_balancer) => {
+            sync_services(hcloud_conf, &load_balancer, service).await?;
+            sync_targets(hcloud_conf, &load_balancer, &targets).await?;
+            load_balancer
+        }
+        None => {
+            let load_balancer = create(hcloud_conf, config, service, &targets).await?;
+            println!("created load balancer {} for {}", load_balancer.name, name);
+            trace::record(format!("create load balancer {}", load_balancer.name));
+            load_balancer
+        }
+    };
+    SYNCED_TARGETS.lock().unwrap().insert(name, targets);
+
+    let ip = match &load_balancer.public_net.ipv4.ip {
+        Some(ip) => ip,
+        // Assigned shortly after the creation, published on the next
+        // reconcile.
+        None => return Ok(()),
+    };
+    if !claimed_ips(service).contains(&ip) {
+        ingress::publish(&ctx.services_api, service, ip).await?;
+    }
+    Ok(())
+}
+
+/// Updates the targets of the Load Balancers whose eligible nodes changed,
+/// after a node event.
+pub async fn reconcile_targets(ctx: &Context, config: &LoadBalancerConfig) -> Result<(), Error> {
+    for service in ctx.services.state() {
+        if !is_load_balancer(&service)
+            || !is_backed(&service)
+            || service.metadata.deletion_timestamp.is_some()
+        {
+            continue;
+        }
+        let targets = desired_targets(ctx, &service).await?;
+        let synced = SYNCED_TARGETS
+            .lock()
+            .unwrap()
+            .get(&full_name(&service))
+            .map(|synced| synced == &targets)
+            .unwrap_or(false);
+        if !synced {
+            reconcile(ctx, config, &service).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the Load Balancer of `service`, if any.
+pub async fn delete(ctx: &Context, service: &KubeService) -> Result<(), Error> {
+    let _sync = SYNC.lock().await;
+    let project = ctx.projects.first().ok_or("no project")?;
+    let hcloud_conf = &project.conf();
+    let load_balancer = match find(hcloud_conf, service).await? {
+        Some(load_balancer) => load_balancer,
+        None => return Ok(()),
+    };
+    if is_dry_run() {
+        println!("dry run: would delete load balancer {}", load_balancer.name);
+        return Ok(());
+    }
+    println!(
+        "deleting load balancer {} of deleted service {}",
+        load_balancer.name,
+        full_name(service)
+    );
+    trace::record(format!("delete load balancer {}", load_balancer.name));
+    hcloud_api::api()
+        .delete_load_balancer(hcloud_conf, load_balancer.id)
+        .await?;
+    SYNCED_TARGETS.lock().unwrap().remove(&full_name(service));
+    Ok(())
+}
@@ -1,175 +1,1896 @@
+mod actions;
+mod admin;
+mod admission;
+mod agent;
+mod alias_ips;
+mod assign;
+mod audit;
+mod bundle;
+mod canary;
+mod check;
+mod circuit;
+mod clusters;
+mod config;
+mod config_file;
+mod conflicts;
+mod cooldown;
+mod debug;
+mod drain;
+mod drift;
+mod dual_stack;
+mod egress;
+mod endpoints;
+mod events;
+mod failback;
+mod fip_cache;
+mod fip_filter;
+mod fip_locks;
+mod fip_status;
+mod gateway;
+mod hcloud_api;
+mod health;
+mod ingress;
+mod load_balancer;
+mod maintenance;
+mod mapping;
+mod metrics;
+mod node_exclude;
+mod notify;
+mod otlp;
+mod ownership;
+mod pause;
+mod pin;
+mod placement;
+mod pools;
+mod priority;
+mod projects;
+mod provider;
+mod provision;
+mod queue;
+mod rdns;
+mod release;
+mod reload;
+mod resync;
+mod robot;
+mod rotation;
+mod secrets;
+mod server_failures;
+mod settings;
+mod shutdown;
+mod snapshot;
+mod standalone;
+mod startup;
+mod status;
+mod systemd;
+mod taints;
+mod throttle;
+mod trace;
+mod triggers;
+mod verify;
+
+use alias_ips::AliasIp;
+use clap::{CommandFactory, FromArgMatches};
+use clusters::Peer;
+use config::{Cli, Command};
+use config_file::ConfigFile;
 use dotenv::dotenv;
+use drift::DriftPolicy;
+use events::EventPublisher;
 use futures::stream::select;
-use futures::{pin_mut, TryStreamExt};
+use futures::{pin_mut, FutureExt, StreamExt, TryStreamExt};
+use gateway::GatewayConfig;
 use hcloud::apis::configuration::Configuration;
-use hcloud::models::{AssignFloatingIpToServerRequest, FloatingIp};
+use hcloud::models::{FloatingIp, Server};
+use health::TargetProbe;
 use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
 use kube::api::ListParams;
+use kube::runtime::reflector::{self, Store};
 use kube::runtime::{watcher, WatchStreamExt};
-use kube::{Api, Client as KubeClient};
-use rand::seq::SliceRandom;
-use std::collections::HashSet;
-use std::env;
+use kube::{Api, Resource};
+use load_balancer::LoadBalancerConfig;
+use once_cell::sync::{Lazy, OnceCell};
+use placement::{FailureDomain, LocationPolicy, NoTargetPolicy};
+use projects::Project;
+use provider::Provider;
+use provision::ProvisionConfig;
+use queue::WorkQueue;
+use regex::Regex;
+use release::ReleaseConfig;
+use robot::RobotClient;
+use serde::de::DeserializeOwned;
+use shutdown::Shutdown;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
+use std::ffi::OsString;
 use std::fmt::Debug;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use throttle::ActionClass;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use verify::VerifyConfig;
+
+pub(crate) type Error = Box<dyn StdError + Send + Sync>;
+
+/// Set once at startup, when enabled every decision is made but no IP is
+/// actually moved.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum KubeResource {
+    Node(Box<KubeNode>),
+    Service(Box<KubeService>),
+}
+
+impl KubeResource {
+    /// Identifies the object, events of the same object are coalesced.
+    fn key(&self) -> String {
+        match self {
+            KubeResource::Node(node) => format!("node/{}", node.metadata.name.as_ref().unwrap()),
+            KubeResource::Service(service) => format!(
+                "service/{}/{}",
+                service.metadata.namespace.as_deref().unwrap_or_default(),
+                service.metadata.name.as_ref().unwrap()
+            ),
+        }
+    }
+
+    /// What the object looked like when its reconcile started, for traces.
+    fn summary(&self) -> String {
+        match self {
+            KubeResource::Node(node) => format!(
+                "provider id {:?}, {}",
+                provider_id(node),
+                evacuation_reason(node).unwrap_or_else(|| "available".into())
+            ),
+            KubeResource::Service(service) => format!(
+                "type {}, ips [{}]",
+                service
+                    .spec
+                    .as_ref()
+                    .and_then(|spec| spec.type_.as_deref())
+                    .unwrap_or_default(),
+                conflicts::claimed_ips(service)
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// What becomes of an object once its reconcile task finished.
+enum Outcome {
+    Done,
+    /// The reconcile failed, it is retried after a backoff.
+    Retry,
+    /// The node is draining, it is reconciled again once the drain is over.
+    DrainUntil(tokio::time::Instant),
+}
+
+/// The `spec.loadBalancerClass` of the managed LoadBalancer Services, set
+/// once at startup. Only Services without a class are managed when not set.
+static LOAD_BALANCER_CLASS: OnceCell<String> = OnceCell::new();
+
+/// Whether `service` is a LoadBalancer of the managed class, Services of
+/// other classes belonging to other controllers.
+pub(crate) fn is_load_balancer(service: &KubeService) -> bool {
+    let spec = service.spec.as_ref().unwrap();
+    spec.type_.as_ref().unwrap() == "LoadBalancer"
+        && spec.load_balancer_class.as_ref() == LOAD_BALANCER_CLASS.get()
+}
 
-type Error = Box<dyn StdError>;
+/// Whether the IPs of `service` are managed: it is a LoadBalancer, or has
+/// external IPs whatever its type.
+pub(crate) fn claims_ips(service: &KubeService) -> bool {
+    is_load_balancer(service)
+        || service
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.external_ips.as_ref())
+            .map(|ips| !ips.is_empty())
+            .unwrap_or(false)
+}
+
+/// Extracts the server ID from nonstandard provider IDs, set once at startup.
+static PROVIDER_ID_PATTERN: OnceCell<Regex> = OnceCell::new();
 
-#[derive(Debug)]
-enum KubeResource {
-    Node(KubeNode),
-    Service(KubeService),
+fn parse_hc_server_id(provider_id: &str) -> Option<i32> {
+    if provider_id.starts_with("hrobot://") {
+        return None;
+    }
+    match PROVIDER_ID_PATTERN.get() {
+        Some(pattern) => {
+            let captures = pattern.captures(provider_id)?;
+            let id = captures.name("id").or_else(|| captures.get(1))?;
+            id.as_str().parse().ok()
+        }
+        None => provider_id.strip_prefix("hcloud://")?.parse().ok(),
+    }
 }
 
-fn is_load_balancer(service: &KubeService) -> bool {
-    service.spec.as_ref().unwrap().type_.as_ref().unwrap() == "LoadBalancer"
+fn provider_id(node: &KubeNode) -> Option<&String> {
+    node.spec.as_ref()?.provider_id.as_ref()
 }
 
-fn get_hc_server_id(node: &KubeNode) -> i32 {
-    let provider_id = node.spec.as_ref().unwrap().provider_id.as_ref().unwrap();
-    provider_id
-        .strip_prefix("hcloud://")
-        .map(|a| a.parse::<i32>().unwrap())
-        .unwrap()
+pub(crate) fn is_hcloud_node(node: &KubeNode) -> bool {
+    get_hc_server_id(node).is_some()
 }
 
-async fn fetch_available_hc_server_ids(nodes_api: &Api<KubeNode>) -> Result<HashSet<i32>, Error> {
-    let nodes = nodes_api.list(&ListParams::default()).await?;
-    Ok(nodes
+pub(crate) fn get_robot_server_number(node: &KubeNode) -> Option<i32> {
+    node.spec
+        .as_ref()?
+        .provider_id
+        .as_ref()?
+        .strip_prefix("hrobot://")?
+        .parse()
+        .ok()
+}
+
+/// The hcloud server ID of `node`, from its provider ID or else the annotation
+/// set by the agent, none for Robot, on-prem or not yet initialized nodes.
+pub(crate) fn get_hc_server_id(node: &KubeNode) -> Option<i32> {
+    match provider_id(node) {
+        Some(provider_id) if provider_id.starts_with("hrobot://") => None,
+        Some(provider_id) => parse_hc_server_id(provider_id).or_else(|| annotated_server_id(node)),
+        None => annotated_server_id(node),
+    }
+}
+
+fn annotated_server_id(node: &KubeNode) -> Option<i32> {
+    node.metadata
+        .annotations
+        .as_ref()?
+        .get(agent::SERVER_ID_ANNOTATION)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Nodes already logged as not managed, so hybrid clusters don't flood the
+/// log on every node update.
+static UNMANAGED_NODES: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+fn log_unmanaged_once(node: &KubeNode, reason: &str) {
+    let name = node.metadata.name.clone().unwrap_or_default();
+    if UNMANAGED_NODES.lock().unwrap().insert(name.clone()) {
+        println!("ignoring node {}, {}", name, reason);
+    }
+}
+
+/// Why the IPs have to move off `node`, by default cordoned or carrying one
+/// of the `--evacuate-taints`, as configured by `--evacuate-when`, it is
+/// opted out, or its server failed in hcloud.
+pub(crate) fn evacuation_reason(node: &KubeNode) -> Option<String> {
+    node_exclude::reason(node)
+        .or_else(|| triggers::reason(node))
+        .or_else(|| server_failures::reason(node))
+}
+
+/// The schedulable nodes, read from the cache kept up to date by the node
+/// watch instead of listing them on every event.
+pub(crate) fn available_nodes(nodes: &Store<KubeNode>) -> Vec<KubeNode> {
+    nodes
+        .state()
+        .into_iter()
+        .filter(|node| evacuation_reason(node).is_none())
+        .map(|node| (*node).clone())
+        .collect()
+}
+
+pub(crate) fn available_hc_server_ids(nodes: &Store<KubeNode>) -> HashSet<i32> {
+    available_nodes(nodes)
         .iter()
-        .filter(|node| {
-            node.spec
-                .as_ref()
-                .unwrap()
-                .unschedulable
-                .map(|unschedulable| !unschedulable)
-                .unwrap_or(true)
-        })
-        .map(get_hc_server_id)
-        .collect())
+        .filter_map(get_hc_server_id)
+        .collect()
+}
+
+pub(crate) fn available_robot_server_numbers(nodes: &Store<KubeNode>) -> Vec<i32> {
+    available_nodes(nodes)
+        .iter()
+        .flat_map(get_robot_server_number)
+        .collect()
+}
+
+/// Objects fetched per request by `list_all`.
+const LIST_CHUNK: u32 = 500;
+
+/// Every object of `api` matching `params`, listed in chunks so a large
+/// cluster isn't sent in a single response.
+pub(crate) async fn list_all<K>(api: &Api<K>, params: &ListParams) -> Result<Vec<K>, Error>
+where
+    K: Clone + DeserializeOwned + Debug,
+{
+    let mut objects = vec![];
+    let mut params = params.clone().limit(LIST_CHUNK);
+    loop {
+        let list = api.list(&params).await?;
+        objects.extend(list.items);
+        match list.metadata.continue_ {
+            Some(token) if !token.is_empty() => params = params.continue_token(&token),
+            _ => return Ok(objects),
+        }
+    }
 }
 
-async fn assign_floating_ip_to_server(
+pub(crate) async fn assign_floating_ip_to_server(
     hcloud_conf: &Configuration,
     fip_id: &i32,
     server_id: &i32,
 ) -> Result<(), Error> {
+    if is_dry_run() {
+        println!("dry run: would assign {} to {}", fip_id, server_id);
+        trace::record(format!(
+            "dry run: assign floating ip {} to {}",
+            fip_id, server_id
+        ));
+        return Ok(());
+    }
     println!("assigning {} to {}", fip_id, server_id);
-    hcloud::apis::floating_ips_api::assign_floating_ip_to_server(
-        hcloud_conf,
-        hcloud::apis::floating_ips_api::AssignFloatingIpToServerParams {
-            id: *fip_id,
-            assign_floating_ip_to_server_request: Some(AssignFloatingIpToServerRequest {
-                server: *server_id,
-            }),
-        },
-    )
-    .await?;
-    Ok(())
+    trace::record(format!("assign floating ip {} to {}", fip_id, server_id));
+    let what = format!("assigning {} to {}", fip_id, server_id);
+    let result = actions::confirm(hcloud_conf, &what, || async {
+        let result = hcloud_api::api()
+            .assign_floating_ip(hcloud_conf, *fip_id, *server_id)
+            .await;
+        if let Err(hcloud::apis::Error::ResponseError(content)) = &result {
+            if content.status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                throttle::throttled();
+            }
+        }
+        result
+    })
+    .await;
+    // Even a failed assignment may have gone through.
+    fip_cache::invalidate(hcloud_conf);
+    if result.is_ok() {
+        drift::assigned(*fip_id, *server_id);
+        cooldown::moved(*fip_id);
+    }
+    result
 }
 
-async fn fetch_floating_ips(hcloud_conf: &Configuration) -> Result<Vec<FloatingIp>, Error> {
-    let fips = hcloud::apis::floating_ips_api::list_floating_ips(
-        hcloud_conf,
-        hcloud::apis::floating_ips_api::ListFloatingIpsParams::default(),
-    )
-    .await?
-    .floating_ips;
+pub(crate) async fn unassign_floating_ip(
+    hcloud_conf: &Configuration,
+    fip_id: &i32,
+) -> Result<(), Error> {
+    if is_dry_run() {
+        println!("dry run: would unassign {}", fip_id);
+        trace::record(format!("dry run: unassign floating ip {}", fip_id));
+        return Ok(());
+    }
+    println!("unassigning {}", fip_id);
+    trace::record(format!("unassign floating ip {}", fip_id));
+    let what = format!("unassigning {}", fip_id);
+    let result = actions::confirm(hcloud_conf, &what, || {
+        hcloud_api::api().unassign_floating_ip(hcloud_conf, *fip_id)
+    })
+    .await;
+    fip_cache::invalidate(hcloud_conf);
+    if result.is_ok() {
+        drift::unassigned(*fip_id);
+    }
+    result
+}
+
+/// Assigns `fip` to `server_id` unless another reconcile task moved it since
+/// it was listed, or it is paused, and returns whether it did. Waits for its
+/// turn by `class` when moves are throttled.
+pub(crate) async fn move_floating_ip(
+    hcloud_conf: &Configuration,
+    fip: &FloatingIp,
+    server_id: i32,
+    class: ActionClass,
+) -> Result<bool, Error> {
+    fip_locks::locked(fip.id, move_locked(hcloud_conf, fip, server_id, class)).await
+}
+
+/// `move_floating_ip` under the lock of `fip`.
+async fn move_locked(
+    hcloud_conf: &Configuration,
+    fip: &FloatingIp,
+    server_id: i32,
+    class: ActionClass,
+) -> Result<bool, Error> {
+    let _permit = throttle::acquire(class).await;
+    let current = hcloud_api::api()
+        .get_floating_ip(hcloud_conf, fip.id)
+        .await?;
+    if current.server != fip.server {
+        println!(
+            "{} was moved meanwhile, leaving it on {:?}",
+            fip.ip, current.server
+        );
+        trace::record(format!("skip {}, moved meanwhile", fip.ip));
+        audit::skipped(&fip.ip, current.server, class.label(), "moved meanwhile");
+        return Ok(false);
+    }
+    // Manual moves are checked by their callers, which may override the
+    // ownership, and a pause only holds the controller's own moves.
+    if class != ActionClass::Manual {
+        if let Some(reason) = ownership::blocker(&current).or_else(|| pause::blocker(&current)) {
+            println!("leaving {} on {:?}, {}", fip.ip, current.server, reason);
+            trace::record(format!("skip {}, {}", fip.ip, reason));
+            audit::skipped(&fip.ip, current.server, class.label(), &reason);
+            return Ok(false);
+        }
+    }
+    let result = assign_floating_ip_to_server(hcloud_conf, &fip.id, &server_id).await;
+    audit::moved(&fip.ip, fip.server, server_id, class.label(), &result);
+    if !is_dry_run() && !canary::is_canary(fip) {
+        match &result {
+            Ok(()) => {
+                notify::reassigned(&fip.ip, fip.server, server_id);
+                fip_status::changed();
+            }
+            Err(err) => notify::assign_failed(&fip.ip, server_id, &err.to_string()),
+        }
+    }
+    result.map(|()| true)
+}
+
+/// Moves an alias IP like `move_floating_ip` does a floating IP, under its
+/// lock and unless another task moved it since `servers` were listed.
+async fn move_alias_ip(
+    hcloud_conf: &Configuration,
+    servers: &[Server],
+    alias: &AliasIp,
+    server_id: i32,
+    class: ActionClass,
+) -> Result<(), Error> {
+    let from = alias_ips::find_holder(servers, alias).map(|holder| holder.id);
+    fip_locks::alias_locked(alias, async {
+        let _permit = throttle::acquire(class).await;
+        let servers = fetch_servers(hcloud_conf).await?;
+        let current = alias_ips::find_holder(&servers, alias).map(|holder| holder.id);
+        if current != from {
+            println!(
+                "alias ip {} was moved meanwhile, leaving it on {:?}",
+                alias.ip, current
+            );
+            trace::record(format!("skip {}, moved meanwhile", alias.ip));
+            audit::skipped(&alias.ip, current, class.label(), "moved meanwhile");
+            return Ok(());
+        }
+        move_alias_locked(hcloud_conf, &servers, alias, server_id, class).await
+    })
+    .await
+}
+
+/// `move_alias_ip` under the lock of `alias`.
+async fn move_alias_locked(
+    hcloud_conf: &Configuration,
+    servers: &[Server],
+    alias: &AliasIp,
+    server_id: i32,
+    class: ActionClass,
+) -> Result<(), Error> {
+    let from = alias_ips::find_holder(servers, alias).map(|holder| holder.id);
+    let result = alias_ips::move_alias_ip(hcloud_conf, servers, alias, server_id).await;
+    audit::moved(&alias.ip, from, server_id, class.label(), &result);
+    if !is_dry_run() {
+        match &result {
+            Ok(()) => notify::reassigned(&alias.ip, from, server_id),
+            Err(err) => notify::assign_failed(&alias.ip, server_id, &err.to_string()),
+        }
+    }
+    result
+}
+
+/// Lists the managed floating IPs of the project, served from the cache for
+/// up to `--fip-cache-ttl`.
+pub(crate) async fn fetch_floating_ips(
+    hcloud_conf: &Configuration,
+) -> Result<Vec<FloatingIp>, Error> {
+    if let Some(fips) = fip_cache::get(hcloud_conf) {
+        return Ok(fips);
+    }
+    let fips = hcloud_api::api()
+        .list_floating_ips(hcloud_conf, None)
+        .await?
+        .into_iter()
+        .filter(|fip| !canary::is_canary(fip) && fip_filter::is_managed(fip))
+        .collect::<Vec<_>>();
+    fip_cache::insert(hcloud_conf, &fips);
     Ok(fips)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok();
+pub(crate) async fn fetch_servers(hcloud_conf: &Configuration) -> Result<Vec<Server>, Error> {
+    hcloud_api::api().list_servers(hcloud_conf).await
+}
 
-    let hcloud_token = env::var("HCLOUD_TOKEN")
-        .map_err(|_| "Missing environment variable HCLOUD_TOKEN")
-        .unwrap();
+/// Narrows `candidates` down to the servers hcloud says are running and not
+/// locked, as nodes can outlive their server, and to those passing the
+/// health check with `--probe-targets`.
+async fn probed_candidates(
+    ctx: &Context,
+    hcloud_conf: &Configuration,
+    candidates: &HashSet<i32>,
+) -> Result<HashSet<i32>, Error> {
+    let servers = fetch_servers(hcloud_conf).await?;
+    let mut usable = HashSet::new();
+    for &server_id in candidates {
+        match server_failures::target_blocker(&servers, server_id) {
+            Some(reason) => {
+                println!("server {} is {}, skipping it", server_id, reason);
+                trace::record(format!("skip server {}, {}", server_id, reason));
+            }
+            None => {
+                usable.insert(server_id);
+            }
+        }
+    }
+    match &ctx.target_probe {
+        Some(probe) => Ok(probe.healthy(&servers, &usable).await),
+        None => Ok(usable),
+    }
+}
 
-    let mut hcloud_conf = Configuration::new();
-    hcloud_conf.bearer_access_token = Some(hcloud_token);
+/// Applies the `--no-target-policy` to `fip`, which no available server can
+/// take.
+async fn no_target(
+    ctx: &Context,
+    hcloud_conf: &Configuration,
+    fip: &FloatingIp,
+    class: ActionClass,
+) -> Result<(), Error> {
+    notify::no_target(&fip.ip, fip.server);
+    match (ctx.no_target_policy, ctx.fallback_server) {
+        (NoTargetPolicy::Fallback, Some(fallback)) if fip.server != Some(fallback) => {
+            println!(
+                "no available server in {} for {}, moving it to fallback server {}",
+                fip.home_location.name, fip.ip, fallback
+            );
+            move_floating_ip(hcloud_conf, fip, fallback, class)
+                .await
+                .map(|_| ())
+        }
+        (NoTargetPolicy::Unassign, _) if fip.server.is_some() => {
+            println!(
+                "no available server in {} for {}, unassigning it",
+                fip.home_location.name, fip.ip
+            );
+            let result =
+                fip_locks::locked(fip.id, unassign_floating_ip(hcloud_conf, &fip.id)).await;
+            audit::unassigned(&fip.ip, fip.server, class.label(), &result);
+            result
+        }
+        _ => {
+            println!(
+                "no available server in {} for {}, leaving it in place",
+                fip.home_location.name, fip.ip
+            );
+            trace::record(format!("skip {}, no available server", fip.ip));
+            audit::skipped(&fip.ip, fip.server, class.label(), "no available server");
+            Ok(())
+        }
+    }
+}
 
-    let kube_client = KubeClient::try_default().await.unwrap();
-    let services_api = Api::<KubeService>::all(kube_client.clone());
-    let nodes_api = Api::<KubeNode>::all(kube_client.clone());
+/// Publishes a warning event on the Services claiming `ip`.
+pub(crate) async fn warn_claimants(ctx: &Context, ip: &String, reason: &str, note: String) {
+    for service in ctx.services.state() {
+        if claims_ips(&service) && conflicts::claimed_ips(&service).contains(&ip) {
+            ctx.events
+                .warning(service.object_ref(&()), reason, "Reconcile", note.clone())
+                .await;
+        }
+    }
+}
 
-    let nodes_stream = watcher(nodes_api.clone(), ListParams::default()).applied_objects();
-    let services_stream = watcher(services_api.clone(), ListParams::default()).applied_objects();
-    let stream = select(
-        nodes_stream.map_ok(KubeResource::Node),
-        services_stream.map_ok(KubeResource::Service),
-    );
-    pin_mut!(stream);
+/// Leaves `fip` in place and warns on the Services claiming it.
+async fn warn_not_owned(ctx: &Context, fip: &FloatingIp, class: ActionClass, reason: &str) {
+    println!("leaving {} on {:?}, {}", fip.ip, fip.server, reason);
+    trace::record(format!("skip {}, {}", fip.ip, reason));
+    audit::skipped(&fip.ip, fip.server, class.label(), reason);
+    let note = format!("{} is not moved, {}", fip.ip, reason);
+    warn_claimants(ctx, &fip.ip, "FloatingIPNotOwned", note).await;
+}
 
-    while let Some(resource) = stream.try_next().await? {
-        match resource {
-            KubeResource::Node(node) => {
-                let spec = node.spec.as_ref().unwrap();
-                if !spec.unschedulable.unwrap_or(false) {
-                    continue;
+/// Moves each floating IP to its planned server, or applies the no target
+/// policy when it has none. The moves run concurrently, as many at once as
+/// the throttle lets through, so the last IPs of a failed node don't wait for
+/// the first ones. Every move is attempted and the first failure returned.
+async fn move_floating_ips(
+    ctx: &Context,
+    project: &Project,
+    moves: Vec<(FloatingIp, Option<i32>)>,
+    class: ActionClass,
+) -> Result<(), Error> {
+    let hcloud_conf = &project.conf();
+    let mut allowed = vec![];
+    for (fip, target_id) in moves {
+        if let Some(reason) = ownership::blocker(&fip) {
+            warn_not_owned(ctx, &fip, class, &reason).await;
+        } else if let Some(reason) = pause::blocker(&fip)
+            .or_else(|| cooldown::blocker(&ctx.nodes, &fip))
+            .or_else(|| maintenance::blocker(&ctx.nodes, &fip, class))
+        {
+            println!("holding {} back on {:?}, {}", fip.ip, fip.server, reason);
+            trace::record(format!("skip {}, {}", fip.ip, reason));
+            audit::skipped(&fip.ip, fip.server, class.label(), &reason);
+        } else {
+            allowed.push((fip, target_id));
+        }
+    }
+    let results: Vec<_> = futures::stream::iter(allowed)
+        .map(|(fip, target_id)| async move {
+            match target_id {
+                Some(target_id) => {
+                    if move_floating_ip(hcloud_conf, &fip, target_id, class).await? {
+                        failback::moved(&fip, target_id, class);
+                        if let Some(verify) = &ctx.verify {
+                            verify::spawn(verify, project, &ctx.nodes, &fip, target_id);
+                        }
+                    }
+                    Ok(())
                 }
+                None => no_target(ctx, hcloud_conf, &fip, class).await,
+            }
+        })
+        .buffer_unordered(throttle::max_in_flight())
+        .collect()
+        .await;
+    results.into_iter().collect()
+}
 
+/// Moves the floating and alias IPs of `project` held by `server_id` to the
+/// available servers.
+pub(crate) async fn evacuate_server(
+    ctx: &Context,
+    project: &Project,
+    server_id: i32,
+    available_hc_server_ids: &HashSet<i32>,
+    priorities: &HashMap<i32, i32>,
+) -> Result<(), Error> {
+    let hcloud_conf = &project.conf();
+
+    let floating_ips = fetch_floating_ips(hcloud_conf).await?;
+    let mut load = placement::load(&floating_ips);
+    let floating_ips_to_reassign: Vec<_> = floating_ips
+        .iter()
+        .filter(|fip| fip.server.map(|id| id == server_id).unwrap_or(false))
+        .cloned()
+        .collect();
+    let servers = if ctx.alias_ips.is_empty() {
+        vec![]
+    } else {
+        fetch_servers(hcloud_conf).await?
+    };
+    let alias_ips_to_reassign: Vec<_> = ctx
+        .alias_ips
+        .iter()
+        .filter(|alias| {
+            alias_ips::find_holder(&servers, alias)
+                .map(|holder| holder.id == server_id)
+                .unwrap_or(false)
+        })
+        .collect();
+    if floating_ips_to_reassign.is_empty() && alias_ips_to_reassign.is_empty() {
+        return Ok(());
+    }
+    let candidates = probed_candidates(ctx, hcloud_conf, available_hc_server_ids).await?;
+
+    // Evacuated IPs are spread across the preferred servers.
+    let locations = placement::server_locations(hcloud_conf, ctx.location_policy).await?;
+    let domains = placement::server_domains(hcloud_conf, ctx.failure_domain).await?;
+    let nodes = pools::known_nodes(ctx);
+    let mut moves: Vec<_> = floating_ips_to_reassign
+        .into_iter()
+        .map(|fip| {
+            let pooled = pools::restrict(&nodes, &fip, &candidates);
+            let home = placement::in_home_location(ctx.location_policy, &fip, &pooled, &locations);
+            let ids = priority::preferred(home, priorities);
+            let target_id = placement::least_loaded(&ids, &mut load, &domains);
+            (fip, target_id)
+        })
+        .collect();
+    // Dual-stack pairs fail over together.
+    let pairs = dual_stack::pairs(ctx, &floating_ips);
+    dual_stack::align(&mut moves, &pairs, &floating_ips, &candidates);
+    move_floating_ips(ctx, project, moves, ActionClass::Failover).await?;
+
+    for alias in alias_ips_to_reassign {
+        let ids = priority::preferred(
+            alias_ips::attached_server_ids(&servers, alias, &candidates),
+            priorities,
+        );
+        match placement::least_loaded(&ids, &mut load, &domains) {
+            Some(target_id) => {
+                move_alias_ip(
+                    hcloud_conf,
+                    &servers,
+                    alias,
+                    target_id,
+                    ActionClass::Failover,
+                )
+                .await?;
+            }
+            None => {
                 println!(
-                    "node {} is unschedulable, finding it's assigned floating ips",
-                    node.metadata.name.as_ref().unwrap()
+                    "no available server in network {} for alias ip {}, leaving it in place",
+                    alias.network, alias.ip
                 );
+                trace::record(format!("skip alias ip {}, no available server", alias.ip));
+                let holder = alias_ips::find_holder(&servers, alias).map(|holder| holder.id);
+                notify::no_target(&alias.ip, holder);
+                audit::skipped(&alias.ip, holder, "failover", "no available server");
+            }
+        }
+    }
+    Ok(())
+}
 
-                let server_id = get_hc_server_id(&node);
+/// Makes sure the floating and alias IPs of `project` among `ips` are held by
+/// an available server.
+pub(crate) async fn reassign_service_ips(
+    ctx: &Context,
+    project: &Project,
+    ips: &HashSet<&String>,
+    available_hc_server_ids: &HashSet<i32>,
+    priorities: &HashMap<i32, i32>,
+) -> Result<(), Error> {
+    let hcloud_conf = &project.conf();
 
-                let floating_ips_to_reassign: Vec<_> = fetch_floating_ips(&hcloud_conf)
-                    .await?
-                    .into_iter()
-                    .filter(|fip| fip.server.map(|id| id == server_id).unwrap_or(false))
-                    .collect();
+    let floating_ips = fetch_floating_ips(hcloud_conf).await?;
+    let mut load = placement::load(&floating_ips);
+    // IPs moved between eligible servers out of band are moved back, the
+    // others are reassigned below anyway.
+    let mut drifted = vec![];
+    for fip in floating_ips
+        .iter()
+        .filter(|fip| dual_stack::claims(ips, fip))
+    {
+        let desired = match drift::check(fip) {
+            Some(desired) => desired,
+            None => continue,
+        };
+        let held = fip
+            .server
+            .map(|server| available_hc_server_ids.contains(&server))
+            .unwrap_or(false);
+        let correct = held
+            && drift::policy() == DriftPolicy::Correct
+            && available_hc_server_ids.contains(&desired);
+        drift::record(fip, desired, correct);
+        let note = format!(
+            "{} was moved from server {} to {:?} outside of the controller",
+            fip.ip, desired, fip.server
+        );
+        warn_claimants(ctx, &fip.ip, "FloatingIPDrift", note).await;
+        if correct {
+            drifted.push((fip.clone(), Some(desired)));
+        }
+    }
+    // The IPv6 floating IP of a dual-stack Service held apart from the IPv4
+    // one is brought back next to it.
+    let pairs: Vec<_> = dual_stack::pairs(ctx, &floating_ips)
+        .into_iter()
+        .filter(|(v4, _)| {
+            floating_ips
+                .iter()
+                .any(|fip| fip.id == *v4 && dual_stack::claims(ips, fip))
+        })
+        .collect();
+    for diverged in dual_stack::diverged(&pairs, &floating_ips, available_hc_server_ids) {
+        if !drifted.iter().any(|(fip, _)| fip.id == diverged.0.id) {
+            drifted.push(diverged);
+        }
+    }
+    // IPs held by a server outside of their pool are reassigned too.
+    let nodes = pools::known_nodes(ctx);
+    let floating_ips_to_rassign: Vec<_> = floating_ips
+        .iter()
+        .filter(|fip| dual_stack::claims(ips, fip))
+        .filter(|fip| {
+            fip.server
+                .map(|server| {
+                    !available_hc_server_ids.contains(&server)
+                        || !pools::allows(&nodes, fip, server)
+                })
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect();
 
-                let available_hc_server_ids = fetch_available_hc_server_ids(&nodes_api).await?;
+    let service_alias_ips: Vec<&AliasIp> = ctx
+        .alias_ips
+        .iter()
+        .filter(|alias| ips.contains(&alias.ip))
+        .collect();
+    let servers = if service_alias_ips.is_empty() {
+        vec![]
+    } else {
+        fetch_servers(hcloud_conf).await?
+    };
+    let alias_ips_to_reassign: Vec<&AliasIp> = service_alias_ips
+        .into_iter()
+        // Alias IPs of other projects' networks are not visible here.
+        .filter(|alias| {
+            servers.iter().any(|server| {
+                server
+                    .private_net
+                    .iter()
+                    .any(|net| net.network == Some(alias.network))
+            })
+        })
+        .filter(|alias| {
+            alias_ips::find_holder(&servers, alias)
+                .map(|holder| !available_hc_server_ids.contains(&holder.id))
+                .unwrap_or(true)
+        })
+        .collect();
+    move_floating_ips(ctx, project, drifted, ActionClass::Reassign).await?;
+    if floating_ips_to_rassign.is_empty() && alias_ips_to_reassign.is_empty() {
+        return Ok(());
+    }
+    let candidates = probed_candidates(ctx, hcloud_conf, available_hc_server_ids).await?;
 
-                for fip in floating_ips_to_reassign {
-                    let ids = available_hc_server_ids.iter().collect::<Vec<_>>();
-                    let server_id = ids.choose(&mut rand::thread_rng()).unwrap();
-                    assign_floating_ip_to_server(&hcloud_conf, &fip.id, server_id).await?;
-                }
+    let locations = placement::server_locations(hcloud_conf, ctx.location_policy).await?;
+    let domains = placement::server_domains(hcloud_conf, ctx.failure_domain).await?;
+    let mut moves: Vec<_> = floating_ips_to_rassign
+        .into_iter()
+        .map(|fip| {
+            let pooled = pools::restrict(&nodes, &fip, &candidates);
+            let home = placement::in_home_location(ctx.location_policy, &fip, &pooled, &locations);
+            let ids = priority::preferred(home, priorities);
+            let target_id = placement::least_loaded(&ids, &mut load, &domains);
+            if let Some(target_id) = target_id {
+                println!("Reassigning {} to {}", fip.ip, target_id);
             }
-            KubeResource::Service(service) => {
-                if !is_load_balancer(&service) {
-                    continue;
-                }
+            (fip, target_id)
+        })
+        .collect();
+    dual_stack::align(&mut moves, &pairs, &floating_ips, &candidates);
+    move_floating_ips(ctx, project, moves, ActionClass::Reassign).await?;
 
-                let ips: HashSet<_> = service
-                    .status
-                    .as_ref()
-                    .and_then(|s| s.load_balancer.as_ref())
-                    .and_then(|lb| lb.ingress.as_ref())
-                    .map(|ingress| ingress.iter().flat_map(|i| i.ip.as_ref()).collect())
-                    .unwrap_or_default();
-
-                let floating_ips = hcloud::apis::floating_ips_api::list_floating_ips(
-                    &hcloud_conf,
-                    hcloud::apis::floating_ips_api::ListFloatingIpsParams::default(),
-                )
+    for alias in alias_ips_to_reassign {
+        let ids = priority::preferred(
+            alias_ips::attached_server_ids(&servers, alias, &candidates),
+            priorities,
+        );
+        let target_id = match placement::least_loaded(&ids, &mut load, &domains) {
+            Some(target_id) => target_id,
+            None => {
+                println!(
+                    "no available server in network {} for alias ip {}, leaving it unassigned",
+                    alias.network, alias.ip
+                );
+                trace::record(format!("skip alias ip {}, no available server", alias.ip));
+                let holder = alias_ips::find_holder(&servers, alias).map(|holder| holder.id);
+                notify::no_target(&alias.ip, holder);
+                audit::skipped(&alias.ip, holder, "reassign", "no available server");
+                continue;
+            }
+        };
+        println!("Reassigning alias ip {} to {}", alias.ip, target_id);
+        move_alias_ip(
+            hcloud_conf,
+            &servers,
+            alias,
+            target_id,
+            ActionClass::Reassign,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// What the reconcile loop needs besides the event being reconciled.
+pub(crate) struct Context {
+    projects: Vec<Project>,
+    nodes_api: Api<KubeNode>,
+    nodes: Store<KubeNode>,
+    alias_ips: Vec<AliasIp>,
+    gateway_config: Option<GatewayConfig>,
+    robot: Option<RobotClient>,
+    providers: Vec<Box<dyn Provider>>,
+    location_policy: LocationPolicy,
+    failure_domain: Option<FailureDomain>,
+    no_target_policy: NoTargetPolicy,
+    fallback_server: Option<i32>,
+    target_probe: Option<TargetProbe>,
+    verify: Option<VerifyConfig>,
+    provision: Option<ProvisionConfig>,
+    release: Option<ReleaseConfig>,
+    publish_load_balancer_ip: bool,
+    load_balancers: LoadBalancerConfig,
+    services_api: Api<KubeService>,
+    services: Store<KubeService>,
+    endpoint_slices: Store<EndpointSlice>,
+    events: EventPublisher,
+    peers: Vec<Peer>,
+}
+
+/// The floating and alias IPs held by `server_id` across every project.
+async fn fetch_held_ips(ctx: &Context, server_id: i32) -> Result<Vec<String>, Error> {
+    let mut ips = vec![];
+    for project in &ctx.projects {
+        let hcloud_conf = &project.conf();
+        ips.extend(
+            fetch_floating_ips(hcloud_conf)
                 .await?
-                .floating_ips
                 .into_iter()
-                .filter(|fip| ips.contains(&fip.ip));
+                .filter(|fip| fip.server == Some(server_id))
+                .map(|fip| fip.ip),
+        );
+        if !ctx.alias_ips.is_empty() {
+            let servers = fetch_servers(hcloud_conf).await?;
+            ips.extend(
+                ctx.alias_ips
+                    .iter()
+                    .filter(|alias| {
+                        alias_ips::find_holder(&servers, alias)
+                            .map(|holder| holder.id == server_id)
+                            .unwrap_or(false)
+                    })
+                    .map(|alias| alias.ip.clone()),
+            );
+        }
+    }
+    Ok(ips)
+}
 
-                let available_hc_server_ids = fetch_available_hc_server_ids(&nodes_api).await?;
+/// Starts draining the evacuated `node` for the longest drain delay of the
+/// services whose IPs it holds, returns whether its IPs may move now. The
+/// node's reconcile is queued again for the end of the drain, IPs held by a
+/// server that is down for good aren't drained.
+pub(crate) async fn drain_node(
+    ctx: &Context,
+    node: &KubeNode,
+    server_id: i32,
+) -> Result<bool, Error> {
+    if cooldown::is_hard_down(&ctx.nodes, server_id) {
+        return Ok(true);
+    }
+    let name = node.metadata.name.as_ref().unwrap();
+    if let Some(deadline) = drain::deadline(name) {
+        return Ok(deadline <= tokio::time::Instant::now());
+    }
+    let mut floating_ips = vec![];
+    for project in &ctx.projects {
+        floating_ips.extend(fetch_floating_ips(&project.conf()).await?);
+    }
+    let drain_delays = drain::drain_delays(&ctx.services, &floating_ips);
+    if drain_delays.is_empty() {
+        return Ok(true);
+    }
+    let held_ips = fetch_held_ips(ctx, server_id).await?;
+    let delay = match held_ips.iter().filter_map(|ip| drain_delays.get(ip)).max() {
+        Some(delay) if !delay.is_zero() => *delay,
+        _ => return Ok(true),
+    };
 
-                let floating_ips_to_rassign: Vec<_> = floating_ips
-                    .filter(|fip| {
-                        fip.server
-                            .map(|server| !available_hc_server_ids.contains(&server))
-                            .unwrap_or(true)
-                    })
-                    .collect();
+    println!(
+        "draining node {} for {:?} before moving its ips",
+        name, delay
+    );
+    drain::start(name, delay);
+    ctx.events
+        .normal(
+            node.object_ref(&()),
+            "FloatingIPDraining",
+            "Drain",
+            format!(
+                "moving {} off the node in {} seconds",
+                held_ips.join(", "),
+                delay.as_secs()
+            ),
+        )
+        .await;
+    Ok(false)
+}
+
+async fn reconcile_gateway(ctx: &Context, gateway_config: &GatewayConfig) -> Result<(), Error> {
+    let nodes = available_nodes(&ctx.nodes);
+    let server_ids: HashSet<i32> = nodes.iter().filter_map(get_hc_server_id).collect();
+    for project in &ctx.projects {
+        let server_ids = projects::project_server_ids(&ctx.projects, project, &server_ids).await?;
+        let project_nodes: Vec<KubeNode> = nodes
+            .iter()
+            .filter(|node| {
+                get_hc_server_id(node)
+                    .map(|id| server_ids.contains(&id))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        gateway::reconcile(&project.conf(), gateway_config, &project_nodes).await?;
+    }
+    Ok(())
+}
+
+async fn reconcile_node(ctx: &Context, node: &KubeNode) -> Result<(), Error> {
+    let name = node.metadata.name.as_ref().unwrap();
+    let reason = match evacuation_reason(node) {
+        Some(reason) => reason,
+        None => {
+            if drain::cancel(name) {
+                println!(
+                    "node {} became available again while draining, keeping its ips",
+                    name
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    println!(
+        "node {} is {}, finding it's assigned floating ips",
+        name, reason
+    );
+
+    match ctx.providers.iter().find(|provider| provider.manages(node)) {
+        Some(provider) => provider.evacuate(ctx, node).await,
+        None if get_robot_server_number(node).is_some() => {
+            log_unmanaged_once(node, "it is a Robot node but no Robot credentials are set");
+            Ok(())
+        }
+        None => {
+            log_unmanaged_once(node, "its provider id is neither an hcloud nor a Robot one");
+            Ok(())
+        }
+    }
+}
+
+/// The available nodes the IPs of `service` may be held by: those running a
+/// ready endpoint when it follows its endpoints, any otherwise, or when none
+/// runs one. Those of a peer cluster when none is available.
+fn eligible_nodes(ctx: &Context, service: &KubeService) -> Vec<KubeNode> {
+    let available = available_nodes(&ctx.nodes);
+    if available.is_empty() {
+        return clusters::fallback_nodes(&ctx.peers, Some(service));
+    }
+    if !endpoints::follows(service) {
+        return available;
+    }
+    let ready = endpoints::ready_nodes(service, &ctx.endpoint_slices);
+    let local: Vec<KubeNode> = available
+        .iter()
+        .filter(|node| {
+            node.metadata
+                .name
+                .as_ref()
+                .map(|name| ready.contains(name))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect();
+    if local.is_empty() {
+        println!(
+            "no available node runs a ready endpoint of {}, using any node",
+            service.metadata.name.as_ref().unwrap()
+        );
+        return available;
+    }
+    local
+}
+
+async fn reconcile_service(ctx: &Context, service: &KubeService) -> Result<(), Error> {
+    // Released once resumed, the finalizer holds the deletion until then.
+    if pause::is_paused(service) {
+        trace::record("skip, the service is paused".into());
+        return Ok(());
+    }
+    if release::is_released(service) {
+        return release::release(ctx, ctx.release.as_ref(), service).await;
+    }
+    if !claims_ips(service) {
+        return Ok(());
+    }
+    // The finalizer of Services backed by a Load Balancer is always managed.
+    if load_balancer::is_backed(service) {
+        release::ensure_finalizer(ctx, service).await?;
+        return load_balancer::reconcile(ctx, &ctx.load_balancers, service).await;
+    }
+    if ctx.release.is_some() {
+        release::ensure_finalizer(ctx, service).await?;
+    }
+
+    let conflicts = conflicts::find_conflicts(service, &ctx.services);
+    conflicts::update_metric(&ctx.services);
+    if !conflicts.is_empty() {
+        conflicts::report(&ctx.events, service, &ctx.services, &conflicts).await;
+    }
+    let provisioned = match &ctx.provision {
+        Some(config) => provision::ensure(ctx, config, service).await?,
+        None => None,
+    };
+    let published = if ctx.publish_load_balancer_ip {
+        ingress::bind_load_balancer_ip(ctx, service).await?
+    } else {
+        None
+    };
+    let mut claimed: Vec<&String> = conflicts::claimed_ips(service)
+        .into_iter()
+        .filter(|ip| !conflicts.iter().any(|(conflict, _)| conflict == *ip))
+        .collect();
+    // A pinned floating IP may be named by its name, it is managed by its IP.
+    let resolved = match (pin::pinned(service), claimed.is_empty()) {
+        (Some(id), false) => pin::resolve(ctx, service, id).await?,
+        _ => None,
+    };
+    if pin::pinned(service).is_some() {
+        claimed = resolved.iter().collect();
+    }
+    let ips: HashSet<_> = claimed
+        .into_iter()
+        .chain(provisioned.as_ref())
+        .chain(published.as_ref())
+        .collect();
+
+    let eligible = eligible_nodes(ctx, service);
+    for provider in &ctx.providers {
+        provider.reassign(ctx, &ips, &eligible).await?;
+    }
+    rdns::sync(ctx, service, &ips).await
+}
+
+pub(crate) async fn reconcile(ctx: &Context, resource: KubeResource) -> Result<(), Error> {
+    if pause::is_global() {
+        trace::record("skip, the controller is paused".into());
+        return Ok(());
+    }
+    if let Some(gateway_config) = &ctx.gateway_config {
+        return reconcile_gateway(ctx, gateway_config).await;
+    }
+
+    match resource {
+        KubeResource::Node(node) => {
+            reconcile_node(ctx, &node).await?;
+            load_balancer::reconcile_targets(ctx, &ctx.load_balancers).await
+        }
+        KubeResource::Service(service) => reconcile_service(ctx, &service).await,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    dotenv().ok();
+
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let mut matches = Cli::command().get_matches_from(&args);
+    let path = matches.get_one::<PathBuf>("config").cloned();
+    let file = path.as_deref().map(ConfigFile::load).transpose()?;
+    let loaded = reload::Loaded::new(args, &matches, path.clone(), file.as_ref());
+    if file.is_some() {
+        matches = Cli::command().get_matches_from(loaded.args());
+    }
+    let loaded: reload::Shared = Arc::new(Mutex::new(loaded));
+    let cli = Cli::from_arg_matches(&matches)?;
+    clusters::set_local(cli.config.kubeconfig.clone(), cli.config.context.clone());
+    match &cli.command {
+        Some(Command::Crd) => return status::print_crd(),
+        Some(Command::Agent { node_name }) => return agent::run(node_name).await,
+        _ => {}
+    }
+
+    let config = cli.config;
+    config.validate();
+    if config.dry_run {
+        println!("dry run enabled, no ip will be moved");
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
+    let api: Box<dyn hcloud_api::HcloudApi> = match &config.fake_hcloud {
+        Some(path) => {
+            println!("simulating hcloud from {}", path.display());
+            Box::new(hcloud_api::Fake::load(path)?)
+        }
+        None => Box::new(hcloud_api::Client),
+    };
+    circuit::set_circuit(
+        config.hcloud_circuit_threshold,
+        Duration::from_secs(config.hcloud_circuit_open),
+    );
+    let api = Box::new(circuit::Breaker(api));
+    match config.otlp_config() {
+        Some(otlp_config) => {
+            otlp::start(otlp_config);
+            hcloud_api::set(Box::new(hcloud_api::Traced(api)));
+        }
+        None => hcloud_api::set(api),
+    }
+    if let Some(pattern) = &config.provider_id_pattern {
+        PROVIDER_ID_PATTERN.set(pattern.clone()).unwrap();
+    }
+    if let Some(fip_label) = &config.fip_pool_label {
+        let node_label = config.node_pool_label.as_ref().unwrap_or(fip_label);
+        pools::set_labels(fip_label.clone(), node_label.clone());
+    }
+    if let Some(class) = &config.load_balancer_class {
+        LOAD_BALANCER_CLASS.set(class.clone()).unwrap();
+    }
+    projects::set_client(&config.hcloud_client_config())?;
+    reload::apply(&config);
+    ownership::set_owner(config.owner.clone(), config.respect_protection);
+    if let Some(namespace) = &config.fip_lease_namespace {
+        fip_locks::set_leases(
+            namespace.clone(),
+            Duration::from_secs(config.fip_lease_duration),
+        );
+    }
+    let watch_backoff = || resync::watch_backoff(Duration::from_secs(config.watch_backoff_max));
+    endpoints::set_follow_all(config.follow_endpoints);
+    throttle::set_max_in_flight(config.hcloud_max_inflight);
+    hcloud_api::set_per_page(config.hcloud_per_page);
+    if let Some(path) = &config.audit_log {
+        audit::open(path)?;
+    }
+
+    let projects = projects::projects_from_config(&config).await?;
+    match cli.command {
+        Some(Command::Snapshot {
+            output,
+            configmap,
+            namespace,
+        }) => {
+            let location = snapshot::Location::new(output, configmap, namespace);
+            return snapshot::save(&projects, &location).await;
+        }
+        Some(Command::Restore {
+            path,
+            configmap,
+            namespace,
+            yes,
+        }) => {
+            let location = snapshot::Location::new(path, configmap, namespace);
+            return snapshot::restore(&projects, &location, yes).await;
+        }
+        Some(Command::ExportConfig) => return bundle::export_config(&matches, &projects).await,
+        Some(Command::ImportConfig { path }) => {
+            return bundle::import_config(&path, &projects).await;
+        }
+        Some(Command::Status { output }) => return mapping::print(&projects, output).await,
+        Some(Command::Check) => return check::run(&projects, &config).await,
+        Some(Command::Assign { fip, target, force }) => {
+            return assign::run(&projects, &fip, &target, force).await;
+        }
+        _ => {}
+    }
+    projects::watch_token_files(&projects);
+
+    let mut shutdown = Shutdown::listen();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
 
-                for fip in floating_ips_to_rassign {
-                    let server_id = *available_hc_server_ids.iter().next().unwrap();
-                    println!("Reassigning {} to {}", fip.ip, server_id);
-                    assign_floating_ip_to_server(&hcloud_conf, &fip.id, &server_id).await?;
+    let alias_ips = config.alias_ips.clone();
+    let gateway_config = config.gateway_config();
+    let robot = config.robot();
+    let rotation_config = config.rotation_config();
+    let canary_config = config.canary_config();
+    let failback_config = config.failback_config();
+    let egress_config = config.egress_config();
+    if let Some(config) = &failback_config {
+        failback::enable(config.delay);
+    }
+    if let Some(canary_config) = &canary_config {
+        canary::set_ip(canary_config.ip.clone());
+    }
+
+    let metrics_listener = systemd::take_listener()
+        .map(metrics::Listener::Tcp)
+        .or(config.metrics_addr.map(metrics::Listener::Addr));
+    if let Some(listener) = metrics_listener {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(listener).await {
+                println!("metrics server failed: {}", err);
+            }
+        });
+    }
+    if let Some(admission_config) = config.admission_config() {
+        let projects = projects.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admission::serve(admission_config, projects).await {
+                println!("admission webhook failed: {}", err);
+            }
+        });
+    }
+
+    if config.hcloud_circuit_threshold > 0 {
+        tokio::spawn(circuit::run(projects.clone()));
+    }
+
+    if let Some(standalone_config) = config.standalone_config() {
+        systemd::ready();
+        systemd::spawn_watchdog();
+        let result = standalone::run(projects, standalone_config, shutdown, shutdown_timeout).await;
+        systemd::stopping();
+        shutdown::flush();
+        return result;
+    }
+
+    let kube_client = clusters::local_client().await?;
+    let services_api = Api::<KubeService>::all(kube_client.clone());
+    let nodes_api = Api::<KubeNode>::all(kube_client.clone());
+    let events = EventPublisher::new(kube_client.clone());
+
+    if let Some(name) = &config.status_resource {
+        tokio::spawn(status::run(
+            kube_client.clone(),
+            name.clone(),
+            projects.clone(),
+        ));
+    }
+
+    let mut peers = vec![];
+    for spec in &config.peer_clusters {
+        peers.push(clusters::connect(spec, watch_backoff()).await?);
+    }
+
+    let (nodes, nodes_writer) = reflector::store();
+
+    let rotation = rotation_config.map(|config| {
+        tokio::spawn(rotation::run(
+            projects.clone(),
+            nodes.clone(),
+            events.clone(),
+            config,
+            shutdown.clone(),
+            shutdown_timeout,
+        ))
+    });
+
+    let canary = canary_config.map(|config| {
+        tokio::spawn(canary::run(
+            projects.clone(),
+            nodes.clone(),
+            config,
+            shutdown.clone(),
+            shutdown_timeout,
+        ))
+    });
+
+    let mut nodes_stream = Box::pin(
+        reflector::reflector(
+            nodes_writer,
+            watcher(nodes_api.clone(), ListParams::default()).backoff(watch_backoff()),
+        )
+        .applied_objects(),
+    );
+    // Candidate servers are read from the node cache, it has to be filled
+    // before the first Service is reconciled.
+    let first_node = loop {
+        match nodes_stream.try_next().await {
+            Ok(node) => break node,
+            Err(err) => println!("watch failed: {}", err),
+        }
+    };
+    let nodes_stream = futures::stream::iter(first_node.map(Ok)).chain(nodes_stream);
+    let (services, services_writer) = reflector::store();
+    pause::set_services(services.clone());
+    let mut services_events = Box::pin(reflector::reflector(
+        services_writer,
+        watcher(services_api.clone(), ListParams::default()).backoff(watch_backoff()),
+    ));
+    // The startup reconcile and drain delays read the Service cache, the
+    // initial list has to be in it first.
+    let first_services = loop {
+        match services_events.try_next().await {
+            Ok(event) => break event,
+            Err(err) => println!("watch failed: {}", err),
+        }
+    };
+    let services_stream = futures::stream::iter(first_services.map(Ok))
+        .chain(services_events)
+        .applied_objects();
+    if config.floating_ip_statuses {
+        tokio::spawn(fip_status::run(
+            kube_client.clone(),
+            projects.clone(),
+            nodes.clone(),
+            services.clone(),
+        ));
+    }
+    // Services following their endpoints are reconciled when they change.
+    let (endpoint_slices, endpoint_slices_writer) = reflector::store();
+    let endpoint_slices_stream = reflector::reflector(
+        endpoint_slices_writer,
+        watcher(
+            Api::<EndpointSlice>::all(kube_client.clone()),
+            ListParams::default(),
+        )
+        .backoff(watch_backoff()),
+    )
+    .applied_objects()
+    .try_filter_map({
+        let services = services.clone();
+        let follow = gateway_config.is_none();
+        move |slice| {
+            let service = endpoints::owner(&slice, &services)
+                .filter(|service| follow && claims_ips(service) && endpoints::follows(service));
+            async move { Ok(service.map(|service| KubeResource::Service(Box::new(service)))) }
+        }
+    });
+    // Nodes whose server failed or recovered in hcloud are reconciled again.
+    let server_failures_stream =
+        futures::stream::iter(config.server_check_interval.map(|interval| {
+            server_failures::watch(
+                projects.clone(),
+                nodes.clone(),
+                Duration::from_secs(interval),
+            )
+        }))
+        .flatten()
+        .map(|node| Ok(KubeResource::Node(Box::new(node))));
+    let resync_stream = resync::watch(nodes.clone(), services.clone()).map(Ok);
+    if let Some(config_map) = &config.pause_configmap {
+        pause::init(kube_client.clone(), config_map).await?;
+    }
+    let pause_stream = futures::stream::iter(config.pause_configmap.as_ref().map(|config_map| {
+        pause::watch(
+            kube_client.clone(),
+            config_map,
+            nodes.clone(),
+            services.clone(),
+            Duration::from_secs(config.watch_backoff_max),
+        )
+    }))
+    .flatten()
+    .map(Ok);
+    if let Some(config_map) = &config.settings_configmap {
+        settings::init(kube_client.clone(), config_map, &loaded).await?;
+    }
+    let settings_stream =
+        futures::stream::iter(config.settings_configmap.as_ref().map(|config_map| {
+            settings::watch(
+                kube_client.clone(),
+                config_map,
+                loaded.clone(),
+                nodes.clone(),
+                services.clone(),
+                Duration::from_secs(config.watch_backoff_max),
+            )
+        }))
+        .flatten()
+        .map(Ok);
+    // Everything is reconciled again after a SIGHUP reload.
+    let reload_stream = futures::stream::iter(
+        path.map(|_| reload::watch(loaded.clone(), nodes.clone(), services.clone())),
+    )
+    .flatten()
+    .map(Ok);
+    let reconnecting =
+        |kind, stream| resync::after_reconnect(kind, stream, nodes.clone(), services.clone());
+    let stream = select(
+        select(
+            reconnecting(
+                "nodes",
+                nodes_stream
+                    .map_ok(|node| KubeResource::Node(Box::new(node)))
+                    .boxed(),
+            ),
+            reconnecting(
+                "services",
+                services_stream
+                    .map_ok(|service| KubeResource::Service(Box::new(service)))
+                    .boxed(),
+            ),
+        ),
+        select(
+            reconnecting("endpointslices", endpoint_slices_stream.boxed()),
+            select(
+                server_failures_stream,
+                select(
+                    resync_stream,
+                    select(reload_stream, select(settings_stream, pause_stream)),
+                ),
+            ),
+        ),
+    );
+    pin_mut!(stream);
+
+    systemd::ready();
+    systemd::spawn_watchdog();
+
+    let ctx = Context {
+        projects,
+        nodes_api,
+        nodes,
+        alias_ips,
+        gateway_config,
+        providers: provider::providers(robot.clone()),
+        robot,
+        location_policy: config.location_policy,
+        failure_domain: config.spread_failure_domain,
+        no_target_policy: config.no_target_policy,
+        fallback_server: config.fallback_server,
+        target_probe: config.target_probe(),
+        verify: config.verify_config(),
+        provision: config.provision_config(),
+        release: config.release_config(),
+        publish_load_balancer_ip: config.publish_load_balancer_ip,
+        load_balancers: config.load_balancer_config(),
+        services_api,
+        services,
+        endpoint_slices,
+        events,
+        peers,
+    };
+
+    let providers: Vec<_> = ctx
+        .providers
+        .iter()
+        .map(|provider| provider.name())
+        .collect();
+    println!("moving ips with the {} providers", providers.join(", "));
+    if let Err(err) = startup::run(&ctx).await {
+        println!("startup reconcile failed: {}", err);
+    }
+
+    // Node and Service reconciles run in separate pools, so a flood of Service
+    // updates can't hold back the failover of a failed node.
+    let ctx = Arc::new(ctx);
+    debug::set_context(ctx.clone());
+    let failback = failback_config.map(|config| {
+        tokio::spawn(failback::run(
+            ctx.clone(),
+            config,
+            shutdown.clone(),
+            shutdown_timeout,
+        ))
+    });
+    let egress = egress_config.map(|config| {
+        tokio::spawn(egress::run(
+            ctx.clone(),
+            config,
+            shutdown.clone(),
+            shutdown_timeout,
+        ))
+    });
+    if let Some(admin_config) = config.admin_config() {
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admin::serve(admin_config, ctx).await {
+                println!("admin api failed: {}", err);
+            }
+        });
+    }
+    let node_permits = Arc::new(Semaphore::new(config.node_concurrency));
+    let service_permits = Arc::new(Semaphore::new(config.service_concurrency));
+    let mut tasks = JoinSet::new();
+    let mut queue = WorkQueue::new(Duration::from_millis(config.debounce_ms));
+    loop {
+        let deadline = queue.deadline();
+        tokio::select! {
+            next = stream.next() => match next {
+                Some(Ok(resource)) => {
+                    // Every event leads to the same pass in gateway mode.
+                    let key = match ctx.gateway_config {
+                        Some(_) => "gateway".to_string(),
+                        None => resource.key(),
+                    };
+                    queue.push(key, resource);
+                }
+                // The watchers recover by themselves, with a backoff.
+                Some(Err(err)) => {
+                    println!("watch failed: {}", err);
+                    status::record_error(&err.into());
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(deadline.unwrap()), if deadline.is_some() => {
+                for (key, resource) in queue.drain_due() {
+                    let permits = match resource {
+                        KubeResource::Node(_) => node_permits.clone(),
+                        KubeResource::Service(_) => service_permits.clone(),
+                    };
+                    let ctx = ctx.clone();
+                    let mut shutdown = shutdown.clone();
+                    tasks.spawn(async move {
+                        let _permit = permits.acquire_owned().await.unwrap();
+                        // A panicking reconcile still hands its key back to the queue.
+                        let outcome = AssertUnwindSafe(async {
+                        let input = format!(
+                            "{}, {} available nodes",
+                            resource.summary(),
+                            available_nodes(&ctx.nodes).len()
+                        );
+                        let result = trace::scope(
+                            key.clone(),
+                            input,
+                            |result| match result {
+                                Some(Ok(())) => "ok".into(),
+                                Some(Err(err)) => format!("error: {}", err),
+                                None => "aborted by shutdown".into(),
+                            },
+                            shutdown::run_graceful(
+                                &mut shutdown,
+                                shutdown_timeout,
+                                "reconcile",
+                                reconcile(&ctx, resource.clone()),
+                            ),
+                        )
+                        .await;
+                        let outcome = match result {
+                            Some(Ok(())) => {
+                                status::record_sync();
+                                match &resource {
+                                    KubeResource::Node(node) => drain::pending(node.metadata.name.as_ref().unwrap())
+                                        .map(Outcome::DrainUntil)
+                                        .unwrap_or(Outcome::Done),
+                                    KubeResource::Service(_) => Outcome::Done,
+                                }
+                            }
+                            Some(Err(err)) => {
+                                println!("reconcile of {} failed: {}", key, err);
+                                status::record_error(&err);
+                                Outcome::Retry
+                            }
+                            None => Outcome::Done,
+                        };
+                        outcome
+                        })
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|_| {
+                            println!("reconcile of {} panicked", key);
+                            Outcome::Retry
+                        });
+                        (key, resource, outcome)
+                    });
+                }
+            }
+            Some(finished) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Ok((key, _, _)) = &finished {
+                    queue.done(key);
+                }
+                match finished {
+                    Ok((key, resource, Outcome::Retry)) if !shutdown.is_requested() => {
+                        let delay = queue.requeue(key.clone(), resource);
+                        println!("retrying {} in {:?}", key, delay);
+                    }
+                    Ok((key, resource, Outcome::DrainUntil(at))) => {
+                        queue.forget(&key);
+                        queue.requeue_at(key, resource, at);
+                    }
+                    Ok((key, _, _)) => queue.forget(&key),
+                    Err(err) => println!("reconcile task failed: {}", err),
                 }
             }
+            _ = shutdown.wait() => break,
         }
     }
 
+    systemd::stopping();
+    let deadline = tokio::time::Instant::now() + shutdown_timeout;
+    shutdown::finish_before(deadline, "reconciles", async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+    if let Some(rotation) = rotation {
+        shutdown::finish_before(deadline, "rotation", rotation).await;
+    }
+    if let Some(failback) = failback {
+        shutdown::finish_before(deadline, "failback", failback).await;
+    }
+    if let Some(egress) = egress {
+        shutdown::finish_before(deadline, "egress", egress).await;
+    }
+    if let Some(canary) = canary {
+        shutdown::finish_before(deadline, "canary", canary).await;
+    }
+    println!("shut down");
+    shutdown::flush();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hcloud::models::server::Status as ServerStatus;
+    use hcloud::models::ServerPrivateNet;
+    use std::sync::Once;
+
+    const FAILED: i32 = 1;
+    const HEALTHY: i32 = 2;
+    const UNAVAILABLE: i32 = 3;
+    const STRANDED: i32 = 4;
+    /// Servers of the private networks, one per alias IP test.
+    const ALIAS_HOLDER: i32 = 5;
+    const ALIAS_TARGET: i32 = 6;
+    const ALIAS_OTHER: i32 = 7;
+    const NETWORK: i32 = 100;
+    const ROLLBACK_NETWORK: i32 = 101;
+
+    fn fip(id: i32, server: i32) -> FloatingIp {
+        FloatingIp {
+            id,
+            ip: format!("198.51.100.{}", id),
+            server: Some(server),
+            ..Default::default()
+        }
+    }
+
+    /// Every test shares the hcloud fake, set once, with its own floating
+    /// IPs.
+    fn fake_hcloud() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let floating_ips = vec![
+                fip(10, FAILED),
+                fip(11, FAILED),
+                fip(12, HEALTHY),
+                fip(20, UNAVAILABLE),
+                fip(21, UNAVAILABLE),
+                fip(30, STRANDED),
+            ];
+            let mut servers: Vec<Server> = [FAILED, HEALTHY, UNAVAILABLE, STRANDED]
+                .into_iter()
+                .chain([ALIAS_HOLDER, ALIAS_TARGET, ALIAS_OTHER])
+                .map(|id| Server {
+                    id,
+                    name: format!("server-{}", id),
+                    status: ServerStatus::Running,
+                    ..Default::default()
+                })
+                .collect();
+            for (server, network, alias_ips) in [
+                (ALIAS_HOLDER, NETWORK, vec!["10.0.0.100"]),
+                (ALIAS_TARGET, NETWORK, vec![]),
+                (ALIAS_HOLDER, ROLLBACK_NETWORK, vec!["10.1.0.100"]),
+                (ALIAS_TARGET, ROLLBACK_NETWORK, vec![]),
+                (ALIAS_OTHER, ROLLBACK_NETWORK, vec!["10.1.0.200"]),
+            ] {
+                servers[server as usize - 1]
+                    .private_net
+                    .push(ServerPrivateNet {
+                        network: Some(network),
+                        alias_ips: Some(alias_ips.into_iter().map(String::from).collect()),
+                        ..Default::default()
+                    });
+            }
+            let state = serde_json::json!({ "floating_ips": floating_ips, "servers": servers });
+            let fake = hcloud_api::Fake::parse(&state.to_string(), "test").unwrap();
+            hcloud_api::set(Box::new(fake));
+        });
+    }
+
+    fn context() -> Context {
+        let client =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+                .unwrap();
+        Context {
+            projects: vec![Project::new("test".into(), "unused".into())],
+            nodes_api: Api::all(client.clone()),
+            nodes: reflector::store().0,
+            alias_ips: vec![],
+            gateway_config: None,
+            robot: None,
+            providers: provider::providers(None),
+            location_policy: LocationPolicy::Ignore,
+            failure_domain: None,
+            no_target_policy: NoTargetPolicy::Keep,
+            fallback_server: None,
+            target_probe: None,
+            verify: None,
+            provision: None,
+            release: None,
+            publish_load_balancer_ip: false,
+            load_balancers: LoadBalancerConfig {
+                location: None,
+                load_balancer_type: "lb11".into(),
+            },
+            services_api: Api::all(client.clone()),
+            services: reflector::store().0,
+            endpoint_slices: reflector::store().0,
+            events: EventPublisher::new(client),
+            peers: vec![],
+        }
+    }
+
+    async fn server_of(ctx: &Context, fip_id: i32) -> Option<i32> {
+        hcloud_api::api()
+            .get_floating_ip(&ctx.projects[0].conf(), fip_id)
+            .await
+            .unwrap()
+            .server
+    }
+
+    #[tokio::test]
+    async fn evacuate_moves_the_ips_of_the_server_only() {
+        fake_hcloud();
+        let ctx = context();
+        let available = HashSet::from([HEALTHY]);
+        evacuate_server(&ctx, &ctx.projects[0], FAILED, &available, &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(server_of(&ctx, 10).await, Some(HEALTHY));
+        assert_eq!(server_of(&ctx, 11).await, Some(HEALTHY));
+        assert_eq!(server_of(&ctx, 12).await, Some(HEALTHY));
+    }
+
+    #[tokio::test]
+    async fn evacuate_keeps_the_ips_without_target() {
+        fake_hcloud();
+        let ctx = context();
+        evacuate_server(
+            &ctx,
+            &ctx.projects[0],
+            STRANDED,
+            &HashSet::new(),
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(server_of(&ctx, 30).await, Some(STRANDED));
+    }
+
+    #[tokio::test]
+    async fn reassign_moves_the_claimed_ips_only() {
+        fake_hcloud();
+        let ctx = context();
+        let claimed = "198.51.100.20".to_string();
+        let available = HashSet::from([HEALTHY]);
+        reassign_service_ips(
+            &ctx,
+            &ctx.projects[0],
+            &HashSet::from([&claimed]),
+            &available,
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(server_of(&ctx, 20).await, Some(HEALTHY));
+        assert_eq!(server_of(&ctx, 21).await, Some(UNAVAILABLE));
+    }
+
+    fn alias(network: i32, ip: &str) -> AliasIp {
+        AliasIp {
+            network,
+            ip: ip.into(),
+        }
+    }
+
+    async fn alias_holder(ctx: &Context, alias: &AliasIp) -> Option<i32> {
+        let servers = fetch_servers(&ctx.projects[0].conf()).await.unwrap();
+        alias_ips::find_holder(&servers, alias).map(|holder| holder.id)
+    }
+
+    #[tokio::test]
+    async fn alias_ips_move_between_servers() {
+        fake_hcloud();
+        let ctx = context();
+        let conf = &ctx.projects[0].conf();
+        let alias = alias(NETWORK, "10.0.0.100");
+        let servers = fetch_servers(conf).await.unwrap();
+        move_alias_ip(conf, &servers, &alias, ALIAS_TARGET, ActionClass::Failover)
+            .await
+            .unwrap();
+        assert_eq!(alias_holder(&ctx, &alias).await, Some(ALIAS_TARGET));
+    }
+
+    #[tokio::test]
+    async fn alias_ips_go_back_when_the_target_refuses_them() {
+        fake_hcloud();
+        let ctx = context();
+        let conf = &ctx.projects[0].conf();
+        let alias = alias(ROLLBACK_NETWORK, "10.1.0.100");
+        // The target is listed with an alias IP of another server, hcloud
+        // refuses the change.
+        let mut servers = fetch_servers(conf).await.unwrap();
+        let target = servers
+            .iter_mut()
+            .find(|server| server.id == ALIAS_TARGET)
+            .unwrap();
+        let net = target
+            .private_net
+            .iter_mut()
+            .find(|net| net.network == Some(ROLLBACK_NETWORK))
+            .unwrap();
+        net.alias_ips = Some(vec!["10.1.0.200".into()]);
+        assert!(
+            alias_ips::move_alias_ip(conf, &servers, &alias, ALIAS_TARGET)
+                .await
+                .is_err()
+        );
+        assert_eq!(alias_holder(&ctx, &alias).await, Some(ALIAS_HOLDER));
+    }
+}
@@ -0,0 +1,208 @@
+//! Time windows, in UTC. During `--maintenance-windows` floating IPs don't
+//! fail over off cordoned, drained or tainted nodes, so planned node reboots
+//! don't bounce the public IPs between servers; IPs held by a server that is
+//! down still move. Outside of `--rebalance-windows`, when set, planned
+//! moves, rotation and failback, don't happen.
+
+use crate::cooldown;
+use crate::throttle::ActionClass;
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use k8s_openapi::chrono::{DateTime, Datelike, Timelike, Utc};
+use kube::runtime::reflector::Store;
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Given as `[DAYS ]HH:MM-HH:MM`, where days are `*`, the default, or a comma
+/// separated list of days and ranges such as `mon-fri,sun`. A window ending
+/// before it starts runs past midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    /// Indexed from Monday.
+    days: [bool; 7],
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+fn parse_day(s: &str) -> Result<usize, String> {
+    DAYS.iter()
+        .position(|day| day.eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown day {:?}, expected one of {}", s, DAYS.join(", ")))
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    if s == "*" {
+        return Ok([true; 7]);
+    }
+    let mut days = [false; 7];
+    for item in s.split(',') {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(item)?, parse_day(item)?),
+        };
+        let mut day = first;
+        loop {
+            days[day] = true;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time {:?}, expected HH:MM", s);
+    let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (days, times) = match s.rsplit_once(char::is_whitespace) {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => ([true; 7], s),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("invalid window {:?}, expected HH:MM-HH:MM", s))?;
+        let window = Window {
+            days,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("window {:?} is empty", s));
+        }
+        Ok(window)
+    }
+}
+
+impl Window {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        let day = time.weekday().num_days_from_monday() as usize;
+        let minute = time.hour() * 60 + time.minute();
+        if self.start < self.end {
+            return self.days[day] && self.start <= minute && minute < self.end;
+        }
+        (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+    }
+}
+
+static MAINTENANCE: Lazy<RwLock<Vec<Window>>> = Lazy::new(Default::default);
+static REBALANCE: Lazy<RwLock<Vec<Window>>> = Lazy::new(Default::default);
+
+pub fn set_windows(maintenance: Vec<Window>, rebalance: Vec<Window>) {
+    *MAINTENANCE.write().unwrap() = maintenance;
+    *REBALANCE.write().unwrap() = rebalance;
+}
+
+/// Whether a maintenance window is open, only hard failures move IPs then.
+pub fn in_maintenance() -> bool {
+    let now = Utc::now();
+    MAINTENANCE
+        .read()
+        .unwrap()
+        .iter()
+        .any(|window| window.contains(now))
+}
+
+/// Whether planned rebalancing may run, always without rebalance windows.
+pub fn may_rebalance() -> bool {
+    let now = Utc::now();
+    let windows = REBALANCE.read().unwrap();
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+/// Why `fip` must stay where it is, if a maintenance window is open and the
+/// server holding it still serves.
+pub fn blocker(nodes: &Store<KubeNode>, fip: &FloatingIp, class: ActionClass) -> Option<String> {
+    if class != ActionClass::Failover || !in_maintenance() {
+        return None;
+    }
+    let server_id = fip.server?;
+    if cooldown::is_hard_down(nodes, server_id) {
+        return None;
+    }
+    Some("a maintenance window is open".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(
+            "22:00-02:00".parse(),
+            Ok(Window {
+                days: [true; 7],
+                start: 22 * 60,
+                end: 2 * 60,
+            })
+        );
+        assert_eq!(
+            " Mon-wed,sun 08:30-24:00 ".parse(),
+            Ok(Window {
+                days: [true, true, true, false, false, false, true],
+                start: 8 * 60 + 30,
+                end: 24 * 60,
+            })
+        );
+        // Day ranges wrap around the week.
+        assert_eq!(
+            "sat-mon 01:00-02:00".parse::<Window>().unwrap().days,
+            [true, false, false, false, false, true, true]
+        );
+        for invalid in [
+            "",
+            "22:00",
+            "25:00-01:00",
+            "24:01-01:00",
+            "08:60-09:00",
+            "8-9",
+            "08:00-08:00",
+            "someday 08:00-09:00",
+            "mon- 08:00-09:00",
+        ] {
+            assert!(invalid.parse::<Window>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn windows_contain_their_days_and_times() {
+        // 2023-01-06 is a Friday.
+        let window: Window = "fri 08:00-17:00".parse().unwrap();
+        assert!(window.contains(at("2023-01-06T08:00:00Z")));
+        assert!(window.contains(at("2023-01-06T16:59:59Z")));
+        assert!(!window.contains(at("2023-01-06T17:00:00Z")));
+        assert!(!window.contains(at("2023-01-07T12:00:00Z")));
+    }
+
+    #[test]
+    fn windows_run_past_midnight_from_their_days() {
+        let window: Window = "fri 22:00-02:00".parse().unwrap();
+        assert!(window.contains(at("2023-01-06T23:00:00Z")));
+        assert!(window.contains(at("2023-01-07T01:59:00Z")));
+        assert!(!window.contains(at("2023-01-07T02:00:00Z")));
+        assert!(!window.contains(at("2023-01-07T23:00:00Z")));
+        assert!(!window.contains(at("2023-01-06T01:00:00Z")));
+    }
+}
@@ -0,0 +1,143 @@
+//! Where each floating IP is: its Service, server and node, and whether the
+//! node can keep it, for the admin API and the `status` subcommand.
+
+use crate::clusters;
+use crate::conflicts::claimed_ips;
+use crate::projects::Project;
+use crate::{claims_ips, evacuation_reason, fetch_floating_ips, get_hc_server_id, list_all, Error};
+use clap::ValueEnum;
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::api::ListParams;
+use kube::{Api, ResourceExt};
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize)]
+pub struct Mapping {
+    pub ip: String,
+    pub name: String,
+    pub project: String,
+    /// `namespace/name` of the Service claiming the IP.
+    pub service: Option<String>,
+    pub server: Option<i32>,
+    pub node: Option<String>,
+    /// `available`, why the node is evacuated, `unassigned` or `not a node`.
+    pub health: String,
+}
+
+/// The `namespace/name` of the Service claiming each IP.
+pub fn claimants<S: Borrow<KubeService>>(
+    services: impl IntoIterator<Item = S>,
+) -> HashMap<String, String> {
+    let mut claimants = HashMap::new();
+    for service in services {
+        let service = service.borrow();
+        if !claims_ips(service) {
+            continue;
+        }
+        let name = format!(
+            "{}/{}",
+            service.namespace().unwrap_or_default(),
+            service.name_any()
+        );
+        for ip in claimed_ips(service) {
+            claimants.insert(ip.clone(), name.clone());
+        }
+    }
+    claimants
+}
+
+/// The mapping of every floating IP of `projects`.
+pub async fn mappings<N: Borrow<KubeNode>>(
+    projects: &[Project],
+    nodes: impl IntoIterator<Item = N>,
+    claimants: &HashMap<String, String>,
+) -> Result<Vec<Mapping>, Error> {
+    let nodes: HashMap<i32, N> = nodes
+        .into_iter()
+        .filter_map(|node| Some((get_hc_server_id(node.borrow())?, node)))
+        .collect();
+    let mut mappings = vec![];
+    for project in projects {
+        for fip in fetch_floating_ips(&project.conf()).await? {
+            let node = fip.server.and_then(|id| nodes.get(&id)).map(Borrow::borrow);
+            let health = match (fip.server, node) {
+                (None, _) => "unassigned".into(),
+                (Some(_), None) => "not a node".into(),
+                (Some(_), Some(node)) => {
+                    evacuation_reason(node).unwrap_or_else(|| "available".into())
+                }
+            };
+            mappings.push(Mapping {
+                service: claimants
+                    .get(&fip.ip)
+                    .or_else(|| claimants.get(&fip.name))
+                    .cloned(),
+                node: node.map(|node| node.name_any()),
+                server: fip.server,
+                project: project.name.clone(),
+                name: fip.name,
+                ip: fip.ip,
+                health,
+            });
+        }
+    }
+    Ok(mappings)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+fn print_table(mappings: &[Mapping]) {
+    let dash = || "-".to_string();
+    let rows: Vec<[String; 6]> = mappings
+        .iter()
+        .map(|mapping| {
+            [
+                mapping.ip.clone(),
+                mapping.project.clone(),
+                mapping.service.clone().unwrap_or_else(dash),
+                mapping.server.map(|id| id.to_string()).unwrap_or_else(dash),
+                mapping.node.clone().unwrap_or_else(dash),
+                mapping.health.clone(),
+            ]
+        })
+        .collect();
+    let header = ["IP", "PROJECT", "SERVICE", "SERVER", "NODE", "HEALTH"].map(String::from);
+    let mut widths = header.clone().map(|title| title.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Prints the mapping of every floating IP, with the Services and nodes read
+/// from the cluster.
+pub async fn print(projects: &[Project], output: Output) -> Result<(), Error> {
+    let client = clusters::local_client().await?;
+    let nodes = list_all(
+        &Api::<KubeNode>::all(client.clone()),
+        &ListParams::default(),
+    )
+    .await?;
+    let services = list_all(&Api::<KubeService>::all(client), &ListParams::default()).await?;
+    let mappings = mappings(projects, &nodes, &claimants(&services)).await?;
+    match output {
+        Output::Table => print_table(&mappings),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&mappings)?),
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use crate::{circuit, Error};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+
+pub static HCLOUD_CIRCUIT_OPEN: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hcloud_fip_hcloud_circuit_open",
+        "1 while the hcloud API circuit breaker is open"
+    )
+    .unwrap()
+});
+
+pub static PAUSED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hcloud_fip_paused",
+        "1 while the controller is paused by its pause ConfigMap"
+    )
+    .unwrap()
+});
+
+pub static CONFLICTING_IPS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hcloud_fip_conflicting_ips",
+        "IPs currently claimed by more than one LoadBalancer Service"
+    )
+    .unwrap()
+});
+
+pub static WATCH_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_watch_errors_total",
+        "Failures of the Kubernetes watches, each restarted with a backoff",
+        &["resource"]
+    )
+    .unwrap()
+});
+
+pub static DRIFTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_drifts_total",
+        "Floating IPs found moved outside of the controller",
+        &["action"]
+    )
+    .unwrap()
+});
+
+pub static ROTATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_rotations_total",
+        "Floating IP moves performed by scheduled rotation",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static ROTATION_RUNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_rotation_runs_total",
+        "Scheduled rotation runs",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static LAST_ROTATION: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hcloud_fip_last_rotation_timestamp_seconds",
+        "Unix timestamp of the last completed rotation run"
+    )
+    .unwrap()
+});
+
+pub static ACTION_QUEUE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "hcloud_fip_action_queue_seconds",
+        "Time IP moves waited for their turn, by priority class",
+        &["class"],
+        vec![0.005, 0.05, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0]
+    )
+    .unwrap()
+});
+
+pub static ACTIONS_WAITING: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "hcloud_fip_actions_waiting",
+        "IP moves currently waiting for their turn, by priority class",
+        &["class"]
+    )
+    .unwrap()
+});
+
+pub static CANARY_RUNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_canary_runs_total",
+        "Canary floating IP failovers",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static CANARY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "hcloud_fip_canary_seconds",
+        "Time from the start of a canary failover until the IP was assigned, or answered on its new node",
+        &["stage"],
+        vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0]
+    )
+    .unwrap()
+});
+
+pub static LAST_CANARY_SUCCESS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hcloud_fip_canary_last_success_timestamp_seconds",
+        "Unix timestamp of the last successful canary failover"
+    )
+    .unwrap()
+});
+
+pub static VERIFICATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hcloud_fip_verifications_total",
+        "Reachability checks of moved floating IPs, and the moves they led to",
+        &["result"]
+    )
+    .unwrap()
+});
+
+fn render() -> Response<Body> {
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap()
+}
+
+/// Ready unless the hcloud API circuit is open.
+fn render_ready() -> Response<Body> {
+    match circuit::open_since() {
+        Some(since) => Response::builder()
+            .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from(format!(
+                "hcloud api circuit open since {}\n",
+                since.to_rfc3339()
+            )))
+            .unwrap(),
+        None => Response::new(Body::from("ok\n")),
+    }
+}
+
+/// Where the metrics server listens.
+pub enum Listener {
+    Addr(SocketAddr),
+    /// A socket passed by systemd socket activation.
+    Tcp(TcpListener),
+}
+
+/// Serves the Prometheus metrics of the default registry and the readiness on
+/// `/readyz`. The reports of the controller's state are on the admin API, they
+/// name nodes and servers.
+pub async fn serve(listener: Listener) -> Result<(), Error> {
+    let make_service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            Ok::<_, Infallible>(match req.uri().path() {
+                "/readyz" => render_ready(),
+                _ => render(),
+            })
+        }))
+    });
+    let server = match listener {
+        Listener::Addr(addr) => Server::try_bind(&addr)?,
+        Listener::Tcp(listener) => Server::from_tcp(listener)?,
+    };
+    let server = server.serve(make_service);
+    println!("serving metrics on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
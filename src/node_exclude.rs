@@ -0,0 +1,25 @@
+//! Nodes opted out of holding floating IPs, e.g. behind firewall rules that
+//! would blackhole the traffic of a public IP. They are never picked as a
+//! target, and IPs found on them are evacuated like on a cordoned node.
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::ResourceExt;
+
+/// Annotation or label opting a node out when set to `true`.
+pub const EXCLUDE_ANNOTATION: &str = "fip.hcloud.barodeur.io/exclude";
+
+fn is_true(value: Option<&String>) -> bool {
+    value
+        .map(|value| value.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Why `node` can't hold IPs when it is opted out.
+pub fn reason(node: &KubeNode) -> Option<String> {
+    if is_true(node.annotations().get(EXCLUDE_ANNOTATION))
+        || is_true(node.labels().get(EXCLUDE_ANNOTATION))
+    {
+        return Some("excluded".into());
+    }
+    None
+}
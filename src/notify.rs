@@ -0,0 +1,141 @@
+//! Notifications of IP moves to webhooks and Slack, so on-call engineers
+//! know when public IPs move.
+
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::env;
+use std::sync::RwLock;
+
+static NOTIFIER: Lazy<RwLock<Option<Notifier>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub client: reqwest::Client,
+    /// Receive the JSON payload of every notification.
+    pub webhook_urls: Vec<String>,
+    /// Slack incoming webhooks, receive a message.
+    pub slack_urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    Reassigned,
+    AssignFailed,
+    NoTarget,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub kind: Kind,
+    pub ip: String,
+    /// hcloud server ID, or server IP for Robot failover IPs.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub error: Option<String>,
+    pub timestamp: String,
+    pub instance: Option<String>,
+}
+
+impl Notification {
+    fn new(kind: Kind, ip: &str) -> Self {
+        Notification {
+            kind,
+            ip: ip.to_string(),
+            from: None,
+            to: None,
+            error: None,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            instance: env::var("POD_NAME").ok(),
+        }
+    }
+
+    fn text(&self) -> String {
+        match self.kind {
+            Kind::Reassigned => format!(
+                ":arrows_counterclockwise: {} moved from server {} to {}",
+                self.ip,
+                server(&self.from),
+                server(&self.to)
+            ),
+            Kind::AssignFailed => format!(
+                ":x: failed to move {} to server {}: {}",
+                self.ip,
+                server(&self.to),
+                self.error.as_deref().unwrap_or_default()
+            ),
+            Kind::NoTarget => format!(
+                ":warning: no eligible server for {}, left on server {}",
+                self.ip,
+                server(&self.from)
+            ),
+        }
+    }
+}
+
+fn server(server: &Option<String>) -> &str {
+    server.as_deref().unwrap_or("none")
+}
+
+pub fn set(notifier: Notifier) {
+    let enabled = !notifier.webhook_urls.is_empty() || !notifier.slack_urls.is_empty();
+    *NOTIFIER.write().unwrap() = enabled.then_some(notifier);
+}
+
+fn send(notification: Notification) {
+    let notifier = match NOTIFIER.read().unwrap().clone() {
+        Some(notifier) => notifier,
+        None => return,
+    };
+    let text = notification.text();
+    let posts = notifier
+        .webhook_urls
+        .iter()
+        .map(|url| (url.clone(), serde_json::to_value(&notification).unwrap()))
+        .chain(
+            notifier
+                .slack_urls
+                .iter()
+                .map(|url| (url.clone(), serde_json::json!({ "text": text }))),
+        );
+    for (url, body) in posts {
+        let client = notifier.client.clone();
+        // Notifications never hold back a failover.
+        tokio::spawn(async move {
+            let result = client
+                .post(&url)
+                .json(&body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                println!("notification to {} failed: {}", url, err);
+            }
+        });
+    }
+}
+
+pub fn reassigned(ip: &str, from: Option<impl ToString>, to: impl ToString) {
+    send(Notification {
+        from: from.map(|from| from.to_string()),
+        to: Some(to.to_string()),
+        ..Notification::new(Kind::Reassigned, ip)
+    });
+}
+
+pub fn assign_failed(ip: &str, to: impl ToString, error: &str) {
+    send(Notification {
+        to: Some(to.to_string()),
+        error: Some(error.to_string()),
+        ..Notification::new(Kind::AssignFailed, ip)
+    });
+}
+
+pub fn no_target(ip: &str, from: Option<impl ToString>) {
+    send(Notification {
+        from: from.map(|from| from.to_string()),
+        ..Notification::new(Kind::NoTarget, ip)
+    });
+}
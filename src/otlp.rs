@@ -0,0 +1,189 @@
+//! Export of the reconciles as OpenTelemetry traces, over OTLP/HTTP with the
+//! JSON encoding, so a slow failover can be followed in Jaeger or Tempo: each
+//! reconcile is a span with its decisions as events and the hcloud calls it
+//! made as child spans timing the API latencies.
+
+use crate::Error;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// Base URL of the collector, spans are POSTed to `/v1/traces` under it.
+    pub endpoint: String,
+    pub service_name: String,
+}
+
+/// Spans are sent once this many are pending, or every flush interval.
+const BATCH_SIZE: usize = 256;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+static SPANS: OnceCell<UnboundedSender<Value>> = OnceCell::new();
+
+#[derive(Clone)]
+struct Current {
+    trace_id: String,
+    span_id: String,
+    events: Arc<Mutex<Vec<Value>>>,
+}
+
+tokio::task_local! {
+    static CURRENT: Current;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Client = 3,
+}
+
+fn now() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// Runs `future` as a span, a child of the span running on this task if any.
+/// `error` tells whether the result is a failure and why. Nothing is recorded
+/// unless the export is started.
+pub async fn span<F, T>(
+    name: &str,
+    kind: SpanKind,
+    attributes: Vec<(&str, String)>,
+    error: impl FnOnce(&T) -> Option<String>,
+    future: F,
+) -> T
+where
+    F: Future<Output = T>,
+{
+    let spans = match SPANS.get() {
+        Some(spans) => spans,
+        None => return future.await,
+    };
+    let parent = CURRENT.try_with(Clone::clone).ok();
+    let current = Current {
+        trace_id: match &parent {
+            Some(parent) => parent.trace_id.clone(),
+            None => random_id(16),
+        },
+        span_id: random_id(8),
+        events: Default::default(),
+    };
+    let start = now();
+    let result = CURRENT.scope(current.clone(), future).await;
+    let status = match error(&result) {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+    let events = std::mem::take(&mut *current.events.lock().unwrap());
+    let _ = spans.send(json!({
+        "traceId": current.trace_id,
+        "spanId": current.span_id,
+        "parentSpanId": parent.map(|parent| parent.span_id).unwrap_or_default(),
+        "name": name,
+        "kind": kind as i32,
+        "startTimeUnixNano": start,
+        "endTimeUnixNano": now(),
+        "attributes": attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<_>>(),
+        "events": events,
+        "status": status,
+    }));
+    result
+}
+
+/// Adds an event to the span running on this task, ignored outside of one.
+pub fn event(name: &str) {
+    let _ = CURRENT.try_with(|current| {
+        current
+            .events
+            .lock()
+            .unwrap()
+            .push(json!({ "timeUnixNano": now(), "name": name }));
+    });
+}
+
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    service_name: &str,
+    spans: Vec<Value>,
+) -> Result<(), Error> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", service_name)] },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans,
+            }],
+        }],
+    });
+    client
+        .post(url)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn export(config: OtlpConfig, mut spans: UnboundedReceiver<Value>) {
+    let client = reqwest::Client::new();
+    let url = format!("{}/v1/traces", config.endpoint.trim_end_matches('/'));
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut batch = vec![];
+    loop {
+        tokio::select! {
+            span = spans.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => return,
+            },
+            _ = interval.tick() => {}
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let count = batch.len();
+        let spans = std::mem::take(&mut batch);
+        if let Err(err) = send(&client, &url, &config.service_name, spans).await {
+            println!("failed to export {} spans to {}: {}", count, url, err);
+        }
+    }
+}
+
+/// Starts recording spans and exporting them in the background.
+pub fn start(config: OtlpConfig) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    if SPANS.set(sender).is_ok() {
+        println!("exporting traces to {}", config.endpoint);
+        tokio::spawn(export(config, receiver));
+    }
+}
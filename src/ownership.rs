@@ -0,0 +1,32 @@
+//! Floating IPs belonging to someone else: labelled as owned by another
+//! controller, or protected in hcloud when `--respect-protection` is set. The
+//! controller leaves them where they are and warns instead of fighting over
+//! them.
+
+use hcloud::models::FloatingIp;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// hcloud label naming the controller a floating IP belongs to.
+pub const OWNER_LABEL: &str = "fip.hcloud.barodeur.io/owned-by";
+
+static OWNER: OnceCell<String> = OnceCell::new();
+static RESPECT_PROTECTION: AtomicBool = AtomicBool::new(false);
+
+pub fn set_owner(owner: String, respect_protection: bool) {
+    let _ = OWNER.set(owner);
+    RESPECT_PROTECTION.store(respect_protection, Ordering::Relaxed);
+}
+
+/// Why `fip` must not be moved by this controller, if it must not.
+pub fn blocker(fip: &FloatingIp) -> Option<String> {
+    if let Some(owner) = fip.labels.get(OWNER_LABEL) {
+        if Some(owner) != OWNER.get() {
+            return Some(format!("it is owned by {}", owner));
+        }
+    }
+    if RESPECT_PROTECTION.load(Ordering::Relaxed) && fip.protection.delete {
+        return Some("it is protected".into());
+    }
+    None
+}
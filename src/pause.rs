@@ -0,0 +1,194 @@
+//! Pause switches for when humans move IPs by hand, e.g. during a migration.
+//! A Service annotated `fip.hcloud.barodeur.io/paused: "true"` is no longer
+//! reconciled and the floating IPs it claims stay where they are, even when
+//! their node is evacuated. With `--pause-configmap` the whole controller is
+//! paused while that ConfigMap has `paused: "true"`. The status resources,
+//! metrics and events keep being published meanwhile, and everything is
+//! reconciled again once the global pause is lifted.
+
+use crate::conflicts::claimed_ips;
+use crate::{claims_ips, dual_stack, metrics, Error, KubeResource};
+use crate::{clusters, resync};
+use futures::{Stream, StreamExt};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::{ConfigMap, Node as KubeNode, Service as KubeService};
+use kube::api::ListParams;
+use kube::runtime::reflector::Store;
+use kube::runtime::watcher::{self, watcher};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client as KubeClient, ResourceExt};
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub const PAUSED_ANNOTATION: &str = "fip.hcloud.barodeur.io/paused";
+
+/// Key of the pause ConfigMap.
+const PAUSED_KEY: &str = "paused";
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+static SERVICES: OnceCell<Store<KubeService>> = OnceCell::new();
+
+/// Makes the paused Services of `services` known, only the global pause
+/// applies before.
+pub fn set_services(services: Store<KubeService>) {
+    let _ = SERVICES.set(services);
+}
+
+fn is_true(value: Option<&String>) -> bool {
+    value
+        .map(|value| value.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Whether the whole controller is paused.
+pub fn is_global() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Whether `service` is paused.
+pub fn is_paused(service: &KubeService) -> bool {
+    is_true(service.annotations().get(PAUSED_ANNOTATION))
+}
+
+/// The paused Service of `services` claiming an IP according to `claims`,
+/// described for logs.
+fn paused_claimer(
+    services: &Store<KubeService>,
+    claims: impl Fn(&KubeService) -> bool,
+) -> Option<String> {
+    services
+        .state()
+        .iter()
+        .find(|service| is_paused(service) && claims_ips(service) && claims(service))
+        .map(|service| {
+            format!(
+                "its Service {}/{} is paused",
+                service.namespace().unwrap_or_default(),
+                service.name_any()
+            )
+        })
+}
+
+/// Why `fip` must stay where it is, if the controller or a Service claiming
+/// it is paused.
+pub fn blocker(fip: &FloatingIp) -> Option<String> {
+    if is_global() {
+        return Some("the controller is paused".into());
+    }
+    paused_claimer(SERVICES.get()?, |service| {
+        dual_stack::claims(claimed_ips(service), fip)
+    })
+}
+
+/// Why the Robot failover IP `ip` must stay where it is, if the controller
+/// or a Service claiming it is paused.
+pub fn ip_blocker(ip: &str) -> Option<String> {
+    if is_global() {
+        return Some("the controller is paused".into());
+    }
+    paused_claimer(SERVICES.get()?, |service| {
+        claimed_ips(service).iter().any(|claimed| *claimed == ip)
+    })
+}
+
+/// Sets the global pause from `config_map`, absent meaning not paused, and
+/// returns whether it was just lifted.
+fn update(config_map: Option<&ConfigMap>) -> bool {
+    let paused = is_true(
+        config_map
+            .and_then(|config_map| config_map.data.as_ref())
+            .and_then(|data| data.get(PAUSED_KEY)),
+    );
+    let was_paused = PAUSED.swap(paused, Ordering::Relaxed);
+    metrics::PAUSED.set(paused.into());
+    match (was_paused, paused) {
+        (false, true) => println!("controller paused, no floating ip is moved until resumed"),
+        (true, false) => println!("controller resumed"),
+        _ => {}
+    }
+    was_paused && !paused
+}
+
+/// Reads the pause ConfigMap once, so a controller restarted while paused
+/// stays paused from its first reconcile.
+pub async fn init(client: KubeClient, config_map: &str) -> Result<(), Error> {
+    let (namespace, name) = clusters::namespaced_name(config_map);
+    let api = Api::<ConfigMap>::namespaced(client, &namespace);
+    update(api.get_opt(&name).await?.as_ref());
+    Ok(())
+}
+
+/// Follows the pause ConfigMap, yielding every cached node and Service when
+/// the pause is lifted.
+pub fn watch(
+    client: KubeClient,
+    config_map: &str,
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+    max_backoff: Duration,
+) -> impl Stream<Item = KubeResource> {
+    let (namespace, name) = clusters::namespaced_name(config_map);
+    println!(
+        "following the pause switch of configmap {}/{}",
+        namespace, name
+    );
+    let api = Api::<ConfigMap>::namespaced(client, &namespace);
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    watcher(api, params)
+        .backoff(resync::watch_backoff(max_backoff))
+        .filter_map(move |event| {
+            let resumed = match event {
+                Ok(watcher::Event::Applied(config_map)) => update(Some(&config_map)),
+                Ok(watcher::Event::Deleted(_)) => update(None),
+                Ok(watcher::Event::Restarted(config_maps)) => update(config_maps.first()),
+                Err(err) => {
+                    println!("pause configmap watch failed: {}", err);
+                    false
+                }
+            };
+            let resources = if resumed {
+                resync::all(&nodes, &services)
+            } else {
+                vec![]
+            };
+            futures::future::ready(Some(futures::stream::iter(resources)))
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::runtime::reflector;
+
+    fn service(name: &str, paused: bool, ip: &str) -> KubeService {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": name,
+                "namespace": "default",
+                "annotations": {PAUSED_ANNOTATION: paused.to_string()},
+            },
+            "spec": {"type": "ClusterIP", "externalIPs": [ip]},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn paused_services_block_their_ips_only() {
+        let (services, mut writer) = reflector::store();
+        writer.apply_watcher_event(&watcher::Event::Restarted(vec![
+            service("paused", true, "198.51.100.1"),
+            service("running", false, "198.51.100.2"),
+        ]));
+        let claims = |ip: &'static str| {
+            move |service: &KubeService| claimed_ips(service).iter().any(|c| *c == ip)
+        };
+        assert_eq!(
+            paused_claimer(&services, claims("198.51.100.1")).as_deref(),
+            Some("its Service default/paused is paused")
+        );
+        assert_eq!(paused_claimer(&services, claims("198.51.100.2")), None);
+    }
+}
@@ -0,0 +1,44 @@
+//! Floating IPs pinned to a Service with the `fip.hcloud.barodeur.io/ip`
+//! annotation, by address or name. The pinned IP replaces the load balancer
+//! ingress status of the Service, which may be empty or stale.
+
+use crate::assign::find_floating_ip;
+use crate::{Context, Error};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::{Resource, ResourceExt};
+
+pub const IP_ANNOTATION: &str = "fip.hcloud.barodeur.io/ip";
+
+/// The floating IP address or name pinned to `service`, if any.
+pub fn pinned(service: &KubeService) -> Option<&String> {
+    service
+        .annotations()
+        .get(IP_ANNOTATION)
+        .filter(|id| !id.trim().is_empty())
+}
+
+/// The IP of the floating IP named by `id`, warning on `service` when there
+/// is none.
+pub async fn resolve(
+    ctx: &Context,
+    service: &KubeService,
+    id: &str,
+) -> Result<Option<String>, Error> {
+    if let Some((_, fip)) = find_floating_ip(&ctx.projects, id.trim()).await? {
+        return Ok(Some(fip.ip));
+    }
+    println!(
+        "{} pinned to {} is not a floating ip of any project",
+        id,
+        service.name_any()
+    );
+    ctx.events
+        .warning(
+            service.object_ref(&()),
+            "FloatingIPNotFound",
+            "Reconcile",
+            format!("pinned floating ip {} is not in any project", id),
+        )
+        .await;
+    Ok(None)
+}
@@ -0,0 +1,213 @@
+//! Placement of reassigned IPs: in the home location of the floating IP when
+//! possible, and spread so a drained node's IPs don't all land on the same
+//! server or, optionally, in the same datacenter or location.
+
+use crate::{fetch_servers, Error};
+use clap::ValueEnum;
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::FloatingIp;
+use std::collections::{HashMap, HashSet};
+
+/// Whether floating IPs may move to servers outside their home location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LocationPolicy {
+    /// Any available server
+    Ignore,
+    /// Servers in the home location of the floating IP, others when there is none
+    Prefer,
+    /// Only servers in the home location of the floating IP
+    Require,
+}
+
+/// The failure domain IPs are spread across.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FailureDomain {
+    /// hcloud datacenter, e.g. fsn1-dc14
+    Datacenter,
+    /// hcloud location, e.g. fsn1
+    Location,
+}
+
+/// What happens to a floating IP no available server can take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NoTargetPolicy {
+    /// Leave it where it is
+    Keep,
+    /// Unassign it, so it no longer reaches the unavailable server
+    Unassign,
+    /// Assign it to the --fallback-server
+    Fallback,
+}
+
+/// Failure domain of every server of the project, left empty when IPs are
+/// not spread across domains.
+pub async fn server_domains(
+    hcloud_conf: &Configuration,
+    domain: Option<FailureDomain>,
+) -> Result<HashMap<i32, String>, Error> {
+    let domain = match domain {
+        Some(domain) => domain,
+        None => return Ok(HashMap::new()),
+    };
+    Ok(fetch_servers(hcloud_conf)
+        .await?
+        .into_iter()
+        .map(|server| {
+            let name = match domain {
+                FailureDomain::Datacenter => server.datacenter.name,
+                FailureDomain::Location => server.datacenter.location.name,
+            };
+            (server.id, name)
+        })
+        .collect())
+}
+
+/// Location name of every server of the project, left empty when locations
+/// are ignored.
+pub async fn server_locations(
+    hcloud_conf: &Configuration,
+    policy: LocationPolicy,
+) -> Result<HashMap<i32, String>, Error> {
+    if policy == LocationPolicy::Ignore {
+        return Ok(HashMap::new());
+    }
+    Ok(fetch_servers(hcloud_conf)
+        .await?
+        .into_iter()
+        .map(|server| (server.id, server.datacenter.location.name))
+        .collect())
+}
+
+/// Narrows `candidates` down to the servers in the home location of `fip`
+/// according to `policy`.
+pub fn in_home_location(
+    policy: LocationPolicy,
+    fip: &FloatingIp,
+    candidates: &HashSet<i32>,
+    locations: &HashMap<i32, String>,
+) -> Vec<i32> {
+    let home: Vec<i32> = candidates
+        .iter()
+        .copied()
+        .filter(|id| locations.get(id) == Some(&fip.home_location.name))
+        .collect();
+    match policy {
+        LocationPolicy::Ignore => candidates.iter().copied().collect(),
+        LocationPolicy::Prefer if home.is_empty() => candidates.iter().copied().collect(),
+        LocationPolicy::Prefer | LocationPolicy::Require => home,
+    }
+}
+
+/// Number of floating IPs held by each server.
+pub fn load(floating_ips: &[FloatingIp]) -> HashMap<i32, usize> {
+    let mut load = HashMap::new();
+    for server in floating_ips.iter().flat_map(|fip| fip.server) {
+        *load.entry(server).or_insert(0) += 1;
+    }
+    load
+}
+
+/// Number of IPs held by the servers in the same failure domain as
+/// `server_id`, none when IPs are not spread across domains.
+fn domain_load(
+    server_id: i32,
+    load: &HashMap<i32, usize>,
+    domains: &HashMap<i32, String>,
+) -> usize {
+    match domains.get(&server_id) {
+        Some(domain) => load
+            .iter()
+            .filter(|(id, _)| domains.get(id) == Some(domain))
+            .map(|(_, count)| count)
+            .sum(),
+        None => 0,
+    }
+}
+
+/// Picks the candidate in the failure domain holding the fewest IPs, then
+/// holding the fewest IPs itself, the lowest ID on a tie, and counts the IP
+/// it receives so the next pick accounts for it.
+pub fn least_loaded(
+    candidates: &[i32],
+    load: &mut HashMap<i32, usize>,
+    domains: &HashMap<i32, String>,
+) -> Option<i32> {
+    let target = *candidates.iter().min_by_key(|id| {
+        (
+            domain_load(**id, load, domains),
+            load.get(id).copied().unwrap_or(0),
+            **id,
+        )
+    })?;
+    *load.entry(target).or_insert(0) += 1;
+    Some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hcloud::models::Location;
+
+    fn fip(home: &str, server: Option<i32>) -> FloatingIp {
+        FloatingIp {
+            server,
+            home_location: Box::new(Location {
+                name: home.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn sorted(mut ids: Vec<i32>) -> Vec<i32> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn narrows_candidates_down_to_the_home_location() {
+        let locations: HashMap<i32, String> = [(1, "fsn1"), (2, "nbg1"), (3, "fsn1")]
+            .into_iter()
+            .map(|(id, name)| (id, name.to_string()))
+            .collect();
+        let candidates: HashSet<i32> = [1, 2, 3].into();
+        let fsn1 = fip("fsn1", None);
+        let hel1 = fip("hel1", None);
+        let home = |policy, fip| sorted(in_home_location(policy, fip, &candidates, &locations));
+
+        assert_eq!(home(LocationPolicy::Ignore, &fsn1), vec![1, 2, 3]);
+        assert_eq!(home(LocationPolicy::Prefer, &fsn1), vec![1, 3]);
+        assert_eq!(home(LocationPolicy::Require, &fsn1), vec![1, 3]);
+        assert_eq!(home(LocationPolicy::Prefer, &hel1), vec![1, 2, 3]);
+        assert_eq!(home(LocationPolicy::Require, &hel1), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn spreads_ips_over_the_least_loaded_servers() {
+        let mut load = load(&[
+            fip("fsn1", Some(1)),
+            fip("fsn1", Some(1)),
+            fip("fsn1", None),
+        ]);
+        let domains = HashMap::new();
+        let picks: Vec<_> = (0..4)
+            .map(|_| least_loaded(&[1, 2, 3], &mut load, &domains))
+            .collect();
+        assert_eq!(picks, vec![Some(2), Some(3), Some(2), Some(3)]);
+        assert_eq!(load[&1], 2);
+        assert_eq!(least_loaded(&[], &mut load, &domains), None);
+    }
+
+    #[test]
+    fn spreads_ips_over_failure_domains_first() {
+        let domains: HashMap<i32, String> = [(1, "fsn1-dc14"), (2, "fsn1-dc14"), (3, "nbg1-dc3")]
+            .into_iter()
+            .map(|(id, name)| (id, name.to_string()))
+            .collect();
+        let mut load = load(&[fip("fsn1", Some(1))]);
+        let picks: Vec<_> = (0..3)
+            .map(|_| least_loaded(&[1, 2, 3], &mut load, &domains))
+            .collect();
+        assert_eq!(picks, vec![Some(3), Some(2), Some(3)]);
+    }
+}
@@ -0,0 +1,77 @@
+//! Node-pool affinity. With `--fip-pool-label pool` a floating IP labeled
+//! `pool=edge` in hcloud only ever goes to the nodes whose
+//! `--node-pool-label`, the same key unless set, is `edge`, e.g. to keep the
+//! ingress and egress IPs on their own pools. Unlabeled floating IPs go to
+//! any node.
+
+use crate::{get_hc_server_id, Context};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::ResourceExt;
+use once_cell::sync::OnceCell;
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// hcloud label key of the floating IPs, and node label key.
+static LABELS: OnceCell<(String, String)> = OnceCell::new();
+
+pub fn set_labels(fip_label: String, node_label: String) {
+    let _ = LABELS.set((fip_label, node_label));
+}
+
+/// The hcloud label key of the floating IPs and the label key of the nodes,
+/// when pools are enabled.
+pub fn labels() -> Option<(&'static str, &'static str)> {
+    LABELS
+        .get()
+        .map(|(fip_label, node_label)| (fip_label.as_str(), node_label.as_str()))
+}
+
+/// The pool of `fip`, if it is bound to one.
+pub fn pool(fip: &FloatingIp) -> Option<&String> {
+    let (fip_label, _) = LABELS.get()?;
+    fip.labels.get(fip_label)
+}
+
+fn node_in_pool(node: &KubeNode, pool: &str) -> bool {
+    let (_, node_label) = LABELS.get().unwrap();
+    node.labels().get(node_label).map(String::as_str) == Some(pool)
+}
+
+/// Whether `fip` may be held by `server_id`, whose node must be among
+/// `nodes` when the IP is bound to a pool.
+pub fn allows<N: Borrow<KubeNode>>(nodes: &[N], fip: &FloatingIp, server_id: i32) -> bool {
+    let pool = match pool(fip) {
+        Some(pool) => pool,
+        None => return true,
+    };
+    nodes
+        .iter()
+        .map(Borrow::borrow)
+        .find(|node| get_hc_server_id(node) == Some(server_id))
+        .map(|node| node_in_pool(node, pool))
+        .unwrap_or(false)
+}
+
+/// The servers among `server_ids` that may hold `fip`.
+pub fn restrict<N: Borrow<KubeNode>>(
+    nodes: &[N],
+    fip: &FloatingIp,
+    server_ids: &HashSet<i32>,
+) -> HashSet<i32> {
+    server_ids
+        .iter()
+        .copied()
+        .filter(|server_id| allows(nodes, fip, *server_id))
+        .collect()
+}
+
+/// The nodes of this cluster and of the peer clusters.
+pub fn known_nodes(ctx: &Context) -> Vec<Arc<KubeNode>> {
+    let mut nodes = ctx.nodes.state();
+    for peer in &ctx.peers {
+        nodes.extend(peer.nodes.state());
+    }
+    nodes
+}
@@ -0,0 +1,65 @@
+//! Failover target preference from the `fip.hcloud.barodeur.io/priority` node
+//! annotation. IPs go to the available nodes with the highest priority, nodes
+//! without the annotation have priority 0.
+
+use crate::{get_hc_server_id, get_robot_server_number};
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use std::collections::HashMap;
+
+pub const PRIORITY_ANNOTATION: &str = "fip.hcloud.barodeur.io/priority";
+
+fn node_priority(node: &KubeNode) -> i32 {
+    let value = match node
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PRIORITY_ANNOTATION))
+    {
+        Some(value) => value,
+        None => return 0,
+    };
+    value.trim().parse().unwrap_or_else(|_| {
+        println!(
+            "ignoring invalid {} annotation {:?} of node {}",
+            PRIORITY_ANNOTATION,
+            value,
+            node.metadata.name.as_deref().unwrap_or_default()
+        );
+        0
+    })
+}
+
+/// Priority of every hcloud node, by server ID.
+pub fn hc_priorities(nodes: &Store<KubeNode>) -> HashMap<i32, i32> {
+    nodes
+        .state()
+        .iter()
+        .filter_map(|node| Some((get_hc_server_id(node)?, node_priority(node))))
+        .collect()
+}
+
+/// Priority of every Robot node, by server number.
+pub fn robot_priorities(nodes: &Store<KubeNode>) -> HashMap<i32, i32> {
+    nodes
+        .state()
+        .iter()
+        .flat_map(|node| Some((get_robot_server_number(node)?, node_priority(node))))
+        .collect()
+}
+
+/// Keeps the candidates sharing the highest priority, sorted by ID.
+pub fn preferred(
+    candidates: impl IntoIterator<Item = i32>,
+    priorities: &HashMap<i32, i32>,
+) -> Vec<i32> {
+    let priority = |id: &i32| priorities.get(id).copied().unwrap_or(0);
+    let mut candidates: Vec<i32> = candidates.into_iter().collect();
+    let highest = match candidates.iter().map(priority).max() {
+        Some(highest) => highest,
+        None => return vec![],
+    };
+    candidates.retain(|id| priority(id) == highest);
+    candidates.sort_unstable();
+    candidates
+}
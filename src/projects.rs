@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::secrets::SecretBackend;
+use crate::{fetch_servers, Error};
+use hcloud::apis::configuration::Configuration;
+use once_cell::sync::OnceCell;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const TOKEN_PREFIX: &str = "HCLOUD_TOKEN_";
+const FILE_SUFFIX: &str = "_FILE";
+const TOKEN_FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const SECRET_BACKEND_POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// An hcloud project the controller manages floating IPs in.
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub name: String,
+    conf: Arc<RwLock<Configuration>>,
+    token_source: Option<TokenSource>,
+}
+
+/// Where the token of a project is re-read from.
+#[derive(Debug, Clone)]
+enum TokenSource {
+    File(PathBuf),
+    Secret(SecretBackend),
+}
+
+impl TokenSource {
+    async fn read(&self) -> Result<String, Error> {
+        match self {
+            TokenSource::File(path) => read_token_file(path),
+            TokenSource::Secret(backend) => backend.fetch_token().await,
+        }
+    }
+
+    fn poll_interval(&self) -> Duration {
+        match self {
+            TokenSource::File(_) => TOKEN_FILE_POLL_INTERVAL,
+            TokenSource::Secret(_) => SECRET_BACKEND_POLL_INTERVAL,
+        }
+    }
+}
+
+/// How the hcloud API is reached.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    /// Proxy URL of every hcloud call, the `HTTPS_PROXY` one when not set.
+    pub proxy: Option<String>,
+    /// PEM file of CA certificates trusted in addition to the system ones,
+    /// e.g. of a TLS intercepting proxy.
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// HTTP client of every project, set once at startup.
+static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+
+/// The certificates of a PEM bundle, one or more.
+fn read_certificates(path: &PathBuf) -> Result<Vec<reqwest::Certificate>, Error> {
+    let pem = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let end = "-----END CERTIFICATE-----";
+    let certificates = pem
+        .split_inclusive(end)
+        .filter(|block| block.contains(end))
+        .map(|block| reqwest::Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid certificate in {}: {}", path.display(), err))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path.display()).into());
+    }
+    Ok(certificates)
+}
+
+pub fn set_client(config: &ClientConfig) -> Result<(), Error> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.timeout);
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+    }
+    if let Some(path) = &config.ca_cert {
+        for certificate in read_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let _ = CLIENT.set(builder.build()?);
+    Ok(())
+}
+
+fn configuration(token: String) -> Configuration {
+    let mut conf = Configuration::new();
+    if let Some(client) = CLIENT.get() {
+        conf.client = client.clone();
+    }
+    conf.bearer_access_token = Some(token);
+    conf
+}
+
+fn read_token_file(path: &PathBuf) -> Result<String, Error> {
+    let token = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    Ok(token.trim().to_string())
+}
+
+impl Project {
+    pub fn new(name: String, token: String) -> Self {
+        Project {
+            name,
+            conf: Arc::new(RwLock::new(configuration(token))),
+            token_source: None,
+        }
+    }
+
+    /// A project whose token is read from `path`, e.g. a mounted Secret.
+    pub fn from_token_file(name: String, path: PathBuf) -> Result<Self, Error> {
+        let token = read_token_file(&path)?;
+        Ok(Project {
+            token_source: Some(TokenSource::File(path)),
+            ..Project::new(name, token)
+        })
+    }
+
+    /// A project whose token is read from an external secret backend.
+    pub async fn from_secret_backend(name: String, backend: SecretBackend) -> Result<Self, Error> {
+        let token = backend
+            .fetch_token()
+            .await
+            .map_err(|err| format!("failed to fetch hcloud token of project {}: {}", name, err))?;
+        Ok(Project {
+            token_source: Some(TokenSource::Secret(backend)),
+            ..Project::new(name, token)
+        })
+    }
+
+    /// The hcloud configuration with the current token.
+    pub fn conf(&self) -> Configuration {
+        self.conf.read().unwrap().clone()
+    }
+
+    /// Re-reads the token, returns whether it changed.
+    async fn reload_token(&self) -> Result<bool, Error> {
+        let source = match &self.token_source {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+        let token = source.read().await?;
+        let mut conf = self.conf.write().unwrap();
+        if conf.bearer_access_token.as_ref() == Some(&token) || token.is_empty() {
+            return Ok(false);
+        }
+        *conf = configuration(token);
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+struct ProjectTokens {
+    token: Option<String>,
+    file: Option<String>,
+}
+
+/// Builds the projects from the default project's token, the
+/// `--project-token`/`--project-token-file` flags and any
+/// `HCLOUD_TOKEN_<NAME>` or `HCLOUD_TOKEN_<NAME>_FILE` variable.
+pub async fn projects_from_config(config: &Config) -> Result<Vec<Project>, Error> {
+    let mut sources: BTreeMap<String, ProjectTokens> = BTreeMap::new();
+    for (key, value) in env::vars() {
+        let (key, is_file) = match key.strip_suffix(FILE_SUFFIX) {
+            Some(key) => (key.to_string(), true),
+            None => (key, false),
+        };
+        let name = match key.strip_prefix(TOKEN_PREFIX) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        let source = sources.entry(name).or_default();
+        if is_file {
+            source.file = Some(value);
+        } else {
+            source.token = Some(value);
+        }
+    }
+    for (name, token) in &config.project_tokens {
+        sources.entry(name.clone()).or_default().token = Some(token.clone());
+    }
+    for (name, file) in &config.project_token_files {
+        sources.entry(name.clone()).or_default().file = Some(file.clone());
+    }
+
+    let mut projects = Vec::with_capacity(sources.len() + 1);
+    if let Some(backend) = config.secret_backend() {
+        projects.push(Project::from_secret_backend("default".into(), backend).await?);
+    } else if let Some(file) = &config.hcloud_token_file {
+        projects.push(Project::from_token_file("default".into(), file.clone())?);
+    } else if let Some(token) = &config.hcloud_token {
+        projects.push(Project::new("default".into(), token.clone()));
+    }
+
+    for (name, source) in sources {
+        if projects.iter().any(|project| project.name == name) {
+            return Err(format!("project {} is configured twice", name).into());
+        }
+        let project = match source {
+            ProjectTokens {
+                token: Some(_),
+                file: Some(_),
+            } => {
+                return Err(format!(
+                    "both a token and a token file are configured for project {}",
+                    name
+                )
+                .into())
+            }
+            ProjectTokens {
+                file: Some(file), ..
+            } => Project::from_token_file(name, file.into())?,
+            ProjectTokens {
+                token: Some(token), ..
+            } => Project::new(name, token),
+            _ => unreachable!(),
+        };
+        projects.push(project);
+    }
+
+    if projects.is_empty() {
+        return Err("missing hcloud token, set --hcloud-token or HCLOUD_TOKEN".into());
+    }
+    Ok(projects)
+}
+
+/// Polls the token files and secret backends of `projects` and swaps in a
+/// new configuration when the token changes, so Secret rotation doesn't need
+/// a restart.
+pub fn watch_token_files(projects: &[Project]) {
+    for project in projects {
+        let poll_interval = match &project.token_source {
+            Some(source) => source.poll_interval(),
+            None => continue,
+        };
+        let project = project.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(poll_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match project.reload_token().await {
+                    Ok(true) => println!("reloaded hcloud token of project {}", project.name),
+                    Ok(false) => {}
+                    Err(err) => println!(
+                        "failed to reload hcloud token of project {}: {}",
+                        project.name, err
+                    ),
+                }
+            }
+        });
+    }
+}
+
+/// Restricts `server_ids` to the servers of `project`.
+///
+/// Floating IPs can only be assigned to servers of their own project. With a
+/// single project every server is assumed to belong to it and no API call is
+/// made.
+pub async fn project_server_ids(
+    projects: &[Project],
+    project: &Project,
+    server_ids: &HashSet<i32>,
+) -> Result<HashSet<i32>, Error> {
+    if projects.len() == 1 {
+        return Ok(server_ids.clone());
+    }
+    let owned: HashSet<i32> = fetch_servers(&project.conf())
+        .await?
+        .iter()
+        .map(|server| server.id)
+        .collect();
+    Ok(server_ids.intersection(&owned).copied().collect())
+}
@@ -0,0 +1,170 @@
+//! The backends that hold the IPs on the servers of the nodes. The event loop
+//! decides which nodes must give their IPs up and which nodes a Service's IPs
+//! may move to, and leaves the moves themselves to each provider: hcloud
+//! floating and alias IPs, and Robot failover IPs.
+
+use crate::clusters;
+use crate::robot::{self, RobotClient};
+use crate::{
+    available_hc_server_ids, available_robot_server_numbers, drain_node, evacuate_server,
+    get_hc_server_id, get_robot_server_number, priority, projects, reassign_service_ips, Context,
+    Error,
+};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use std::collections::HashSet;
+
+pub trait Provider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the server of `node` is one this provider moves IPs off.
+    fn manages(&self, node: &KubeNode) -> bool;
+
+    /// Moves every IP held by the server of `node` to an available node.
+    fn evacuate<'a>(
+        &'a self,
+        ctx: &'a Context,
+        node: &'a KubeNode,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Makes sure the IPs among `ips` this provider knows of are held by one of
+    /// the `eligible` nodes.
+    fn reassign<'a>(
+        &'a self,
+        ctx: &'a Context,
+        ips: &'a HashSet<&'a String>,
+        eligible: &'a [KubeNode],
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Floating and alias IPs of the hcloud projects.
+pub struct HcloudProvider;
+
+impl Provider for HcloudProvider {
+    fn name(&self) -> &'static str {
+        "hcloud"
+    }
+
+    fn manages(&self, node: &KubeNode) -> bool {
+        get_hc_server_id(node).is_some()
+    }
+
+    fn evacuate<'a>(
+        &'a self,
+        ctx: &'a Context,
+        node: &'a KubeNode,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let server_id = match get_hc_server_id(node) {
+                Some(server_id) => server_id,
+                None => return Ok(()),
+            };
+            if !drain_node(ctx, node, server_id).await? {
+                return Ok(());
+            }
+            let mut available_hc_server_ids = available_hc_server_ids(&ctx.nodes);
+            if available_hc_server_ids.is_empty() {
+                available_hc_server_ids = clusters::fallback_nodes(&ctx.peers, None)
+                    .iter()
+                    .filter_map(get_hc_server_id)
+                    .collect();
+            }
+            let priorities = priority::hc_priorities(&ctx.nodes);
+            for project in &ctx.projects {
+                let available =
+                    projects::project_server_ids(&ctx.projects, project, &available_hc_server_ids)
+                        .await?;
+                evacuate_server(ctx, project, server_id, &available, &priorities).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn reassign<'a>(
+        &'a self,
+        ctx: &'a Context,
+        ips: &'a HashSet<&'a String>,
+        eligible: &'a [KubeNode],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let available_hc_server_ids: HashSet<i32> =
+                eligible.iter().filter_map(get_hc_server_id).collect();
+            let priorities = priority::hc_priorities(&ctx.nodes);
+            for project in &ctx.projects {
+                let available =
+                    projects::project_server_ids(&ctx.projects, project, &available_hc_server_ids)
+                        .await?;
+                reassign_service_ips(ctx, project, ips, &available, &priorities).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Failover IPs routed between Robot dedicated servers.
+pub struct RobotProvider {
+    pub robot: RobotClient,
+}
+
+impl Provider for RobotProvider {
+    fn name(&self) -> &'static str {
+        "robot"
+    }
+
+    fn manages(&self, node: &KubeNode) -> bool {
+        get_robot_server_number(node).is_some()
+    }
+
+    fn evacuate<'a>(
+        &'a self,
+        ctx: &'a Context,
+        node: &'a KubeNode,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let server_number = match get_robot_server_number(node) {
+                Some(server_number) => server_number,
+                None => return Ok(()),
+            };
+            let mut available = available_robot_server_numbers(&ctx.nodes);
+            if available.is_empty() {
+                available = clusters::fallback_nodes(&ctx.peers, None)
+                    .iter()
+                    .filter_map(get_robot_server_number)
+                    .collect();
+            }
+            let available = priority::preferred(available, &priority::robot_priorities(&ctx.nodes));
+            robot::evacuate(ctx, &self.robot, server_number, &available).await
+        }
+        .boxed()
+    }
+
+    fn reassign<'a>(
+        &'a self,
+        ctx: &'a Context,
+        ips: &'a HashSet<&'a String>,
+        eligible: &'a [KubeNode],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let available: Vec<i32> = eligible
+                .iter()
+                .filter_map(get_robot_server_number)
+                .collect();
+            let available = priority::preferred(available, &priority::robot_priorities(&ctx.nodes));
+            robot::reassign(ctx, &self.robot, ips, &available).await
+        }
+        .boxed()
+    }
+}
+
+/// The providers of the configured backends.
+pub fn providers(robot: Option<RobotClient>) -> Vec<Box<dyn Provider>> {
+    let mut providers: Vec<Box<dyn Provider>> = vec![];
+    if let Some(robot) = robot {
+        providers.push(Box::new(RobotProvider { robot }));
+    }
+    providers.push(Box::new(HcloudProvider));
+    providers
+}
@@ -0,0 +1,167 @@
+//! On-demand floating IPs for LoadBalancer Services that have none, created
+//! in hcloud with labels naming their Service and published in its status,
+//! so the controller provisions LoadBalancer IPs on its own.
+
+use crate::conflicts::claimed_ips;
+use crate::projects::Project;
+use crate::{
+    fetch_floating_ips, fip_cache, hcloud_api, ingress, is_dry_run, is_load_balancer, trace,
+    Context, Error,
+};
+use hcloud::models::{CreateFloatingIpRequest, FloatingIp, IpType};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::ResourceExt;
+use std::collections::HashMap;
+
+/// Overrides `--provision-location` for the IP of a Service.
+pub const LOCATION_ANNOTATION: &str = "fip.hcloud.barodeur.io/location";
+
+pub const NAMESPACE_LABEL: &str = "fip.hcloud.barodeur.io/service-namespace";
+pub const NAME_LABEL: &str = "fip.hcloud.barodeur.io/service-name";
+
+#[derive(Debug, Clone)]
+pub struct ProvisionConfig {
+    /// Home location of the created IPs, e.g. `fsn1`.
+    pub location: String,
+    /// Name of the created IPs, `{namespace}` and `{name}` are replaced by
+    /// those of the Service.
+    pub name_template: String,
+    /// Set on the created IPs besides the Service labels, templated as the
+    /// name.
+    pub labels: Vec<(String, String)>,
+    /// Project the IPs are created in, the first one by default.
+    pub project: Option<String>,
+}
+
+fn render(template: &str, service: &KubeService) -> String {
+    template
+        .replace("{namespace}", &service.namespace().unwrap_or_default())
+        .replace("{name}", &service.name_any())
+}
+
+/// Whether `fip` was created for `service`.
+pub fn is_owned_by(fip: &FloatingIp, service: &KubeService) -> bool {
+    fip.labels.get(NAMESPACE_LABEL) == service.namespace().as_ref()
+        && fip.labels.get(NAME_LABEL) == Some(&service.name_any())
+}
+
+/// Services asking for a specific IP or already having one are left alone.
+fn needs_ip(service: &KubeService) -> bool {
+    is_load_balancer(service)
+        && claimed_ips(service).is_empty()
+        && service
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.load_balancer_ip.as_ref())
+            .is_none()
+}
+
+fn is_ipv6_only(service: &KubeService) -> bool {
+    service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ip_families.as_ref())
+        .and_then(|families| families.first())
+        .map(|family| family == "IPv6")
+        .unwrap_or(false)
+}
+
+fn project<'a>(projects: &'a [Project], config: &ProvisionConfig) -> Result<&'a Project, Error> {
+    match &config.project {
+        Some(name) => projects
+            .iter()
+            .find(|project| &project.name == name)
+            .ok_or_else(|| format!("unknown provisioning project {}", name).into()),
+        None => projects
+            .first()
+            .ok_or_else(|| "no project to provision floating ips in".into()),
+    }
+}
+
+async fn create(
+    project: &Project,
+    config: &ProvisionConfig,
+    service: &KubeService,
+) -> Result<FloatingIp, Error> {
+    let location = service
+        .annotations()
+        .get(LOCATION_ANNOTATION)
+        .cloned()
+        .unwrap_or_else(|| config.location.clone());
+    let mut labels: HashMap<String, String> = config
+        .labels
+        .iter()
+        .map(|(key, value)| (key.clone(), render(value, service)))
+        .collect();
+    labels.insert(
+        NAMESPACE_LABEL.into(),
+        service.namespace().unwrap_or_default(),
+    );
+    labels.insert(NAME_LABEL.into(), service.name_any());
+    let request = CreateFloatingIpRequest {
+        description: Some(format!(
+            "LoadBalancer IP of {}/{}",
+            service.namespace().unwrap_or_default(),
+            service.name_any()
+        )),
+        home_location: Some(location),
+        labels: Some(labels),
+        name: Some(render(&config.name_template, service)),
+        ..CreateFloatingIpRequest::new(IpType::Ipv4)
+    };
+    let hcloud_conf = &project.conf();
+    let fip = hcloud_api::api()
+        .create_floating_ip(hcloud_conf, request)
+        .await?;
+    fip_cache::invalidate(hcloud_conf);
+    Ok(fip)
+}
+
+/// Gives `service` a floating IP of its own when it has none, returning it.
+/// An IP created earlier for the Service is published again rather than
+/// creating another one.
+pub async fn ensure(
+    ctx: &Context,
+    config: &ProvisionConfig,
+    service: &KubeService,
+) -> Result<Option<String>, Error> {
+    if !needs_ip(service) {
+        return Ok(None);
+    }
+    let full_name = format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    );
+    if is_ipv6_only(service) {
+        println!(
+            "not provisioning a floating ip for IPv6 service {}, only IPv4 is supported",
+            full_name
+        );
+        return Ok(None);
+    }
+    let project = project(&ctx.projects, config)?;
+    let existing = fetch_floating_ips(&project.conf())
+        .await?
+        .into_iter()
+        .find(|fip| is_owned_by(fip, service));
+    let fip = match existing {
+        Some(fip) => fip,
+        None if is_dry_run() => {
+            println!("would create a floating ip for service {}", full_name);
+            trace::record(format!("would create a floating ip for {}", full_name));
+            return Ok(None);
+        }
+        None => {
+            let fip = create(project, config, service).await?;
+            println!(
+                "created floating ip {} ({}) for service {}",
+                fip.ip, fip.name, full_name
+            );
+            trace::record(format!("create {} for {}", fip.ip, full_name));
+            fip
+        }
+    };
+    ingress::publish(&ctx.services_api, service, &fip.ip).await?;
+    Ok(Some(fip.ip))
+}
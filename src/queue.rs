@@ -0,0 +1,227 @@
+//! Work queue of the reconcile loop.
+//!
+//! Bursts of watch events, e.g. during a rolling upgrade, are coalesced into a
+//! single reconcile of the latest version of each object once things calm
+//! down. Failed reconciles are requeued with an exponential backoff until they
+//! succeed or a newer event of the same object arrives, and items that have
+//! to wait, e.g. a draining node, are queued again for when they are due.
+//! An object is reconciled by one task at a time, events arriving meanwhile
+//! are held back until that task is done.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Events keep being held back for at most this many quiet periods.
+const MAX_DELAY_FACTOR: u32 = 10;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+struct Pending<T> {
+    item: T,
+    /// Set for retries, fresh events are due with the rest of their burst.
+    retry_at: Option<Instant>,
+}
+
+pub struct WorkQueue<T> {
+    quiet_period: Duration,
+    pending: HashMap<String, Pending<T>>,
+    first_at: Option<Instant>,
+    last_at: Option<Instant>,
+    failures: HashMap<String, u32>,
+    /// Keys handed out by `drain_due` whose task is not done yet.
+    running: HashSet<String>,
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new(quiet_period: Duration) -> Self {
+        WorkQueue {
+            quiet_period,
+            pending: HashMap::new(),
+            first_at: None,
+            last_at: None,
+            failures: HashMap::new(),
+            running: HashSet::new(),
+        }
+    }
+
+    /// Queues the latest version of an object, replacing the pending one
+    /// with the same key.
+    pub fn push(&mut self, key: String, item: T) {
+        let now = Instant::now();
+        self.first_at.get_or_insert(now);
+        self.last_at = Some(now);
+        self.pending.insert(
+            key,
+            Pending {
+                item,
+                retry_at: None,
+            },
+        );
+    }
+
+    /// Queues a failed item again after a backoff, unless a newer version is
+    /// already pending.
+    pub fn requeue(&mut self, key: String, item: T) -> Duration {
+        let failures = self.failures.entry(key.clone()).or_insert(0);
+        *failures += 1;
+        let delay = MIN_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(*failures - 1))
+            .min(MAX_RETRY_DELAY);
+        self.pending.entry(key).or_insert(Pending {
+            item,
+            retry_at: Some(Instant::now() + delay),
+        });
+        delay
+    }
+
+    /// Queues an item again at `at`, unless a newer version is already
+    /// pending.
+    pub fn requeue_at(&mut self, key: String, item: T, at: Instant) {
+        self.pending.entry(key).or_insert(Pending {
+            item,
+            retry_at: Some(at),
+        });
+    }
+
+    /// Resets the backoff of an item that was reconciled successfully.
+    pub fn forget(&mut self, key: &str) {
+        self.failures.remove(key);
+    }
+
+    /// Marks the task of an item as finished, an event that arrived while
+    /// it was running is due right away.
+    pub fn done(&mut self, key: &str) {
+        self.running.remove(key);
+        if let Some(pending) = self.pending.get_mut(key) {
+            pending.retry_at.get_or_insert_with(Instant::now);
+        }
+    }
+
+    fn burst_deadline(&self) -> Option<Instant> {
+        let quiet = self.last_at? + self.quiet_period;
+        let latest = self.first_at? + self.quiet_period * MAX_DELAY_FACTOR;
+        Some(quiet.min(latest))
+    }
+
+    /// When the next items are due: fresh events after a quiet period without
+    /// new ones or once they have been held back for too long, retries once
+    /// their backoff is over.
+    pub fn deadline(&self) -> Option<Instant> {
+        let retry = self
+            .pending
+            .iter()
+            .filter(|(key, _)| !self.running.contains(*key))
+            .filter_map(|(_, pending)| pending.retry_at)
+            .min();
+        match (self.burst_deadline(), retry) {
+            (Some(burst), Some(retry)) => Some(burst.min(retry)),
+            (burst, retry) => burst.or(retry),
+        }
+    }
+
+    /// Takes the items that are due, with their key, and marks them as
+    /// running until `done` is called. Items whose key is still running stay
+    /// queued.
+    pub fn drain_due(&mut self) -> Vec<(String, T)> {
+        let now = Instant::now();
+        let burst_due = self.burst_deadline().map(|at| at <= now).unwrap_or(false);
+        if burst_due {
+            self.first_at = None;
+            self.last_at = None;
+        }
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(key, _)| !self.running.contains(*key))
+            .filter(|(_, pending)| match pending.retry_at {
+                Some(at) => at <= now,
+                None => burst_due,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.into_iter()
+            .map(|key| {
+                let pending = self.pending.remove(&key).unwrap();
+                self.running.insert(key.clone());
+                (key, pending.item)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_bursts_into_the_latest_version() {
+        let quiet = Duration::from_secs(1);
+        let mut queue = WorkQueue::new(quiet);
+        queue.push("node/a".into(), 1);
+        queue.push("node/a".into(), 2);
+        assert!(queue.deadline().unwrap() > Instant::now());
+        assert!(queue.drain_due().is_empty());
+
+        // Held back for ten quiet periods at most, even if events keep coming.
+        queue.first_at = Some(Instant::now() - quiet * MAX_DELAY_FACTOR);
+        queue.push("node/a".into(), 3);
+        assert!(queue.deadline().unwrap() <= Instant::now());
+        assert_eq!(queue.drain_due(), vec![("node/a".to_string(), 3)]);
+        assert_eq!(queue.deadline(), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_until_forgotten() {
+        let mut queue = WorkQueue::new(Duration::ZERO);
+        let delays: Vec<Duration> = (0..10)
+            .map(|_| {
+                let delay = queue.requeue("node/a".into(), 1);
+                queue.pending.clear();
+                delay
+            })
+            .collect();
+        let secs: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300]);
+
+        queue.forget("node/a");
+        assert_eq!(queue.requeue("node/a".into(), 1), MIN_RETRY_DELAY);
+        assert!(queue.drain_due().is_empty());
+    }
+
+    #[test]
+    fn retries_do_not_replace_newer_events() {
+        let mut queue = WorkQueue::new(Duration::ZERO);
+        queue.push("service/a".into(), 2);
+        queue.requeue("service/a".into(), 1);
+        queue.requeue_at("service/a".into(), 1, Instant::now());
+        assert_eq!(queue.drain_due(), vec![("service/a".to_string(), 2)]);
+    }
+
+    #[test]
+    fn holds_back_events_of_running_keys() {
+        let mut queue = WorkQueue::new(Duration::ZERO);
+        queue.push("node/a".into(), 1);
+        assert_eq!(queue.drain_due(), vec![("node/a".to_string(), 1)]);
+
+        queue.push("node/a".into(), 2);
+        queue.push("node/b".into(), 3);
+        assert_eq!(queue.drain_due(), vec![("node/b".to_string(), 3)]);
+        assert_eq!(queue.deadline(), None);
+
+        queue.done("node/a");
+        assert!(queue.deadline().unwrap() <= Instant::now());
+        assert_eq!(queue.drain_due(), vec![("node/a".to_string(), 2)]);
+    }
+
+    #[test]
+    fn newer_events_win_over_retries_of_running_keys() {
+        let mut queue = WorkQueue::new(Duration::ZERO);
+        queue.push("service/a".into(), 1);
+        queue.drain_due();
+        queue.push("service/a".into(), 2);
+        queue.done("service/a");
+        queue.requeue("service/a".into(), 1);
+        assert_eq!(queue.drain_due(), vec![("service/a".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Reverse DNS of floating IPs from the `fip.hcloud.barodeur.io/rdns` Service
+//! annotation, kept in sync on every reconcile so PTR records follow the IP
+//! mail and ingress workloads are reached on.
+
+use crate::{fetch_floating_ips, fip_cache, hcloud_api, is_dry_run, trace, Context, Error};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::ResourceExt;
+use std::collections::HashSet;
+
+pub const RDNS_ANNOTATION: &str = "fip.hcloud.barodeur.io/rdns";
+
+/// Whether `value` is a hostname hcloud accepts as a PTR record.
+pub fn is_hostname(value: &str) -> bool {
+    let value = value.strip_suffix('.').unwrap_or(value);
+    !value.is_empty()
+        && value.len() <= 253
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Sets the PTR record of the floating IPs of `service` among `ips` to its
+/// annotation. IPs of Services without the annotation are left as they are.
+pub async fn sync(
+    ctx: &Context,
+    service: &KubeService,
+    ips: &HashSet<&String>,
+) -> Result<(), Error> {
+    let hostname = match service.annotations().get(RDNS_ANNOTATION) {
+        Some(hostname) => hostname.trim(),
+        None => return Ok(()),
+    };
+    if !is_hostname(hostname) {
+        println!(
+            "ignoring invalid {} annotation {:?} of service {}",
+            RDNS_ANNOTATION,
+            hostname,
+            service.name_any()
+        );
+        return Ok(());
+    }
+    for project in &ctx.projects {
+        let hcloud_conf = &project.conf();
+        for fip in fetch_floating_ips(hcloud_conf).await? {
+            if !ips.contains(&fip.ip) {
+                continue;
+            }
+            let current = fip
+                .dns_ptr
+                .iter()
+                .find(|ptr| ptr.ip == fip.ip)
+                .map(|ptr| ptr.dns_ptr.as_str());
+            if current == Some(hostname) {
+                continue;
+            }
+            if is_dry_run() {
+                println!("dry run: would set the rdns of {} to {}", fip.ip, hostname);
+                continue;
+            }
+            println!("setting the rdns of {} to {}", fip.ip, hostname);
+            trace::record(format!("set rdns of {} to {}", fip.ip, hostname));
+            let result = hcloud_api::api()
+                .set_floating_ip_rdns(
+                    hcloud_conf,
+                    fip.id,
+                    fip.ip.clone(),
+                    Some(hostname.to_string()),
+                )
+                .await;
+            fip_cache::invalidate(hcloud_conf);
+            result?;
+        }
+    }
+    Ok(())
+}
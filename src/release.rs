@@ -0,0 +1,139 @@
+//! Release of the floating IPs and Load Balancers of deleted Services. A
+//! finalizer keeps a managed Service around until its IPs are unassigned, and
+//! deleted when the controller created them, so no paid IP is left orphaned.
+
+use crate::conflicts::claimed_ips;
+use crate::dual_stack;
+use crate::provision;
+use crate::{
+    audit, claims_ips, fetch_floating_ips, fip_cache, fip_locks, hcloud_api, is_dry_run,
+    load_balancer,
+};
+use crate::{trace, unassign_floating_ip, Context, Error};
+use k8s_openapi::api::core::v1::Service as KubeService;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, ResourceExt};
+
+pub const FINALIZER: &str = "fip.hcloud.barodeur.io/release";
+
+#[derive(Debug, Clone)]
+pub struct ReleaseConfig {
+    /// Also delete the IPs created by `--provision-ips` for the Service.
+    pub delete_provisioned: bool,
+}
+
+fn has_finalizer(service: &KubeService) -> bool {
+    service.finalizers().iter().any(|f| f == FINALIZER)
+}
+
+/// Whether the IPs of `service` have to be released: it is being deleted or
+/// no longer claims IPs, and still carries the finalizer.
+pub fn is_released(service: &KubeService) -> bool {
+    has_finalizer(service)
+        && (service.metadata.deletion_timestamp.is_some() || !claims_ips(service))
+}
+
+/// Replaces the finalizers of `service`, failing when it changed since it was
+/// read rather than overwriting another controller's.
+async fn set_finalizers(
+    ctx: &Context,
+    service: &KubeService,
+    finalizers: Vec<String>,
+) -> Result<(), Error> {
+    let api = Api::<KubeService>::namespaced(
+        ctx.services_api.clone().into_client(),
+        &service.namespace().unwrap_or_default(),
+    );
+    let patch = serde_json::json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": service.resource_version(),
+        }
+    });
+    api.patch(
+        &service.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(&patch),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Adds the finalizer to a managed Service not carrying it yet.
+pub async fn ensure_finalizer(ctx: &Context, service: &KubeService) -> Result<(), Error> {
+    if has_finalizer(service) || service.metadata.deletion_timestamp.is_some() || is_dry_run() {
+        return Ok(());
+    }
+    let mut finalizers = service.finalizers().to_vec();
+    finalizers.push(FINALIZER.to_string());
+    set_finalizers(ctx, service, finalizers).await
+}
+
+/// Unassigns the floating IPs of `service`, deletes the ones created for it
+/// when configured to and its Load Balancer, then removes the finalizer.
+pub async fn release(
+    ctx: &Context,
+    config: Option<&ReleaseConfig>,
+    service: &KubeService,
+) -> Result<(), Error> {
+    // Also when the Service is no longer annotated for a Load Balancer.
+    load_balancer::delete(ctx, service).await?;
+    let delete_provisioned = config
+        .map(|config| config.delete_provisioned)
+        .unwrap_or(false);
+    let full_name = format!(
+        "{}/{}",
+        service.namespace().unwrap_or_default(),
+        service.name_any()
+    );
+    let claimed = claimed_ips(service);
+    for project in &ctx.projects {
+        let hcloud_conf = &project.conf();
+        let fips = fetch_floating_ips(hcloud_conf).await?;
+        for fip in fips
+            .iter()
+            .filter(|fip| dual_stack::claims(&claimed, fip) || provision::is_owned_by(fip, service))
+        {
+            let delete = delete_provisioned && provision::is_owned_by(fip, service);
+            if is_dry_run() {
+                println!(
+                    "dry run: would {} {} of deleted service {}",
+                    if delete { "delete" } else { "unassign" },
+                    fip.ip,
+                    full_name
+                );
+                continue;
+            }
+            if !delete && fip.server.is_none() {
+                continue;
+            }
+            // Deleting an IP unassigns it.
+            let result = fip_locks::locked(fip.id, async {
+                if delete {
+                    println!("deleting {} of deleted service {}", fip.ip, full_name);
+                    trace::record(format!("delete floating ip {}", fip.ip));
+                    hcloud_api::api()
+                        .delete_floating_ip(hcloud_conf, fip.id)
+                        .await
+                } else {
+                    println!("unassigning {} of deleted service {}", fip.ip, full_name);
+                    unassign_floating_ip(hcloud_conf, &fip.id).await
+                }
+            })
+            .await;
+            fip_cache::invalidate(hcloud_conf);
+            audit::released(&fip.ip, fip.server, delete, &result);
+            result?;
+        }
+    }
+    if is_dry_run() {
+        return Ok(());
+    }
+    let finalizers = service
+        .finalizers()
+        .iter()
+        .filter(|f| *f != FINALIZER)
+        .cloned()
+        .collect();
+    set_finalizers(ctx, service, finalizers).await
+}
@@ -0,0 +1,274 @@
+//! Reload of the configuration file on SIGHUP, so a selector or a policy can
+//! be tweaked during an incident without restarting the controller and
+//! relisting everything. The file is read again and what changed is logged;
+//! the settings below apply right away and every node and Service is
+//! reconciled with them, the other ones on the next restart. A file that
+//! doesn't load or parse keeps the current configuration.
+//!
+//! The same settings can also come from the settings ConfigMap, see
+//! `settings`, whose values override the file ones.
+
+use crate::config::{Cli, Config};
+use crate::config_file::ConfigFile;
+use crate::resync;
+use crate::{cooldown, drift, fip_cache, fip_filter, maintenance, notify, taints, trace, triggers};
+use crate::{Error, KubeResource};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::runtime::reflector::Store;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Environment variables of the settings applied without a restart.
+pub const RELOADABLE: &[&str] = &[
+    "FIP_INCLUDE",
+    "FIP_EXCLUDE",
+    "FIP_CACHE_TTL",
+    "DRIFT_POLICY",
+    "MOVE_COOLDOWN",
+    "MAINTENANCE_WINDOWS",
+    "REBALANCE_WINDOWS",
+    "EVACUATE_TAINTS",
+    "EVACUATE_WHEN",
+    "EVACUATE_POOL_LABEL",
+    "EVACUATE_WHEN_POOL",
+    "RESYNC_INTERVAL",
+    "JITTER_PERCENT",
+    "TRACE_BUFFER",
+    "NOTIFY_WEBHOOK_URLS",
+    "NOTIFY_SLACK_URLS",
+];
+
+/// Settings whose values are never logged.
+const SECRET: &[&str] = &["NOTIFY_WEBHOOK_URLS", "NOTIFY_SLACK_URLS"];
+
+/// Applies the reloadable settings of `config`, at startup and on reload.
+pub fn apply(config: &Config) {
+    fip_cache::set_ttl(Duration::from_secs(config.fip_cache_ttl));
+    fip_filter::set_filter(config.fip_filter());
+    drift::set_policy(config.drift_policy);
+    cooldown::set_cooldown(Duration::from_secs(config.move_cooldown));
+    maintenance::set_windows(
+        config.maintenance_windows.clone(),
+        config.rebalance_windows.clone(),
+    );
+    taints::set_triggers(config.evacuate_taints.clone());
+    triggers::set_triggers(config.triggers());
+    resync::set_interval(Duration::from_secs(config.resync_interval));
+    resync::set_jitter_percent(config.jitter_percent);
+    trace::set_capacity(config.trace_buffer);
+    notify::set(config.notifier());
+}
+
+/// The configuration in use, shared by the SIGHUP and ConfigMap watches.
+pub type Shared = Arc<Mutex<Loaded>>;
+
+/// The file and ConfigMap values are merged into the command line rather
+/// than set as environment variables, the environment mustn't change while
+/// other threads read it.
+pub struct Loaded {
+    pub path: Option<PathBuf>,
+    /// The command line the controller was started with.
+    args: Vec<OsString>,
+    /// Environment variables of the settings given by a flag or in the
+    /// environment, which take precedence over the file and the ConfigMap.
+    overridden: BTreeSet<String>,
+    /// Values of the file keyed by environment variable.
+    file: BTreeMap<&'static str, String>,
+    /// Values of the settings ConfigMap keyed by environment variable.
+    config_map: BTreeMap<&'static str, String>,
+    /// The file values overridden by the ConfigMap ones.
+    vars: BTreeMap<&'static str, String>,
+}
+
+impl Loaded {
+    /// Parses `args`, the command line matched by `matches`, with the values
+    /// of `file`.
+    pub fn new(
+        args: Vec<OsString>,
+        matches: &ArgMatches,
+        path: Option<PathBuf>,
+        file: Option<&ConfigFile>,
+    ) -> Self {
+        let overridden = Cli::command()
+            .get_arguments()
+            .filter(|arg| {
+                matches!(
+                    matches.value_source(arg.get_id().as_str()),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+            .filter_map(|arg| Some(arg.get_env()?.to_str()?.to_string()))
+            .collect();
+        let file: BTreeMap<_, _> = file
+            .map(|file| file.to_env().into_iter().collect())
+            .unwrap_or_default();
+        Loaded {
+            path,
+            args,
+            overridden,
+            vars: file.clone(),
+            file,
+            config_map: BTreeMap::new(),
+        }
+    }
+
+    /// The command line with the flags of the values in use.
+    pub fn args(&self) -> Vec<OsString> {
+        self.args_with(&self.vars)
+    }
+
+    /// The command line preceded by the flags of `vars` it doesn't override.
+    /// Empty values are left out, like clap does for environment variables.
+    fn args_with(&self, vars: &BTreeMap<&'static str, String>) -> Vec<OsString> {
+        let mut args: Vec<OsString> = self.args.iter().take(1).cloned().collect();
+        for arg in Cli::command().get_arguments() {
+            let (env, long) = match (arg.get_env().and_then(|env| env.to_str()), arg.get_long()) {
+                (Some(env), Some(long)) => (env, long),
+                _ => continue,
+            };
+            let value = match vars.get(env) {
+                Some(value) if !value.is_empty() && !self.overridden.contains(env) => value,
+                _ => continue,
+            };
+            match arg.get_action() {
+                ArgAction::SetTrue if value == "true" => args.push(format!("--{}", long).into()),
+                ArgAction::SetTrue => {}
+                _ => args.push(format!("--{}={}", long, value).into()),
+            }
+        }
+        args.extend(self.args.iter().skip(1).cloned());
+        args
+    }
+
+    /// Logs what changed from `previous`, the values in use before, prefixed
+    /// with `origin`.
+    fn log_changes(&self, origin: &str, previous: &BTreeMap<&'static str, String>) {
+        let mut keys: Vec<&str> = previous.keys().chain(self.vars.keys()).copied().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            let (old, new) = (previous.get(key), self.vars.get(key));
+            if old == new {
+                continue;
+            }
+            let change = if SECRET.contains(&key) {
+                "changed".to_string()
+            } else {
+                let value = |value: Option<&String>| {
+                    value.map_or("unset".to_string(), |value| format!("{:?}", value))
+                };
+                format!("{} -> {}", value(old), value(new))
+            };
+            let effect = if new.is_some() && self.overridden.contains(key) {
+                ", overridden by a flag or the environment"
+            } else if !RELOADABLE.contains(&key) {
+                ", applies after a restart"
+            } else {
+                ""
+            };
+            println!("{}: {} {}{}", origin, key, change, effect);
+        }
+    }
+
+    /// Applies the file values overridden by the ConfigMap ones, returns
+    /// whether anything changed. `origin` is what changed, for the logs.
+    fn update(&mut self, origin: &str) -> Result<bool, Error> {
+        let mut vars = self.file.clone();
+        vars.extend(self.config_map.clone());
+        if vars == self.vars {
+            return Ok(false);
+        }
+        // The other settings are checked by the next restart, a mistake in
+        // them mustn't exit the running controller.
+        let config = Cli::command()
+            .try_get_matches_from(self.args_with(&vars))
+            .and_then(|matches| Cli::from_arg_matches(&matches))
+            .map_err(|err| err.to_string().trim().to_string())?
+            .config;
+        let previous = std::mem::replace(&mut self.vars, vars);
+        self.log_changes(origin, &previous);
+        apply(&config);
+        Ok(true)
+    }
+
+    /// Reads the file again and applies it, returns whether anything
+    /// changed.
+    fn reload(&mut self) -> Result<bool, Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let file: BTreeMap<_, _> = ConfigFile::load(path)?.to_env().into_iter().collect();
+        if file == self.file {
+            println!("config reload: {} unchanged", path.display());
+            return Ok(false);
+        }
+        let previous = std::mem::replace(&mut self.file, file);
+        let result = self.update("config reload");
+        if result.is_err() {
+            self.file = previous;
+        }
+        result
+    }
+
+    /// Applies the runtime settings of the settings ConfigMap `origin`,
+    /// merged with the command line like the file values, returns whether
+    /// anything changed.
+    pub fn set_config_map(
+        &mut self,
+        config_map: BTreeMap<&'static str, String>,
+        origin: &str,
+    ) -> Result<bool, Error> {
+        if config_map == self.config_map {
+            return Ok(false);
+        }
+        let previous = std::mem::replace(&mut self.config_map, config_map);
+        let result = self.update(&format!("configmap {}", origin));
+        if result.is_err() {
+            self.config_map = previous;
+        }
+        result
+    }
+}
+
+/// Reloads the file of `loaded` on every SIGHUP, followed by every cached
+/// node and Service when something changed.
+pub fn watch(
+    loaded: Shared,
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+) -> impl Stream<Item = KubeResource> {
+    let hangup = signal(SignalKind::hangup()).unwrap();
+    futures::stream::unfold(hangup, move |mut hangup| {
+        let loaded = loaded.clone();
+        let nodes = nodes.clone();
+        let services = services.clone();
+        async move {
+            hangup.recv().await?;
+            let mut loaded = loaded.lock().unwrap();
+            if let Some(path) = &loaded.path {
+                println!("received SIGHUP, reloading {}", path.display());
+            }
+            let resources = match loaded.reload() {
+                Ok(true) => resync::all(&nodes, &services),
+                Ok(false) => vec![],
+                Err(err) => {
+                    println!(
+                        "config reload failed, keeping the current configuration: {}",
+                        err
+                    );
+                    vec![]
+                }
+            };
+            Some((futures::stream::iter(resources), hangup))
+        }
+    })
+    .flatten()
+}
@@ -0,0 +1,130 @@
+//! Periodic reconcile of every node and Service from the caches, catching up
+//! on floating IPs moved behind the controller's back. The period and the
+//! watch restart backoff are jittered so the controllers of many clusters
+//! sharing a project don't hit the hcloud API at the same time.
+//!
+//! Failed watches are restarted with that backoff, resuming from their last
+//! resource version, or relisting when it expired, and everything is
+//! reconciled again once one is back, so a control plane restart loses no
+//! change.
+
+use crate::{metrics, KubeResource};
+use backoff::ExponentialBackoff;
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Service as KubeService};
+use kube::runtime::reflector::Store;
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Seconds between two passes of every node and Service, 0 disables them.
+static INTERVAL_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// How often a disabled resync checks whether it was enabled meanwhile.
+const DISABLED_POLL: Duration = Duration::from_secs(10);
+
+pub fn set_interval(interval: Duration) {
+    INTERVAL_SECS.store(interval.as_secs(), Ordering::Relaxed);
+}
+
+/// Spread of the periods, in percent of their length either way.
+static JITTER_PERCENT: AtomicU64 = AtomicU64::new(20);
+
+pub fn set_jitter_percent(percent: u64) {
+    JITTER_PERCENT.store(percent, Ordering::Relaxed);
+}
+
+fn jitter_factor() -> f64 {
+    JITTER_PERCENT.load(Ordering::Relaxed) as f64 / 100.0
+}
+
+/// `period` moved by a random amount up to the jitter either way.
+pub fn jittered(period: Duration) -> Duration {
+    let factor = jitter_factor();
+    if factor == 0.0 {
+        return period;
+    }
+    period.mul_f64(rand::thread_rng().gen_range(1.0 - factor..=1.0 + factor))
+}
+
+/// Keeps retrying failed watches forever, up to every `max_interval`.
+pub fn watch_backoff(max_interval: Duration) -> ExponentialBackoff {
+    ExponentialBackoff {
+        max_interval,
+        max_elapsed_time: None,
+        randomization_factor: jitter_factor(),
+        ..Default::default()
+    }
+}
+
+/// Every cached node and Service, about every `--resync-interval`, which
+/// applies from the next pass when it changes. The first pass waits a full
+/// period, the startup reconcile already covers it.
+pub fn watch(
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+) -> impl Stream<Item = KubeResource> {
+    futures::stream::unfold((), move |()| {
+        let nodes = nodes.clone();
+        let services = services.clone();
+        async move {
+            loop {
+                let interval = Duration::from_secs(INTERVAL_SECS.load(Ordering::Relaxed));
+                if interval.is_zero() {
+                    tokio::time::sleep(DISABLED_POLL).await;
+                    continue;
+                }
+                tokio::time::sleep(jittered(interval)).await;
+                if INTERVAL_SECS.load(Ordering::Relaxed) > 0 {
+                    break;
+                }
+            }
+            Some((futures::stream::iter(all(&nodes, &services)), ()))
+        }
+    })
+    .flatten()
+}
+
+/// Every cached node and Service.
+pub fn all(nodes: &Store<KubeNode>, services: &Store<KubeService>) -> Vec<KubeResource> {
+    nodes
+        .state()
+        .into_iter()
+        .map(|node| KubeResource::Node(Box::new((*node).clone())))
+        .chain(
+            services
+                .state()
+                .into_iter()
+                .map(|service| KubeResource::Service(Box::new((*service).clone()))),
+        )
+        .collect()
+}
+
+/// Passes on the events of the `kind` watch, followed by every cached node
+/// and Service at the first event after the watch failed.
+pub fn after_reconnect<E>(
+    kind: &'static str,
+    stream: impl Stream<Item = Result<KubeResource, E>>,
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+) -> impl Stream<Item = Result<KubeResource, E>> {
+    stream
+        .scan(false, move |failed, item| {
+            let mut items = vec![];
+            match &item {
+                Err(_) => {
+                    metrics::WATCH_ERRORS.with_label_values(&[kind]).inc();
+                    *failed = true;
+                }
+                Ok(_) if *failed => {
+                    println!("watch of {} is back, reconciling everything", kind);
+                    *failed = false;
+                    items.extend(all(&nodes, &services).into_iter().map(Ok));
+                }
+                Ok(_) => {}
+            }
+            items.insert(0, item);
+            futures::future::ready(Some(futures::stream::iter(items)))
+        })
+        .flatten()
+}
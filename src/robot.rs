@@ -0,0 +1,338 @@
+use crate::{audit, is_dry_run, notify, pause, placement, trace, warn_claimants, Context, Error};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+const ROBOT_BASE_PATH: &str = "https://robot-ws.your-server.de";
+
+/// A failover IP as returned by the Robot webservice.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FailoverIp {
+    pub ip: String,
+    pub active_server_ip: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FailoverIpEnvelope {
+    failover: FailoverIp,
+}
+
+#[derive(Deserialize)]
+struct RobotServer {
+    server_ip: String,
+}
+
+#[derive(Deserialize)]
+struct RobotServerEnvelope {
+    server: RobotServer,
+}
+
+/// Minimal client for the Hetzner Robot webservice, used to route failover
+/// IPs between dedicated servers.
+#[derive(Debug, Clone)]
+pub struct RobotClient {
+    client: reqwest::Client,
+    base_path: String,
+    user: String,
+    password: String,
+}
+
+impl RobotClient {
+    pub fn new(user: String, password: String) -> Self {
+        RobotClient {
+            client: reqwest::Client::new(),
+            base_path: ROBOT_BASE_PATH.to_owned(),
+            user,
+            password,
+        }
+    }
+
+    pub async fn list_failover_ips(&self) -> Result<Vec<FailoverIp>, Error> {
+        let failover_ips = self
+            .client
+            .get(format!("{}/failover", self.base_path))
+            .basic_auth(&self.user, Some(&self.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<FailoverIpEnvelope>>()
+            .await?
+            .into_iter()
+            .map(|envelope| envelope.failover)
+            .collect();
+        Ok(failover_ips)
+    }
+
+    /// Returns the main IP of a dedicated server, which is what failover IPs
+    /// are routed to.
+    pub async fn fetch_server_ip(&self, server_number: i32) -> Result<String, Error> {
+        let server = self
+            .client
+            .get(format!("{}/server/{}", self.base_path, server_number))
+            .basic_auth(&self.user, Some(&self.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RobotServerEnvelope>()
+            .await?
+            .server;
+        Ok(server.server_ip)
+    }
+
+    pub async fn route_failover_ip(&self, ip: &str, active_server_ip: &str) -> Result<(), Error> {
+        if is_dry_run() {
+            println!(
+                "dry run: would route failover ip {} to {}",
+                ip, active_server_ip
+            );
+            trace::record(format!(
+                "dry run: route failover ip {} to {}",
+                ip, active_server_ip
+            ));
+            return Ok(());
+        }
+        println!("routing failover ip {} to {}", ip, active_server_ip);
+        trace::record(format!("route failover ip {} to {}", ip, active_server_ip));
+        self.client
+            .post(format!("{}/failover/{}", self.base_path, ip))
+            .basic_auth(&self.user, Some(&self.password))
+            .form(&[("active_server_ip", active_server_ip)])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+async fn route(
+    robot: &RobotClient,
+    failover: &FailoverIp,
+    target: &str,
+    strategy: &str,
+) -> Result<(), Error> {
+    let result = robot.route_failover_ip(&failover.ip, target).await;
+    audit::moved(
+        &failover.ip,
+        failover.active_server_ip.as_ref(),
+        target,
+        strategy,
+        &result,
+    );
+    if !is_dry_run() {
+        match &result {
+            Ok(()) => notify::reassigned(&failover.ip, failover.active_server_ip.as_ref(), target),
+            Err(err) => notify::assign_failed(&failover.ip, target, &err.to_string()),
+        }
+    }
+    result
+}
+
+/// Whether `failover` must stay where it is, logging and auditing why.
+fn is_held(failover: &FailoverIp, strategy: &str) -> bool {
+    match pause::ip_blocker(&failover.ip) {
+        Some(reason) => {
+            println!("leaving failover ip {} in place, {}", failover.ip, reason);
+            audit::skipped(
+                &failover.ip,
+                failover.active_server_ip.as_ref(),
+                strategy,
+                &reason,
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+async fn fetch_server_ips(
+    robot: &RobotClient,
+    server_numbers: &[i32],
+) -> Result<Vec<String>, Error> {
+    let mut server_ips = Vec::with_capacity(server_numbers.len());
+    for server_number in server_numbers {
+        server_ips.push(robot.fetch_server_ip(*server_number).await?);
+    }
+    Ok(server_ips)
+}
+
+/// Picks the target of each of `moving` among `available`, whose main IPs
+/// are `server_ips` in the same order: the server routing the fewest of
+/// `failover_ips`, the lowest number on a tie, so the IPs are spread rather
+/// than all moved to the same server. `None` when no server is available.
+fn targets(
+    failover_ips: &[FailoverIp],
+    moving: &[FailoverIp],
+    available: &[i32],
+    server_ips: &[String],
+) -> Vec<Option<String>> {
+    let by_ip: HashMap<&String, i32> = server_ips.iter().zip(available.iter().copied()).collect();
+    let mut load: HashMap<i32, usize> = HashMap::new();
+    for server_number in failover_ips
+        .iter()
+        .filter_map(|failover| by_ip.get(failover.active_server_ip.as_ref()?))
+    {
+        *load.entry(*server_number).or_insert(0) += 1;
+    }
+    let by_number: HashMap<i32, &String> = available.iter().copied().zip(server_ips).collect();
+    moving
+        .iter()
+        .map(|_| {
+            placement::least_loaded(available, &mut load, &HashMap::new())
+                .map(|server_number| by_number[&server_number].clone())
+        })
+        .collect()
+}
+
+/// Leaves `failover` where it is, as no dedicated server can take it, and
+/// warns on the Services claiming it.
+async fn no_target(ctx: &Context, failover: &FailoverIp, strategy: &str) {
+    println!(
+        "no available dedicated server for failover ip {}, leaving it in place",
+        failover.ip
+    );
+    trace::record(format!("skip {}, no available server", failover.ip));
+    notify::no_target(&failover.ip, failover.active_server_ip.as_ref());
+    audit::skipped(
+        &failover.ip,
+        failover.active_server_ip.as_ref(),
+        strategy,
+        "no available server",
+    );
+    let note = format!(
+        "{} is not moved, no dedicated server is available",
+        failover.ip
+    );
+    warn_claimants(ctx, &failover.ip, "NoAvailableServer", note).await;
+}
+
+/// Routes `moving` to their targets among `available`, whose main IPs are
+/// `server_ips`.
+async fn route_all(
+    ctx: &Context,
+    robot: &RobotClient,
+    failover_ips: &[FailoverIp],
+    moving: &[FailoverIp],
+    available: &[i32],
+    server_ips: &[String],
+    strategy: &str,
+) -> Result<(), Error> {
+    let targets = targets(failover_ips, moving, available, server_ips);
+    for (failover, target) in moving.iter().zip(targets) {
+        match target {
+            Some(target) => route(robot, failover, &target, strategy).await?,
+            None => no_target(ctx, failover, strategy).await,
+        }
+    }
+    Ok(())
+}
+
+/// Routes every failover IP currently pointing at `server_number` to the
+/// available dedicated servers, `available_server_numbers` being the
+/// preferred ones.
+pub async fn evacuate(
+    ctx: &Context,
+    robot: &RobotClient,
+    server_number: i32,
+    available_server_numbers: &[i32],
+) -> Result<(), Error> {
+    let server_ip = robot.fetch_server_ip(server_number).await?;
+    let failover_ips = robot.list_failover_ips().await?;
+    let moving: Vec<_> = failover_ips
+        .iter()
+        .filter(|failover| failover.active_server_ip.as_ref() == Some(&server_ip))
+        .filter(|failover| !is_held(failover, "failover"))
+        .cloned()
+        .collect();
+    if moving.is_empty() {
+        return Ok(());
+    }
+    let server_ips = fetch_server_ips(robot, available_server_numbers).await?;
+    route_all(
+        ctx,
+        robot,
+        &failover_ips,
+        &moving,
+        available_server_numbers,
+        &server_ips,
+        "failover",
+    )
+    .await
+}
+
+/// Makes sure the failover IPs among `ips` point at an available dedicated
+/// server, `available_server_numbers` being the preferred ones.
+pub async fn reassign(
+    ctx: &Context,
+    robot: &RobotClient,
+    ips: &HashSet<&String>,
+    available_server_numbers: &[i32],
+) -> Result<(), Error> {
+    let failover_ips = robot.list_failover_ips().await?;
+    if !failover_ips
+        .iter()
+        .any(|failover| ips.contains(&failover.ip))
+    {
+        return Ok(());
+    }
+    let server_ips = fetch_server_ips(robot, available_server_numbers).await?;
+    let moving: Vec<_> = failover_ips
+        .iter()
+        .filter(|failover| ips.contains(&failover.ip))
+        .filter(|failover| {
+            !failover
+                .active_server_ip
+                .as_ref()
+                .map(|ip| server_ips.contains(ip))
+                .unwrap_or(false)
+        })
+        .filter(|failover| !is_held(failover, "reassign"))
+        .cloned()
+        .collect();
+    if moving.is_empty() {
+        return Ok(());
+    }
+    route_all(
+        ctx,
+        robot,
+        &failover_ips,
+        &moving,
+        available_server_numbers,
+        &server_ips,
+        "reassign",
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failover(ip: &str, active_server_ip: Option<&str>) -> FailoverIp {
+        FailoverIp {
+            ip: ip.into(),
+            active_server_ip: active_server_ip.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn spreads_failover_ips_over_the_least_loaded_servers() {
+        let failover_ips = vec![
+            failover("198.51.100.1", Some("192.0.2.1")),
+            failover("198.51.100.2", Some("192.0.2.1")),
+            failover("198.51.100.3", Some("192.0.2.2")),
+            failover("198.51.100.4", Some("192.0.2.9")),
+            failover("198.51.100.5", None),
+        ];
+        let moving = &failover_ips[3..];
+        let server_ips = vec![
+            "192.0.2.1".to_string(),
+            "192.0.2.2".to_string(),
+            "192.0.2.3".to_string(),
+        ];
+        let spread = targets(&failover_ips, moving, &[1, 2, 3], &server_ips);
+        assert_eq!(
+            spread,
+            vec![Some("192.0.2.3".to_string()), Some("192.0.2.2".to_string())]
+        );
+        assert_eq!(targets(&failover_ips, moving, &[], &[]), vec![None, None]);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::canary;
+use crate::events::EventPublisher;
+use crate::fip_filter;
+use crate::hcloud_api;
+use crate::maintenance;
+use crate::pause;
+use crate::pools;
+use crate::projects::{self, Project};
+use crate::shutdown::{self, Shutdown};
+use crate::throttle::ActionClass;
+use crate::{available_nodes, get_hc_server_id, is_hcloud_node, metrics, move_floating_ip, Error};
+use clap::ValueEnum;
+use hcloud::apis::configuration::Configuration;
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use kube::Resource;
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How floating IPs are moved on each rotation run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RotationPolicy {
+    /// Every floating IP moves to the next node, ordered by server ID.
+    Shift,
+    /// Every floating IP moves to a random node other than its current one.
+    Shuffle,
+}
+
+#[derive(Debug, Clone)]
+pub struct RotationConfig {
+    pub interval: Duration,
+    pub policy: RotationPolicy,
+    /// hcloud label selector of the floating IPs taking part in the rotation.
+    pub fip_selector: Option<String>,
+    /// Only nodes carrying this label receive rotated floating IPs.
+    pub node_label: Option<String>,
+}
+
+/// Computes the `(floating ip, server)` moves of a rotation run.
+///
+/// `server_ids` must be sorted, floating IPs already on their target are left
+/// out of the plan.
+pub fn plan_rotation(
+    policy: RotationPolicy,
+    floating_ips: &[FloatingIp],
+    server_ids: &[i32],
+) -> Vec<(i32, i32)> {
+    if server_ids.len() < 2 {
+        return vec![];
+    }
+
+    floating_ips
+        .iter()
+        .enumerate()
+        .filter_map(|(i, fip)| {
+            let current = fip
+                .server
+                .and_then(|server| server_ids.iter().position(|id| *id == server));
+            let target = match (policy, current) {
+                (RotationPolicy::Shift, Some(current)) => {
+                    server_ids[(current + 1) % server_ids.len()]
+                }
+                (RotationPolicy::Shift, None) => server_ids[i % server_ids.len()],
+                (RotationPolicy::Shuffle, _) => **server_ids
+                    .iter()
+                    .filter(|id| Some(**id) != fip.server)
+                    .collect::<Vec<_>>()
+                    .choose(&mut rand::thread_rng())
+                    .unwrap(),
+            };
+            Some((fip.id, target)).filter(|_| fip.server != Some(target))
+        })
+        .collect()
+}
+
+async fn fetch_rotated_floating_ips(
+    hcloud_conf: &Configuration,
+    config: &RotationConfig,
+) -> Result<Vec<FloatingIp>, Error> {
+    let mut fips = hcloud_api::api()
+        .list_floating_ips(hcloud_conf, config.fip_selector.clone())
+        .await?;
+    fips.retain(|fip| !canary::is_canary(fip) && fip_filter::is_managed(fip));
+    fips.sort_by_key(|fip| fip.id);
+    Ok(fips)
+}
+
+fn has_label(node: &KubeNode, label: &Option<String>) -> bool {
+    match label {
+        Some(label) => node
+            .metadata
+            .labels
+            .as_ref()
+            .map(|labels| labels.contains_key(label))
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+async fn rotate_projects(
+    projects: &[Project],
+    nodes: &Store<KubeNode>,
+    events: &EventPublisher,
+    config: &RotationConfig,
+) -> Result<(), Error> {
+    let nodes: Vec<KubeNode> = available_nodes(nodes)
+        .into_iter()
+        .filter(|node| is_hcloud_node(node) && has_label(node, &config.node_label))
+        .collect();
+    let server_ids: HashSet<i32> = nodes.iter().filter_map(get_hc_server_id).collect();
+
+    for project in projects {
+        let server_ids = projects::project_server_ids(projects, project, &server_ids).await?;
+        let project_nodes = nodes
+            .iter()
+            .filter(|node| {
+                get_hc_server_id(node)
+                    .map(|id| server_ids.contains(&id))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        rotate(&project.conf(), project_nodes, events, config).await?;
+    }
+    Ok(())
+}
+
+async fn rotate(
+    hcloud_conf: &Configuration,
+    mut nodes: Vec<KubeNode>,
+    events: &EventPublisher,
+    config: &RotationConfig,
+) -> Result<(), Error> {
+    let fips = fetch_rotated_floating_ips(hcloud_conf, config).await?;
+    nodes.sort_by_key(get_hc_server_id);
+    let server_ids: Vec<i32> = nodes.iter().filter_map(get_hc_server_id).collect();
+
+    if server_ids.len() < 2 {
+        println!("rotation skipped, less than two eligible nodes");
+        return Ok(());
+    }
+
+    for (fip_id, server_id) in plan_rotation(config.policy, &fips, &server_ids) {
+        let fip = fips.iter().find(|fip| fip.id == fip_id).unwrap();
+        if !pools::allows(&nodes, fip, server_id) {
+            println!(
+                "rotation leaves {} in place, server {} is not in its pool",
+                fip.ip, server_id
+            );
+            continue;
+        }
+        if let Some(reason) = pause::blocker(fip) {
+            println!("rotation leaves {} in place, {}", fip.ip, reason);
+            continue;
+        }
+        let node = nodes
+            .iter()
+            .find(|node| get_hc_server_id(node) == Some(server_id))
+            .unwrap();
+        match move_floating_ip(hcloud_conf, fip, server_id, ActionClass::Rebalance).await {
+            Ok(false) => {}
+            Ok(true) => {
+                metrics::ROTATIONS.with_label_values(&["success"]).inc();
+                events
+                    .normal(
+                        node.object_ref(&()),
+                        "FloatingIPRotated",
+                        "Rotate",
+                        format!(
+                            "floating ip {} rotated from server {:?} to {}",
+                            fip.ip, fip.server, server_id
+                        ),
+                    )
+                    .await;
+            }
+            Err(err) => {
+                metrics::ROTATIONS.with_label_values(&["error"]).inc();
+                events
+                    .warning(
+                        node.object_ref(&()),
+                        "FloatingIPRotationFailed",
+                        "Rotate",
+                        format!("failed to rotate floating ip {}: {}", fip.ip, err),
+                    )
+                    .await;
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rotates the floating IPs every `config.interval`, starting one interval
+/// after startup, until shutdown is requested.
+pub async fn run(
+    projects: Vec<Project>,
+    nodes: Store<KubeNode>,
+    events: EventPublisher,
+    config: RotationConfig,
+    mut shutdown: Shutdown,
+    shutdown_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return,
+        }
+        if !maintenance::may_rebalance() {
+            println!("outside of the rebalance windows, skipping rotation");
+            continue;
+        }
+        println!("rotating floating ips");
+        let result = shutdown::run_graceful(
+            &mut shutdown,
+            shutdown_timeout,
+            "rotation",
+            rotate_projects(&projects, &nodes, &events, &config),
+        )
+        .await;
+        match result {
+            Some(Ok(())) => {
+                metrics::ROTATION_RUNS.with_label_values(&["success"]).inc();
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                metrics::LAST_ROTATION.set(now.as_secs() as i64);
+            }
+            Some(Err(err)) => {
+                metrics::ROTATION_RUNS.with_label_values(&["error"]).inc();
+                println!("rotation failed: {}", err);
+            }
+            None => metrics::ROTATION_RUNS.with_label_values(&["aborted"]).inc(),
+        }
+        if shutdown.is_requested() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! External secret backends the hcloud token can be read from, so it never
+//! has to be stored in a plain Kubernetes Secret.
+
+use crate::Error;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SecretBackendKind {
+    /// HashiCorp Vault KV secret
+    Vault,
+    /// SOPS encrypted file, decrypted with the sops binary
+    Sops,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VaultAuthMethod {
+    /// Log in with the service account token of the pod
+    Kubernetes,
+    /// Log in with a role ID and secret ID
+    Approle,
+}
+
+#[derive(Debug, Clone)]
+pub enum VaultAuth {
+    Kubernetes { role: String },
+    AppRole { role_id: String, secret_id: String },
+}
+
+/// A secret in a Vault KV engine, version 1 and 2 are both supported.
+#[derive(Debug, Clone)]
+pub struct Vault {
+    pub client: reqwest::Client,
+    pub addr: String,
+    pub auth: VaultAuth,
+    /// Mount path of the auth method, `kubernetes` or `approle` by default.
+    pub auth_mount: String,
+    /// API path of the secret, e.g. `secret/data/hcloud-fip-controller`.
+    pub path: String,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+impl Vault {
+    async fn login(&self) -> Result<String, Error> {
+        let body = match &self.auth {
+            VaultAuth::Kubernetes { role } => {
+                let jwt = fs::read_to_string(SERVICE_ACCOUNT_TOKEN)
+                    .map_err(|err| format!("failed to read {}: {}", SERVICE_ACCOUNT_TOKEN, err))?;
+                serde_json::json!({ "role": role, "jwt": jwt.trim() })
+            }
+            VaultAuth::AppRole { role_id, secret_id } => {
+                serde_json::json!({ "role_id": role_id, "secret_id": secret_id })
+            }
+        };
+        let response = self
+            .client
+            .post(format!("{}/v1/auth/{}/login", self.addr, self.auth_mount))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<LoginResponse>()
+            .await?;
+        Ok(response.auth.client_token)
+    }
+
+    async fn fetch(&self) -> Result<String, Error> {
+        let token = self.login().await?;
+        let body = self
+            .client
+            .get(format!("{}/v1/{}", self.addr, self.path))
+            .header("X-Vault-Token", token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        // KV version 2 nests the secret under a second `data`.
+        let data = &body["data"];
+        let data = if data["data"].is_object() {
+            &data["data"]
+        } else {
+            data
+        };
+        data[&self.key]
+            .as_str()
+            .map(|token| token.trim().to_string())
+            .ok_or_else(|| format!("vault secret {} has no key {}", self.path, self.key).into())
+    }
+}
+
+/// A SOPS encrypted file, age or any other key type sops supports. The
+/// decryption keys are picked up by sops itself, e.g. from
+/// `SOPS_AGE_KEY_FILE`.
+#[derive(Debug, Clone)]
+pub struct SopsFile {
+    pub path: PathBuf,
+    pub key: String,
+}
+
+impl SopsFile {
+    async fn fetch(&self) -> Result<String, Error> {
+        let output = Command::new("sops")
+            .arg("--decrypt")
+            .args(["--output-type", "json"])
+            .arg(&self.path)
+            .output()
+            .await
+            .map_err(|err| format!("failed to run sops: {}", err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to decrypt {}: {}",
+                self.path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        let data: Value = serde_json::from_slice(&output.stdout)?;
+        data[&self.key]
+            .as_str()
+            .map(|token| token.trim().to_string())
+            .ok_or_else(|| format!("{} has no key {}", self.path.display(), self.key).into())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SecretBackend {
+    Vault(Vault),
+    Sops(SopsFile),
+}
+
+impl SecretBackend {
+    /// Reads the current hcloud token from the backend.
+    pub async fn fetch_token(&self) -> Result<String, Error> {
+        match self {
+            SecretBackend::Vault(vault) => vault.fetch().await,
+            SecretBackend::Sops(file) => file.fetch().await,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Server failures seen from hcloud rather than Kubernetes. During an API
+//! server partition the Node of a server that was powered off, rebuilt or
+//! deleted can keep looking healthy, so the servers are polled and the nodes
+//! of failed ones are evacuated like a cordoned node.
+
+use crate::projects::Project;
+use crate::{fetch_servers, get_hc_server_id};
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use hcloud::models::server::Status;
+use hcloud::models::Server;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use kube::ResourceExt;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Why the servers that can't take IPs can't, by server ID.
+static FAILED: Lazy<Mutex<HashMap<i32, String>>> = Lazy::new(Default::default);
+
+fn failure(status: Status) -> Option<&'static str> {
+    match status {
+        Status::Off => Some("off"),
+        Status::Stopping => Some("stopping"),
+        Status::Deleting => Some("deleting"),
+        Status::Rebuilding => Some("rebuilding"),
+        _ => None,
+    }
+}
+
+/// Why the server with `server_id` can't take IPs right now, looked up in
+/// `servers` just listed: it is gone, locked by a running action, or not
+/// running.
+pub fn target_blocker(servers: &[Server], server_id: i32) -> Option<String> {
+    let server = match servers.iter().find(|server| server.id == server_id) {
+        Some(server) => server,
+        None => return Some("gone from hcloud".into()),
+    };
+    if server.locked {
+        return Some("locked".into());
+    }
+    match server.status {
+        Status::Running => None,
+        status => Some(format!("{:?}", status).to_lowercase()),
+    }
+}
+
+/// Why `node` can't hold IPs according to hcloud, as of the last check.
+pub fn reason(node: &KubeNode) -> Option<String> {
+    let server_id = get_hc_server_id(node)?;
+    FAILED
+        .lock()
+        .unwrap()
+        .get(&server_id)
+        .map(|reason| format!("{} in hcloud", reason))
+}
+
+/// The failed servers of `nodes` among the servers of every project, or
+/// `None` when a project couldn't be listed.
+async fn check(projects: &[Project], nodes: &Store<KubeNode>) -> Option<HashMap<i32, String>> {
+    let mut statuses = HashMap::new();
+    for project in projects {
+        match fetch_servers(&project.conf()).await {
+            Ok(servers) => {
+                statuses.extend(servers.into_iter().map(|server| (server.id, server.status)))
+            }
+            Err(err) => {
+                println!("server check of project {} failed: {}", project.name, err);
+                return None;
+            }
+        }
+    }
+    Some(
+        nodes
+            .state()
+            .iter()
+            .filter_map(|node| get_hc_server_id(node))
+            .filter_map(|server_id| match statuses.get(&server_id) {
+                None => Some((server_id, "deleted".to_string())),
+                Some(status) => failure(*status).map(|reason| (server_id, reason.to_string())),
+            })
+            .collect(),
+    )
+}
+
+/// Checks the servers of `nodes` every `interval`, yielding the nodes whose
+/// server failed or recovered since the previous check.
+pub fn watch(
+    projects: Vec<Project>,
+    nodes: Store<KubeNode>,
+    interval: Duration,
+) -> UnboundedReceiver<KubeNode> {
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let failed = match check(&projects, &nodes).await {
+                Some(failed) => failed,
+                None => continue,
+            };
+            let previous = std::mem::replace(&mut *FAILED.lock().unwrap(), failed.clone());
+            for node in nodes.state() {
+                let server_id = match get_hc_server_id(&node) {
+                    Some(server_id) => server_id,
+                    None => continue,
+                };
+                match (previous.get(&server_id), failed.get(&server_id)) {
+                    (None, Some(reason)) => println!(
+                        "server {} of node {} is {} in hcloud, moving its ips",
+                        server_id,
+                        node.name_any(),
+                        reason
+                    ),
+                    (Some(_), None) => println!(
+                        "server {} of node {} recovered in hcloud",
+                        server_id,
+                        node.name_any()
+                    ),
+                    _ => continue,
+                }
+                if sender.unbounded_send((*node).clone()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
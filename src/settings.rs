@@ -0,0 +1,118 @@
+//! Runtime settings from a watched ConfigMap, so failover behavior can be
+//! tuned through GitOps without redeploying the controller. With
+//! `--settings-configmap` the `config.yaml` key of that ConfigMap is read
+//! like the configuration file, and every change is applied live and logged,
+//! followed by a reconcile of every node and Service. Only the settings that
+//! apply without a restart may be set there; they override the file values
+//! and are overridden by flags and environment variables, merged into the
+//! command line without changing the environment. A ConfigMap that doesn't
+//! parse or holds an invalid value keeps the current settings.
+
+use crate::config_file::ConfigFile;
+use crate::reload::{Shared, RELOADABLE};
+use crate::{clusters, resync, Error, KubeResource};
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::core::v1::{ConfigMap, Node as KubeNode, Service as KubeService};
+use kube::api::ListParams;
+use kube::runtime::reflector::Store;
+use kube::runtime::watcher::{self, watcher};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client as KubeClient};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Key of the settings in the ConfigMap.
+const SETTINGS_KEY: &str = "config.yaml";
+
+/// The runtime settings of `config_map`, none when it is absent.
+fn settings(
+    config_map: Option<&ConfigMap>,
+    origin: &str,
+) -> Result<BTreeMap<&'static str, String>, Error> {
+    let content = config_map
+        .and_then(|config_map| config_map.data.as_ref())
+        .and_then(|data| data.get(SETTINGS_KEY));
+    let content = match content {
+        Some(content) => content,
+        None => return Ok(BTreeMap::new()),
+    };
+    let mut settings = BTreeMap::new();
+    for (key, value) in ConfigFile::parse(content, false, origin)?.to_env() {
+        if RELOADABLE.contains(&key) {
+            settings.insert(key, value);
+        } else {
+            println!(
+                "ignoring {} of configmap {}, it only applies after a restart",
+                key, origin
+            );
+        }
+    }
+    Ok(settings)
+}
+
+/// Applies `config_map`, returns whether anything changed.
+fn update(loaded: &Shared, config_map: Option<&ConfigMap>, origin: &str) -> bool {
+    let result = settings(config_map, origin)
+        .and_then(|settings| loaded.lock().unwrap().set_config_map(settings, origin));
+    match result {
+        Ok(changed) => changed,
+        Err(err) => {
+            println!(
+                "settings of configmap {} not applied, keeping the current ones: {}",
+                origin, err
+            );
+            false
+        }
+    }
+}
+
+/// Reads the settings ConfigMap once, so the first reconciles already use
+/// its settings.
+pub async fn init(client: KubeClient, config_map: &str, loaded: &Shared) -> Result<(), Error> {
+    let (namespace, name) = clusters::namespaced_name(config_map);
+    let api = Api::<ConfigMap>::namespaced(client, &namespace);
+    let origin = format!("{}/{}", namespace, name);
+    update(loaded, api.get_opt(&name).await?.as_ref(), &origin);
+    Ok(())
+}
+
+/// Follows the settings ConfigMap, yielding every cached node and Service
+/// when its settings changed.
+pub fn watch(
+    client: KubeClient,
+    config_map: &str,
+    loaded: Shared,
+    nodes: Store<KubeNode>,
+    services: Store<KubeService>,
+    max_backoff: Duration,
+) -> impl Stream<Item = KubeResource> {
+    let (namespace, name) = clusters::namespaced_name(config_map);
+    let origin = format!("{}/{}", namespace, name);
+    println!("following the settings of configmap {}", origin);
+    let api = Api::<ConfigMap>::namespaced(client, &namespace);
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    watcher(api, params)
+        .backoff(resync::watch_backoff(max_backoff))
+        .filter_map(move |event| {
+            let changed = match event {
+                Ok(watcher::Event::Applied(config_map)) => {
+                    update(&loaded, Some(&config_map), &origin)
+                }
+                Ok(watcher::Event::Deleted(_)) => update(&loaded, None, &origin),
+                Ok(watcher::Event::Restarted(config_maps)) => {
+                    update(&loaded, config_maps.first(), &origin)
+                }
+                Err(err) => {
+                    println!("settings configmap watch failed: {}", err);
+                    false
+                }
+            };
+            let resources = if changed {
+                resync::all(&nodes, &services)
+            } else {
+                vec![]
+            };
+            futures::future::ready(Some(futures::stream::iter(resources)))
+        })
+        .flatten()
+}
@@ -0,0 +1,80 @@
+use std::future::Future;
+use std::io::Write;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Resolves once SIGTERM or SIGINT has been received. Cheap to clone and can
+/// be awaited any number of times.
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Installs the signal handlers.
+    pub fn listen() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            let mut sigterm = signal(SignalKind::terminate()).unwrap();
+            tokio::select! {
+                _ = sigterm.recv() => println!("received SIGTERM, shutting down"),
+                _ = tokio::signal::ctrl_c() => println!("received SIGINT, shutting down"),
+            }
+            let _ = sender.send(true);
+        });
+        Shutdown { receiver }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub async fn wait(&mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Lets `future` finish unless `deadline` passes first, returns `None` when
+/// it was abandoned.
+pub async fn finish_before<F: Future>(
+    deadline: Instant,
+    what: &str,
+    future: F,
+) -> Option<F::Output> {
+    match tokio::time::timeout_at(deadline, future).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            println!("gave up waiting for {} to finish", what);
+            None
+        }
+    }
+}
+
+/// Runs `future` to completion, unless shutdown is requested meanwhile and it
+/// then takes longer than `grace` to finish.
+pub async fn run_graceful<F: Future>(
+    shutdown: &mut Shutdown,
+    grace: Duration,
+    what: &str,
+    future: F,
+) -> Option<F::Output> {
+    tokio::pin!(future);
+    tokio::select! {
+        output = &mut future => Some(output),
+        _ = shutdown.wait() => {
+            println!("waiting up to {:?} for {} to finish", grace, what);
+            finish_before(Instant::now() + grace, what, future).await
+        }
+    }
+}
+
+pub fn flush() {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+}
@@ -0,0 +1,250 @@
+//! Snapshots of the floating IP to server assignments, taken before risky
+//! maintenance so a known-good layout can be reinstated afterwards.
+
+use crate::clusters;
+use crate::projects::Project;
+use crate::throttle::ActionClass;
+use crate::{fetch_floating_ips, fip_cache, move_floating_ip, ownership, Error};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::api::{Patch, PatchParams};
+use kube::Api;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+const API_VERSION: &str = "fip.hcloud.barodeur.io/v1";
+const KIND: &str = "AssignmentSnapshot";
+const CONFIG_MAP_KEY: &str = "snapshot.yaml";
+const FIELD_MANAGER: &str = "hcloud-fip-controller";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub api_version: String,
+    pub kind: String,
+    pub created_at: String,
+    #[serde(default)]
+    pub assignments: Vec<Assignment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Assignment {
+    pub project: String,
+    pub floating_ip: String,
+    pub id: i32,
+    pub server: Option<i32>,
+}
+
+/// Where a snapshot is written to or read from.
+pub enum Location {
+    /// Standard output or input.
+    Stdio,
+    File(PathBuf),
+    ConfigMap {
+        namespace: String,
+        name: String,
+    },
+}
+
+impl Location {
+    pub fn new(
+        path: Option<PathBuf>,
+        config_map: Option<String>,
+        namespace: Option<String>,
+    ) -> Self {
+        match (path, config_map) {
+            (_, Some(name)) => Location::ConfigMap {
+                namespace: namespace
+                    .or_else(|| env::var("POD_NAMESPACE").ok())
+                    .unwrap_or_else(|| "default".into()),
+                name,
+            },
+            (Some(path), None) => Location::File(path),
+            (None, None) => Location::Stdio,
+        }
+    }
+
+    async fn write(&self, content: &str) -> Result<(), Error> {
+        match self {
+            Location::Stdio => print!("{}", content),
+            Location::File(path) => fs::write(path, content)
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))?,
+            Location::ConfigMap { namespace, name } => {
+                let api = Api::<ConfigMap>::namespaced(clusters::local_client().await?, namespace);
+                let config_map = serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": { "name": name },
+                    "data": { CONFIG_MAP_KEY: content },
+                });
+                api.patch(
+                    name,
+                    &PatchParams::apply(FIELD_MANAGER).force(),
+                    &Patch::Apply(&config_map),
+                )
+                .await?;
+                eprintln!("snapshot saved to configmap {}/{}", namespace, name);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self) -> Result<String, Error> {
+        match self {
+            Location::Stdio => Ok(io::read_to_string(io::stdin())?),
+            Location::File(path) => Ok(fs::read_to_string(path)
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err))?),
+            Location::ConfigMap { namespace, name } => {
+                let api = Api::<ConfigMap>::namespaced(clusters::local_client().await?, namespace);
+                api.get(name)
+                    .await?
+                    .data
+                    .and_then(|mut data| data.remove(CONFIG_MAP_KEY))
+                    .ok_or_else(|| {
+                        format!(
+                            "configmap {}/{} has no {} key",
+                            namespace, name, CONFIG_MAP_KEY
+                        )
+                        .into()
+                    })
+            }
+        }
+    }
+}
+
+async fn take(projects: &[Project]) -> Result<Snapshot, Error> {
+    let mut assignments = vec![];
+    for project in projects {
+        let hcloud_conf = &project.conf();
+        fip_cache::invalidate(hcloud_conf);
+        for fip in fetch_floating_ips(hcloud_conf).await? {
+            assignments.push(Assignment {
+                project: project.name.clone(),
+                floating_ip: fip.ip,
+                id: fip.id,
+                server: fip.server,
+            });
+        }
+    }
+    Ok(Snapshot {
+        api_version: API_VERSION.into(),
+        kind: KIND.into(),
+        created_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        assignments,
+    })
+}
+
+/// Saves the current assignments of every project.
+pub async fn save(projects: &[Project], location: &Location) -> Result<(), Error> {
+    let snapshot = take(projects).await?;
+    location.write(&serde_yaml::to_string(&snapshot)?).await
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Prints the moves needed to get back to the snapshot at `location` and
+/// performs them once confirmed, or right away with `yes`.
+///
+/// Floating IPs unassigned in the snapshot, that no longer exist or are owned
+/// by another controller, are left alone. The moves go through
+/// `move_floating_ip` like manual ones, so they take the floating IP locks,
+/// are throttled, audited and notified, and skipped when an IP was moved
+/// since the plan was printed.
+pub async fn restore(projects: &[Project], location: &Location, yes: bool) -> Result<(), Error> {
+    if matches!(location, Location::Stdio) && !yes {
+        return Err(
+            "confirmation is read from standard input, pass --yes to restore from it".into(),
+        );
+    }
+    let snapshot: Snapshot = serde_yaml::from_str(&location.read().await?)?;
+    if snapshot.api_version != API_VERSION || snapshot.kind != KIND {
+        return Err(format!(
+            "unsupported snapshot {}/{}, expected {}/{}",
+            snapshot.api_version, snapshot.kind, API_VERSION, KIND
+        )
+        .into());
+    }
+
+    let mut current: BTreeMap<(String, i32), FloatingIp> = BTreeMap::new();
+    for project in projects {
+        let hcloud_conf = &project.conf();
+        fip_cache::invalidate(hcloud_conf);
+        for fip in fetch_floating_ips(hcloud_conf).await? {
+            current.insert((project.name.clone(), fip.id), fip);
+        }
+    }
+    let plan: Vec<&Assignment> = snapshot
+        .assignments
+        .iter()
+        .filter(|assignment| assignment.server.is_some())
+        .filter(
+            |assignment| match current.get(&(assignment.project.clone(), assignment.id)) {
+                Some(fip) if fip.server == assignment.server => false,
+                Some(fip) => match ownership::blocker(fip) {
+                    Some(reason) => {
+                        eprintln!("skipping {}, {}", assignment.floating_ip, reason);
+                        false
+                    }
+                    None => true,
+                },
+                None => {
+                    eprintln!(
+                        "skipping {}, it no longer exists in project {}",
+                        assignment.floating_ip, assignment.project
+                    );
+                    false
+                }
+            },
+        )
+        .collect();
+
+    if plan.is_empty() {
+        eprintln!(
+            "assignments already match the snapshot of {}",
+            snapshot.created_at
+        );
+        return Ok(());
+    }
+    eprintln!("restoring the snapshot of {}:", snapshot.created_at);
+    for assignment in &plan {
+        eprintln!(
+            "  {} ({}): {:?} -> {}",
+            assignment.floating_ip,
+            assignment.project,
+            current[&(assignment.project.clone(), assignment.id)].server,
+            assignment.server.unwrap()
+        );
+    }
+    if !yes && !confirm(&format!("move {} floating ips?", plan.len()))? {
+        eprintln!("aborted");
+        return Ok(());
+    }
+
+    for assignment in plan {
+        let project = projects
+            .iter()
+            .find(|project| project.name == assignment.project)
+            .unwrap();
+        let fip = &current[&(assignment.project.clone(), assignment.id)];
+        move_floating_ip(
+            &project.conf(),
+            fip,
+            assignment.server.unwrap(),
+            ActionClass::Manual,
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Floating IP failover between a static list of hcloud servers, without
+//! Kubernetes. Servers are considered available when they pass the health
+//! check.
+
+use crate::fip_filter;
+use crate::hcloud_api;
+use crate::health::{self, HealthCheck};
+use crate::projects::Project;
+use crate::shutdown::{self, Shutdown};
+use crate::{assign_floating_ip_to_server, fetch_servers, fip_locks, Error};
+use hcloud::models::FloatingIp;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct StandaloneConfig {
+    /// Candidate servers, in order of preference.
+    pub server_ids: Vec<i32>,
+    /// hcloud label selector of the managed floating IPs, all by default.
+    pub fip_selector: Option<String>,
+    pub health_check: HealthCheck,
+    /// Probe the servers on their IP in this private network instead of
+    /// their public IPv4.
+    pub network: Option<i32>,
+    pub interval: Duration,
+}
+
+async fn fetch_managed_floating_ips(
+    project: &Project,
+    config: &StandaloneConfig,
+) -> Result<Vec<FloatingIp>, Error> {
+    let mut fips = hcloud_api::api()
+        .list_floating_ips(&project.conf(), config.fip_selector.clone())
+        .await?;
+    fips.retain(fip_filter::is_managed);
+    Ok(fips)
+}
+
+/// Returns the configured servers of `project` passing the health check, in
+/// order of preference.
+async fn healthy_server_ids(
+    project: &Project,
+    config: &StandaloneConfig,
+) -> Result<Vec<i32>, Error> {
+    let servers = fetch_servers(&project.conf()).await?;
+    let mut healthy = vec![];
+    for server_id in &config.server_ids {
+        let server = match servers.iter().find(|server| server.id == *server_id) {
+            Some(server) => server,
+            None => continue,
+        };
+        let ip = match health::probe_address(server, config.network) {
+            Some(ip) => ip,
+            None => {
+                println!("server {} has no address to probe", server.id);
+                continue;
+            }
+        };
+        if config.health_check.probe(ip).await {
+            healthy.push(server.id);
+        } else {
+            println!("server {} failed its health check", server.id);
+        }
+    }
+    Ok(healthy)
+}
+
+async fn reconcile(project: &Project, config: &StandaloneConfig) -> Result<(), Error> {
+    let healthy = healthy_server_ids(project, config).await?;
+    let fips = fetch_managed_floating_ips(project, config).await?;
+
+    for fip in fips {
+        let is_healthy = fip
+            .server
+            .map(|server| healthy.contains(&server))
+            .unwrap_or(false);
+        if is_healthy {
+            continue;
+        }
+        match healthy.first() {
+            Some(server_id) => {
+                let hcloud_conf = project.conf();
+                fip_locks::locked(
+                    fip.id,
+                    assign_floating_ip_to_server(&hcloud_conf, &fip.id, server_id),
+                )
+                .await?
+            }
+            None => println!("no healthy server for floating ip {}", fip.ip),
+        }
+    }
+    Ok(())
+}
+
+/// Checks the servers every `config.interval` and moves the floating IPs off
+/// the unhealthy ones, until shutdown is requested.
+pub async fn run(
+    projects: Vec<Project>,
+    config: StandaloneConfig,
+    mut shutdown: Shutdown,
+    shutdown_timeout: Duration,
+) -> Result<(), Error> {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return Ok(()),
+        }
+        let round = async {
+            for project in &projects {
+                if let Err(err) = reconcile(project, &config).await {
+                    println!("reconcile of project {} failed: {}", project.name, err);
+                }
+            }
+        };
+        shutdown::run_graceful(&mut shutdown, shutdown_timeout, "health check round", round).await;
+        if shutdown.is_requested() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! One-shot report of the initial reconcile pass, so operators can check that a
+//! new deployment did what they expected. It is logged, published as an event
+//! on the controller's pod and served on `/v1/startup-report` by the admin
+//! API.
+
+use crate::conflicts;
+use crate::{
+    available_hc_server_ids, claims_ips, evacuation_reason, fetch_floating_ips, fip_cache,
+    get_robot_server_number, is_hcloud_node, reconcile, Context, Error, KubeResource,
+};
+use k8s_openapi::api::core::v1::ObjectReference;
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartupReport {
+    pub generated_at: String,
+    /// Floating IPs the controller is responsible for.
+    pub managed_floating_ips: Vec<String>,
+    /// Managed floating IPs already on an available server, left in place.
+    pub adopted_floating_ips: Vec<String>,
+    pub drift: Vec<Drift>,
+    pub actions: Vec<Action>,
+    pub skipped: Vec<Skipped>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Drift {
+    pub ip: String,
+    pub server: Option<i32>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Action {
+    pub ip: String,
+    pub from: Option<i32>,
+    pub to: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Skipped {
+    pub kind: String,
+    pub name: String,
+    pub reason: String,
+}
+
+static REPORT: OnceCell<StartupReport> = OnceCell::new();
+
+/// The report once the initial pass is over.
+pub fn report() -> Option<&'static StartupReport> {
+    REPORT.get()
+}
+
+/// Floating IP to server of every project.
+async fn fetch_assignments(ctx: &Context) -> Result<HashMap<String, Option<i32>>, Error> {
+    let mut assignments = HashMap::new();
+    for project in &ctx.projects {
+        let hcloud_conf = &project.conf();
+        fip_cache::invalidate(hcloud_conf);
+        for fip in fetch_floating_ips(hcloud_conf).await? {
+            assignments.insert(fip.ip, fip.server);
+        }
+    }
+    Ok(assignments)
+}
+
+fn skipped_nodes(ctx: &Context) -> Vec<Skipped> {
+    ctx.nodes
+        .state()
+        .iter()
+        .filter_map(|node| {
+            let reason = if let Some(reason) = evacuation_reason(node) {
+                reason
+            } else if get_robot_server_number(node).is_some() && ctx.robot.is_none() {
+                "Robot node but no Robot credentials configured".into()
+            } else if !is_hcloud_node(node) && get_robot_server_number(node).is_none() {
+                "neither an hcloud nor a Robot node".into()
+            } else {
+                return None;
+            };
+            Some(Skipped {
+                kind: "Node".into(),
+                name: node.metadata.name.clone().unwrap_or_default(),
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// Reconciles every node and service once, then records and publishes what
+/// was found and done.
+pub async fn run(ctx: &Context) -> Result<(), Error> {
+    let services = ctx.services.state();
+    let before = fetch_assignments(ctx).await?;
+    let available = available_hc_server_ids(&ctx.nodes);
+
+    let mut skipped = skipped_nodes(ctx);
+    let mut managed: HashSet<String> = HashSet::new();
+    if ctx.gateway_config.is_some() {
+        managed.extend(before.keys().cloned());
+    } else {
+        for service in &services {
+            let name = format!(
+                "{}/{}",
+                service.metadata.namespace.as_deref().unwrap_or_default(),
+                service.metadata.name.as_deref().unwrap_or_default()
+            );
+            let skip = |reason: &str| Skipped {
+                kind: "Service".into(),
+                name: name.clone(),
+                reason: reason.into(),
+            };
+            if !claims_ips(service) {
+                continue;
+            }
+            let ips = conflicts::claimed_ips(service);
+            if ips.is_empty() {
+                skipped.push(skip("no ingress ip yet"));
+                continue;
+            }
+            let conflicts = conflicts::find_conflicts(service, &ctx.services);
+            for ip in ips.into_iter().cloned() {
+                if let Some((_, other)) = conflicts.iter().find(|(conflict, _)| *conflict == ip) {
+                    skipped.push(skip(&format!(
+                        "ingress ip {} is also claimed by {}",
+                        ip, other
+                    )));
+                    continue;
+                }
+                let is_alias_ip = ctx.alias_ips.iter().any(|alias| alias.ip == ip);
+                if before.contains_key(&ip) {
+                    managed.insert(ip);
+                } else if !is_alias_ip && ctx.robot.is_none() {
+                    skipped.push(skip(&format!("ingress ip {} is not a floating ip", ip)));
+                }
+            }
+        }
+    }
+
+    let mut managed: Vec<String> = managed.into_iter().collect();
+    managed.sort();
+    let mut adopted = vec![];
+    let mut drift = vec![];
+    for ip in &managed {
+        match before[ip] {
+            Some(server) if available.contains(&server) => adopted.push(ip.clone()),
+            Some(server) => drift.push(Drift {
+                ip: ip.clone(),
+                server: Some(server),
+                reason: format!("held by unavailable server {}", server),
+            }),
+            None => drift.push(Drift {
+                ip: ip.clone(),
+                server: None,
+                reason: "unassigned".into(),
+            }),
+        }
+    }
+
+    let mut errors = vec![];
+    let mut resources: Vec<KubeResource> = ctx
+        .nodes
+        .state()
+        .iter()
+        .filter(|node| is_hcloud_node(node) || get_robot_server_number(node).is_some())
+        .map(|node| KubeResource::Node(Box::new((**node).clone())))
+        .collect();
+    if ctx.gateway_config.is_some() {
+        resources.truncate(1);
+    } else {
+        resources.extend(
+            services
+                .into_iter()
+                .map(|service| KubeResource::Service(Box::new((*service).clone()))),
+        );
+    }
+    for resource in resources {
+        if let Err(err) = reconcile(ctx, resource).await {
+            errors.push(err.to_string());
+        }
+    }
+
+    let after = fetch_assignments(ctx).await?;
+    let actions = managed
+        .iter()
+        .filter(|ip| before[*ip] != after.get(*ip).copied().flatten())
+        .map(|ip| Action {
+            ip: ip.clone(),
+            from: before[ip],
+            to: after.get(ip).copied().flatten(),
+        })
+        .collect();
+
+    let report = StartupReport {
+        generated_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        managed_floating_ips: managed,
+        adopted_floating_ips: adopted,
+        drift,
+        actions,
+        skipped,
+        errors,
+    };
+    publish(ctx, &report).await;
+    let _ = REPORT.set(report);
+    Ok(())
+}
+
+fn summary(report: &StartupReport) -> String {
+    format!(
+        "startup reconcile: {} managed, {} adopted, {} drifted, {} moved, {} skipped, {} errors",
+        report.managed_floating_ips.len(),
+        report.adopted_floating_ips.len(),
+        report.drift.len(),
+        report.actions.len(),
+        report.skipped.len(),
+        report.errors.len()
+    )
+}
+
+async fn publish(ctx: &Context, report: &StartupReport) {
+    println!("{}", summary(report));
+    for drift in &report.drift {
+        println!("  drift: {} {}", drift.ip, drift.reason);
+    }
+    for action in &report.actions {
+        println!(
+            "  moved: {} from {:?} to {:?}",
+            action.ip, action.from, action.to
+        );
+    }
+    for skipped in &report.skipped {
+        println!(
+            "  skipped: {} {}: {}",
+            skipped.kind, skipped.name, skipped.reason
+        );
+    }
+    for error in &report.errors {
+        println!("  error: {}", error);
+    }
+
+    if let (Ok(name), Ok(namespace)) = (env::var("POD_NAME"), env::var("POD_NAMESPACE")) {
+        let pod = ObjectReference {
+            api_version: Some("v1".into()),
+            kind: Some("Pod".into()),
+            name: Some(name),
+            namespace: Some(namespace),
+            ..Default::default()
+        };
+        ctx.events
+            .normal(pod, "StartupReconciled", "Reconcile", summary(report))
+            .await;
+    }
+}
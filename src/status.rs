@@ -0,0 +1,208 @@
+//! Cluster-scoped `FipControllerStatus` resource summarizing the health of
+//! the controller, so `kubectl get fipcontrollerstatus` works as an
+//! at-a-glance check without metrics infrastructure.
+
+use crate::fip_status::FloatingIpStatus;
+use crate::projects::Project;
+use crate::{circuit, fetch_floating_ips, fip_locks, Error};
+use k8s_openapi::chrono::{DateTime, SecondsFormat, Utc};
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client as KubeClient, CustomResource, CustomResourceExt, Resource};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const FIELD_MANAGER: &str = "hcloud-fip-controller";
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the status Lease outlives a leader that stopped publishing.
+const LEADER_LEASE: Duration = Duration::from_secs(90);
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[kube(
+    group = "fip.hcloud.barodeur.io",
+    version = "v1",
+    kind = "FipControllerStatus",
+    status = "ControllerStatus",
+    shortname = "fipstatus",
+    printcolumn = r#"{"name":"Leader", "type":"string", "jsonPath":".status.leader"}"#,
+    printcolumn = r#"{"name":"Managed", "type":"integer", "jsonPath":".status.managedFloatingIps"}"#,
+    printcolumn = r#"{"name":"Errors", "type":"integer", "jsonPath":".status.reconcileErrors"}"#,
+    printcolumn = r#"{"name":"Last Sync", "type":"date", "jsonPath":".status.lastSync"}"#
+)]
+pub struct FipControllerStatusSpec {}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ControllerStatus {
+    /// Holder of the status Lease publishing this status, the instance
+    /// itself without `--fip-lease-namespace`.
+    pub leader: Option<String>,
+    /// Last time a watch event was reconciled successfully.
+    pub last_sync: Option<String>,
+    pub managed_floating_ips: i64,
+    pub reconcile_errors: i64,
+    pub last_error: Option<String>,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub last_transition_time: String,
+}
+
+#[derive(Default)]
+struct State {
+    last_sync: Option<DateTime<Utc>>,
+    last_error: Option<(DateTime<Utc>, String)>,
+    reconcile_errors: i64,
+    degraded_since: Option<DateTime<Utc>>,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(Default::default);
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Records a successfully reconciled event.
+pub fn record_sync() {
+    let mut state = STATE.lock().unwrap();
+    state.last_sync = Some(Utc::now());
+    state.degraded_since = None;
+}
+
+/// Records a failed reconcile, the controller is degraded until the next
+/// successful one.
+pub fn record_error(err: &Error) {
+    let mut state = STATE.lock().unwrap();
+    let now = Utc::now();
+    state.reconcile_errors += 1;
+    state.last_error = Some((now, err.to_string()));
+    state.degraded_since.get_or_insert(now);
+}
+
+/// The failed reconciles so far, and the last error with when it happened.
+pub fn errors() -> (i64, Option<(String, String)>) {
+    let state = STATE.lock().unwrap();
+    let last_error = state
+        .last_error
+        .as_ref()
+        .map(|(at, message)| (timestamp(*at), message.clone()));
+    (state.reconcile_errors, last_error)
+}
+
+fn instance() -> String {
+    env::var("POD_NAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+fn snapshot(leader: String, managed_floating_ips: i64) -> ControllerStatus {
+    let state = STATE.lock().unwrap();
+    let degraded = match (state.degraded_since, &state.last_error) {
+        _ if circuit::open_since().is_some() => Condition {
+            type_: "Degraded".into(),
+            status: "True".into(),
+            reason: Some("HcloudUnavailable".into()),
+            message: Some("hcloud api circuit open".into()),
+            last_transition_time: timestamp(circuit::open_since().unwrap()),
+        },
+        (Some(since), Some((_, message))) => Condition {
+            type_: "Degraded".into(),
+            status: "True".into(),
+            reason: Some("ReconcileFailed".into()),
+            message: Some(message.clone()),
+            last_transition_time: timestamp(since),
+        },
+        _ => Condition {
+            type_: "Degraded".into(),
+            status: "False".into(),
+            reason: None,
+            message: None,
+            last_transition_time: timestamp(state.last_sync.unwrap_or_else(Utc::now)),
+        },
+    };
+    ControllerStatus {
+        leader: Some(leader),
+        last_sync: state.last_sync.map(timestamp),
+        managed_floating_ips,
+        reconcile_errors: state.reconcile_errors,
+        last_error: state
+            .last_error
+            .as_ref()
+            .map(|(_, message)| message.clone()),
+        conditions: vec![degraded],
+    }
+}
+
+async fn count_floating_ips(projects: &[Project]) -> Result<i64, Error> {
+    let mut count = 0;
+    for project in projects {
+        count += fetch_floating_ips(&project.conf()).await?.len() as i64;
+    }
+    Ok(count)
+}
+
+async fn publish(
+    api: &Api<FipControllerStatus>,
+    name: &str,
+    projects: &[Project],
+) -> Result<(), Error> {
+    // Only the replica holding the status Lease publishes, so the leader
+    // doesn't flip between replicas every interval.
+    let leader = match fip_locks::leases_enabled() {
+        true => {
+            let lease = format!("hcloud-fip-status-{}", name);
+            if !fip_locks::hold(&lease, LEADER_LEASE).await? {
+                return Ok(());
+            }
+            fip_locks::identity().to_string()
+        }
+        false => instance(),
+    };
+    let managed = count_floating_ips(projects).await?;
+    let params = PatchParams::apply(FIELD_MANAGER).force();
+
+    let object = FipControllerStatus::new(name, FipControllerStatusSpec {});
+    api.patch(name, &params, &Patch::Apply(&object)).await?;
+
+    let status = serde_json::json!({
+        "apiVersion": FipControllerStatus::api_version(&()),
+        "kind": FipControllerStatus::kind(&()),
+        "status": snapshot(leader, managed),
+    });
+    api.patch_status(name, &params, &Patch::Apply(&status))
+        .await?;
+    Ok(())
+}
+
+/// Publishes the controller status to the `FipControllerStatus` named `name`
+/// every 30 seconds, from one replica at a time with `--fip-lease-namespace`.
+pub async fn run(client: KubeClient, name: String, projects: Vec<Project>) {
+    let api = Api::<FipControllerStatus>::all(client);
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = publish(&api, &name, &projects).await {
+            println!("failed to publish controller status: {}", err);
+        }
+    }
+}
+
+/// Prints the CustomResourceDefinitions of `FipControllerStatus` and
+/// `FloatingIPStatus`.
+pub fn print_crd() -> Result<(), Error> {
+    print!("{}", serde_yaml::to_string(&FipControllerStatus::crd())?);
+    println!("---");
+    print!("{}", serde_yaml::to_string(&FloatingIpStatus::crd())?);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Integration with systemd for deployments outside Kubernetes: readiness and
+//! watchdog notifications, and socket activation of the metrics listener.
+
+use std::env;
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+
+/// First file descriptor passed by systemd socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sends `state` to the systemd notification socket, if any.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(err) = result {
+        println!("failed to notify systemd: {}", err);
+    }
+}
+
+pub fn ready() {
+    notify("READY=1");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Returns whether variables set by systemd for a process are meant for us
+/// rather than inherited from a parent.
+fn is_for_this_process(pid_var: &str) -> bool {
+    match env::var(pid_var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(process::id()),
+        Err(_) => true,
+    }
+}
+
+/// The watchdog interval requested by systemd through `WATCHDOG_USEC`.
+fn watchdog_interval() -> Option<Duration> {
+    if !is_for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Pings the systemd watchdog at half the requested interval so systemd
+/// restarts the controller when its runtime hangs.
+pub fn spawn_watchdog() {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval / 2,
+        None => return,
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Takes the first socket passed by systemd socket activation, if any.
+pub fn take_listener() -> Option<TcpListener> {
+    if !is_for_this_process("LISTEN_PID") || env::var("LISTEN_PID").is_err() {
+        return None;
+    }
+    let count = env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    if count < 1 {
+        return None;
+    }
+    // SAFETY: systemd passes ownership of the descriptors starting at
+    // LISTEN_FDS_START to this process, nothing else uses them.
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
@@ -0,0 +1,26 @@
+//! Node taints that make the controller evacuate a node like a cordon does,
+//! so node-problem-detector or an operator can move the IPs off a node
+//! without draining it.
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use once_cell::sync::Lazy;
+use std::sync::RwLock;
+
+static TRIGGERS: Lazy<RwLock<Vec<String>>> = Lazy::new(Default::default);
+
+pub fn set_triggers(keys: Vec<String>) {
+    *TRIGGERS.write().unwrap() = keys;
+}
+
+/// The key of the first taint of `node` that triggers an evacuation, whatever
+/// its effect.
+pub fn trigger(node: &KubeNode) -> Option<&str> {
+    let triggers = TRIGGERS.read().unwrap();
+    node.spec
+        .as_ref()?
+        .taints
+        .as_ref()?
+        .iter()
+        .map(|taint| taint.key.as_str())
+        .find(|key| triggers.iter().any(|trigger| trigger == key))
+}
@@ -0,0 +1,270 @@
+//! Priority ordering of hcloud IP moves.
+//!
+//! At most `--hcloud-max-inflight` moves run at once, and every move is held
+//! back for a while after hcloud answered with 429 Too Many Requests. Moves
+//! waiting for their turn are let through by class, failovers of failed nodes
+//! first and rebalances last, so throttling delays the least urgent work.
+
+use crate::metrics;
+use once_cell::sync::Lazy;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// How long moves are held back after hcloud throttled one.
+const THROTTLE_PAUSE: Duration = Duration::from_secs(10);
+
+static MAX_IN_FLIGHT: AtomicUsize = AtomicUsize::new(4);
+
+/// Why an IP is moved, in decreasing order of urgency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ActionClass {
+    /// Moving IPs off a failed or cordoned node.
+    Failover,
+    /// Moves asked for through the admin API.
+    Manual,
+    /// Giving an available server to IPs that have none.
+    Reassign,
+    /// Moving IPs that are fine where they are, e.g. rotation.
+    Rebalance,
+}
+
+impl ActionClass {
+    pub fn label(self) -> &'static str {
+        match self {
+            ActionClass::Failover => "failover",
+            ActionClass::Manual => "manual",
+            ActionClass::Reassign => "reassign",
+            ActionClass::Rebalance => "rebalance",
+        }
+    }
+}
+
+struct Waiter {
+    class: ActionClass,
+    seq: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    /// The most urgent class first, then the longest waiting.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .class
+            .cmp(&self.class)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Gate {
+    in_flight: usize,
+    paused_until: Option<Instant>,
+    waiters: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+impl Gate {
+    fn can_start(&self) -> bool {
+        let paused = self
+            .paused_until
+            .map(|until| Instant::now() < until)
+            .unwrap_or(false);
+        !paused && self.in_flight < MAX_IN_FLIGHT.load(Ordering::Relaxed)
+    }
+
+    fn dispatch(&mut self) {
+        while self.can_start() {
+            let waiter = match self.waiters.pop() {
+                Some(waiter) => waiter,
+                None => return,
+            };
+            metrics::ACTIONS_WAITING
+                .with_label_values(&[waiter.class.label()])
+                .dec();
+            // The waiter is gone when its reconcile was abandoned.
+            if waiter.wake.send(()).is_ok() {
+                self.in_flight += 1;
+            }
+        }
+    }
+}
+
+static GATE: Lazy<Mutex<Gate>> = Lazy::new(Default::default);
+
+pub fn set_max_in_flight(max_in_flight: usize) {
+    MAX_IN_FLIGHT.store(max_in_flight, Ordering::Relaxed);
+}
+
+pub fn max_in_flight() -> usize {
+    MAX_IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+fn release() {
+    let mut gate = GATE.lock().unwrap();
+    gate.in_flight -= 1;
+    gate.dispatch();
+}
+
+/// Allows one hcloud move until dropped.
+pub struct Permit(());
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+/// Releases the turn given to a waiter that stopped waiting in between.
+struct Waiting(Option<oneshot::Receiver<()>>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.0.take() {
+            if receiver.try_recv().is_ok() {
+                release();
+            }
+        }
+    }
+}
+
+/// Waits for the turn of a move of the given class.
+pub async fn acquire(class: ActionClass) -> Permit {
+    let started = Instant::now();
+    let receiver = {
+        let mut gate = GATE.lock().unwrap();
+        if gate.waiters.is_empty() && gate.can_start() {
+            gate.in_flight += 1;
+            None
+        } else {
+            let (wake, receiver) = oneshot::channel();
+            let seq = gate.next_seq;
+            gate.next_seq += 1;
+            gate.waiters.push(Waiter { class, seq, wake });
+            metrics::ACTIONS_WAITING
+                .with_label_values(&[class.label()])
+                .inc();
+            Some(receiver)
+        }
+    };
+    if let Some(receiver) = receiver {
+        let mut waiting = Waiting(Some(receiver));
+        // The sender is only dropped once the turn was given.
+        let _ = waiting.0.as_mut().unwrap().await;
+        waiting.0 = None;
+    }
+    metrics::ACTION_QUEUE_SECONDS
+        .with_label_values(&[class.label()])
+        .observe(started.elapsed().as_secs_f64());
+    Permit(())
+}
+
+/// Holds every move back for a while after hcloud throttled one.
+pub fn throttled() {
+    let until = Instant::now() + THROTTLE_PAUSE;
+    GATE.lock().unwrap().paused_until = Some(until);
+    println!(
+        "hcloud is throttling requests, holding moves back for {:?}",
+        THROTTLE_PAUSE
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep_until(until).await;
+        GATE.lock().unwrap().dispatch();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait(gate: &mut Gate, class: ActionClass) -> oneshot::Receiver<()> {
+        let (wake, receiver) = oneshot::channel();
+        let seq = gate.next_seq;
+        gate.next_seq += 1;
+        gate.waiters.push(Waiter { class, seq, wake });
+        receiver
+    }
+
+    #[test]
+    fn lets_the_most_urgent_and_longest_waiting_moves_through_first() {
+        let mut gate = Gate {
+            in_flight: max_in_flight(),
+            ..Default::default()
+        };
+        let mut rebalance = wait(&mut gate, ActionClass::Rebalance);
+        let mut first_reassign = wait(&mut gate, ActionClass::Reassign);
+        let mut second_reassign = wait(&mut gate, ActionClass::Reassign);
+        let mut failover = wait(&mut gate, ActionClass::Failover);
+        gate.dispatch();
+        assert!(failover.try_recv().is_err());
+
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            gate.in_flight -= 1;
+            gate.dispatch();
+            for (name, receiver) in [
+                ("failover", &mut failover),
+                ("first reassign", &mut first_reassign),
+                ("second reassign", &mut second_reassign),
+                ("rebalance", &mut rebalance),
+            ] {
+                if receiver.try_recv().is_ok() {
+                    order.push(name);
+                }
+            }
+        }
+        assert_eq!(
+            order,
+            vec!["failover", "first reassign", "second reassign", "rebalance"]
+        );
+        assert_eq!(gate.in_flight, max_in_flight());
+    }
+
+    #[test]
+    fn holds_moves_back_while_throttled() {
+        let mut gate = Gate {
+            paused_until: Some(Instant::now() + THROTTLE_PAUSE),
+            ..Default::default()
+        };
+        let mut failover = wait(&mut gate, ActionClass::Failover);
+        gate.dispatch();
+        assert!(failover.try_recv().is_err());
+
+        gate.paused_until = Some(Instant::now());
+        gate.dispatch();
+        assert!(failover.try_recv().is_ok());
+        assert_eq!(gate.in_flight, 1);
+    }
+
+    #[test]
+    fn skips_abandoned_waiters() {
+        let mut gate = Gate {
+            in_flight: max_in_flight(),
+            ..Default::default()
+        };
+        drop(wait(&mut gate, ActionClass::Failover));
+        let mut manual = wait(&mut gate, ActionClass::Manual);
+        gate.in_flight -= 1;
+        gate.dispatch();
+        assert!(manual.try_recv().is_ok());
+        assert!(gate.waiters.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! In-memory history of the last reconcile decisions, served on `/v1/traces`
+//! by the admin API so a support request can include what the
+//! controller saw and did recently without debug logging. They are also
+//! exported as OpenTelemetry spans when the OTLP export is enabled.
+
+use crate::otlp::{self, SpanKind};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(200);
+
+static TRACES: Lazy<Mutex<VecDeque<Trace>>> = Lazy::new(Default::default);
+
+tokio::task_local! {
+    static RESOURCE: String;
+    static ACTIONS: RefCell<Vec<String>>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Trace {
+    pub started_at: String,
+    pub duration_ms: u128,
+    /// Key of the reconciled object, e.g. `node/worker-1`.
+    pub resource: String,
+    /// What the object looked like when the reconcile started.
+    pub input: String,
+    /// Moves made or deliberately skipped, in order.
+    pub actions: Vec<String>,
+    pub outcome: String,
+}
+
+/// Keeps the last `capacity` traces, 0 disables tracing.
+pub fn set_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::Relaxed);
+}
+
+/// Notes a decision of the reconcile running on this task, ignored outside
+/// of one.
+pub fn record(action: String) {
+    otlp::event(&action);
+    let _ = ACTIONS.try_with(|actions| actions.borrow_mut().push(action));
+}
+
+/// Key of the object reconciled on this task, if any.
+pub fn current_resource() -> Option<String> {
+    RESOURCE.try_with(Clone::clone).ok()
+}
+
+/// Runs the reconcile of `resource` and keeps its trace. `outcome` tells how
+/// the result is reported.
+pub async fn scope<F, T>(
+    resource: String,
+    input: String,
+    outcome: impl FnOnce(&T) -> String,
+    future: F,
+) -> T
+where
+    F: Future<Output = T>,
+{
+    let started_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let started = Instant::now();
+    let mut outcome_text = String::new();
+    let (result, actions) = otlp::span(
+        "reconcile",
+        SpanKind::Internal,
+        vec![("resource", resource.clone()), ("input", input.clone())],
+        |(result, _)| {
+            outcome_text = outcome(result);
+            (outcome_text != "ok").then(|| outcome_text.clone())
+        },
+        RESOURCE.scope(
+            resource.clone(),
+            ACTIONS.scope(RefCell::new(vec![]), async {
+                let result = future.await;
+                (result, ACTIONS.with(|actions| actions.take()))
+            }),
+        ),
+    )
+    .await;
+    push(Trace {
+        started_at,
+        duration_ms: started.elapsed().as_millis(),
+        resource,
+        input,
+        actions,
+        outcome: outcome_text,
+    });
+    result
+}
+
+fn push(trace: Trace) {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if capacity == 0 {
+        return;
+    }
+    let mut traces = TRACES.lock().unwrap();
+    while traces.len() >= capacity {
+        traces.pop_front();
+    }
+    traces.push_back(trace);
+}
+
+/// The kept traces, newest first, optionally only those of `resource`.
+pub fn recent(resource: Option<&str>) -> Vec<Trace> {
+    TRACES
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|trace| resource.map(|r| trace.resource == r).unwrap_or(true))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,371 @@
+//! The conditions that make the controller evacuate a node, given as an
+//! expression such as `cordoned || (not-ready && tainted)`, with overrides for
+//! the node pools that define "should not hold public IPs" differently.
+
+use crate::taints;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::ResourceExt;
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::sync::RwLock;
+
+/// What nodes were evacuated on before triggers were configurable.
+pub const DEFAULT: &str = "cordoned || tainted";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// `spec.unschedulable` is set.
+    Cordoned,
+    /// The node carries one of the `--evacuate-taints`.
+    Tainted,
+    /// The `Ready` condition of the node is not `True`.
+    NotReady,
+    /// The node is being deleted.
+    Deleting,
+    /// The node has the annotation, with the value when one is given.
+    Annotation(String, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    Condition(Condition),
+    /// Every trigger holds.
+    All(Vec<Trigger>),
+    /// At least one trigger holds.
+    Any(Vec<Trigger>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Word(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Word(word) => write!(f, "{:?}", word),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            '&' if chars.peek() == Some(&'&') => Some(Token::And),
+            '|' if chars.peek() == Some(&'|') => Some(Token::Or),
+            c if c.is_whitespace() => None,
+            c => {
+                word.push(c);
+                continue;
+            }
+        };
+        if !word.is_empty() {
+            tokens.push(Token::Word(std::mem::take(&mut word)));
+        }
+        if let Some(token) = token {
+            if matches!(token, Token::And | Token::Or) {
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    tokens
+}
+
+fn parse_condition(word: &str) -> Result<Condition, String> {
+    match word {
+        "cordoned" => Ok(Condition::Cordoned),
+        "tainted" => Ok(Condition::Tainted),
+        "not-ready" => Ok(Condition::NotReady),
+        "deleting" => Ok(Condition::Deleting),
+        _ => match word.strip_prefix("annotation:") {
+            Some(annotation) if !annotation.is_empty() => Ok(match annotation.split_once('=') {
+                Some((key, value)) => Condition::Annotation(key.into(), Some(value.into())),
+                None => Condition::Annotation(annotation.into(), None),
+            }),
+            _ => Err(format!(
+                "unknown condition {:?}, expected cordoned, tainted, not-ready, deleting or annotation:<key>[=<value>]",
+                word
+            )),
+        },
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// `a || b || ...`, binding looser than `&&`.
+    fn any(&mut self) -> Result<Trigger, String> {
+        let mut triggers = vec![self.all()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            triggers.push(self.all()?);
+        }
+        Ok(match triggers.len() {
+            1 => triggers.remove(0),
+            _ => Trigger::Any(triggers),
+        })
+    }
+
+    /// `a && b && ...`.
+    fn all(&mut self) -> Result<Trigger, String> {
+        let mut triggers = vec![self.atom()?];
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            triggers.push(self.atom()?);
+        }
+        Ok(match triggers.len() {
+            1 => triggers.remove(0),
+            _ => Trigger::All(triggers),
+        })
+    }
+
+    fn atom(&mut self) -> Result<Trigger, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                let trigger = self.any()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(trigger),
+                    _ => Err("missing closing parenthesis".into()),
+                }
+            }
+            Some(Token::Word(word)) => parse_condition(&word).map(Trigger::Condition),
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of the expression".into()),
+        }
+    }
+}
+
+impl Trigger {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            position: 0,
+        };
+        let trigger = parser.any()?;
+        match parser.next() {
+            None => Ok(trigger),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    /// Why `node` has to be evacuated, when the trigger holds.
+    pub fn reason(&self, node: &KubeNode) -> Option<String> {
+        match self {
+            Trigger::Condition(condition) => condition.reason(node),
+            Trigger::All(triggers) => triggers
+                .iter()
+                .map(|trigger| trigger.reason(node))
+                .collect::<Option<Vec<_>>>()
+                .map(|reasons| reasons.join(" and ")),
+            Trigger::Any(triggers) => triggers.iter().find_map(|trigger| trigger.reason(node)),
+        }
+    }
+}
+
+impl Condition {
+    pub fn reason(&self, node: &KubeNode) -> Option<String> {
+        match self {
+            Condition::Cordoned => node
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.unschedulable)
+                .unwrap_or(false)
+                .then(|| "unschedulable".into()),
+            Condition::Tainted => taints::trigger(node).map(|key| format!("tainted {}", key)),
+            Condition::NotReady => {
+                let ready = node
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.conditions.as_ref())
+                    .and_then(|conditions| {
+                        conditions
+                            .iter()
+                            .find(|condition| condition.type_ == "Ready")
+                    })
+                    .map(|condition| condition.status == "True")
+                    .unwrap_or(false);
+                (!ready).then(|| "not ready".into())
+            }
+            Condition::Deleting => node
+                .metadata
+                .deletion_timestamp
+                .is_some()
+                .then(|| "being deleted".into()),
+            Condition::Annotation(key, value) => {
+                let matches = match (node.annotations().get(key), value) {
+                    (Some(actual), Some(value)) => actual == value,
+                    (actual, None) => actual.is_some(),
+                    (None, Some(_)) => false,
+                };
+                matches.then(|| format!("annotated {}", key))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Triggers {
+    pub default: Trigger,
+    /// Node label naming the pool of a node.
+    pub pool_label: Option<String>,
+    /// Triggers replacing the default for the nodes of a pool.
+    pub pools: Vec<(String, Trigger)>,
+}
+
+static TRIGGERS: Lazy<RwLock<Option<Triggers>>> = Lazy::new(Default::default);
+
+pub fn set_triggers(triggers: Triggers) {
+    *TRIGGERS.write().unwrap() = Some(triggers);
+}
+
+/// Why the IPs have to move off `node` according to the trigger of its pool.
+pub fn reason(node: &KubeNode) -> Option<String> {
+    let triggers = TRIGGERS.read().unwrap();
+    let triggers = match triggers.as_ref() {
+        Some(triggers) => triggers,
+        None => return Trigger::parse(DEFAULT).unwrap().reason(node),
+    };
+    let pool = triggers
+        .pool_label
+        .as_ref()
+        .and_then(|label| node.labels().get(label));
+    triggers
+        .pools
+        .iter()
+        .find(|(name, _)| Some(name) == pool)
+        .map(|(_, trigger)| trigger)
+        .unwrap_or(&triggers.default)
+        .reason(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(condition: Condition) -> Trigger {
+        Trigger::Condition(condition)
+    }
+
+    fn node(value: serde_json::Value) -> KubeNode {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            Trigger::parse("cordoned || not-ready && deleting"),
+            Ok(Trigger::Any(vec![
+                condition(Condition::Cordoned),
+                Trigger::All(vec![
+                    condition(Condition::NotReady),
+                    condition(Condition::Deleting),
+                ]),
+            ]))
+        );
+        assert_eq!(
+            Trigger::parse("(cordoned||not-ready)&&annotation:example.com/drain=now"),
+            Ok(Trigger::All(vec![
+                Trigger::Any(vec![
+                    condition(Condition::Cordoned),
+                    condition(Condition::NotReady),
+                ]),
+                condition(Condition::Annotation(
+                    "example.com/drain".into(),
+                    Some("now".into())
+                )),
+            ]))
+        );
+        assert_eq!(
+            Trigger::parse(" annotation:example.com/drain "),
+            Ok(condition(Condition::Annotation(
+                "example.com/drain".into(),
+                None
+            )))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for (expression, error) in [
+            ("", "unexpected end of the expression"),
+            ("cordoned &&", "unexpected end of the expression"),
+            ("(cordoned", "missing closing parenthesis"),
+            ("cordoned)", "unexpected )"),
+            ("cordoned not-ready", "unexpected \"not-ready\""),
+            ("|| cordoned", "unexpected ||"),
+        ] {
+            assert_eq!(
+                Trigger::parse(expression),
+                Err(error.into()),
+                "{:?}",
+                expression
+            );
+        }
+        assert!(Trigger::parse("rebooting")
+            .unwrap_err()
+            .starts_with("unknown condition \"rebooting\""));
+        assert!(Trigger::parse("annotation:").is_err());
+    }
+
+    #[test]
+    fn explains_why_a_node_is_evacuated() {
+        let trigger = Trigger::parse("deleting || cordoned && not-ready").unwrap();
+        let cordoned = node(serde_json::json!({
+            "metadata": {"name": "a"},
+            "spec": {"unschedulable": true},
+            "status": {"conditions": [{"type": "Ready", "status": "True"}]},
+        }));
+        assert_eq!(trigger.reason(&cordoned), None);
+
+        let unready = node(serde_json::json!({
+            "metadata": {"name": "a"},
+            "spec": {"unschedulable": true},
+            "status": {"conditions": [{"type": "Ready", "status": "Unknown"}]},
+        }));
+        assert_eq!(
+            trigger.reason(&unready),
+            Some("unschedulable and not ready".into())
+        );
+
+        let annotated = node(serde_json::json!({
+            "metadata": {"name": "a", "annotations": {"example.com/drain": "later"}},
+        }));
+        let now = Trigger::parse("annotation:example.com/drain=now").unwrap();
+        let any = Trigger::parse("annotation:example.com/drain").unwrap();
+        assert_eq!(now.reason(&annotated), None);
+        assert_eq!(
+            any.reason(&annotated),
+            Some("annotated example.com/drain".into())
+        );
+    }
+}
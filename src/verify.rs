@@ -0,0 +1,142 @@
+//! Reachability check of floating IPs after they moved, since a node can
+//! take the assignment without ever configuring the address. An IP that
+//! stays dark for the whole window is moved to another available server.
+
+use crate::health::HealthCheck;
+use crate::pools;
+use crate::projects::Project;
+use crate::server_failures;
+use crate::throttle::ActionClass;
+use crate::{
+    audit, available_hc_server_ids, fetch_floating_ips, fetch_servers, is_dry_run, metrics,
+    move_floating_ip, notify, placement, Error,
+};
+use hcloud::models::FloatingIp;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::runtime::reflector::Store;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct VerifyConfig {
+    /// Probed on the floating IP itself.
+    pub health_check: HealthCheck,
+    /// How long the IP may stay dark before it is moved again.
+    pub window: Duration,
+}
+
+async fn answers_within(config: &VerifyConfig, ip: IpAddr) -> bool {
+    let deadline = Instant::now() + config.window;
+    while Instant::now() < deadline {
+        if config.health_check.probe(ip).await {
+            return true;
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+    false
+}
+
+/// Checks in the background that `fip` answers on `server_id`, it was just
+/// moved to.
+pub fn spawn(
+    config: &VerifyConfig,
+    project: &Project,
+    nodes: &Store<KubeNode>,
+    fip: &FloatingIp,
+    server_id: i32,
+) {
+    if is_dry_run() {
+        return;
+    }
+    let ip: IpAddr = match fip.ip.parse() {
+        Ok(ip) => ip,
+        // Only the network of IPv6 floating IPs is known.
+        Err(_) => return,
+    };
+    let config = config.clone();
+    let project = project.clone();
+    let nodes = nodes.clone();
+    let fip_id = fip.id;
+    tokio::spawn(async move {
+        if let Err(err) = verify(&config, &project, &nodes, fip_id, ip, server_id).await {
+            println!("verification of {} failed: {}", ip, err);
+        }
+    });
+}
+
+async fn verify(
+    config: &VerifyConfig,
+    project: &Project,
+    nodes: &Store<KubeNode>,
+    fip_id: i32,
+    ip: IpAddr,
+    mut server_id: i32,
+) -> Result<(), Error> {
+    let mut dark = HashSet::new();
+    loop {
+        if answers_within(config, ip).await {
+            metrics::VERIFICATIONS
+                .with_label_values(&["reachable"])
+                .inc();
+            return Ok(());
+        }
+        metrics::VERIFICATIONS.with_label_values(&["dark"]).inc();
+        dark.insert(server_id);
+
+        let hcloud_conf = &project.conf();
+        let fips = fetch_floating_ips(hcloud_conf).await?;
+        let fip = match fips.iter().find(|fip| fip.id == fip_id) {
+            Some(fip) if fip.server == Some(server_id) => fip,
+            // Moved by a reconcile meanwhile, which verifies it in turn.
+            _ => return Ok(()),
+        };
+        let project_servers = fetch_servers(hcloud_conf).await?;
+        let pool_nodes = nodes.state();
+        let mut candidates: Vec<i32> = available_hc_server_ids(nodes)
+            .into_iter()
+            .filter(|id| {
+                server_failures::target_blocker(&project_servers, *id).is_none()
+                    && !dark.contains(id)
+            })
+            .filter(|id| pools::allows(&pool_nodes, fip, *id))
+            .collect();
+        candidates.sort();
+        let target = match placement::least_loaded(
+            &candidates,
+            &mut placement::load(&fips),
+            &HashMap::new(),
+        ) {
+            Some(target) => target,
+            None => {
+                println!(
+                    "{} is not answering on server {} and no other server is left, leaving it there",
+                    ip, server_id
+                );
+                notify::no_target(&fip.ip, Some(server_id));
+                audit::skipped(
+                    &fip.ip,
+                    Some(server_id),
+                    "failover",
+                    "not answering, no other server left",
+                );
+                return Ok(());
+            }
+        };
+        println!(
+            "{} is not answering on server {} after {:?}, moving it to {}",
+            ip, server_id, config.window, target
+        );
+        // Held back, e.g. paused, or moved meanwhile by a reconcile.
+        if !move_floating_ip(hcloud_conf, fip, target, ActionClass::Failover).await? {
+            return Ok(());
+        }
+        metrics::VERIFICATIONS
+            .with_label_values(&["refailover"])
+            .inc();
+        server_id = target;
+    }
+}